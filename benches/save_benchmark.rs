@@ -0,0 +1,84 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use manic::async_client::{Chunk, ChunkVec};
+use std::time::Duration;
+use tokio::runtime::Builder;
+
+/// 8 MiB split into 1 KiB chunks
+const CHUNKS: u64 = 8192;
+const CHUNK_SIZE: u64 = 1024;
+
+fn chunk_vec() -> ChunkVec {
+    let parts = (0..CHUNKS)
+        .map(|i| {
+            let mut chunk = Chunk::new(i * CHUNK_SIZE, (i + 1) * CHUNK_SIZE - 1).unwrap();
+            chunk.buf = vec![i as u8; CHUNK_SIZE as usize];
+            chunk
+        })
+        .collect();
+    ChunkVec::from_parts(parts).unwrap()
+}
+
+async fn save(data: &ChunkVec, buffer_size: Option<usize>) -> manic::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("out");
+    match buffer_size {
+        Some(size) => data.save_to_file_buffered(&path, size).await,
+        None => data.save_to_file(&path).await,
+    }
+}
+
+fn save_bench(c: &mut Criterion) {
+    let data = chunk_vec();
+    let mut group = c.benchmark_group("save_bench");
+    for buffer_size in [None, Some(64 << 10), Some(1 << 20)] {
+        let name = match buffer_size {
+            Some(size) => format!("buffered_{}", size),
+            None => "per_chunk".to_string(),
+        };
+        group.bench_with_input(
+            BenchmarkId::new("save_bench", name),
+            &buffer_size,
+            |b, s| {
+                b.to_async(
+                    Builder::new_multi_thread()
+                        .worker_threads(10)
+                        .enable_all()
+                        .build()
+                        .unwrap(),
+                )
+                .iter(|| save(&data, *s))
+            },
+        );
+    }
+}
+
+/// Chunks in reverse order, as they may come back from the requests, sorted when assembled
+fn parts(count: u64) -> Vec<Chunk> {
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut chunk = Chunk::new(i * CHUNK_SIZE, (i + 1) * CHUNK_SIZE - 1).unwrap();
+            chunk.pos = i;
+            chunk.buf = vec![i as u8; CHUNK_SIZE as usize];
+            chunk
+        })
+        .collect()
+}
+
+/// Run with and without the `rayon` feature to compare the parallel and sequential paths
+fn assemble_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("assemble_bench");
+    for count in [8, 64, CHUNKS] {
+        let input = parts(count);
+        group.bench_with_input(BenchmarkId::new("assemble_bench", count), &input, |b, i| {
+            b.iter_batched(|| i.clone(), ChunkVec::from, BatchSize::LargeInput)
+        });
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(20)).sample_size(10);
+    targets = save_bench, assemble_bench
+}
+criterion_main!(benches);
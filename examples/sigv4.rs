@@ -0,0 +1,182 @@
+//! Reference AWS SigV4 [`RequestSigner`] for downloads from private S3 buckets
+//!
+//! Reads the credentials from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_REGION`:
+//!
+//! `cargo run --example sigv4 -- https://bucket.s3.eu-west-1.amazonaws.com/path/to/object`
+use futures::future::BoxFuture;
+use manic::async_client::Request;
+use manic::header::{HeaderValue, AUTHORIZATION, HOST};
+use manic::{Downloader, RequestSigner, Result};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Digest of the empty payload, downloads never send a body
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+struct SigV4 {
+    access_key: String,
+    secret_key: String,
+    region: String,
+    service: String,
+}
+
+impl fmt::Debug for SigV4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SigV4")
+            .field("access_key", &self.access_key)
+            .field("region", &self.region)
+            .field("service", &self.service)
+            .finish()
+    }
+}
+
+impl RequestSigner for SigV4 {
+    fn sign<'a>(&'a self, req: &'a mut Request) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let (amz_date, date) = timestamp();
+            let host = req.url().host_str().unwrap_or_default();
+            let host = match req.url().port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_string(),
+            };
+            let headers = req.headers_mut();
+            headers.insert(HOST, header_value(&host));
+            headers.insert("x-amz-date", header_value(&amz_date));
+            headers.insert("x-amz-content-sha256", header_value(EMPTY_SHA256));
+
+            let mut canonical = req
+                .headers()
+                .iter()
+                .map(|(k, v)| {
+                    let v = v.to_str().unwrap_or_default().trim().to_string();
+                    (k.as_str().to_string(), v)
+                })
+                .collect::<Vec<_>>();
+            canonical.sort();
+            let signed_headers = canonical
+                .iter()
+                .map(|(k, _)| k.as_str())
+                .collect::<Vec<_>>()
+                .join(";");
+            let canonical_headers = canonical
+                .iter()
+                .map(|(k, v)| format!("{}:{}\n", k, v))
+                .collect::<String>();
+            let mut query = req
+                .url()
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .filter(|x| !x.is_empty())
+                .map(|x| match x.contains('=') {
+                    true => x.to_string(),
+                    false => format!("{}=", x),
+                })
+                .collect::<Vec<_>>();
+            query.sort();
+            let canonical_request = format!(
+                "{}\n{}\n{}\n{}\n{}\n{}",
+                req.method(),
+                req.url().path(),
+                query.join("&"),
+                canonical_headers,
+                signed_headers,
+                EMPTY_SHA256
+            );
+
+            let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+            let string_to_sign = format!(
+                "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+                amz_date,
+                scope,
+                hex(&Sha256::digest(canonical_request.as_bytes()))
+            );
+            let key = [self.region.as_str(), self.service.as_str(), "aws4_request"]
+                .iter()
+                .fold(
+                    hmac(
+                        format!("AWS4{}", self.secret_key).as_bytes(),
+                        date.as_bytes(),
+                    ),
+                    |key, part| hmac(&key, part.as_bytes()),
+                );
+            let signature = hex(&hmac(&key, string_to_sign.as_bytes()));
+            let authorization = format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, scope, signed_headers, signature
+            );
+            req.headers_mut()
+                .insert(AUTHORIZATION, header_value(&authorization));
+            Ok(())
+        })
+    }
+}
+
+fn header_value(value: &str) -> HeaderValue {
+    HeaderValue::from_str(value).expect("SigV4 header values are ASCII")
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.iter().map(|b| b ^ 0x36).collect::<Vec<u8>>());
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(block.iter().map(|b| b ^ 0x5c).collect::<Vec<u8>>());
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Current UTC time as `YYYYMMDDTHHMMSSZ` and `YYYYMMDD`
+fn timestamp() -> (String, String) {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock is past the epoch")
+        .as_secs();
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let rem = secs % 86400;
+    let time = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    );
+    (time, date)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let url = std::env::args().nth(1).expect("usage: sigv4 <object url>");
+    let env = |key: &str| std::env::var(key).unwrap_or_else(|_| panic!("{} is not set", key));
+    let signer = SigV4 {
+        access_key: env("AWS_ACCESS_KEY_ID"),
+        secret_key: env("AWS_SECRET_ACCESS_KEY"),
+        region: env("AWS_REGION"),
+        service: "s3".to_string(),
+    };
+    let downloader = Downloader::new_signed(url, 5, Arc::new(signer)).await?;
+    downloader.download_and_save(".").await?;
+    Ok(())
+}
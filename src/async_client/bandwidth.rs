@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Length of the window the speed is sampled over
+const SAMPLE_MS: u64 = 1000;
+
+/// Byte counters shared by every chunk request of a [`MultiDownloader`][super::MultiDownloader] batch
+///
+/// Only atomics so reading them while the batch runs never waits on a download
+#[derive(Debug)]
+pub(crate) struct Bandwidth {
+    origin: Instant,
+    total: AtomicU64,
+    downloaded: AtomicU64,
+    /// Milliseconds since `origin` the current sample started at
+    window_start: AtomicU64,
+    window_bytes: AtomicU64,
+    /// Bytes per second over the last finished sample
+    rate: AtomicU64,
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bandwidth {
+    pub(crate) fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            total: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            window_start: AtomicU64::new(0),
+            window_bytes: AtomicU64::new(0),
+            rate: AtomicU64::new(0),
+        }
+    }
+    fn now(&self) -> u64 {
+        self.origin.elapsed().as_millis() as u64
+    }
+    /// Start counting a new batch of `total` bytes
+    pub(crate) fn reset(&self, total: u64) {
+        self.total.store(total, Ordering::Relaxed);
+        self.downloaded.store(0, Ordering::Relaxed);
+        self.window_bytes.store(0, Ordering::Relaxed);
+        self.window_start.store(self.now(), Ordering::Relaxed);
+        self.rate.store(0, Ordering::Relaxed);
+    }
+    /// Expect `n` more bytes, for a download that joined the running batch
+    pub(crate) fn grow(&self, n: u64) {
+        self.total.fetch_add(n, Ordering::Relaxed);
+    }
+    /// Count `n` received bytes, closing the sample once it's old enough
+    pub(crate) fn add(&self, n: u64) {
+        self.downloaded.fetch_add(n, Ordering::Relaxed);
+        self.window_bytes.fetch_add(n, Ordering::Relaxed);
+        let now = self.now();
+        let start = self.window_start.load(Ordering::Relaxed);
+        let elapsed = now.saturating_sub(start);
+        // Only the request winning the exchange closes the sample
+        if elapsed >= SAMPLE_MS
+            && self
+                .window_start
+                .compare_exchange(start, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            let bytes = self.window_bytes.swap(0, Ordering::Relaxed);
+            self.rate.store(bytes * 1000 / elapsed, Ordering::Relaxed);
+        }
+    }
+    /// Give back bytes of a failed attempt that's about to be retried
+    pub(crate) fn release(&self, n: u64) {
+        let _ = self
+            .downloaded
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                Some(x.saturating_sub(n))
+            });
+    }
+    pub(crate) fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+    pub(crate) fn remaining(&self) -> u64 {
+        self.total
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.downloaded())
+    }
+    /// Bytes per second, estimated from the running sample until the first one is finished
+    /// and zero once nothing arrived for two samples
+    pub(crate) fn speed(&self) -> u64 {
+        let elapsed = self
+            .now()
+            .saturating_sub(self.window_start.load(Ordering::Relaxed));
+        if elapsed >= 2 * SAMPLE_MS {
+            return 0;
+        }
+        match self.rate.load(Ordering::Relaxed) {
+            0 => self.window_bytes.load(Ordering::Relaxed) * 1000 / elapsed.max(1),
+            rate => rate,
+        }
+    }
+}
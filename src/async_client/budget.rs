@@ -0,0 +1,46 @@
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Cap on the downloaded data a [`MultiDownloader`][super::MultiDownloader] batch holds in memory
+///
+/// A download reserves its length before it's started and keeps the reservation while its
+/// chunks are in memory, until they're spilled to the staging directory or its
+/// [`Downloaded`][super::Downloaded] entry is dropped
+#[derive(Debug, Clone)]
+pub(crate) struct MemoryBudget {
+    sem: Arc<Semaphore>,
+    limit: u32,
+}
+
+/// Share of a [`MemoryBudget`], given back when dropped
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(bytes: u64) -> Self {
+        // Downloads reserve at most `limit` so one larger than the whole budget still gets through
+        let limit = bytes.clamp(1, u32::MAX as u64) as u32;
+        Self {
+            sem: Arc::new(Semaphore::new(limit as usize)),
+            limit,
+        }
+    }
+    /// Reserve `len` bytes, capped at the whole budget, `None` if they aren't available
+    pub(crate) fn try_reserve(&self, len: u64) -> Option<Reservation> {
+        let permit = self
+            .sem
+            .clone()
+            .try_acquire_many_owned(self.share(len))
+            .ok()?;
+        Some(Reservation { _permit: permit })
+    }
+    /// Whether [`try_reserve`][Self::try_reserve] would get `len` bytes right now
+    pub(crate) fn fits(&self, len: u64) -> bool {
+        self.sem.available_permits() >= self.share(len) as usize
+    }
+    fn share(&self, len: u64) -> u32 {
+        std::cmp::min(len, self.limit as u64) as u32
+    }
+}
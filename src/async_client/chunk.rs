@@ -1,17 +1,26 @@
-use super::downloader::{join_all, join_all_futures};
-use super::Client;
-use crate::header::RANGE;
+use super::downloader::join_all;
+use super::request::RequestContext;
+use super::service::{self, ChunkRequest, ChunkService};
+use crate::hash;
+use crate::io::{write_all_at, write_sequential, HASHED_WRITE_BUFFER};
+use crate::partial::PartialFile;
+use crate::retry::{RangeFailures, Retry};
 use crate::Hash;
+use crate::HttpVersionPolicy;
+use crate::JoinPolicy;
+use crate::ToUrl;
 use crate::{ManicError, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
 #[cfg(feature = "progress")]
 use indicatif::ProgressBar;
+#[cfg(feature = "rayon")]
 use rayon::prelude::*;
-use std::io::SeekFrom;
+use reqwest::{Client, Url};
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::fs::File;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt};
-use tracing::{info, instrument};
+use tracing::{debug, instrument};
 
 /// Iterator over remote file chunks that returns a formatted [`RANGE`][reqwest::header::RANGE] header value
 #[derive(Debug, Clone, Copy)]
@@ -28,38 +37,192 @@ pub struct ChunkVec {
 }
 
 impl ChunkVec {
+    /// Save to `path`, the data goes to `<path>.part` first which is removed if saving fails or is cancelled
     pub async fn save_to_file<T: AsRef<Path>>(&self, path: T) -> Result<()> {
-        let f = File::create(path).await?;
-        self.save(f).await
+        let partial = PartialFile::new(path.as_ref());
+        let f = File::create(partial.path()).await?;
+        self.save(f).await?;
+        Ok(partial.persist()?)
+    }
+    /// Save to `path` like [`save_to_file`][Self::save_to_file], but check the file read back from disk
+    /// against `hash` instead of the buffer, it's only moved into place if it matches
+    pub async fn save_and_verify<T: AsRef<Path>>(&self, path: T, hash: Hash) -> Result<()> {
+        Ok(self.stage_verified(path.as_ref(), hash).await?.persist()?)
+    }
+    /// Everything [`save_and_verify`][Self::save_and_verify] does but the move into place,
+    /// so more checks can run on the `.part` file
+    pub(crate) async fn stage_verified(&self, path: &Path, hash: Hash) -> Result<PartialFile> {
+        let partial = PartialFile::new(path);
+        let f = File::create(partial.path()).await?;
+        self.save(f).await?;
+        let part = partial.path().to_path_buf();
+        tokio::task::spawn_blocking(move || hash::verify_file(&part, hash)).await??;
+        Ok(partial)
+    }
+    /// Save to `path` like [`save_to_file`][Self::save_to_file], but write the chunks in order through
+    /// a buffer of `buffer_size` bytes instead of once per chunk, fewer syscalls for many small chunks
+    pub async fn save_to_file_buffered<T: AsRef<Path>>(
+        &self,
+        path: T,
+        buffer_size: usize,
+    ) -> Result<()> {
+        let partial = PartialFile::new(path.as_ref());
+        let f = File::create(partial.path()).await?;
+        self.save_buffered(f, buffer_size).await?;
+        Ok(partial.persist()?)
+    }
+    /// Save to `path` like [`save_to_file`][Self::save_to_file], writing the chunks in offset order
+    /// and hashing them in the same pass, returns the hex SHA256 of the data
+    pub async fn save_and_hash<T: AsRef<Path>>(&self, path: T) -> Result<String> {
+        let partial = PartialFile::new(path.as_ref());
+        let f = File::create(partial.path()).await?;
+        let hash = self
+            .save_hashed(f, Hash::new_sha256(String::new()), HASHED_WRITE_BUFFER)
+            .await?;
+        partial.persist()?;
+        Ok(hash.finalize())
+    }
+    /// Write the chunks in order through a buffer of `buffer_size` bytes, feeding each to `hash` on the way
+    pub(crate) async fn save_hashed(
+        &self,
+        output: File,
+        mut hash: Hash,
+        buffer_size: usize,
+    ) -> Result<Hash> {
+        let output = output.into_std().await;
+        let chunks = self.chunks.clone();
+        tokio::task::spawn_blocking(move || {
+            let parts = chunks.iter().map(|x| {
+                hash.update(&x.buf);
+                (x.low, x.buf.as_slice())
+            });
+            write_sequential(&output, parts, buffer_size)?;
+            output.sync_all()?;
+            Ok(hash)
+        })
+        .await?
+    }
+    pub(crate) async fn save_buffered(&self, output: File, buffer_size: usize) -> Result<()> {
+        let output = output.into_std().await;
+        let chunks = self.chunks.clone();
+        tokio::task::spawn_blocking(move || {
+            let parts = chunks.iter().map(|x| (x.low, x.buf.as_slice()));
+            write_sequential(&output, parts, buffer_size)?;
+            output.sync_all()
+        })
+        .await??;
+        Ok(())
     }
     pub(crate) async fn save(&self, output: File) -> Result<()> {
+        let output = Arc::new(output.into_std().await);
         let mut fut_vec = Vec::new();
         for i in self.chunks.iter() {
-            let f = output.try_clone().await?;
+            let f = output.clone();
             let c = i.clone();
-            fut_vec.push(tokio::spawn(c.save(f)))
+            fut_vec.push(tokio::task::spawn_blocking(move || c.save(&f)))
         }
-        join_all(fut_vec).await?;
-        output.sync_all().await?;
+        join_all(fut_vec, JoinPolicy::AllRequired).await?;
+        tokio::task::spawn_blocking(move || output.sync_all()).await??;
         Ok(())
     }
+    /// Total size of the downloaded data
+    pub(crate) fn byte_len(&self) -> u64 {
+        self.chunks.iter().map(|x| x.buf.len() as u64).sum()
+    }
+    /// The chunks' data in offset order
+    pub(crate) fn blocks(&self) -> Vec<&[u8]> {
+        self.chunks.iter().map(|x| x.buf.as_slice()).collect()
+    }
+    /// The chunks' buffers in offset order
+    #[cfg(feature = "decompress")]
+    pub(crate) fn into_blocks(self) -> Vec<Vec<u8>> {
+        Arc::try_unwrap(self.chunks)
+            .unwrap_or_else(|shared| shared.as_ref().clone())
+            .into_iter()
+            .map(|x| x.buf)
+            .collect()
+    }
     pub async fn to_vec(&self) -> Vec<u8> {
         self.chunks
             .iter()
             .flat_map(|x| x.buf.to_vec())
             .collect::<Vec<u8>>()
     }
-    pub(crate) async fn verify(&self, mut hash: Hash) -> Result<()> {
-        self.chunks
-            .iter()
-            .for_each(|x| hash.update(x.buf.as_slice()));
-        hash.verify()
+    /// Hash the chunks and compare against `hash`
+    ///
+    /// The progress bar switches to a "verifying" phase counting the hashed bytes from zero,
+    /// so a long hash pass doesn't look like a hang at 100%
+    pub(crate) async fn verify(
+        &self,
+        mut hash: Hash,
+        #[cfg(feature = "progress")] pb: Option<&ProgressBar>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        #[cfg(feature = "progress")]
+        if let Some(bar) = pb {
+            bar.set_message("verifying");
+            bar.set_length(self.chunks.iter().map(|x| x.buf.len() as u64).sum());
+            bar.set_position(0);
+        }
+        for chunk in self.chunks.iter() {
+            hash.update(chunk.buf.as_ref());
+            #[cfg(feature = "progress")]
+            if let Some(bar) = pb {
+                bar.inc(chunk.buf.len() as u64);
+            }
+        }
+        let res = hash.verify();
+        debug!("Verified in {:?}", start.elapsed());
+        #[cfg(feature = "progress")]
+        if let (Some(bar), Ok(())) = (pb, &res) {
+            bar.set_message("verified");
+        }
+        res
+    }
+}
+
+impl ChunkVec {
+    /// Wrap content that was fetched in one piece
+    pub(crate) fn from_buf(buf: Vec<u8>) -> Self {
+        Self::from(vec![Chunk::whole(buf)])
+    }
+}
+
+impl ChunkVec {
+    /// Reassemble chunks fetched with custom scheduling so they can be saved, verified or collected
+    ///
+    /// The chunks are ordered by offset, each one has to hold exactly its range and together
+    /// they have to cover the content from byte 0 without gaps or overlaps
+    pub fn from_parts(mut chunks: Vec<Chunk>) -> Result<Self> {
+        chunks.sort_unstable_by_key(|x| x.low);
+        let mut next = 0;
+        for (i, chunk) in chunks.iter_mut().enumerate() {
+            if chunk.hi < chunk.low || chunk.buf.len() as u64 != chunk.hi - chunk.low + 1 {
+                return Err(ManicError::InvalidChunk(format!(
+                    "{} holds {} bytes",
+                    chunk.bytes,
+                    chunk.buf.len()
+                )));
+            }
+            if chunk.low != next {
+                return Err(ManicError::InvalidChunk(format!(
+                    "{} doesn't start at byte {}",
+                    chunk.bytes, next
+                )));
+            }
+            next = chunk.hi + 1;
+            chunk.pos = i as u64 + 1;
+        }
+        Ok(Self::from(chunks))
     }
 }
 
 impl From<Vec<Chunk>> for ChunkVec {
     fn from(mut v: Vec<Chunk>) -> Self {
+        #[cfg(feature = "rayon")]
         v.par_sort_unstable_by(|a, b| a.pos.cmp(&b.pos));
+        #[cfg(not(feature = "rayon"))]
+        v.sort_unstable_by_key(|x| x.pos);
         Self {
             chunks: Arc::new(v),
         }
@@ -76,6 +239,45 @@ pub struct Chunk {
     pub bytes: String,
 }
 
+impl Chunk {
+    /// Empty chunk for bytes `low..=hi`, fill `buf` before passing it to [`ChunkVec::from_parts`]
+    pub fn new(low: u64, hi: u64) -> Result<Self> {
+        if hi < low {
+            return Err(ManicError::InvalidChunk(format!(
+                "range {}-{} ends before it starts",
+                low, hi
+            )));
+        }
+        Ok(Self {
+            buf: Vec::new(),
+            low,
+            hi,
+            pos: 1,
+            len: hi - low,
+            bytes: format!("bytes={}-{}", low, hi),
+        })
+    }
+}
+
+impl Chunk {
+    /// The only chunk of a file fetched in one request
+    pub(crate) fn whole(buf: Vec<u8>) -> Self {
+        let hi = (buf.len() as u64).saturating_sub(1);
+        Self {
+            buf,
+            low: 0,
+            hi,
+            pos: 1,
+            len: hi,
+            bytes: format!("bytes=0-{}", hi),
+        }
+    }
+    /// Whether the bytes of `other` are part of this chunk, true for every chunk of a whole file
+    pub(crate) fn covers(&self, other: &Chunk) -> bool {
+        self.low <= other.low && other.hi <= self.hi
+    }
+}
+
 impl AsRef<Chunk> for Chunk {
     fn as_ref(&self) -> &Chunk {
         self
@@ -84,30 +286,16 @@ impl AsRef<Chunk> for Chunk {
 
 impl Chunk {
     #[instrument(skip(self, output), fields(low=%self.low, hi=%self.hi, range=%self.bytes, pos=%self.pos))]
-    pub(crate) async fn save(self, mut output: File) -> Result<()> {
-        output.seek(SeekFrom::Start(self.low)).await?;
-        info!("Seeked");
-        output.write_all(self.buf.as_slice()).await?;
+    pub(crate) fn save(&self, output: &std::fs::File) -> Result<()> {
+        write_all_at(output, self.buf.as_slice(), self.low)?;
         Ok(())
     }
-    #[instrument(skip(self, client, pb), fields(range = %self.bytes))]
-    pub(crate) async fn download(
-        mut self,
-        client: &Client,
-        url: String,
-        #[cfg(feature = "progress")] pb: Option<ProgressBar>,
-    ) -> Result<Self> {
-        let resp = client
-            .get(url.to_string())
-            .header(RANGE, self.bytes.clone())
-            .send()
+    #[instrument(skip(self, url, service), fields(range = %self.bytes))]
+    pub(crate) async fn download(mut self, url: &Url, service: &dyn ChunkService) -> Result<Self> {
+        let resp = service
+            .call(ChunkRequest::new(url.clone(), self.low..=self.hi))
             .await?;
-        let b = resp.bytes().await?;
-        #[cfg(feature = "progress")]
-        if let Some(bar) = pb {
-            bar.inc(b.len() as u64);
-        }
-        self.buf = b.to_vec();
+        self.buf = resp.bytes;
         Ok(self)
     }
 }
@@ -129,24 +317,62 @@ impl Chunks {
             current_pos: 1,
         })
     }
+    /// Download every chunk of `url` at once, failing if any of them fails
     pub async fn download(
         &self,
         client: &Client,
         url: String,
         #[cfg(feature = "progress")] pb: Option<ProgressBar>,
     ) -> Result<ChunkVec> {
-        let fut_vec = self
-            .map(|x| {
-                x.download(
-                    client,
-                    url.clone(),
-                    #[cfg(feature = "progress")]
-                    pb.clone(),
-                )
-            })
-            .collect::<Vec<_>>();
-        let list = join_all_futures(fut_vec).await?;
-        Ok(ChunkVec::from(list))
+        let url = url.to_url()?;
+        let ctx = Arc::new(RequestContext {
+            client: client.clone(),
+            signer: None,
+            map: None,
+            bandwidth: None,
+            rate_limit: None,
+            scope: None,
+            pause: None,
+            limit: None,
+            retry: Retry::default(),
+            // Nothing to fall back to here, a range the server refuses is an error
+            range_failures: RangeFailures::new(0),
+            http_version: HttpVersionPolicy::default(),
+            if_range: None,
+            #[cfg(feature = "progress")]
+            pb,
+        });
+        let service = service::stack(&ctx, None);
+        let mut done = Vec::new();
+        fetch_chunks(*self, &url, service.as_ref(), usize::MAX, &mut done).await?;
+        Ok(ChunkVec::from(done))
+    }
+}
+
+/// Download `chunks` into `done` with at most `workers` requests in flight
+///
+/// Every chunk is required, so the first failure is returned as is and the requests still
+/// in flight are dropped. Chunks that finished stay in `done`, also when the future is dropped
+pub(crate) async fn fetch_chunks(
+    chunks: impl IntoIterator<Item = Chunk>,
+    url: &Url,
+    service: &dyn ChunkService,
+    workers: usize,
+    done: &mut Vec<Chunk>,
+) -> Result<()> {
+    let mut queue = chunks.into_iter().map(|x| x.download(url, service));
+    let mut running = FuturesUnordered::new();
+    loop {
+        while running.len() < workers.max(1) {
+            match queue.next() {
+                Some(fut) => running.push(fut),
+                None => break,
+            }
+        }
+        match running.next().await {
+            Some(res) => done.push(res?),
+            None => return Ok(()),
+        }
     }
 }
 
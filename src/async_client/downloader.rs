@@ -1,30 +1,138 @@
 #![allow(dead_code)]
-use super::chunk::{ChunkVec, Chunks};
-use super::multi::Downloaded;
+use super::bandwidth::Bandwidth;
+use super::budget::Reservation;
+use super::chunk::{self, Chunk, ChunkVec, Chunks};
+use super::handle::{Control, DownloadHandle, DownloadState};
+use super::multi::{Downloaded, Payload};
+use super::request::{send, Hooks, RequestContext, RequestMap, RequestParts, RequestSigner};
+use super::service::{self, ChunkService, ServiceStack};
+use crate::events;
+use crate::filename;
+use crate::info::{check_mirrors, RemoteInfo};
+use crate::io::HASHED_WRITE_BUFFER;
+use crate::limit::{MemoryCap, SizeLimit};
+use crate::local::{is_local, local_len, read_local};
+use crate::partial::PartialFile;
+use crate::retry::{is_range_failure, RangeFailures, Retry};
+#[cfg(feature = "sig-verify")]
+use crate::signature::Signed;
+use crate::to_url::{check_scheme, check_workers};
+use crate::util::{PauseToken, RateLimiter, RequestScheduler, Scope};
+use crate::ClientOptions;
 use crate::Hash;
+use crate::JoinPolicy;
+use crate::LockPolicy;
 use crate::ManicError;
+use crate::MetadataCache;
 use crate::Result;
+#[cfg(feature = "sig-verify")]
+use crate::SignaturePolicy;
+use crate::ToUrl;
+#[cfg(feature = "decompress")]
+use crate::{Compression, Decompressed};
+use crate::{HttpVersionPolicy, SocketOptions};
+#[cfg(feature = "metalink")]
+use crate::{MetalinkFile, Pieces};
+#[cfg(feature = "builder")]
+use derive_builder::Builder;
 use futures::Future;
 #[cfg(feature = "progress")]
 use indicatif::ProgressBar;
-use reqwest::header::{CONTENT_LENGTH, RANGE};
+use reqwest::header::{CONTENT_LENGTH, IF_MODIFIED_SINCE, IF_NONE_MATCH, RANGE};
 use reqwest::Client;
+use reqwest::{StatusCode, Url};
+use std::net::IpAddr;
 use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
-use tracing::{debug, instrument};
+use tracing::{debug, instrument, warn};
 
-#[derive(Debug, Clone, Builder)]
+/// Used for `data:` URLs, which carry no name of their own
+const DATA_FILENAME: &str = "download";
+/// Smallest chunk [`Downloader::download_range`] splits a range into, smaller ranges take one request
+#[cfg(feature = "remote-zip")]
+const MIN_RANGE_CHUNK: u64 = 1024 * 1024;
+
+/// Outcome of [`Downloader::download_conditional`]
+#[derive(Debug, Clone)]
+pub struct DownloadResult {
+    /// The new content, `None` if the server answered `304 Not Modified`
+    pub data: Option<ChunkVec>,
+    /// `ETag` to revalidate with next time
+    pub etag: Option<String>,
+    /// `Last-Modified` to revalidate with next time
+    pub last_modified: Option<String>,
+    /// Whether the cached copy is still fresh
+    pub not_modified: bool,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "builder", derive(Builder))]
+#[cfg_attr(
+    feature = "builder",
+    builder(build_fn(validate = "Self::validate", error = "ManicError"))
+)]
 pub struct Downloader {
     filename: String,
-    #[builder(default, setter(skip))]
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
     client: Client,
+    #[cfg_attr(feature = "builder", builder(default))]
+    client_opts: ClientOptions,
+    /// The client was passed in by the caller and can't be rebuilt from `client_opts`
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    custom_client: bool,
     workers: u8,
-    url: reqwest::Url,
+    url: Url,
     hash: Option<Hash>,
     length: u64,
     chunks: Chunks,
+    #[cfg_attr(feature = "builder", builder(default))]
+    check_size: bool,
+    #[cfg_attr(feature = "builder", builder(default))]
+    lock: LockPolicy,
+    #[cfg_attr(feature = "builder", builder(default))]
+    max_size: Option<u64>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    write_buffer: Option<usize>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    retry: Retry,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    info: Option<RemoteInfo>,
+    /// Strong `ETag` sent as `If-Range` with chunk requests, set for the download of `download_conditional`
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    if_range: Option<String>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    metadata_cache: Option<Arc<dyn MetadataCache>>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    bandwidth: Option<Arc<Bandwidth>>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    rate_limit: Option<RateLimiter>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    scope: Option<Scope>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    pause: Option<PauseToken>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    signer: Option<Arc<dyn RequestSigner>>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    map: Option<RequestMap>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    service: Option<ServiceStack>,
+    #[cfg(feature = "sig-verify")]
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    signature: Option<SignaturePolicy>,
+    #[cfg(feature = "decompress")]
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    decompress: Option<Compression>,
+    /// Piece hashes of a metalink, checked on every download
+    #[cfg(feature = "metalink")]
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    pieces: Option<Pieces>,
     #[cfg(feature = "progress")]
     pb: Option<ProgressBar>,
 }
@@ -36,52 +144,109 @@ impl Downloader {
     pub fn get_url(&self) -> String {
         self.url.to_string()
     }
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
     pub fn get_len(&self) -> u64 {
         self.length
     }
     pub fn filename(&self) -> &str {
         &self.filename
     }
+    /// The `(low, hi)` byte ranges the file is split into, inclusive on both ends
+    ///
+    /// Only for inspection, nothing is downloaded. `file:` and `data:` URLs are read in one piece regardless
+    pub fn chunk_plan(&self) -> Vec<(u64, u64)> {
+        self.chunks.map(|x| (x.low, x.hi)).collect()
+    }
+    /// What the probe request found out about the URL, `None` for [`new_manual`][Self::new_manual]
+    pub fn remote_info(&self) -> Option<&RemoteInfo> {
+        self.info.as_ref()
+    }
+    /// Name the file gets when [`download_and_save`][Self::download_and_save] is given a directory
+    ///
+    /// Names taken from the URL are percent-decoded, stripped of control characters and
+    /// truncated to 255 bytes, a name set through the builder goes through the same checks here
+    pub fn target_filename(&self) -> Result<String> {
+        filename::sanitize(&self.filename)
+            .ok_or_else(|| ManicError::NoFilename(self.url.to_string()))
+    }
     async fn assemble_downloader(
-        url: &str,
+        url: Url,
         workers: u8,
         length: u64,
         client: Client,
     ) -> Result<Self> {
-        let parsed = reqwest::Url::parse(url)?;
+        check_scheme(&url)?;
+        check_workers(workers)?;
         if length == 0 {
             return Err(ManicError::NoLen);
         }
         let chunks = Chunks::new(0, length - 1, length / workers as u64)?;
-        let filename = Self::url_to_filename(&parsed)?;
-        #[cfg(not(feature = "progress"))]
-        return Ok(Self {
+        let filename = Self::url_to_filename(&url)?;
+        let hash = Hash::from_fragment(&url);
+        Ok(Self {
             filename,
             client,
+            client_opts: ClientOptions::default(),
+            custom_client: false,
             workers,
-            url: parsed,
-            hash: None,
-            length,
-            chunks,
-        });
-        #[cfg(feature = "progress")]
-        return Ok(Self {
-            filename,
-            client,
-            workers,
-            url: parsed,
-            hash: None,
+            url,
+            hash,
             length,
             chunks,
+            check_size: false,
+            lock: LockPolicy::default(),
+            max_size: None,
+            write_buffer: None,
+            retry: Retry::default(),
+            info: None,
+            if_range: None,
+            metadata_cache: None,
+            bandwidth: None,
+            rate_limit: None,
+            scope: None,
+            pause: None,
+            signer: None,
+            map: None,
+            service: None,
+            #[cfg(feature = "sig-verify")]
+            signature: None,
+            #[cfg(feature = "decompress")]
+            decompress: None,
+            #[cfg(feature = "metalink")]
+            pieces: None,
+            #[cfg(feature = "progress")]
             pb: None,
-        });
+        })
+    }
+    /// Single chunk if the server refuses ranges, otherwise same as [`assemble_downloader`][Self::assemble_downloader]
+    async fn assemble_probed(
+        url: Url,
+        workers: u8,
+        info: RemoteInfo,
+        client: Client,
+    ) -> Result<Self> {
+        let length = info.content_length.ok_or(ManicError::NoLen)?;
+        let workers = if info.supports_chunks() { workers } else { 1 };
+        let mut downloader = Self::assemble_downloader(url, workers, length, client).await?;
+        downloader.info = Some(info);
+        Ok(downloader)
     }
-    pub async fn new_manual(url: &str, workers: u8, length: u64) -> Result<Self> {
+    pub async fn new_manual(url: impl ToUrl, workers: u8, length: u64) -> Result<Self> {
+        let url = url.to_url()?;
+        check_workers(workers)?;
         let client = Client::new();
         Self::assemble_downloader(url, workers, length, client).await
     }
     /// Create a new downloader
     ///
+    /// Besides HTTP(S), `file:` URLs are read from disk and `data:` URLs are decoded inline,
+    /// both go through the same hash verification
+    ///
+    /// A `#sha256=<hex>` fragment, or `md5`, `sha224`, `sha384` and `sha512`, sets the hash to verify
+    /// against as if passed to [`verify`][Self::verify], other fragments are ignored
+    ///
     /// # Arguments
     /// * `url` - URL of the file
     /// * `workers` - amount of concurrent tasks
@@ -98,21 +263,205 @@ impl Downloader {
     /// # Ok(())
     /// # }
     /// ```
-    pub async fn new(url: &str, workers: u8) -> Result<Self> {
+    pub async fn new(url: impl ToUrl, workers: u8) -> Result<Self> {
+        let url = url.to_url()?;
+        check_workers(workers)?;
         let client = Client::new();
-        let length = content_length(&client, url).await?;
-        Self::assemble_downloader(url, workers, length, client).await
+        let info = probe(&client, &url, Hooks::default()).await?;
+        Self::assemble_probed(url, workers, info, client).await
     }
-    pub(crate) fn url_to_filename(url: &reqwest::Url) -> Result<String> {
-        url.path_segments()
-            .and_then(|segments| segments.last())
-            .and_then(|name| {
-                if name.is_empty() {
-                    None
-                } else {
-                    Some(name.to_string())
+    /// Create a new downloader using a preconfigured client, e.g. one built from [`ClientOptions`]
+    ///
+    /// The client is used as is, setters that would rebuild it like [`map_request`][Self::map_request] and [`bind_address`][Self::bind_address]
+    /// fail with [`ManicError::CustomClient`], set those options on the client instead
+    pub async fn new_with_client(url: impl ToUrl, workers: u8, client: Client) -> Result<Self> {
+        let url = url.to_url()?;
+        check_workers(workers)?;
+        let info = probe(&client, &url, Hooks::default()).await?;
+        let mut downloader = Self::assemble_probed(url, workers, info, client).await?;
+        downloader.custom_client = true;
+        Ok(downloader)
+    }
+    /// Fetch the size, range support, type and cache headers of a URL in one request
+    /// without creating a downloader
+    pub async fn probe(url: impl ToUrl) -> Result<RemoteInfo> {
+        let url = url.to_url()?;
+        probe(&Client::new(), &url, Hooks::default()).await
+    }
+    /// Create a new downloader, taking the probe from `cache` if it's there and storing it otherwise
+    ///
+    /// [`clone_for`][Self::clone_for] goes through the same cache
+    pub async fn new_cached(
+        url: impl ToUrl,
+        workers: u8,
+        cache: Arc<dyn MetadataCache>,
+    ) -> Result<Self> {
+        let url = url.to_url()?;
+        check_workers(workers)?;
+        let client = Client::new();
+        let info = cached_probe(Some(cache.as_ref()), &client, &url, Hooks::default()).await?;
+        let mut downloader = Self::assemble_probed(url, workers, info, client).await?;
+        downloader.metadata_cache = Some(cache);
+        Ok(downloader)
+    }
+    /// Probe every mirror of a file and check they serve the same version before
+    /// ranges are split across them, returns the first mirror's probe
+    ///
+    /// The mirrors have to report the same length and, if all of them send one, the same strong
+    /// `ETag`, otherwise [`ManicError::MirrorMismatch`] lists the ones that differ from the first
+    pub async fn probe_mirrors<U: ToUrl>(
+        mirrors: impl IntoIterator<Item = U>,
+    ) -> Result<RemoteInfo> {
+        let client = Client::new();
+        let urls = mirrors
+            .into_iter()
+            .map(|x| x.to_url())
+            .collect::<Result<Vec<_>>>()?;
+        let probes = futures::future::try_join_all(
+            urls.iter().map(|url| probe(&client, url, Hooks::default())),
+        )
+        .await?;
+        let mut probes = urls.into_iter().zip(probes).collect::<Vec<_>>();
+        check_mirrors(&probes)?;
+        Ok(probes.swap_remove(0).1)
+    }
+    /// Create a downloader for a file described by a [`Metalink`][crate::Metalink]
+    ///
+    /// The mirrors are probed in priority order, unreachable ones are skipped with a warning and
+    /// the others have to serve the same file as with [`probe_mirrors`][Self::probe_mirrors].
+    /// The file is downloaded from the most preferred mirror that answered and saved under the
+    /// metalink's name. It's verified against the strongest of the metalink's hashes and each
+    /// piece against its piece hash, a piece that doesn't match fails with [`ManicError::PieceMismatch`]
+    #[cfg(feature = "metalink")]
+    pub async fn from_metalink(file: &MetalinkFile, workers: u8) -> Result<Self> {
+        check_workers(workers)?;
+        let client = Client::new();
+        let probes = futures::future::join_all(
+            file.urls
+                .iter()
+                .map(|url| probe(&client, url, Hooks::default())),
+        )
+        .await;
+        let mut reachable = Vec::new();
+        let mut last_err = None;
+        for (url, res) in file.urls.iter().zip(probes) {
+            match res {
+                Ok(info) => reachable.push((url.clone(), info)),
+                Err(e) => {
+                    warn!(url = %url, error = %e, "Skipping unreachable mirror");
+                    last_err = Some(e);
                 }
-            })
+            }
+        }
+        if reachable.is_empty() {
+            return Err(last_err.unwrap_or(ManicError::NoResults));
+        }
+        check_mirrors(&reachable)?;
+        let (url, info) = reachable.swap_remove(0);
+        if let (Some(expected), Some(actual)) = (file.size, info.content_length) {
+            if expected != actual {
+                return Err(ManicError::SizeMismatch { expected, actual });
+            }
+        }
+        let mut downloader = Self::assemble_probed(url, workers, info, client).await?;
+        downloader.filename = file.name.clone();
+        if let Some(hash) = file.strongest_hash() {
+            downloader.hash = Some(hash.clone());
+        }
+        downloader.pieces = file.pieces.clone();
+        Ok(downloader)
+    }
+    /// Same as [`probe`][Self::probe], answered from `cache` if possible
+    pub async fn probe_cached(url: impl ToUrl, cache: &dyn MetadataCache) -> Result<RemoteInfo> {
+        let url = url.to_url()?;
+        cached_probe(Some(cache), &Client::new(), &url, Hooks::default()).await
+    }
+    /// New downloader for `url` with this one's client, workers, signer, retries and limits
+    ///
+    /// The length, filename and chunks are probed again for the new URL,
+    /// the hash, decompression and progress bar belong to a single file and aren't carried over
+    pub async fn clone_for(&self, url: impl ToUrl) -> Result<Self> {
+        let url = url.to_url()?;
+        let info = cached_probe(
+            self.metadata_cache.as_deref(),
+            &self.client,
+            &url,
+            self.hooks(),
+        )
+        .await?;
+        let mut downloader =
+            Self::assemble_probed(url, self.workers, info, self.client.clone()).await?;
+        downloader.metadata_cache = self.metadata_cache.clone();
+        downloader.client_opts = self.client_opts.clone();
+        downloader.custom_client = self.custom_client;
+        downloader.check_size = self.check_size;
+        downloader.lock = self.lock;
+        downloader.max_size = self.max_size;
+        downloader.write_buffer = self.write_buffer;
+        downloader.retry = self.retry;
+        #[cfg(feature = "sig-verify")]
+        {
+            downloader.signature = self.signature.clone();
+        }
+        downloader.bandwidth = self.bandwidth.clone();
+        downloader.rate_limit = self.rate_limit.clone();
+        downloader.scope = self.scope.clone();
+        downloader.pause = self.pause.clone();
+        downloader.signer = self.signer.clone();
+        downloader.map = self.map.clone();
+        downloader.service = self.service.clone();
+        Ok(downloader)
+    }
+    /// Create a new downloader that signs every request, including the initial HEAD
+    /// and each hop of a redirect, with the given [`RequestSigner`]
+    ///
+    /// The client is built to leave redirects to the downloader so they can be re-signed
+    pub async fn new_signed(
+        url: impl ToUrl,
+        workers: u8,
+        signer: Arc<dyn RequestSigner>,
+    ) -> Result<Self> {
+        let url = url.to_url()?;
+        check_workers(workers)?;
+        let client_opts = ClientOptions::default().manual_redirects();
+        let client = client_opts.build()?;
+        let hooks = Hooks {
+            map: None,
+            signer: Some(signer.as_ref()),
+        };
+        let info = probe(&client, &url, hooks).await?;
+        let mut downloader = Self::assemble_probed(url, workers, info, client).await?;
+        downloader.client_opts = client_opts;
+        downloader.signer = Some(signer);
+        Ok(downloader)
+    }
+    /// Create a new downloader whose requests, the initial HEAD included, all go through `map`
+    /// as set with [`map_request`][Self::map_request]
+    pub async fn new_mapped(
+        url: impl ToUrl,
+        workers: u8,
+        map: impl Fn(RequestParts) -> RequestParts + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let url = url.to_url()?;
+        check_workers(workers)?;
+        let client_opts = ClientOptions::default().manual_redirects();
+        let client = client_opts.build()?;
+        let map = RequestMap(Arc::new(map));
+        let hooks = Hooks {
+            map: Some(&map),
+            signer: None,
+        };
+        let info = probe(&client, &url, hooks).await?;
+        let mut downloader = Self::assemble_probed(url, workers, info, client).await?;
+        downloader.client_opts = client_opts;
+        downloader.map = Some(map);
+        Ok(downloader)
+    }
+    pub(crate) fn url_to_filename(url: &Url) -> Result<String> {
+        url.path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .and_then(filename::from_url_segment)
+            .or_else(|| (url.scheme() == "data").then(|| DATA_FILENAME.to_string()))
             .ok_or_else(|| ManicError::NoFilename(url.to_string()))
     }
     /// Enable progress reporting
@@ -139,6 +488,168 @@ impl Downloader {
         self.hash = Some(hash);
         self.to_owned()
     }
+    /// Replace the client with one built from `opts`, a client passed to
+    /// `new_with_client` is kept and the setter fails instead
+    fn rebuild_client(&mut self, setter: &'static str, opts: ClientOptions) -> Result<()> {
+        if self.custom_client {
+            return Err(ManicError::CustomClient(setter));
+        }
+        self.client = opts.build()?;
+        self.client_opts = opts;
+        Ok(())
+    }
+    /// Bind outgoing connections to a local address, rebuilds the client from its [`ClientOptions`]
+    pub fn bind_address(&mut self, addr: IpAddr) -> Result<&mut Self> {
+        self.rebuild_client("bind_address", self.client_opts.clone().bind_address(addr))?;
+        Ok(self)
+    }
+    /// Set the TCP options of chunk connections, rebuilds the client from its [`ClientOptions`]
+    pub fn socket_options(&mut self, socket: SocketOptions) -> Result<&mut Self> {
+        self.rebuild_client(
+            "socket_options",
+            self.client_opts.clone().socket_options(socket),
+        )?;
+        Ok(self)
+    }
+    /// Reuse connections between chunk requests, on by default, rebuilds the client from its [`ClientOptions`]
+    ///
+    /// Turn it off for servers with broken keep-alive, every request then opens its own connection
+    pub fn keep_alive(&mut self, enabled: bool) -> Result<&mut Self> {
+        self.rebuild_client("keep_alive", self.client_opts.clone().keep_alive(enabled))?;
+        Ok(self)
+    }
+    /// Restrict the HTTP versions chunk requests may use, rebuilds the client from its [`ClientOptions`]
+    ///
+    /// Protocol and handshake failures under a forced version come back as [`ManicError::HttpVersion`],
+    /// transport failures stay network errors and are retried as usual
+    pub fn http_version(&mut self, policy: HttpVersionPolicy) -> Result<&mut Self> {
+        self.rebuild_client(
+            "http_version",
+            self.client_opts.clone().http_version(policy),
+        )?;
+        Ok(self)
+    }
+    /// Decompress the download from `format` as [`download_and_save`][Self::download_and_save] writes it
+    ///
+    /// The hash and a signature set with [`verify_signature`][Self::verify_signature] are checked
+    /// against the file as served before anything is decompressed. Saved into a directory the file
+    /// is named without the format's extension, e.g. `notes.txt.gz` becomes `notes.txt`
+    #[cfg(feature = "decompress")]
+    pub fn decompress_on_save(&mut self, format: Compression) -> &mut Self {
+        self.decompress = Some(format);
+        self
+    }
+    /// Check a detached signature of the download before it's moved into place or handed out,
+    /// fails with [`ManicError::SignatureMismatch`] if it doesn't verify
+    ///
+    /// Saved files are checked as `<path>.part`, downloads returned in memory before they're returned
+    #[cfg(feature = "sig-verify")]
+    pub fn verify_signature(&mut self, policy: SignaturePolicy) -> &mut Self {
+        self.signature = Some(policy);
+        self
+    }
+    /// Throttle chunk bodies through `limiter`, one token per byte
+    ///
+    /// Pass clones of the same [`RateLimiter`] to several downloaders to cap their combined bandwidth
+    pub fn rate_limit(&mut self, limiter: RateLimiter) -> &mut Self {
+        self.rate_limit = Some(limiter);
+        self
+    }
+    /// Take a slot of `scheduler` for every chunk request
+    ///
+    /// Pass the same [`RequestScheduler`] to downloaders sharing a client so they
+    /// share its connections, fairly if the scheduler is in [`fair_share`][RequestScheduler::fair_share] mode
+    pub fn scheduler(&mut self, scheduler: &RequestScheduler) -> &mut Self {
+        self.scope = Some(scheduler.register());
+        self
+    }
+    /// Change every request before it's sent, e.g. to add a per-request token to the query string
+    ///
+    /// `map` runs for chunk requests once their `Range` header is set, for probes of
+    /// [`clone_for`][Self::clone_for], conditional and sidecar requests, and again on every
+    /// redirect hop, the client is rebuilt to leave redirects to the downloader for that.
+    /// The probe of the constructor happened before the hook was set, use
+    /// [`new_mapped`][Self::new_mapped] to map it too. With a [`RequestSigner`]
+    /// the map runs first and the signer second
+    pub fn map_request(
+        &mut self,
+        map: impl Fn(RequestParts) -> RequestParts + Send + Sync + 'static,
+    ) -> Result<&mut Self> {
+        self.rebuild_client("map_request", self.client_opts.clone().manual_redirects())?;
+        self.map = Some(RequestMap(Arc::new(map)));
+        Ok(self)
+    }
+    fn hooks(&self) -> Hooks<'_> {
+        Hooks {
+            map: self.map.as_ref(),
+            signer: self.signer.as_deref(),
+        }
+    }
+    /// Wrap the HTTP service chunk requests go through, e.g. in timeouts, metrics or circuit breaking
+    ///
+    /// `stack` gets manic's HTTP service and returns the service used in its place, it's called
+    /// once per download. Retries and the [`RequestScheduler`] are layered on top
+    /// of the returned service, so an error it returns is retried like a failed request
+    pub fn with_service<S: ChunkService + 'static>(
+        &mut self,
+        stack: impl Fn(Arc<dyn ChunkService>) -> S + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.service = Some(ServiceStack(Arc::new(move |inner| Arc::new(stack(inner)))));
+        self
+    }
+    /// Suspend the chunk requests while `token` is paused
+    pub fn pause_token(&mut self, token: PauseToken) -> &mut Self {
+        self.pause = Some(token);
+        self
+    }
+    /// Let [`download_and_save`][Self::download_and_save] write the chunks in order through a buffer
+    /// of `bytes` instead of writing each chunk on its own, worth it when there are many small chunks
+    pub fn write_buffer_size(&mut self, bytes: usize) -> &mut Self {
+        self.write_buffer = Some(bytes);
+        self
+    }
+    /// Check that the received bytes match the content length, and the saved file's size after
+    /// [`download_and_save`][Self::download_and_save], catches partial writes e.g. on a full disk
+    pub fn check_size(&mut self, check: bool) -> &mut Self {
+        self.check_size = check;
+        self
+    }
+    /// Lock the output path while saving so concurrent downloads to it don't clobber each other
+    pub fn lock_policy(&mut self, policy: LockPolicy) -> &mut Self {
+        self.lock = policy;
+        self
+    }
+    /// Refuse downloads larger than `bytes`
+    ///
+    /// The content length is checked before anything is fetched, the received bytes are counted
+    /// as well so a server sending more than it announced is cut off with [`ManicError::TooLarge`]
+    pub fn max_size(&mut self, bytes: u64) -> &mut Self {
+        self.max_size = Some(bytes);
+        self
+    }
+    /// Retry failed chunk requests up to `attempts` times with exponential backoff,
+    /// only errors [`ErrorCode::is_retryable`][crate::ErrorCode::is_retryable] accepts are retried:
+    /// connection errors, timeouts, errors reading the response body, 429 and 5xx responses
+    pub fn retries(&mut self, attempts: u32) -> &mut Self {
+        self.retry.attempts = attempts;
+        self
+    }
+    /// Randomize the retry backoff to a duration between zero and the exponential delay, on by default
+    ///
+    /// Keeps workers that failed together from retrying in lockstep against the same mirror
+    pub fn jitter(&mut self, jitter: bool) -> &mut Self {
+        self.retry.jitter = jitter;
+        self
+    }
+    /// Give up on ranged requests once `failures` of them in a row came back with more than
+    /// the range or a `416`, and fetch the file in a single request instead, 3 by default
+    ///
+    /// Turns a proxy that mangles `Range` into a slower download instead of a failed one,
+    /// 0 fails with [`ManicError::RangeIgnored`] right away
+    pub fn range_fallback(&mut self, failures: u32) -> &mut Self {
+        self.retry.range_fallback = failures;
+        self
+    }
     /// Download the file and verify if hash is set
     ///
     /// # Example
@@ -153,32 +664,339 @@ impl Downloader {
     /// # Ok(())
     /// # }
     /// ```
-    #[instrument(skip(self), fields(URL=%self.url, tasks=%self.workers))]
     pub async fn download(&self) -> Result<ChunkVec> {
+        self.download_checked(true).await
+    }
+    /// [`download`][Self::download], hashing the data in memory only if `verify` is set,
+    /// [`download_and_save`][Self::download_and_save] hashes while writing instead
+    #[instrument(skip(self, verify), fields(URL=%self.url, tasks=%self.workers))]
+    async fn download_checked(&self, verify: bool) -> Result<ChunkVec> {
+        self.reported(self.fetch_and_verify(verify)).await
+    }
+    /// Run `download` between the `manic.download.*` start and completion or failure events
+    async fn reported(&self, download: impl Future<Output = Result<ChunkVec>>) -> Result<ChunkVec> {
+        let start = Instant::now();
+        events::download_start(&self.url, self.length, self.workers);
+        let res = download.await;
+        match &res {
+            Ok(data) => events::download_complete(&self.url, data.byte_len(), start.elapsed()),
+            Err(e) => events::download_failed(&self.url, start.elapsed(), e),
+        }
+        res
+    }
+    async fn fetch_and_verify(&self, verify: bool) -> Result<ChunkVec> {
         let mb = &self.length / 1000000;
         debug!("File size: {}MB", mb);
         let chnks = self.chunks;
-        let url = self.url.clone();
-        let client = self.client.clone();
-        #[cfg(feature = "progress")]
-        let pb = self.pb.clone();
-        let result = chnks
-            .download(
-                &client,
-                url.to_string(),
-                #[cfg(feature = "progress")]
-                pb,
-            )
-            .await?;
-        if let Some(hash) = &self.hash {
-            result.verify(hash.clone()).await?;
+        let ctx = self.context()?;
+        let result = if is_local(&self.url) {
+            let url = self.url.clone();
+            let buf = tokio::task::spawn_blocking(move || read_local(&url)).await??;
+            #[cfg(feature = "progress")]
+            if let Some(bar) = &ctx.pb {
+                bar.inc(buf.len() as u64);
+            }
+            ChunkVec::from_buf(buf)
+        } else {
+            let mut done = Vec::new();
+            self.fetch_chunks(&ctx, &chnks.collect::<Vec<_>>(), &mut done)
+                .await?;
+            ChunkVec::from(done)
+        };
+        self.check(result, verify).await
+    }
+    /// Checks every finished download goes through, the size if set, the piece hashes of a metalink
+    /// and the hash and signature if `verify` is set
+    async fn check(&self, result: ChunkVec, verify: bool) -> Result<ChunkVec> {
+        if self.check_size && result.byte_len() != self.length {
+            return Err(ManicError::SizeMismatch {
+                expected: self.length,
+                actual: result.byte_len(),
+            });
+        }
+        #[cfg(feature = "metalink")]
+        if let Some(pieces) = &self.pieces {
+            pieces.verify(result.blocks())?;
+        }
+        if let Some(hash) = self.hash.as_ref().filter(|_| verify) {
+            result
+                .verify(
+                    hash.clone(),
+                    #[cfg(feature = "progress")]
+                    self.pb.as_ref(),
+                )
+                .await?;
             debug!("Compared");
         }
+        #[cfg(feature = "sig-verify")]
+        if verify {
+            self.check_signature_data(&result).await?;
+        }
         Ok(result)
     }
-    pub(crate) async fn multi_download(self) -> Result<Downloaded> {
-        let res = self.download().await?;
-        Ok(Downloaded::new(self.get_url(), self.filename, res))
+    /// Fetch the `pending` chunks into `done` with at most `workers` requests in flight
+    ///
+    /// Chunks whose range was ignored are requested again until ranged requests are given up on,
+    /// then the whole file is fetched in one plain request and replaces `done`
+    async fn fetch_chunks(
+        &self,
+        ctx: &Arc<RequestContext>,
+        pending: &[Chunk],
+        done: &mut Vec<Chunk>,
+    ) -> Result<()> {
+        let service = service::stack(ctx, self.service.as_ref());
+        let mut todo = pending.to_vec();
+        loop {
+            let res = chunk::fetch_chunks(
+                todo.iter().cloned(),
+                &self.url,
+                service.as_ref(),
+                self.workers as usize,
+                done,
+            )
+            .await;
+            todo.retain(|c| !done.iter().any(|d| d.covers(c)));
+            match res {
+                Ok(()) => return Ok(()),
+                Err(e) if ctx.range_failures.tripped() => {
+                    warn!(url = %self.url, error = %e, "Ranged requests keep failing, downloading in a single request");
+                    let buf = self.fetch_whole().await?;
+                    done.clear();
+                    done.push(Chunk::whole(buf));
+                    return Ok(());
+                }
+                Err(e) if is_range_failure(&e) && self.retry.range_fallback > 0 => {
+                    debug!(url = %self.url, error = %e, "Range ignored, requesting the missing chunks again");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    /// Start the download in the background, the returned handle can pause, resume and cancel it
+    ///
+    /// Must be called within a tokio runtime. `file:` and `data:` URLs are read in one go
+    /// and can't be paused
+    pub fn start(&self) -> DownloadHandle {
+        let downloader = self.clone();
+        DownloadHandle::new(|control| tokio::spawn(downloader.drive(control)))
+    }
+    async fn drive(self, control: watch::Receiver<Control>) -> Result<ChunkVec> {
+        self.reported(self.run(control)).await
+    }
+    /// Download the chunks that aren't finished yet until done, paused or cancelled
+    async fn run(&self, mut control: watch::Receiver<Control>) -> Result<ChunkVec> {
+        if is_local(&self.url) {
+            return self.fetch_and_verify(true).await;
+        }
+        let mut pending = self.chunks.collect::<Vec<_>>();
+        let mut done = Vec::with_capacity(pending.len());
+        loop {
+            let state = loop {
+                let state = control.borrow().state;
+                if state != DownloadState::Paused {
+                    break state;
+                }
+                // The handle was dropped
+                if control.changed().await.is_err() {
+                    break DownloadState::Cancelled;
+                }
+            };
+            if state == DownloadState::Cancelled {
+                return Err(ManicError::Cancelled);
+            }
+            if pending.is_empty() {
+                break;
+            }
+            let ctx = self.context()?;
+            let finished: u64 = done.iter().map(|c: &Chunk| c.buf.len() as u64).sum();
+            if let Some(limit) = &ctx.limit {
+                limit.add(finished)?;
+            }
+            #[cfg(feature = "progress")]
+            if let Some(bar) = &ctx.pb {
+                bar.set_message("");
+            }
+            let mut round = Box::pin(self.fetch_chunks(&ctx, &pending, &mut done));
+            let res = loop {
+                tokio::select! {
+                    res = &mut round => break Some(res),
+                    changed = control.changed() => {
+                        if changed.is_err() || control.borrow().state != DownloadState::Running {
+                            break None;
+                        }
+                    }
+                }
+            };
+            // Drops the requests still in flight, their bytes no longer count
+            drop(round);
+            pending.retain(|c| !done.iter().any(|d| d.covers(c)));
+            if let Some(res) = res {
+                res?;
+            }
+            #[cfg(feature = "progress")]
+            if let Some(bar) = &ctx.pb {
+                bar.set_position(done.iter().map(|c| c.buf.len() as u64).sum());
+                if !pending.is_empty() {
+                    bar.set_message("paused");
+                }
+            }
+        }
+        self.check(ChunkVec::from(done), true).await
+    }
+    /// Everything the chunk requests of one download need
+    fn context(&self) -> Result<Arc<RequestContext>> {
+        let limit = self.max_size.map(SizeLimit::new);
+        if let Some(limit) = &limit {
+            limit.check(self.length)?;
+        }
+        Ok(Arc::new(RequestContext {
+            client: self.client.clone(),
+            signer: self.signer.clone(),
+            map: self.map.clone(),
+            bandwidth: self.bandwidth.clone(),
+            rate_limit: self.rate_limit.clone(),
+            scope: self.scope.clone(),
+            pause: self.pause.clone(),
+            limit,
+            retry: self.retry,
+            range_failures: RangeFailures::new(
+                self.retry.range_fallback.min(self.chunks.count() as u32),
+            ),
+            http_version: self.client_opts.http_version_policy(),
+            if_range: self.if_range.clone(),
+            #[cfg(feature = "progress")]
+            pb: self.pb.clone(),
+        }))
+    }
+    pub(crate) fn set_bandwidth(&mut self, bandwidth: Option<Arc<Bandwidth>>) {
+        self.bandwidth = bandwidth;
+    }
+    /// Start the download of a [`MultiDownloader`][super::MultiDownloader] batch in the background,
+    /// data over the memory cap is spilled to its staging directory once it's finished
+    ///
+    /// The download's share of the batch's memory budget is held until its data is spilled,
+    /// or by the returned entry while the data stays in memory
+    pub(crate) fn start_multi(
+        &self,
+        cap: Option<Arc<MemoryCap>>,
+        reservation: Option<Reservation>,
+    ) -> DownloadHandle<Downloaded> {
+        let downloader = self.clone();
+        DownloadHandle::new(|control| {
+            tokio::spawn(async move {
+                let res = downloader.reported(downloader.run(control)).await?;
+                downloader.into_payload(res, cap, reservation).await
+            })
+        })
+    }
+    async fn into_payload(
+        self,
+        res: ChunkVec,
+        cap: Option<Arc<MemoryCap>>,
+        reservation: Option<Reservation>,
+    ) -> Result<Downloaded> {
+        let data = match cap {
+            Some(cap) if !cap.admit(res.byte_len()) => {
+                let path = cap.staging_path(&self.url, &self.filename);
+                tokio::fs::create_dir_all(cap.staging()).await?;
+                res.save_to_file(&path).await?;
+                debug!(
+                    "Over the memory cap, spilled {} to {}",
+                    self.url,
+                    path.display()
+                );
+                Payload::Spilled(path)
+            }
+            _ => Payload::Memory(res),
+        };
+        let mut downloaded = self.into_downloaded(data);
+        downloaded.hold(reservation);
+        Ok(downloaded)
+    }
+    pub(crate) fn into_downloaded(self, data: Payload) -> Downloaded {
+        let final_url = self
+            .info
+            .as_ref()
+            .map_or_else(|| self.url.clone(), |x| x.url.clone());
+        Downloaded::new(self.url, final_url, self.filename, data, self.lock)
+    }
+    /// Fetch the whole file in one plain request, for servers and proxies that mangle ranged ones
+    async fn fetch_whole(&self) -> Result<Vec<u8>> {
+        // Fresh size limit, the bytes of the ranged attempts don't count
+        let ctx = self.context()?;
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &ctx.pb {
+            bar.set_position(0);
+        }
+        let mut resp = ctx
+            .send(ctx.client.get(self.url.clone()))
+            .await?
+            .error_for_status()?;
+        let mut buf = Vec::with_capacity(self.length as usize);
+        while let Some(b) = resp.chunk().await? {
+            if let Some(rate) = &ctx.rate_limit {
+                rate.acquire(b.len() as u64).await;
+            }
+            if let Some(limit) = &ctx.limit {
+                limit.add(b.len() as u64)?;
+            }
+            ctx.count(b.len() as u64);
+            buf.extend_from_slice(&b);
+            if let Some(pause) = &ctx.pause {
+                pause.resumed().await;
+            }
+        }
+        Ok(buf)
+    }
+    /// Fetch bytes `low..=hi` in one request
+    pub(crate) async fn fetch_range(&self, low: u64, hi: u64) -> Result<Vec<u8>> {
+        if is_local(&self.url) {
+            let url = self.url.clone();
+            let buf = tokio::task::spawn_blocking(move || read_local(&url)).await??;
+            let hi = std::cmp::min(hi as usize + 1, buf.len());
+            return Ok(buf.get(low as usize..hi).unwrap_or_default().to_vec());
+        }
+        let ctx = self.context()?;
+        let req = ctx
+            .client
+            .get(self.url.clone())
+            .header(RANGE, format!("bytes={}-{}", low, hi));
+        let mut resp = ctx.send(req).await?.error_for_status()?;
+        if resp.status() != StatusCode::PARTIAL_CONTENT && low != 0 {
+            return Err(ManicError::RangeIgnored(format!("bytes={}-{}", low, hi)));
+        }
+        // A server that ignores the range sends the whole file, stop reading once the range is in
+        let len = (hi - low + 1) as usize;
+        let mut buf = Vec::new();
+        while buf.len() < len {
+            match resp.chunk().await? {
+                Some(b) => buf.extend_from_slice(&b),
+                None => break,
+            }
+        }
+        buf.truncate(len);
+        Ok(buf)
+    }
+    /// Fetch bytes `low..=hi` split across the workers like a whole download, for large parts of a file
+    #[cfg(feature = "remote-zip")]
+    pub(crate) async fn download_range(&self, low: u64, hi: u64) -> Result<ChunkVec> {
+        if is_local(&self.url) {
+            return Ok(ChunkVec::from_buf(self.fetch_range(low, hi).await?));
+        }
+        let workers = self.workers as u64;
+        let chunk_size = std::cmp::max((hi - low + workers) / workers, MIN_RANGE_CHUNK);
+        let ctx = self.context()?;
+        let service = service::stack(&ctx, self.service.as_ref());
+        let mut done = Vec::new();
+        chunk::fetch_chunks(
+            Chunks::new(low, hi, chunk_size)?,
+            &self.url,
+            service.as_ref(),
+            self.workers as usize,
+            &mut done,
+        )
+        .await?;
+        Ok(ChunkVec::from(done))
     }
     /// Used to download, save to a file and verify against a SHA256 sum,
     /// returns an error if the connection fails or if the sum doesn't match the one provided
@@ -204,63 +1022,301 @@ impl Downloader {
     ///
     #[instrument(skip(self))]
     pub async fn download_and_save(&self, path: &str) -> Result<()> {
-        let mut result = {
+        let file_path = {
             let original_path = Path::new(path);
-            let file_path = if original_path.is_dir() {
-                original_path.join(&self.filename)
+            if original_path.is_dir() {
+                let name = self.target_filename()?;
+                #[cfg(feature = "decompress")]
+                let name = match self.decompress {
+                    Some(format) => format.strip_extension(&name),
+                    None => name,
+                };
+                original_path.join(name)
             } else {
                 original_path.to_path_buf()
-            };
-            File::create(file_path).await?
+            }
         };
-        let data = self.download().await?;
+        let _lock = self.lock.acquire_async(&file_path).await?;
+        #[cfg(feature = "decompress")]
+        if let Some(format) = self.decompress {
+            return self.save_decompressed(&file_path, format).await;
+        }
+        let partial = PartialFile::new(&file_path);
+        let mut result = File::create(partial.path()).await?;
+        let data = self.download_checked(false).await?;
         let c = result.try_clone().await?;
-        data.save(c).await?;
+        match (&self.hash, self.write_buffer) {
+            // One pass over the data writes and hashes it
+            (Some(hash), size) => data
+                .save_hashed(c, hash.clone(), size.unwrap_or(HASHED_WRITE_BUFFER))
+                .await?
+                .verify()?,
+            (None, Some(size)) => data.save_buffered(c, size).await?,
+            (None, None) => data.save(c).await?,
+        }
         result.sync_all().await?;
         result.flush().await?;
+        if self.check_size {
+            let actual = result.metadata().await?.len();
+            if actual != self.length {
+                return Err(ManicError::SizeMismatch {
+                    expected: self.length,
+                    actual,
+                });
+            }
+        }
+        drop(result);
+        #[cfg(feature = "sig-verify")]
+        self.check_signature(partial.path()).await?;
+        Ok(partial.persist()?)
+    }
+    /// Download the file and return a reader over its decompressed content
+    ///
+    /// The hash and signature are checked against the file as served. The content is decompressed
+    /// from the chunks in memory as it's read, so an archive like a `.tar.gz` can be handed to
+    /// an extractor without staging it on disk
+    #[cfg(feature = "decompress")]
+    pub async fn download_decompressed(&self, format: Compression) -> Result<Decompressed> {
+        let data = self.download().await?;
+        Ok(Decompressed::new(format, data.into_blocks()))
+    }
+    /// Download a compressed tar archive and unpack it into `dir` as it's decompressed
+    ///
+    /// The hash and signature are checked against the archive as served before anything is
+    /// extracted, the archive itself is never written to disk. An entry whose path or link target
+    /// leads outside `dir` fails with [`ManicError::UnsafeEntry`], entries unpacked before it stay in `dir`
+    #[cfg(feature = "decompress")]
+    pub async fn download_and_extract(
+        &self,
+        dir: impl AsRef<Path>,
+        format: Compression,
+    ) -> Result<()> {
+        let data = self.download_decompressed(format).await?;
+        let dir = dir.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || data.extract(&dir)).await?
+    }
+    /// Download and check the compressed file, then decompress it into `<path>.part` and move it into place
+    #[cfg(feature = "decompress")]
+    async fn save_decompressed(&self, path: &Path, format: Compression) -> Result<()> {
+        // The hash and signature are of the file as served
+        let data = self.download_checked(true).await?;
+        let partial = PartialFile::new(path);
+        let part = partial.path().to_path_buf();
+        tokio::task::spawn_blocking(move || format.decompress(data.blocks(), &part)).await??;
+        Ok(partial.persist()?)
+    }
+    /// Fetch the sidecar signature, `None` without a policy or if an optional signature is missing
+    #[cfg(feature = "sig-verify")]
+    async fn fetch_signature(&self) -> Result<Option<(SignaturePolicy, Vec<u8>)>> {
+        let policy = match &self.signature {
+            Some(policy) => policy.clone(),
+            None => return Ok(None),
+        };
+        let sidecar = policy.sidecar_url(&self.url)?;
+        let signature = if is_local(&sidecar) {
+            let url = sidecar.clone();
+            match tokio::task::spawn_blocking(move || read_local(&url)).await? {
+                Err(ManicError::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return policy.missing(&sidecar).map(|_| None)
+                }
+                res => res?,
+            }
+        } else {
+            let req = self.client.get(sidecar.clone());
+            let resp = send(&self.client, req, self.hooks()).await?;
+            if resp.status() == StatusCode::NOT_FOUND {
+                return policy.missing(&sidecar).map(|_| None);
+            }
+            resp.error_for_status()?.bytes().await?.to_vec()
+        };
+        Ok(Some((policy, signature)))
+    }
+    /// Verify the saved file at `path` against the sidecar signature
+    #[cfg(feature = "sig-verify")]
+    async fn check_signature(&self, path: &Path) -> Result<()> {
+        if let Some((policy, signature)) = self.fetch_signature().await? {
+            let path = path.to_path_buf();
+            tokio::task::spawn_blocking(move || policy.verify(Signed::File(&path), &signature))
+                .await??;
+        }
+        Ok(())
+    }
+    /// Verify downloaded data that's handed out without being saved against the sidecar signature
+    #[cfg(feature = "sig-verify")]
+    async fn check_signature_data(&self, data: &ChunkVec) -> Result<()> {
+        if let Some((policy, signature)) = self.fetch_signature().await? {
+            let data = data.clone();
+            tokio::task::spawn_blocking(move || {
+                policy.verify(Signed::Blocks(data.blocks()), &signature)
+            })
+            .await??;
+        }
         Ok(())
     }
+    /// Download only if the file changed since it was fetched with the given validators
+    ///
+    /// A conditional request for the first byte with `If-None-Match` and `If-Modified-Since` is sent first,
+    /// on `304 Not Modified` nothing is downloaded. Otherwise the file is downloaded as usual and returned with its new validators
+    /// so callers can update their cache. The chunks are planned from this downloader's probe,
+    /// if the new length differs the download fails with [`ManicError::SizeMismatch`].
+    /// Chunk requests carry the new strong `ETag` in `If-Range`, a file that changes again
+    /// while it's downloaded fails with [`ManicError::RangeIgnored`] instead of mixing both versions
+    pub async fn download_conditional(
+        &self,
+        etag: Option<&str>,
+        last_modified: Option<&str>,
+    ) -> Result<DownloadResult> {
+        if is_local(&self.url) {
+            return Ok(DownloadResult {
+                data: Some(self.download().await?),
+                etag: None,
+                last_modified: None,
+                not_modified: false,
+            });
+        }
+        let mut req = self.client.get(self.url.clone()).header(RANGE, "bytes=0-0");
+        if let Some(etag) = etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = last_modified {
+            req = req.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let resp = send(&self.client, req, self.hooks())
+            .await?
+            .error_for_status()?;
+        let info =
+            RemoteInfo::from_headers(resp.url(), resp.version(), resp.status(), resp.headers())?;
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            // A 304 doesn't have to repeat the validators, the cached ones are still current
+            return Ok(DownloadResult {
+                data: None,
+                etag: info.etag.or_else(|| etag.map(str::to_string)),
+                last_modified: info
+                    .last_modified
+                    .or_else(|| last_modified.map(str::to_string)),
+                not_modified: true,
+            });
+        }
+        match info.content_length {
+            Some(actual) if actual != self.length => {
+                return Err(ManicError::SizeMismatch {
+                    expected: self.length,
+                    actual,
+                })
+            }
+            _ => {}
+        }
+        let mut changed = self.clone();
+        changed.if_range = info.etag.clone().filter(|x| !x.starts_with("W/"));
+        // A range refused because of If-Range means the file changed, a single request would fetch the new one
+        changed.retry.range_fallback = 0;
+        Ok(DownloadResult {
+            data: Some(changed.download().await?),
+            etag: info.etag,
+            last_modified: info.last_modified,
+            not_modified: false,
+        })
+    }
+    /// Download, check the saved file against `hash` and a signature set with
+    /// [`verify_signature`][Self::verify_signature] and only then move it to `path`
+    ///
+    /// The data is written to `<path>.part` next to the target so the final rename never crosses devices.
+    /// On a mismatch the partial file is removed and an existing file at `path` is left untouched
+    pub async fn download_verify_install<T: AsRef<Path>>(&self, path: T, hash: Hash) -> Result<()> {
+        let path = path.as_ref();
+        let _lock = self.lock.acquire_async(path).await?;
+        let data = self.download_checked(false).await?;
+        let partial = data.stage_verified(path, hash).await?;
+        #[cfg(feature = "sig-verify")]
+        self.check_signature(partial.path()).await?;
+        Ok(partial.persist()?)
+    }
+}
+
+#[cfg(feature = "builder")]
+impl DownloaderBuilder {
+    /// Reject URLs with a scheme no downloader can fetch before anything is built
+    fn validate(&self) -> Result<()> {
+        if let Some(workers) = self.workers {
+            check_workers(workers)?;
+        }
+        match &self.url {
+            Some(url) => check_scheme(url),
+            None => Ok(()),
+        }
+    }
+}
+
+#[instrument(skip(client, url, hooks), fields(URL=%url))]
+/// [`probe`] unless `cache` has the answer, local URLs aren't cached since reading their size is cheap
+async fn cached_probe(
+    cache: Option<&dyn MetadataCache>,
+    client: &Client,
+    url: &Url,
+    hooks: Hooks<'_>,
+) -> Result<RemoteInfo> {
+    let cache = match cache {
+        Some(cache) if !is_local(url) => cache,
+        _ => return probe(client, url, hooks).await,
+    };
+    if let Some(info) = cache.get(url) {
+        debug!("Using cached metadata of {}", url);
+        return Ok(info);
+    }
+    let info = probe(client, url, hooks).await?;
+    cache.put(url, &info);
+    Ok(info)
 }
 
-#[instrument(skip(client, url), fields(URL=%url))]
-async fn content_length(client: &Client, url: &str) -> Result<u64> {
-    let resp = client.head(url).send().await?;
+async fn probe(client: &Client, url: &Url, hooks: Hooks<'_>) -> Result<RemoteInfo> {
+    check_scheme(url)?;
+    if is_local(url) {
+        return Ok(RemoteInfo::local(url, local_len(url)?));
+    }
+    let resp = send(client, client.head(url.clone()), hooks).await?;
     debug!("Response code: {}", resp.status());
     debug!("Received HEAD response: {:?}", resp.headers());
-    let len = resp
-        .headers()
-        .get("content-length")
-        .ok_or(ManicError::NoLen);
-    if len.is_ok() && resp.status().is_success() {
-        len?.to_str()
-            .map_err(|_x| ManicError::NoLen)?
-            .parse::<u64>()
-            .map_err(|e| e.into())
+    if resp.status().is_success() && resp.headers().contains_key(CONTENT_LENGTH) {
+        RemoteInfo::from_headers(resp.url(), resp.version(), resp.status(), resp.headers())
     } else {
-        let resp = client.get(url).header(RANGE, "0-0").send().await?;
+        let resp = send(
+            client,
+            client.get(url.clone()).header(RANGE, "bytes=0-0"),
+            hooks,
+        )
+        .await?;
         debug!("Response code: {}", resp.status());
         debug!("Received GET 1B response: {:?}", resp.headers());
-        resp.headers()
-            .get(CONTENT_LENGTH)
-            .ok_or(ManicError::NoLen)?
-            .to_str()?
-            .parse::<u64>()
-            .map_err(|e| e.into())
+        RemoteInfo::from_headers(resp.url(), resp.version(), resp.status(), resp.headers())
     }
 }
 
-pub(crate) async fn join_all<T: Clone>(i: Vec<JoinHandle<Result<T>>>) -> Result<Vec<T>> {
-    futures::future::join_all(i)
-        .await
-        .into_iter()
-        .map(|x| x.map_err(ManicError::JoinError))
-        .collect::<Result<Vec<Result<T>>>>()?
-        .into_iter()
-        .collect::<Result<Vec<T>>>()
+/// Aborts the task when dropped so spawned tasks don't outlive a cancelled [`join_all`]
+///
+/// Aborting a finished task is a no-op, blocking tasks that already started run to completion
+pub(crate) struct AbortOnDrop<T>(pub(crate) JoinHandle<T>);
+
+impl<T> Future for AbortOnDrop<T> {
+    type Output = std::result::Result<T, tokio::task::JoinError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<T> Drop for AbortOnDrop<T> {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
-pub(crate) async fn join_all_futures<T: Clone, F: Future<Output = Result<T>>>(
-    i: Vec<F>,
+pub(crate) async fn join_all<T>(
+    i: Vec<JoinHandle<Result<T>>>,
+    policy: JoinPolicy,
 ) -> Result<Vec<T>> {
-    futures::future::join_all(i).await.into_iter().collect()
+    let results = futures::future::join_all(i.into_iter().map(AbortOnDrop))
+        .await
+        .into_iter()
+        .map(|x| x.map_err(ManicError::JoinError).and_then(|r| r))
+        .collect::<Vec<Result<T>>>();
+    policy.collect(results)
 }
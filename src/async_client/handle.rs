@@ -0,0 +1,114 @@
+use super::chunk::ChunkVec;
+use crate::{ManicError, Result};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+/// State of a download started with [`Downloader::start`][super::Downloader::start]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadState {
+    Running,
+    Paused,
+    Cancelled,
+}
+
+/// State shared with the driving task, changed in one step so concurrent calls can't interleave
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Control {
+    pub(crate) state: DownloadState,
+    paused_at: Option<Instant>,
+    paused: Duration,
+}
+
+/// Control over a running download
+///
+/// Pausing drops the in-flight chunk requests, their ranges are requested again on resume
+/// while finished chunks are kept. Dropping the handle aborts the download
+#[derive(Debug)]
+pub struct DownloadHandle<T = ChunkVec> {
+    control: watch::Sender<Control>,
+    task: Option<JoinHandle<Result<T>>>,
+}
+
+impl<T> DownloadHandle<T> {
+    pub(crate) fn new(
+        spawn: impl FnOnce(watch::Receiver<Control>) -> JoinHandle<Result<T>>,
+    ) -> Self {
+        let (control, rx) = watch::channel(Control {
+            state: DownloadState::Running,
+            paused_at: None,
+            paused: Duration::ZERO,
+        });
+        Self {
+            control,
+            task: Some(spawn(rx)),
+        }
+    }
+    pub fn state(&self) -> DownloadState {
+        self.control.borrow().state
+    }
+    /// Stop requesting chunks, returns false if the download wasn't running
+    pub fn pause(&self) -> bool {
+        self.control.send_if_modified(|c| {
+            if c.state != DownloadState::Running {
+                return false;
+            }
+            c.state = DownloadState::Paused;
+            c.paused_at = Some(Instant::now());
+            true
+        })
+    }
+    /// Continue with the chunks that aren't finished yet, returns false if the download wasn't paused
+    pub fn resume(&self) -> bool {
+        self.control.send_if_modified(|c| {
+            if c.state != DownloadState::Paused {
+                return false;
+            }
+            c.state = DownloadState::Running;
+            if let Some(at) = c.paused_at.take() {
+                c.paused += at.elapsed();
+            }
+            true
+        })
+    }
+    /// Stop the download, [`await_result`][Self::await_result] then returns [`ManicError::Cancelled`]
+    pub fn cancel(&self) -> bool {
+        self.control.send_if_modified(|c| {
+            if c.state == DownloadState::Cancelled {
+                return false;
+            }
+            c.state = DownloadState::Cancelled;
+            true
+        })
+    }
+    /// Total time spent paused, including the current pause
+    pub fn paused_for(&self) -> Duration {
+        let c = *self.control.borrow();
+        c.paused + c.paused_at.map(|at| at.elapsed()).unwrap_or_default()
+    }
+    /// Wait for the download to finish
+    pub async fn await_result(mut self) -> Result<T> {
+        match self.task.take() {
+            Some(task) => task.await?,
+            None => Err(ManicError::Cancelled),
+        }
+    }
+    /// Poll for the result while keeping the handle, to pause downloads that are being awaited
+    pub(crate) fn poll_result(&mut self, cx: &mut Context<'_>) -> Poll<Result<T>> {
+        match &mut self.task {
+            Some(task) => Pin::new(task).poll(cx).map(|res| res?),
+            None => Poll::Ready(Err(ManicError::Cancelled)),
+        }
+    }
+}
+
+impl<T> Drop for DownloadHandle<T> {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
@@ -1,12 +1,44 @@
+//! Async downloader built on tokio
+//!
+//! ## Cancel safety
+//!
+//! Every future here can be dropped, e.g. by a `select!` timeout, without leaking work:
+//!
+//! - [`Downloader::new`], [`Downloader::new_with_client`], [`Downloader::new_signed`],
+//!   [`Downloader::new_manual`] and [`Downloader::probe`] only send the probe request,
+//!   dropping them drops the request.
+//! - [`Downloader::download`] and [`MultiDownloader::download_one`] poll the chunk requests
+//!   in place, dropping the future drops every request.
+//! - [`Downloader::download_and_save`] and `ChunkVec::save_to_file`
+//!   write to `<target>.part` and delete it when dropped before completion, the target itself
+//!   is only created by renaming the finished file. Writes already handed to the blocking pool
+//!   still finish, into the deleted file.
+//! - [`MultiDownloader::download_all`] aborts the downloads it spawned.
+//! - [`MultiDownloader::add`], [`MultiDownloader::verify`], [`MultiDownloader::len`] and
+//!   [`MultiDownloader::is_empty`] only wait on the downloader map's lock and on the probe.
 pub use reqwest::Client;
+pub use reqwest::Request;
 
+pub use chunk::{Chunk, ChunkVec, Chunks};
+pub use downloader::DownloadResult;
 pub use downloader::Downloader;
+#[cfg(feature = "builder")]
 pub use downloader::DownloaderBuilder;
+pub use handle::DownloadHandle;
+pub use handle::DownloadState;
 pub use multi::Downloaded;
 pub use multi::Map;
 pub use multi::MultiDownloader;
+#[cfg(feature = "builder")]
 pub use multi::MultiDownloaderBuilder;
+pub use request::{RequestParts, RequestSigner};
+pub use service::{ChunkRequest, ChunkResponse, ChunkService};
 
+mod bandwidth;
+mod budget;
 mod chunk;
 mod downloader;
+mod handle;
 mod multi;
+mod request;
+mod service;
@@ -1,18 +1,44 @@
 #![allow(dead_code)]
+use super::bandwidth::Bandwidth;
+use super::budget::{MemoryBudget, Reservation};
 use super::chunk::ChunkVec;
-use super::downloader::join_all;
+use super::handle::{DownloadHandle, DownloadState};
+use crate::filename;
+use crate::limit::{self, MemoryCap};
+use crate::partial::PartialFile;
+use crate::JoinPolicy;
+use crate::LockPolicy;
 use crate::ManicError;
+use crate::MetadataCache;
+use crate::Priority;
 use crate::Result;
+#[cfg(feature = "sig-verify")]
+use crate::SignaturePolicy;
+use crate::ToUrl;
+use crate::{DownloadStatus, SkipStrategy, SyncIndex};
 use crate::{Downloader, Hash};
+#[cfg(feature = "builder")]
+use derive_builder::Builder;
+use futures::stream::{FuturesUnordered, StreamExt};
 #[cfg(feature = "progress")]
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::collections::HashMap;
-use std::path::Path;
-use std::sync::Arc;
-use tokio::sync::{Mutex, MutexGuard};
+use reqwest::Url;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{Mutex, MutexGuard, Notify};
+use tracing::debug;
+
+/// Read size when hashing a spilled download back from the staging directory
+const SPILL_READ_BLOCK: usize = 64 * 1024;
 
 #[derive(Clone, Debug)]
-pub struct Map(Arc<Mutex<HashMap<String, Downloader>>>);
+pub struct Map(Arc<Mutex<HashMap<Url, Downloader>>>);
 
 impl Default for Map {
     fn default() -> Self {
@@ -24,20 +50,20 @@ impl Map {
     pub(crate) fn new() -> Self {
         Self(Arc::new(Mutex::new(HashMap::new())))
     }
-    pub(crate) async fn lock(&self) -> MutexGuard<'_, HashMap<String, Downloader>> {
+    pub(crate) async fn lock(&self) -> MutexGuard<'_, HashMap<Url, Downloader>> {
         self.0.lock().await
     }
-    pub(crate) fn as_inner(&self) -> &Arc<Mutex<HashMap<String, Downloader>>> {
+    pub(crate) fn as_inner(&self) -> &Arc<Mutex<HashMap<Url, Downloader>>> {
         &self.0
     }
-    pub(crate) fn into_inner(self) -> Arc<Mutex<HashMap<String, Downloader>>> {
+    pub(crate) fn into_inner(self) -> Arc<Mutex<HashMap<Url, Downloader>>> {
         self.0
     }
-    pub(crate) async fn insert(&self, k: String, v: Downloader) -> Option<Downloader> {
+    pub(crate) async fn insert(&self, k: Url, v: Downloader) -> Option<Downloader> {
         let mut lock = self.lock().await;
         lock.insert(k, v)
     }
-    pub(crate) async fn get(&self, k: &str) -> Result<Downloader> {
+    pub(crate) async fn get(&self, k: &Url) -> Result<Downloader> {
         let lock = self.lock().await;
         let res = lock.get(k);
         res.cloned().ok_or(ManicError::NotFound)
@@ -46,30 +72,221 @@ impl Map {
 
 #[derive(Debug, Clone)]
 pub struct Downloaded {
-    url: String,
+    url: Url,
+    final_url: Url,
     name: String,
-    data: ChunkVec,
+    data: Payload,
+    lock: LockPolicy,
+    /// Share of the batch's memory budget, held while the data is in memory
+    reservation: Option<Arc<Reservation>>,
+}
+
+/// Where a finished download's data ended up
+#[derive(Debug, Clone)]
+pub(crate) enum Payload {
+    Memory(ChunkVec),
+    /// Written to the staging directory because it didn't fit under the memory cap
+    Spilled(PathBuf),
+    /// Left out by [`MultiDownloader::skip_unchanged`]
+    Skipped,
 }
 
 impl Downloaded {
-    pub(crate) fn new(url: String, name: String, data: ChunkVec) -> Self {
-        Self { url, name, data }
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+    /// URL the download ended up at after redirects
+    pub fn final_url(&self) -> &Url {
+        &self.final_url
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// The downloaded data, `None` if it was spilled to disk or skipped
+    pub fn data(&self) -> Option<&ChunkVec> {
+        match &self.data {
+            Payload::Memory(data) => Some(data),
+            _ => None,
+        }
+    }
+    /// Path of the staged file if the download was spilled to disk
+    pub fn path(&self) -> Option<&Path> {
+        match &self.data {
+            Payload::Spilled(path) => Some(path),
+            _ => None,
+        }
+    }
+    pub fn status(&self) -> DownloadStatus {
+        match &self.data {
+            Payload::Skipped => DownloadStatus::SkippedUnchanged,
+            _ => DownloadStatus::Downloaded,
+        }
+    }
+    /// Record the downloaded content in `index`, skipped downloads keep their entry
+    ///
+    /// Spilled downloads are hashed as they're read back so they never sit in memory whole
+    pub async fn record(&self, index: &mut SyncIndex) -> Result<()> {
+        let mut digest = index.digest();
+        match &self.data {
+            Payload::Memory(data) => data.blocks().into_iter().for_each(|b| digest.update(b)),
+            Payload::Spilled(path) => {
+                let mut file = tokio::fs::File::open(path).await?;
+                let mut buf = vec![0u8; SPILL_READ_BLOCK];
+                loop {
+                    let n = file.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    digest.update(&buf[..n]);
+                }
+            }
+            Payload::Skipped => return Ok(()),
+        }
+        index.record(&self.url, &self.final_url, digest);
+        Ok(())
+    }
+    pub(crate) fn new(
+        url: Url,
+        final_url: Url,
+        name: String,
+        data: Payload,
+        lock: LockPolicy,
+    ) -> Self {
+        Self {
+            url,
+            final_url,
+            name,
+            data,
+            lock,
+            reservation: None,
+        }
+    }
+    /// Keep `reservation` while the data is in memory, it's given back right away otherwise
+    pub(crate) fn hold(&mut self, reservation: Option<Reservation>) {
+        if let Payload::Memory(_) = self.data {
+            self.reservation = reservation.map(Arc::new);
+        }
+    }
+    /// Write data held under the memory budget to the `staging` directory, giving back its share
+    async fn spill(&mut self, staging: &Path) -> Result<()> {
+        if let Payload::Memory(data) = &self.data {
+            let path = limit::staging_path(staging, &self.url, &self.name);
+            tokio::fs::create_dir_all(staging).await?;
+            data.save_to_file(&path).await?;
+            debug!(
+                "Over the memory budget, spilled {} to {}",
+                self.url,
+                path.display()
+            );
+            self.data = Payload::Spilled(path);
+            self.reservation = None;
+        }
+        Ok(())
     }
     pub(crate) async fn save<T: AsRef<Path>>(&self, output_dir: T) -> Result<()> {
-        let output_path = output_dir.as_ref().join(Path::new(&self.name));
-        self.data.save_to_file(output_path).await
+        let name = filename::sanitize(&self.name)
+            .ok_or_else(|| ManicError::NoFilename(self.url.to_string()))?;
+        let output_path = output_dir.as_ref().join(name);
+        let _lock = self.lock.acquire_async(&output_path).await?;
+        match &self.data {
+            Payload::Memory(data) => data.save_to_file(output_path).await,
+            Payload::Spilled(path) => {
+                let partial = PartialFile::new(&output_path);
+                tokio::fs::copy(path, partial.path()).await?;
+                Ok(partial.persist()?)
+            }
+            // The file from the previous run stays as it is
+            Payload::Skipped => Ok(()),
+        }
+    }
+}
+
+/// Start order of a batch, shared by clones of a [`MultiDownloader`]
+#[derive(Debug, Default)]
+pub(crate) struct Queue {
+    order: std::sync::Mutex<Order>,
+    /// Woken when a URL is added or its priority changes
+    changed: Notify,
+}
+
+#[derive(Debug, Default)]
+struct Order {
+    priorities: HashMap<Url, Priority>,
+    /// Position of each URL in the order it was first added
+    added: HashMap<Url, usize>,
+    /// When each URL was first added, Low downloads are promoted by how long they've waited
+    queued_at: HashMap<Url, Instant>,
+}
+
+impl Queue {
+    fn order(&self) -> std::sync::MutexGuard<'_, Order> {
+        self.order.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    fn add(&self, url: &Url, priority: Option<Priority>) {
+        let mut order = self.order();
+        let next = order.added.len();
+        order.added.entry(url.clone()).or_insert(next);
+        order
+            .queued_at
+            .entry(url.clone())
+            .or_insert_with(Instant::now);
+        if let Some(priority) = priority {
+            order.priorities.insert(url.clone(), priority);
+        }
+        drop(order);
+        self.changed.notify_one();
+    }
+}
+
+/// Download of a running batch and the start order it was admitted with
+struct Running {
+    order: (Priority, usize),
+    handle: DownloadHandle<Downloaded>,
+}
+
+impl Future for Running {
+    type Output = Result<Downloaded>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.get_mut().handle.poll_result(cx)
     }
 }
 
-#[derive(Clone, Builder)]
+/// Downloads a batch of files
+///
+/// Clones share their downloads and start order, URLs added through a clone while
+/// [`download_all`][Self::download_all] runs join the running batch
+#[derive(Clone)]
+#[cfg_attr(feature = "builder", derive(Builder))]
 pub struct MultiDownloader {
-    #[builder(default)]
+    #[cfg_attr(feature = "builder", builder(default))]
     downloaders: Map,
-    #[builder(default, setter(skip))]
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
     #[cfg(feature = "progress")]
     progress: Option<Arc<MultiProgress>>,
     #[cfg(feature = "progress")]
     progress_style: Option<ProgressStyle>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    policy: JoinPolicy,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    max_memory: Option<u64>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    staging_dir: Option<PathBuf>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    budget: Option<u64>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    bandwidth: Arc<Bandwidth>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    queue: Arc<Queue>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    max_concurrent: Option<usize>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    max_queue_age: Option<Duration>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    preempt_low: bool,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    skipped: Vec<Downloaded>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    metadata_cache: Option<Arc<dyn MetadataCache>>,
 }
 
 impl MultiDownloader {
@@ -86,37 +303,350 @@ impl MultiDownloader {
             progress: pb,
             #[cfg(feature = "progress")]
             progress_style: None,
+            policy: JoinPolicy::default(),
+            max_memory: None,
+            staging_dir: None,
+            budget: None,
+            bandwidth: Arc::new(Bandwidth::new()),
+            queue: Arc::new(Queue::default()),
+            max_concurrent: None,
+            max_queue_age: None,
+            preempt_low: false,
+            skipped: Vec::new(),
+            metadata_cache: None,
         }
     }
-    pub async fn add(&mut self, url: String, workers: u8) -> Result<()> {
+    pub async fn add(&mut self, url: impl ToUrl, workers: u8) -> Result<()> {
+        let url = url.to_url()?;
         #[allow(unused_mut)]
-        let mut client = Downloader::new(&url, workers).await?;
+        let mut client = match &self.metadata_cache {
+            Some(cache) => Downloader::new_cached(&url, workers, cache.clone()).await?,
+            None => Downloader::new(&url, workers).await?,
+        };
         #[cfg(feature = "progress")]
         if let Some(pb) = &self.progress {
             let mpb = ProgressBar::new(client.get_len());
             let to_add = pb.add(mpb);
             client.connect_progress(to_add);
         }
-        self.downloaders.insert(url, client).await;
+        self.downloaders.insert(url.clone(), client).await;
+        self.queue.add(&url, None);
         Ok(())
     }
-    pub async fn verify(&mut self, url: String, hash: Hash) -> Result<()> {
+    /// Add a URL that's started by `priority` instead of [`Priority::Normal`]
+    pub async fn add_with_priority(
+        &mut self,
+        url: impl ToUrl,
+        workers: u8,
+        priority: Priority,
+    ) -> Result<()> {
+        let url = url.to_url()?;
+        // Set before it's added so a running batch never sees it at the default priority
+        self.queue.order().priorities.insert(url.clone(), priority);
+        self.add(&url, workers).await
+    }
+    pub async fn verify(&mut self, url: impl ToUrl, hash: Hash) -> Result<()> {
+        let url = url.to_url()?;
         let mut lock = self.downloaders.lock().await;
         let chosen: &mut Downloader = lock.get_mut(&url).ok_or(ManicError::NotFound)?;
         let modified = chosen.verify(hash);
         lock.insert(url, modified).ok_or(ManicError::NotFound)?;
         Ok(())
     }
+    /// Check the download of an added URL against a detached signature,
+    /// see [`Downloader::verify_signature`]
+    #[cfg(feature = "sig-verify")]
+    pub async fn verify_signature(
+        &mut self,
+        url: impl ToUrl,
+        policy: SignaturePolicy,
+    ) -> Result<()> {
+        let url = url.to_url()?;
+        let mut lock = self.downloaders.lock().await;
+        let chosen = lock.get_mut(&url).ok_or(ManicError::NotFound)?;
+        chosen.verify_signature(policy);
+        Ok(())
+    }
+    /// Probe URLs passed to [`add`][Self::add] through `cache`
+    pub fn metadata_cache(&mut self, cache: Arc<dyn MetadataCache>) -> &mut Self {
+        self.metadata_cache = Some(cache);
+        self
+    }
+    /// Number of distinct URLs added
+    pub async fn len(&self) -> usize {
+        self.downloaders.lock().await.len()
+    }
+    pub async fn is_empty(&self) -> bool {
+        self.downloaders.lock().await.is_empty()
+    }
+    /// Set how failed downloads affect the result of [`download_all`][Self::download_all],
+    /// by default any failure fails the whole batch
+    pub fn join_policy(&mut self, policy: JoinPolicy) -> &mut Self {
+        self.policy = policy;
+        self
+    }
+    /// Cap the downloaded data all downloads in the batch hold in memory at once.
+    ///
+    /// A download reserves its length before it's started and keeps the reservation while its
+    /// chunks are in memory, so fewer downloads run at once instead of the batch running out of
+    /// memory. Finished downloads keep their share until a waiting download needs it, then
+    /// they're written to the [`staging_dir`][Self::staging_dir] and returned as a
+    /// [`path`][Downloaded::path]. A download larger than the whole budget reserves all of it
+    /// and runs on its own
+    pub fn memory_budget(&mut self, bytes: u64) -> &mut Self {
+        self.budget = Some(bytes);
+        self
+    }
+    /// Cap the downloaded data [`download_all`][Self::download_all] keeps in memory.
+    ///
+    /// Downloads that finish once the cap is reached are written to the staging directory
+    /// and returned as a [`path`][Downloaded::path] instead of bytes. Only finished downloads
+    /// count, the ones still in flight hold their chunks on top of the cap.
+    /// Staged files are left for the caller to move or remove
+    pub fn max_memory(&mut self, bytes: u64) -> &mut Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+    /// Directory downloads over the [`max_memory`][Self::max_memory] cap or the
+    /// [`memory_budget`][Self::memory_budget] are written to, `manic` in the system temp directory by default
+    pub fn staging_dir<T: AsRef<Path>>(&mut self, dir: T) -> &mut Self {
+        self.staging_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+    fn staging(&self) -> PathBuf {
+        self.staging_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("manic"))
+    }
+    fn memory_cap(&self) -> Option<Arc<MemoryCap>> {
+        self.max_memory
+            .map(|max| Arc::new(MemoryCap::new(max, self.staging())))
+    }
+    /// Set the priority of an added URL, [`Priority::Normal`] by default
+    pub async fn priority(&mut self, url: impl ToUrl, priority: Priority) -> Result<()> {
+        let url = url.to_url()?;
+        if !self.downloaders.lock().await.contains_key(&url) {
+            return Err(ManicError::NotFound);
+        }
+        self.queue.add(&url, Some(priority));
+        Ok(())
+    }
+    /// Downloads start by priority, those of equal priority in the order they were added.
+    /// Low downloads that waited longer than the [`max_queue_age`][Self::max_queue_age] count as Normal
+    fn start_order(&self, url: &Url, now: Instant) -> (Priority, usize) {
+        let order = self.queue.order();
+        let mut priority = order.priorities.get(url).copied().unwrap_or_default();
+        let waited = order.queued_at.get(url).map(|at| now.duration_since(*at));
+        if priority == Priority::Low
+            && self
+                .max_queue_age
+                .zip(waited)
+                .is_some_and(|(max, waited)| waited >= max)
+        {
+            priority = Priority::Normal;
+        }
+        (
+            priority,
+            order.added.get(url).copied().unwrap_or(usize::MAX),
+        )
+    }
+    /// Run at most `n` downloads of the batch at once, pending downloads start by [`Priority`]
+    /// as running ones finish. Unlimited by default
+    pub fn max_concurrent(&mut self, n: usize) -> &mut Self {
+        self.max_concurrent = Some(n.max(1));
+        self
+    }
+    /// Start [`Priority::Low`] downloads that waited `age` since they were added as if they
+    /// were [`Priority::Normal`], so a steady stream of Normal ones can't hold them back forever
+    pub fn max_queue_age(&mut self, age: Duration) -> &mut Self {
+        self.max_queue_age = Some(age);
+        self
+    }
+    /// Pause running [`Priority::Low`] downloads to make room for more urgent ones when all of the
+    /// [`max_concurrent`][Self::max_concurrent] slots are taken. Paused downloads keep their
+    /// finished chunks and are resumed once they're the most urgent download waiting
+    pub fn preempt_low(&mut self, preempt: bool) -> &mut Self {
+        self.preempt_low = preempt;
+        self
+    }
+    /// Bytes per second received by all downloads of the running [`download_all`][Self::download_all],
+    /// sampled over about a second
+    pub fn total_speed(&self) -> u64 {
+        self.bandwidth.speed()
+    }
+    /// Bytes received so far by the running or last [`download_all`][Self::download_all]
+    pub fn total_downloaded(&self) -> u64 {
+        self.bandwidth.downloaded()
+    }
+    /// Bytes still to be received by the running [`download_all`][Self::download_all],
+    /// skipped downloads don't count
+    pub fn total_remaining(&self) -> u64 {
+        self.bandwidth.remaining()
+    }
+    /// Take URLs that look unchanged since they were recorded in `index` out of the batch
+    ///
+    /// They're returned by [`download_all`][Self::download_all] with
+    /// [`DownloadStatus::SkippedUnchanged`] and no data. Returns the skipped URLs
+    ///
+    /// With [`SkipStrategy::SizeAndEdges`] a URL whose edges can't be fetched counts as changed
+    pub async fn skip_unchanged(
+        &mut self,
+        index: &SyncIndex,
+        strategy: SkipStrategy,
+    ) -> Result<Vec<Url>> {
+        let mut skipped = Vec::new();
+        let mut map = self.downloaders.lock().await;
+        for (url, dl) in map.iter() {
+            let entry = match index.get(url) {
+                Some(entry) if entry.size == dl.get_len() => entry,
+                _ => continue,
+            };
+            // An empty file has no edges to fetch, the size already matched
+            if strategy == SkipStrategy::SizeAndEdges && entry.size > 0 {
+                let (prefix, suffix) = entry.edges();
+                let probe = async {
+                    let prefix = dl.fetch_range(prefix.0, prefix.1).await?;
+                    let suffix = dl.fetch_range(suffix.0, suffix.1).await?;
+                    Ok::<_, ManicError>(entry.edges_match(&prefix, &suffix))
+                };
+                match probe.await {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        debug!("Downloading {}, its edges couldn't be fetched: {}", url, e);
+                        continue;
+                    }
+                }
+            }
+            skipped.push(url.clone());
+        }
+        for url in skipped.iter() {
+            if let Some(dl) = map.remove(url) {
+                self.skipped.push(dl.into_downloaded(Payload::Skipped));
+            }
+        }
+        Ok(skipped)
+    }
+    /// Download every added URL, at most [`max_concurrent`][Self::max_concurrent] at once
+    ///
+    /// URLs added through a clone while the batch runs are started by the same order as the
+    /// rest as soon as there's room
     pub async fn download_all(&self) -> Result<Vec<Downloaded>> {
-        let mut fut_vec = Vec::new();
-        let lock = self.downloaders.lock().await;
-        for v in lock.values() {
-            let c = v.clone();
-            fut_vec.push(tokio::spawn(c.multi_download()));
+        let cap = self.memory_cap();
+        let budget = self.budget.map(MemoryBudget::new);
+        let limit = self.max_concurrent.unwrap_or(usize::MAX);
+        self.bandwidth.reset(0);
+        let mut started = HashSet::new();
+        let mut running = FuturesUnordered::new();
+        let mut results = Vec::new();
+        loop {
+            let starved = self
+                .admit(&mut started, &mut running, limit, &cap, &budget)
+                .await;
+            if starved {
+                // A finished download gives up its share of the budget for the one held back
+                let held = results
+                    .iter_mut()
+                    .filter_map(|x: &mut Result<Downloaded>| x.as_mut().ok())
+                    .find(|x| x.reservation.is_some());
+                if let Some(held) = held {
+                    held.spill(&self.staging()).await?;
+                    continue;
+                }
+            }
+            if running.is_empty() {
+                break;
+            }
+            tokio::select! {
+                Some(res) = running.next() => results.push(res),
+                _ = self.queue.changed.notified() => {}
+            }
+        }
+        let mut done = self.policy.collect(results)?;
+        done.extend(self.skipped.iter().cloned());
+        Ok(done)
+    }
+    /// Start or resume the most urgent waiting downloads while fewer than `limit` are running,
+    /// then pause Low ones for more urgent downloads if [`preempt_low`][Self::preempt_low] is set
+    ///
+    /// Returns whether the most urgent waiting download was held back by the memory budget
+    async fn admit(
+        &self,
+        started: &mut HashSet<Url>,
+        running: &mut FuturesUnordered<Running>,
+        limit: usize,
+        cap: &Option<Arc<MemoryCap>>,
+        budget: &Option<MemoryBudget>,
+    ) -> bool {
+        let now = Instant::now();
+        let mut waiting = self
+            .downloaders
+            .lock()
+            .await
+            .iter()
+            .filter(|(url, _)| !started.contains(*url))
+            .map(|(url, dl)| (self.start_order(url, now), dl.clone()))
+            .collect::<Vec<_>>();
+        waiting.sort_by_key(|(order, _)| *order);
+        let mut waiting = waiting.into_iter().peekable();
+        let mut starved = false;
+        loop {
+            let in_state = |state| running.iter().filter(move |x| x.handle.state() == state);
+            let next = waiting.peek().map(|(order, dl)| (*order, dl.get_len()));
+            let fits = |len| budget.as_ref().is_none_or(|b| b.fits(len));
+            if in_state(DownloadState::Running).count() < limit {
+                let paused = in_state(DownloadState::Paused).min_by_key(|x| x.order);
+                match (paused, next) {
+                    (Some(paused), next)
+                        if next.is_none_or(|(next, len)| paused.order < next || !fits(len)) =>
+                    {
+                        // Paused downloads already hold their share of the budget
+                        starved |= next.is_some_and(|(_, len)| !fits(len));
+                        paused.handle.resume();
+                    }
+                    (_, Some((_, len))) => {
+                        let reservation = match budget {
+                            Some(budget) => match budget.try_reserve(len) {
+                                Some(reservation) => Some(reservation),
+                                None => {
+                                    starved = true;
+                                    break;
+                                }
+                            },
+                            None => None,
+                        };
+                        let (order, mut dl) = waiting.next().unwrap();
+                        started.insert(dl.url().clone());
+                        dl.set_bandwidth(Some(self.bandwidth.clone()));
+                        self.bandwidth.grow(dl.get_len());
+                        let handle = dl.start_multi(cap.clone(), reservation);
+                        running.push(Running { order, handle });
+                    }
+                    _ => break,
+                }
+                continue;
+            }
+            // Pausing doesn't give back any of the budget, so only for a download that fits
+            if !self.preempt_low
+                || next.is_none_or(|((priority, _), len)| priority == Priority::Low || !fits(len))
+            {
+                break;
+            }
+            // The Low download that would be started last is paused first
+            match in_state(DownloadState::Running)
+                .filter(|x| x.order.0 == Priority::Low)
+                .max_by_key(|x| x.order)
+            {
+                Some(low) => {
+                    low.handle.pause();
+                }
+                None => break,
+            }
         }
-        Ok(join_all(fut_vec).await?.to_vec())
+        starved
     }
-    pub async fn download_one(&self, url: String) -> Result<ChunkVec> {
+    pub async fn download_one(&self, url: impl ToUrl) -> Result<ChunkVec> {
+        let url = url.to_url()?;
         let chosen = self.downloaders.get(&url).await?;
         chosen.download().await
     }
@@ -0,0 +1,180 @@
+use super::bandwidth::Bandwidth;
+use crate::limit::SizeLimit;
+use crate::retry::{RangeFailures, Retry};
+use crate::util::{PauseToken, RateLimiter, Scope};
+use crate::{HttpVersionPolicy, ManicError, Result};
+use futures::future::BoxFuture;
+#[cfg(feature = "progress")]
+use indicatif::ProgressBar;
+use reqwest::header::{HeaderMap, LOCATION};
+use reqwest::{Client, Method, Request, RequestBuilder, Response, Url};
+use std::sync::Arc;
+use tracing::debug;
+
+/// Redirect hops followed before giving up, same as reqwest's default policy
+const MAX_REDIRECTS: usize = 10;
+
+/// Hook that signs every outgoing request, e.g. with AWS SigV4 credentials
+///
+/// `sign` is called once the URL and all headers, `Range` included, are final and right before
+/// the request is sent, then again on every redirect hop since the host may change.
+/// A [`map_request`][super::Downloader::map_request] hook runs before the signer, so its changes are signed.
+/// Downloads never send a body, signers that need a payload digest can use the digest of the empty payload
+pub trait RequestSigner: Send + Sync + std::fmt::Debug {
+    fn sign<'a>(&'a self, req: &'a mut Request) -> BoxFuture<'a, Result<()>>;
+}
+
+/// The parts of an outgoing request a [`map_request`][super::Downloader::map_request] hook can see,
+/// the URL and headers can be changed, downloads never send a body
+#[derive(Debug, Clone)]
+pub struct RequestParts {
+    method: Method,
+    url: Url,
+    headers: HeaderMap,
+}
+
+impl RequestParts {
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+    pub fn url_mut(&mut self) -> &mut Url {
+        &mut self.url
+    }
+    /// Headers as they'll be sent, `Range` included for chunk requests
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+}
+
+/// Hook set with [`map_request`][super::Downloader::map_request]
+#[derive(Clone)]
+pub(crate) struct RequestMap(pub(crate) Arc<dyn Fn(RequestParts) -> RequestParts + Send + Sync>);
+
+impl std::fmt::Debug for RequestMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RequestMap")
+    }
+}
+
+impl RequestMap {
+    fn apply(&self, mut req: Request) -> Request {
+        let parts = (self.0)(RequestParts {
+            method: req.method().clone(),
+            url: req.url().clone(),
+            headers: std::mem::take(req.headers_mut()),
+        });
+        *req.url_mut() = parts.url;
+        *req.headers_mut() = parts.headers;
+        req
+    }
+}
+
+/// What's done to every request right before it's sent, the map first and the signer second
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Hooks<'a> {
+    pub(crate) map: Option<&'a RequestMap>,
+    pub(crate) signer: Option<&'a dyn RequestSigner>,
+}
+
+/// Everything a chunk request needs besides its URL and range
+#[derive(Debug)]
+pub(crate) struct RequestContext {
+    pub(crate) client: Client,
+    pub(crate) signer: Option<Arc<dyn RequestSigner>>,
+    pub(crate) map: Option<RequestMap>,
+    pub(crate) bandwidth: Option<Arc<Bandwidth>>,
+    pub(crate) rate_limit: Option<RateLimiter>,
+    pub(crate) scope: Option<Scope>,
+    pub(crate) pause: Option<PauseToken>,
+    pub(crate) limit: Option<SizeLimit>,
+    pub(crate) retry: Retry,
+    pub(crate) range_failures: RangeFailures,
+    pub(crate) http_version: HttpVersionPolicy,
+    /// Sent as `If-Range` so a changed file isn't mixed into the chunks already received
+    pub(crate) if_range: Option<String>,
+    #[cfg(feature = "progress")]
+    pub(crate) pb: Option<ProgressBar>,
+}
+
+impl RequestContext {
+    pub(crate) async fn send(&self, req: RequestBuilder) -> Result<Response> {
+        let hooks = Hooks {
+            map: self.map.as_ref(),
+            signer: self.signer.as_deref(),
+        };
+        send(&self.client, req, hooks).await.map_err(|e| match e {
+            ManicError::NetError(e) => self.http_version.explain(e),
+            e => e,
+        })
+    }
+    /// Take `n` received bytes into account in the progress bar and the bandwidth totals
+    pub(crate) fn count(&self, n: u64) {
+        if let Some(bw) = &self.bandwidth {
+            bw.add(n);
+        }
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.pb {
+            bar.inc(n);
+        }
+    }
+    /// Give back `n` bytes of an attempt that's retried, they'll be received again
+    pub(crate) fn release(&self, n: u64) {
+        if let Some(limit) = &self.limit {
+            limit.release(n);
+        }
+        if let Some(bw) = &self.bandwidth {
+            bw.release(n);
+        }
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.pb {
+            bar.dec(n);
+        }
+    }
+}
+
+/// Send the request, running the hooks on it first if there are any
+///
+/// With hooks the client must not follow redirects itself, they're followed here
+/// so each hop is mapped and signed for its own URL
+pub(crate) async fn send(
+    client: &Client,
+    req: RequestBuilder,
+    hooks: Hooks<'_>,
+) -> Result<Response> {
+    let mut req = req.build()?;
+    if hooks.map.is_none() && hooks.signer.is_none() {
+        return Ok(client.execute(req).await?);
+    }
+    for _ in 0..=MAX_REDIRECTS {
+        // Cloned before the hooks run so a hop never carries the previous hop's changes
+        let next = req.try_clone();
+        if let Some(map) = hooks.map {
+            req = map.apply(req);
+        }
+        if let Some(signer) = hooks.signer {
+            signer.sign(&mut req).await?;
+        }
+        let resp = client.execute(req).await?;
+        let location = resp
+            .headers()
+            .get(LOCATION)
+            .and_then(|x| x.to_str().ok())
+            .filter(|_| resp.status().is_redirection());
+        match (location, next) {
+            (Some(location), Some(mut next)) => {
+                let target = resp.url().join(location)?;
+                debug!("Following redirect to {}", target);
+                *next.url_mut() = target;
+                req = next;
+            }
+            _ => return Ok(resp),
+        }
+    }
+    Err(ManicError::TooManyRedirects(MAX_REDIRECTS))
+}
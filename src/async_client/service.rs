@@ -0,0 +1,220 @@
+use super::request::RequestContext;
+use crate::events;
+use crate::header::{IF_RANGE, RANGE};
+use crate::retry::is_range_failure;
+use crate::{ManicError, Result};
+use futures::future::BoxFuture;
+use reqwest::header::HeaderMap;
+use reqwest::{StatusCode, Url};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+/// Request for the bytes of one chunk
+#[derive(Debug, Clone)]
+pub struct ChunkRequest {
+    pub url: Url,
+    /// Bytes asked for, both ends included
+    pub range: RangeInclusive<u64>,
+    /// Attempts made before this one, 0 on the first try
+    pub attempt: u32,
+}
+
+impl ChunkRequest {
+    pub(crate) fn new(url: Url, range: RangeInclusive<u64>) -> Self {
+        Self {
+            url,
+            range,
+            attempt: 0,
+        }
+    }
+    /// Value of the `Range` header sent for the chunk
+    pub fn header(&self) -> String {
+        format!("bytes={}-{}", self.range.start(), self.range.end())
+    }
+    fn len(&self) -> u64 {
+        self.range.end() - self.range.start() + 1
+    }
+}
+
+/// Body and headers of a chunk response
+#[derive(Debug, Clone)]
+pub struct ChunkResponse {
+    pub bytes: Vec<u8>,
+    pub headers: HeaderMap,
+}
+
+/// Something that turns a [`ChunkRequest`] into a [`ChunkResponse`], e.g. manic's HTTP service
+/// wrapped in timeouts, metrics or circuit breaking, see [`with_service`][super::Downloader::with_service]
+pub trait ChunkService: Send + Sync + std::fmt::Debug {
+    fn call(&self, req: ChunkRequest) -> BoxFuture<'_, Result<ChunkResponse>>;
+}
+
+impl<S: ChunkService + ?Sized> ChunkService for Arc<S> {
+    fn call(&self, req: ChunkRequest) -> BoxFuture<'_, Result<ChunkResponse>> {
+        (**self).call(req)
+    }
+}
+
+/// Wraps manic's HTTP service in the user's stack
+type Wrap = dyn Fn(Arc<dyn ChunkService>) -> Arc<dyn ChunkService> + Send + Sync;
+
+/// Hook set with [`with_service`][super::Downloader::with_service]
+#[derive(Clone)]
+pub(crate) struct ServiceStack(pub(crate) Arc<Wrap>);
+
+impl std::fmt::Debug for ServiceStack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ServiceStack")
+    }
+}
+
+/// The service chunk requests of one download go through, outermost first:
+/// retries and scheduler, the user's stack and the HTTP request itself
+pub(crate) fn stack(
+    ctx: &Arc<RequestContext>,
+    user: Option<&ServiceStack>,
+) -> Arc<dyn ChunkService> {
+    let http: Arc<dyn ChunkService> = Arc::new(Http { ctx: ctx.clone() });
+    let inner = match user {
+        Some(stack) => (stack.0)(http),
+        None => http,
+    };
+    let throttled = Arc::new(Throttled {
+        inner,
+        ctx: ctx.clone(),
+    });
+    Arc::new(Retrying {
+        inner: throttled,
+        ctx: ctx.clone(),
+    })
+}
+
+/// Holds a scheduler slot for one attempt, given back before a retry waits out its backoff
+#[derive(Debug)]
+struct Throttled {
+    inner: Arc<dyn ChunkService>,
+    ctx: Arc<RequestContext>,
+}
+
+impl ChunkService for Throttled {
+    fn call(&self, req: ChunkRequest) -> BoxFuture<'_, Result<ChunkResponse>> {
+        Box::pin(async move {
+            let _slot = match &self.ctx.scope {
+                Some(s) => Some(s.admit(req.len()).await),
+                None => None,
+            };
+            self.inner.call(req).await
+        })
+    }
+}
+
+/// Retries failed attempts with backoff as configured with [`retries`][super::Downloader::retries]
+#[derive(Debug)]
+struct Retrying {
+    inner: Arc<dyn ChunkService>,
+    ctx: Arc<RequestContext>,
+}
+
+impl ChunkService for Retrying {
+    fn call(&self, mut req: ChunkRequest) -> BoxFuture<'_, Result<ChunkResponse>> {
+        Box::pin(async move {
+            loop {
+                match self.inner.call(req.clone()).await {
+                    Ok(resp) => return Ok(resp),
+                    Err(e) if self.ctx.retry.should_retry(req.attempt, &e) => {
+                        let delay = self.ctx.retry.delay(req.attempt);
+                        events::chunk_retry(&req.url, &req.header(), req.attempt + 1, delay, &e);
+                        tokio::time::sleep(delay).await;
+                        req.attempt += 1;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        })
+    }
+}
+
+/// One ranged GET, the body is throttled and counted frame by frame as it arrives
+#[derive(Debug)]
+struct Http {
+    ctx: Arc<RequestContext>,
+}
+
+impl ChunkService for Http {
+    fn call(&self, req: ChunkRequest) -> BoxFuture<'_, Result<ChunkResponse>> {
+        Box::pin(async move {
+            let failures = &self.ctx.range_failures;
+            if failures.tripped() {
+                return Err(ManicError::RangeIgnored(req.header()));
+            }
+            let res = self.fetch(&req).await;
+            match &res {
+                Ok(_) => failures.record(false),
+                Err(e) if is_range_failure(e) => failures.record(true),
+                Err(_) => {}
+            }
+            res
+        })
+    }
+}
+
+impl Http {
+    async fn fetch(&self, req: &ChunkRequest) -> Result<ChunkResponse> {
+        let ctx = &self.ctx;
+        if let Some(pause) = &ctx.pause {
+            pause.resumed().await;
+        }
+        let mut request = ctx.client.get(req.url.clone()).header(RANGE, req.header());
+        if let Some(etag) = &ctx.if_range {
+            request = request.header(IF_RANGE, etag);
+        }
+        let mut resp = ctx.send(request).await?.error_for_status()?;
+        // A plain `200 OK` is the whole file, only acceptable for a range starting at zero
+        if resp.status() != StatusCode::PARTIAL_CONTENT && *req.range.start() != 0 {
+            return Err(ManicError::RangeIgnored(req.header()));
+        }
+        let headers = resp.headers().clone();
+        let mut buf = Vec::with_capacity(req.len() as usize);
+        let mut counted = Counted { ctx, bytes: 0 };
+        while let Some(b) = resp.chunk().await? {
+            // Fail as soon as the body runs past the end of the range, before the frame counts anywhere
+            if (buf.len() + b.len()) as u64 > req.len() {
+                return Err(ManicError::RangeIgnored(req.header()));
+            }
+            if let Some(rate) = &ctx.rate_limit {
+                rate.acquire(b.len() as u64).await;
+            }
+            if let Some(limit) = &ctx.limit {
+                limit.add(b.len() as u64)?;
+            }
+            counted.bytes += b.len() as u64;
+            ctx.count(b.len() as u64);
+            buf.extend_from_slice(&b);
+            if let Some(pause) = &ctx.pause {
+                pause.resumed().await;
+            }
+        }
+        counted.bytes = 0;
+        Ok(ChunkResponse {
+            bytes: buf,
+            headers,
+        })
+    }
+}
+
+/// Bytes of an attempt counted towards progress and limits, given back unless the chunk is delivered
+///
+/// The attempt may fail, be retried or be dropped while other chunks are requested again,
+/// the bytes are received again either way
+struct Counted<'a> {
+    ctx: &'a RequestContext,
+    bytes: u64,
+}
+
+impl Drop for Counted<'_> {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            self.ctx.release(self.bytes);
+        }
+    }
+}
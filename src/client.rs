@@ -0,0 +1,165 @@
+use crate::{ManicError, Result};
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// HTTP versions the client may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HttpVersionPolicy {
+    /// HTTP/2 if the server offers it through ALPN, HTTP/1.1 otherwise
+    #[default]
+    Auto,
+    /// Never use HTTP/2, e.g. for servers whose HTTP/2 implementation mangles range requests
+    Http1Only,
+    /// Always use HTTP/2, connections to servers that don't speak it fail
+    Http2Only,
+}
+
+impl HttpVersionPolicy {
+    /// Name the policy in protocol and handshake failures, a forced version the server
+    /// doesn't speak otherwise only shows up as an obscure protocol error
+    ///
+    /// Transport failures like a refused or reset connection stay network errors so they're retried
+    pub(crate) fn explain(self, e: reqwest::Error) -> ManicError {
+        if self == Self::Auto || !is_protocol_error(&e) {
+            return e.into();
+        }
+        ManicError::HttpVersion {
+            policy: self,
+            reason: e.to_string(),
+        }
+    }
+}
+
+/// Whether a request failed on the HTTP framing or the TLS handshake rather than the transport
+///
+/// Transport failures, DNS included, carry an IO error, TLS rejecting the handshake is the only
+/// one of those that counts since that's how an ALPN mismatch shows up
+fn is_protocol_error(e: &reqwest::Error) -> bool {
+    if e.status().is_some() || e.is_timeout() || e.is_body() || e.is_decode() || e.is_builder() {
+        return false;
+    }
+    let mut source = std::error::Error::source(e);
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<std::io::Error>() {
+            return io.kind() == std::io::ErrorKind::InvalidData;
+        }
+        source = err.source();
+    }
+    true
+}
+
+/// TCP options applied to every connection the client opens, before the TLS handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Set `TCP_NODELAY`, on by default so small requests aren't held back by Nagle's algorithm
+    pub fn nodelay(mut self, enabled: bool) -> Self {
+        self.nodelay = enabled;
+        self
+    }
+    /// Send TCP keepalive probes after the connection has been idle for `idle`, off by default
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.keepalive = Some(idle);
+        self
+    }
+}
+
+/// Connection settings used to build the HTTP client of a downloader
+///
+/// Both clients are configured from the same settings so the async and threaded
+/// downloaders stay in sync
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    local_address: Option<IpAddr>,
+    manual_redirects: bool,
+    http_version: HttpVersionPolicy,
+    socket: SocketOptions,
+    connection_close: bool,
+}
+
+macro_rules! configure {
+    ($opts:expr, $builder:expr) => {{
+        let mut builder = $builder;
+        if let Some(addr) = $opts.local_address {
+            builder = builder.local_address(addr);
+        }
+        if $opts.manual_redirects {
+            builder = builder.redirect(reqwest::redirect::Policy::none());
+        }
+        builder = builder
+            .tcp_nodelay($opts.socket.nodelay)
+            .tcp_keepalive($opts.socket.keepalive);
+        if $opts.connection_close {
+            let mut headers = reqwest::header::HeaderMap::new();
+            headers.insert(
+                reqwest::header::CONNECTION,
+                reqwest::header::HeaderValue::from_static("close"),
+            );
+            builder = builder.pool_max_idle_per_host(0).default_headers(headers);
+        }
+        builder = match $opts.http_version {
+            HttpVersionPolicy::Auto => builder,
+            HttpVersionPolicy::Http1Only => builder.http1_only(),
+            HttpVersionPolicy::Http2Only => builder.http2_prior_knowledge(),
+        };
+        builder
+    }};
+}
+
+impl ClientOptions {
+    /// Bind outgoing connections to a local address, e.g. to pick the interface on multi-homed hosts
+    pub fn bind_address(mut self, addr: IpAddr) -> Self {
+        self.local_address = Some(addr);
+        self
+    }
+    /// Restrict the HTTP versions the client may use, [`HttpVersionPolicy::Auto`] by default
+    pub fn http_version(mut self, policy: HttpVersionPolicy) -> Self {
+        self.http_version = policy;
+        self
+    }
+    /// Set the TCP options of the client's connections
+    pub fn socket_options(mut self, socket: SocketOptions) -> Self {
+        self.socket = socket;
+        self
+    }
+    /// Reuse connections between requests, on by default
+    ///
+    /// Turned off every request is sent with `Connection: close` and no idle connections are kept,
+    /// for servers that corrupt the responses to later requests on a reused connection
+    pub fn keep_alive(mut self, enabled: bool) -> Self {
+        self.connection_close = !enabled;
+        self
+    }
+    pub(crate) fn http_version_policy(&self) -> HttpVersionPolicy {
+        self.http_version
+    }
+    /// Leave redirects to the caller, request signers have to sign every hop themselves
+    #[cfg(feature = "async")]
+    pub(crate) fn manual_redirects(mut self) -> Self {
+        self.manual_redirects = true;
+        self
+    }
+    /// Build the async client
+    #[cfg(feature = "async")]
+    pub fn build(&self) -> Result<reqwest::Client> {
+        Ok(configure!(self, reqwest::Client::builder()).build()?)
+    }
+    /// Build the blocking client
+    #[cfg(feature = "threaded")]
+    pub fn build_blocking(&self) -> Result<reqwest::blocking::Client> {
+        Ok(configure!(self, reqwest::blocking::Client::builder()).build()?)
+    }
+}
@@ -0,0 +1,147 @@
+use crate::io::Blocks;
+use crate::{ManicError, Result};
+use flate2::read::MultiGzDecoder;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path};
+use tar::{Archive, EntryType};
+
+/// Compression a download is undone with as it's saved,
+/// see [`Downloader::decompress_on_save`][crate::Downloader::decompress_on_save]
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip, a file of several concatenated members is decompressed as a whole
+    Gzip,
+}
+
+impl Compression {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => ".gz",
+        }
+    }
+    /// Filename of the decompressed file, `name` without the format's extension
+    pub(crate) fn strip_extension(self, name: &str) -> String {
+        name.strip_suffix(self.extension())
+            .filter(|x| !x.is_empty())
+            .unwrap_or(name)
+            .to_string()
+    }
+    fn decoder<R: Read>(self, input: R) -> impl Read {
+        match self {
+            Self::Gzip => MultiGzDecoder::new(input),
+        }
+    }
+    /// Decompress the downloaded `blocks` into a new file at `path`
+    pub(crate) fn decompress(self, blocks: Vec<&[u8]>, path: &Path) -> Result<()> {
+        let mut input = self.decoder(Blocks::new(blocks));
+        let mut output = BufWriter::new(File::create(path)?);
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ManicError::Decompress(e.to_string())),
+            };
+            output.write_all(&buf[..n])?;
+        }
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok(())
+    }
+}
+
+/// Decompressed content of a download, decompressed from the chunks in memory as it's read
+///
+/// Returned by [`Downloader::download_decompressed`][crate::Downloader::download_decompressed],
+/// invalid data fails the read with [`io::ErrorKind::InvalidData`]
+pub struct Decompressed {
+    inner: Box<dyn Read + Send>,
+}
+
+impl Decompressed {
+    pub(crate) fn new<B: AsRef<[u8]> + Send + 'static>(
+        format: Compression,
+        blocks: Vec<B>,
+    ) -> Self {
+        Self {
+            inner: Box::new(format.decoder(Blocks::new(blocks))),
+        }
+    }
+    /// Unpack the content as a tar archive into `dir`, entry by entry as it's decompressed
+    ///
+    /// Entries whose path or link target leads outside `dir` fail the extraction with
+    /// [`ManicError::UnsafeEntry`], entries before it are left in place
+    pub(crate) fn extract(self, dir: &Path) -> Result<()> {
+        let mut archive = Archive::new(self);
+        for entry in archive.entries().map_err(invalid)? {
+            let mut entry = entry.map_err(invalid)?;
+            let path = entry.path().map_err(invalid)?.into_owned();
+            // Symlinks resolve from the entry's directory, hard links from the archive's root
+            let link_depth = match entry.header().entry_type() {
+                EntryType::Symlink => Some(depth(&path).map_or(0, |x| x.saturating_sub(1))),
+                EntryType::Link => Some(0),
+                _ => None,
+            };
+            let link = match link_depth {
+                Some(base) => entry
+                    .link_name()
+                    .map_err(invalid)?
+                    .map(|x| (base, x.into_owned())),
+                None => None,
+            };
+            if depth(&path).is_none() || link.is_some_and(|(base, x)| walk(base, &x).is_none()) {
+                return Err(ManicError::UnsafeEntry(path.display().to_string()));
+            }
+            if !entry.unpack_in(dir).map_err(unpack)? {
+                return Err(ManicError::UnsafeEntry(path.display().to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Directories below the archive's root `path` ends in, `None` if it leaves the root
+fn depth(path: &Path) -> Option<usize> {
+    walk(0, path)
+}
+
+/// Directories below the archive's root after following `path` from `base`, `None` if it leaves the root
+fn walk(base: usize, path: &Path) -> Option<usize> {
+    path.components().try_fold(base, |depth, part| match part {
+        Component::Normal(_) => Some(depth + 1),
+        Component::CurDir => Some(depth),
+        Component::ParentDir => depth.checked_sub(1),
+        Component::RootDir | Component::Prefix(_) => None,
+    })
+}
+
+/// Failure to read the archive, from the decompressor or the tar headers
+fn invalid(e: io::Error) -> ManicError {
+    ManicError::Decompress(e.to_string())
+}
+
+/// Failure to unpack an entry, the data ran out or didn't decompress, or writing it failed
+fn unpack(e: io::Error) -> ManicError {
+    match e.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => invalid(e),
+        _ => ManicError::IOError(e),
+    }
+}
+
+impl Read for Decompressed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl fmt::Debug for Decompressed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Decompressed")
+    }
+}
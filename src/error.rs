@@ -1,3 +1,8 @@
+use crate::to_url::SUPPORTED_SCHEMES;
+use crate::HttpVersionPolicy;
+#[cfg(feature = "builder")]
+use derive_builder::UninitializedFieldError;
+use std::fmt;
 use std::num::ParseIntError;
 use thiserror::Error;
 
@@ -5,47 +10,457 @@ use thiserror::Error;
 #[derive(Debug, Error)]
 pub enum ManicError {
     /// Returned when the content length couldn't be parsed
-    #[error("Failed to parse content-length")]
+    #[error("Failed to parse content-length [{}]", ErrorCode::LengthParse)]
     LenParse(#[from] ParseIntError),
     /// Returned when the content-length = 0
-    #[error("Content length is 0")]
+    #[error("Content length is 0 [{}]", ErrorCode::NoLength)]
     NoLen,
     /// Represents problems with IO
-    #[error("IO error: {0}")]
+    #[error("IO error: {0} [{}]", ErrorCode::Io)]
     IOError(#[from] std::io::Error),
-    #[error("Network error: {0}")]
+    #[error("Network error: {0} [{}]", ErrorCode::from_net(.0))]
     NetError(#[from] reqwest::Error),
     /// Returned when the header can't be parsed to a String
-    #[error("Header to string error: {0}")]
+    #[error("Header to string error: {0} [{}]", ErrorCode::HeaderToStr)]
     ToStr(#[from] reqwest::header::ToStrError),
     /// Returned when there's no filename in the url
-    #[error("No filename in url {0}")]
+    #[error("No filename in url {0} [{}]", ErrorCode::NoFilename)]
     NoFilename(String),
     /// Returned when the url couldn't be parsed
-    #[error("URL parsing error: {0}")]
+    #[error("URL parsing error: {0} [{}]", ErrorCode::UrlParse)]
     UrlParseError(#[from] url::ParseError),
+    /// Returned when a URL given to the public API couldn't be parsed
+    #[error("Invalid URL {input}: {reason} [{}]", ErrorCode::InvalidUrl)]
+    InvalidUrl { input: String, reason: String },
+    /// Returned when the URL's scheme isn't one the downloaders can fetch
+    #[error("Unsupported URL scheme {scheme:?} in {url}, expected one of {} [{}]", SUPPORTED_SCHEMES.join(", "), ErrorCode::UnsupportedScheme)]
+    UnsupportedScheme { scheme: String, url: String },
+    /// Returned when a required builder field wasn't set
+    #[error("Builder field {0} is not set [{}]", ErrorCode::UninitializedField)]
+    UninitializedField(&'static str),
+    /// Returned when a `data:` URL couldn't be decoded
+    #[error("Invalid data URL: {0} [{}]", ErrorCode::DataUrl)]
+    DataUrl(String),
     /// Returned when the SHA256 sum didn't match
-    #[error("SHA sum mismatch: {0}")]
+    #[error("SHA sum mismatch: {0} [{}]", ErrorCode::HashMismatch)]
     SHA256MisMatch(String),
+    /// Returned when a detached signature doesn't verify against the trusted key
+    #[error(
+        "Signature doesn't match key {key_id} [{}]",
+        ErrorCode::SignatureMismatch
+    )]
+    SignatureMismatch { key_id: String },
+    /// Returned when a required detached signature couldn't be found at its sidecar URL
+    #[error("No signature at {0} [{}]", ErrorCode::SignatureMissing)]
+    SignatureMissing(String),
+    /// Returned when a signature or public key couldn't be parsed
+    #[error("Malformed signature or key: {0} [{}]", ErrorCode::SignatureFormat)]
+    SignatureFormat(String),
+    /// Returned when the server doesn't speak the HTTP version the client is restricted to,
+    /// i.e. the request failed on the protocol or the TLS handshake
+    #[error(
+        "Request failed with HTTP version policy {policy:?}: {reason} [{}]",
+        ErrorCode::HttpVersion
+    )]
+    HttpVersion {
+        policy: HttpVersionPolicy,
+        reason: String,
+    },
+    /// Returned when a server answers a ranged request with more than the range,
+    /// i.e. it doesn't support byte ranges but didn't say so in `Accept-Ranges`
+    #[error("Server ignored the requested range {0} [{}]", ErrorCode::RangeIgnored)]
+    RangeIgnored(String),
+    /// Returned when the saved file's size differs from the content length
+    #[error(
+        "Saved file is {actual} bytes, expected {expected} [{}]",
+        ErrorCode::SizeMismatch
+    )]
+    SizeMismatch { expected: u64, actual: u64 },
+    /// Returned when mirrors of a file don't report the same length or strong `ETag`,
+    /// lists the mirrors that differ from the first one
+    #[error(
+        "Mirrors don't serve the same file: {} [{}]",
+        .0.join(", "),
+        ErrorCode::MirrorMismatch
+    )]
+    MirrorMismatch(Vec<String>),
+    /// Returned when an entry extracted from a zip archive doesn't match its CRC32
+    #[error(
+        "CRC32 of {name} is {actual:08x}, expected {expected:08x} [{}]",
+        ErrorCode::CrcMismatch
+    )]
+    CrcMismatch {
+        name: String,
+        expected: u32,
+        actual: u32,
+    },
+    /// Returned when a zip archive's directory or an entry's header couldn't be parsed
+    #[error("Malformed zip archive: {0} [{}]", ErrorCode::ZipFormat)]
+    ZipFormat(String),
+    /// Returned when a zip entry is encrypted or compressed with a method other than stored or deflate
+    #[error("Can't extract {name}: {reason} [{}]", ErrorCode::ZipUnsupported)]
+    ZipUnsupported { name: String, reason: String },
+    /// Returned when an entry asked for isn't in the zip archive
+    #[error("No entry {0} in the zip archive [{}]", ErrorCode::ZipEntryNotFound)]
+    ZipEntryNotFound(String),
+    /// Returned when a download set to be decompressed on save isn't valid in that format
+    #[error("Can't decompress the download: {0} [{}]", ErrorCode::Decompress)]
+    Decompress(String),
+    /// Returned when an archive entry's path or link target leads outside the directory it's extracted into
+    #[error(
+        "Archive entry {0} leads outside the target directory [{}]",
+        ErrorCode::UnsafeEntry
+    )]
+    UnsafeEntry(String),
+    /// Returned when a metalink document couldn't be parsed
+    #[error("Malformed metalink: {0} [{}]", ErrorCode::Metalink)]
+    Metalink(String),
+    /// Returned when a piece of the download doesn't match its hash from a metalink
+    #[error(
+        "Piece {index} at offset {offset} doesn't match its hash [{}]",
+        ErrorCode::PieceMismatch
+    )]
+    PieceMismatch { index: usize, offset: u64 },
+    /// Returned when the file is larger than the downloader's `max_size`,
+    /// `size` is the bytes received so far if the server under-reported the length
+    #[error(
+        "Download of {size} bytes exceeds the limit of {limit} bytes [{}]",
+        ErrorCode::TooLarge
+    )]
+    TooLarge { limit: u64, size: u64 },
+    /// Returned when another download holds the lock on the output path
+    #[error(
+        "Another download is writing to {0} [{}]",
+        ErrorCode::ConcurrentDownload
+    )]
+    ConcurrentDownload(String),
+    /// Returned when a signed request was redirected more times than allowed
+    #[error(
+        "Too many redirects, gave up after {0} [{}]",
+        ErrorCode::TooManyRedirects
+    )]
+    TooManyRedirects(usize),
+    /// Returned when a download was cancelled through its handle
+    #[error("Download cancelled [{}]", ErrorCode::Cancelled)]
+    Cancelled,
+    /// Returned when chunks handed to `ChunkVec::from_parts` don't fit together
+    #[error("Invalid chunk: {0} [{}]", ErrorCode::InvalidChunk)]
+    InvalidChunk(String),
+    /// Returned when a setter would rebuild the client of a downloader created with the caller's client
+    #[error(
+        "{0} rebuilds the client, which would drop the one passed to new_with_client [{}]",
+        ErrorCode::CustomClient
+    )]
+    CustomClient(&'static str),
+    /// Returned when a downloader is created with zero workers
+    #[error("Workers must be at least 1 [{}]", ErrorCode::InvalidWorkers)]
+    InvalidWorkers,
     /// Returned when the selected chunk size == 0
-    #[error("Chunk size cannot be 0")]
+    #[error("Chunk size cannot be 0 [{}]", ErrorCode::BadChunkSize)]
     BadChunkSize,
-    #[error("Not found")]
+    #[error("Not found [{}]", ErrorCode::NotFound)]
     NotFound,
-    #[error("No results found")]
+    #[error("No results found [{}]", ErrorCode::NoResults)]
     NoResults,
     #[cfg(feature = "threaded")]
-    #[error("Canceled: {0}")]
+    #[error("Canceled: {0} [{}]", ErrorCode::ChannelCanceled)]
     Canceled(#[from] futures_channel::oneshot::Canceled),
     #[cfg(feature = "async")]
-    #[error("Join error: {0}")]
+    #[error("Join error: {0} [{}]", ErrorCode::Join)]
     JoinError(#[from] tokio::task::JoinError),
-    #[error("PoisonError: {0}")]
+    #[error("PoisonError: {0} [{}]", ErrorCode::Poison)]
     PoisonError(String),
-    #[error("{0}")]
+    #[error("{0} [{}]", ErrorCode::Multiple)]
     MultipleErrors(String),
 }
 
+#[cfg(feature = "builder")]
+impl From<UninitializedFieldError> for ManicError {
+    fn from(e: UninitializedFieldError) -> Self {
+        Self::UninitializedField(e.field_name())
+    }
+}
+
+impl ManicError {
+    /// Stable numeric code of the error, see [`ErrorCode`]
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::LenParse(_) => ErrorCode::LengthParse,
+            Self::NoLen => ErrorCode::NoLength,
+            Self::IOError(_) => ErrorCode::Io,
+            Self::NetError(e) => ErrorCode::from_net(e),
+            Self::ToStr(_) => ErrorCode::HeaderToStr,
+            Self::NoFilename(_) => ErrorCode::NoFilename,
+            Self::UrlParseError(_) => ErrorCode::UrlParse,
+            Self::InvalidUrl { .. } => ErrorCode::InvalidUrl,
+            Self::UnsupportedScheme { .. } => ErrorCode::UnsupportedScheme,
+            Self::UninitializedField(_) => ErrorCode::UninitializedField,
+            Self::DataUrl(_) => ErrorCode::DataUrl,
+            Self::SHA256MisMatch(_) => ErrorCode::HashMismatch,
+            Self::SignatureMismatch { .. } => ErrorCode::SignatureMismatch,
+            Self::SignatureMissing(_) => ErrorCode::SignatureMissing,
+            Self::SignatureFormat(_) => ErrorCode::SignatureFormat,
+            Self::HttpVersion { .. } => ErrorCode::HttpVersion,
+            Self::RangeIgnored(_) => ErrorCode::RangeIgnored,
+            Self::SizeMismatch { .. } => ErrorCode::SizeMismatch,
+            Self::MirrorMismatch(_) => ErrorCode::MirrorMismatch,
+            Self::CrcMismatch { .. } => ErrorCode::CrcMismatch,
+            Self::ZipFormat(_) => ErrorCode::ZipFormat,
+            Self::ZipUnsupported { .. } => ErrorCode::ZipUnsupported,
+            Self::ZipEntryNotFound(_) => ErrorCode::ZipEntryNotFound,
+            Self::Decompress(_) => ErrorCode::Decompress,
+            Self::UnsafeEntry(_) => ErrorCode::UnsafeEntry,
+            Self::Metalink(_) => ErrorCode::Metalink,
+            Self::PieceMismatch { .. } => ErrorCode::PieceMismatch,
+            Self::TooLarge { .. } => ErrorCode::TooLarge,
+            Self::ConcurrentDownload(_) => ErrorCode::ConcurrentDownload,
+            Self::TooManyRedirects(_) => ErrorCode::TooManyRedirects,
+            Self::Cancelled => ErrorCode::Cancelled,
+            Self::BadChunkSize => ErrorCode::BadChunkSize,
+            Self::InvalidChunk(_) => ErrorCode::InvalidChunk,
+            Self::InvalidWorkers => ErrorCode::InvalidWorkers,
+            Self::CustomClient(_) => ErrorCode::CustomClient,
+            Self::NotFound => ErrorCode::NotFound,
+            Self::NoResults => ErrorCode::NoResults,
+            #[cfg(feature = "threaded")]
+            Self::Canceled(_) => ErrorCode::ChannelCanceled,
+            #[cfg(feature = "async")]
+            Self::JoinError(_) => ErrorCode::Join,
+            Self::PoisonError(_) => ErrorCode::Poison,
+            Self::MultipleErrors(_) => ErrorCode::Multiple,
+        }
+    }
+    /// Whether trying again may succeed, e.g. after a dropped connection or a `503`
+    ///
+    /// Chunk retries use the same table, see [`ErrorCode::is_retryable`]
+    pub fn is_retryable(&self) -> bool {
+        self.code().is_retryable()
+    }
+    /// Coarse category reported as `error.kind` in tracing events
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::NetError(_)
+            | Self::TooManyRedirects(_)
+            | Self::HttpVersion { .. }
+            | Self::RangeIgnored(_) => "network",
+            Self::SHA256MisMatch(_)
+            | Self::SizeMismatch { .. }
+            | Self::MirrorMismatch(_)
+            | Self::CrcMismatch { .. }
+            | Self::PieceMismatch { .. }
+            | Self::SignatureMismatch { .. }
+            | Self::SignatureMissing(_) => "verification",
+            Self::IOError(_) | Self::ConcurrentDownload(_) => "filesystem",
+            Self::TooLarge { .. } => "limit",
+            Self::LenParse(_)
+            | Self::NoLen
+            | Self::ToStr(_)
+            | Self::NoFilename(_)
+            | Self::DataUrl(_)
+            | Self::SignatureFormat(_)
+            | Self::ZipFormat(_)
+            | Self::ZipUnsupported { .. }
+            | Self::Decompress(_)
+            | Self::UnsafeEntry(_)
+            | Self::Metalink(_)
+            | Self::BadChunkSize => "response",
+            Self::UrlParseError(_)
+            | Self::InvalidUrl { .. }
+            | Self::UnsupportedScheme { .. }
+            | Self::UninitializedField(_)
+            | Self::InvalidChunk(_)
+            | Self::InvalidWorkers
+            | Self::CustomClient(_)
+            | Self::NotFound
+            | Self::ZipEntryNotFound(_)
+            | Self::NoResults => "usage",
+            Self::Cancelled => "cancelled",
+            #[cfg(feature = "threaded")]
+            Self::Canceled(_) => "cancelled",
+            #[cfg(feature = "async")]
+            Self::JoinError(_) => "internal",
+            Self::PoisonError(_) => "internal",
+            Self::MultipleErrors(_) => "multiple",
+        }
+    }
+}
+
+/// Stable numeric error codes for callers that can't match on [`ManicError`], e.g. across an FFI boundary
+///
+/// Codes are grouped by subsystem: `1xxx` network, `2xxx` verification, `3xxx` filesystem,
+/// `4xxx` protocol, `5xxx` crypto, `6xxx` usage, `7xxx` limits, `8xxx` cancellation
+/// and `9xxx` internal errors. Assigned values never change, new ones are only added.
+/// The code is also appended to every error message as e.g. `[E2001]`
+#[non_exhaustive]
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    /// Couldn't connect to the server
+    Connect = 1001,
+    /// The request timed out
+    Timeout = 1002,
+    /// The connection failed while sending the request or reading the body
+    Request = 1003,
+    /// The server answered with a `5xx` status
+    ServerError = 1004,
+    /// The server answered with `429 Too Many Requests`
+    RateLimited = 1005,
+    /// The server answered with any other error status
+    HttpStatus = 1006,
+    /// Any other failure of the HTTP client
+    Network = 1007,
+    TooManyRedirects = 1008,
+    HttpVersion = 1009,
+    HashMismatch = 2001,
+    SizeMismatch = 2002,
+    MirrorMismatch = 2003,
+    CrcMismatch = 2004,
+    /// A piece of the download doesn't match its hash
+    PieceMismatch = 2005,
+    Io = 3001,
+    ConcurrentDownload = 3002,
+    RangeIgnored = 4001,
+    LengthParse = 4002,
+    NoLength = 4003,
+    HeaderToStr = 4004,
+    NoFilename = 4005,
+    DataUrl = 4006,
+    BadChunkSize = 4007,
+    ZipFormat = 4008,
+    ZipUnsupported = 4009,
+    /// The download isn't valid in the format it was set to be decompressed from
+    Decompress = 4010,
+    /// An archive entry would be extracted outside the target directory
+    UnsafeEntry = 4011,
+    /// A metalink document couldn't be parsed
+    Metalink = 4012,
+    SignatureMismatch = 5001,
+    SignatureMissing = 5002,
+    SignatureFormat = 5003,
+    UrlParse = 6001,
+    InvalidUrl = 6002,
+    UnsupportedScheme = 6003,
+    UninitializedField = 6004,
+    NotFound = 6005,
+    NoResults = 6006,
+    InvalidChunk = 6007,
+    InvalidWorkers = 6008,
+    ZipEntryNotFound = 6009,
+    /// A setter needs to rebuild a client that was passed in by the caller
+    CustomClient = 6010,
+    TooLarge = 7001,
+    Cancelled = 8001,
+    Join = 9001,
+    Poison = 9002,
+    ChannelCanceled = 9003,
+    /// Several chunks failed, the individual codes are in the message
+    Multiple = 9004,
+}
+
+impl ErrorCode {
+    /// Every assigned code
+    pub const ALL: &'static [ErrorCode] = &[
+        Self::Connect,
+        Self::Timeout,
+        Self::Request,
+        Self::ServerError,
+        Self::RateLimited,
+        Self::HttpStatus,
+        Self::Network,
+        Self::TooManyRedirects,
+        Self::HttpVersion,
+        Self::HashMismatch,
+        Self::SizeMismatch,
+        Self::MirrorMismatch,
+        Self::CrcMismatch,
+        Self::PieceMismatch,
+        Self::Io,
+        Self::ConcurrentDownload,
+        Self::RangeIgnored,
+        Self::LengthParse,
+        Self::NoLength,
+        Self::HeaderToStr,
+        Self::NoFilename,
+        Self::DataUrl,
+        Self::BadChunkSize,
+        Self::ZipFormat,
+        Self::ZipUnsupported,
+        Self::Decompress,
+        Self::UnsafeEntry,
+        Self::Metalink,
+        Self::SignatureMismatch,
+        Self::SignatureMissing,
+        Self::SignatureFormat,
+        Self::UrlParse,
+        Self::InvalidUrl,
+        Self::UnsupportedScheme,
+        Self::UninitializedField,
+        Self::NotFound,
+        Self::NoResults,
+        Self::InvalidChunk,
+        Self::InvalidWorkers,
+        Self::ZipEntryNotFound,
+        Self::CustomClient,
+        Self::TooLarge,
+        Self::Cancelled,
+        Self::Join,
+        Self::Poison,
+        Self::ChannelCanceled,
+        Self::Multiple,
+    ];
+    pub fn as_u32(self) -> u32 {
+        self as u32
+    }
+    /// Subsystem of the code: `network`, `verification`, `filesystem`, `protocol`, `crypto`,
+    /// `usage`, `limit`, `cancelled` or `internal`
+    pub fn category(self) -> &'static str {
+        match self.as_u32() / 1000 {
+            1 => "network",
+            2 => "verification",
+            3 => "filesystem",
+            4 => "protocol",
+            5 => "crypto",
+            6 => "usage",
+            7 => "limit",
+            8 => "cancelled",
+            _ => "internal",
+        }
+    }
+    /// Connection problems, timeouts, rate limiting, server errors and IO errors
+    ///
+    /// [`Io`][Self::Io] counts because a chunk request only hits IO errors while reading the
+    /// response body, e.g. a connection reset mid-chunk. Chunk requests never touch the
+    /// filesystem, saving does and isn't retried
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            Self::Connect
+                | Self::Timeout
+                | Self::Request
+                | Self::ServerError
+                | Self::RateLimited
+                | Self::Io
+        )
+    }
+    pub(crate) fn from_net(e: &reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) if status.as_u16() == 429 => Self::RateLimited,
+            Some(status) if status.is_server_error() => Self::ServerError,
+            Some(_) => Self::HttpStatus,
+            None if e.is_timeout() => Self::Timeout,
+            None if e.is_connect() => Self::Connect,
+            None if e.is_request() || e.is_body() => Self::Request,
+            None => Self::Network,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "E{}", self.as_u32())
+    }
+}
+
 pub type Result<T> = std::result::Result<T, ManicError>;
 
 impl<I: Into<ManicError>> From<Vec<I>> for ManicError {
@@ -0,0 +1,79 @@
+use crate::ManicError;
+use reqwest::Url;
+#[cfg(feature = "rayon")]
+use std::path::Path;
+use std::time::Duration;
+use tracing::{event, Level};
+
+/// Target of every download event, subscribers can filter on it
+pub(crate) const DOWNLOAD: &str = "manic::download";
+/// Target of the progress events of [`hash_files`][crate::hash_files]
+#[cfg(feature = "rayon")]
+pub(crate) const HASH: &str = "manic::hash";
+
+pub(crate) fn download_start(url: &Url, bytes: u64, workers: u8) {
+    event!(
+        name: "manic.download.start",
+        target: DOWNLOAD,
+        Level::INFO,
+        url = %url,
+        bytes,
+        workers
+    );
+}
+
+pub(crate) fn chunk_retry(
+    url: &Url,
+    range: &str,
+    attempt: u32,
+    delay: Duration,
+    error: &ManicError,
+) {
+    event!(
+        name: "manic.download.chunk.retry",
+        target: DOWNLOAD,
+        Level::WARN,
+        url = %url,
+        range,
+        attempt,
+        delay_ms = delay.as_millis() as u64,
+        error.kind = error.kind(),
+        error = %error
+    );
+}
+
+pub(crate) fn download_complete(url: &Url, bytes: u64, duration: Duration) {
+    event!(
+        name: "manic.download.complete",
+        target: DOWNLOAD,
+        Level::INFO,
+        url = %url,
+        bytes,
+        duration_ms = duration.as_millis() as u64
+    );
+}
+
+pub(crate) fn download_failed(url: &Url, duration: Duration, error: &ManicError) {
+    event!(
+        name: "manic.download.failed",
+        target: DOWNLOAD,
+        Level::WARN,
+        url = %url,
+        duration_ms = duration.as_millis() as u64,
+        error.kind = error.kind(),
+        error = %error
+    );
+}
+
+#[cfg(feature = "rayon")]
+pub(crate) fn hash_file(path: &Path, bytes: u64, done: usize, total: usize) {
+    event!(
+        name: "manic.hash.file",
+        target: HASH,
+        Level::INFO,
+        path = %path.display(),
+        bytes,
+        done,
+        total
+    );
+}
@@ -0,0 +1,65 @@
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+
+/// Longest file name component ext4 and NTFS accept, in bytes
+const MAX_LEN: usize = 255;
+/// Extensions longer than this are treated as part of the name when truncating
+const MAX_EXT_LEN: usize = 16;
+/// Device names Windows refuses as file names, with or without an extension
+const RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turn the last segment of a URL path into a file name, see [`sanitize`]
+pub(crate) fn from_url_segment(segment: &str) -> Option<String> {
+    sanitize(&percent_decode_str(segment).decode_utf8_lossy())
+}
+
+/// Make `name` safe to create on any common filesystem, `None` if nothing usable is left
+///
+/// Control characters are dropped, separators and characters Windows rejects become `_`,
+/// reserved device names get a `_` prefix and names over 255 bytes are truncated keeping
+/// the extension, with a short hash of the full name appended so truncated names stay unique.
+/// The result is deterministic and sanitizing it again doesn't change it
+pub(crate) fn sanitize(name: &str) -> Option<String> {
+    let cleaned = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect::<String>();
+    let cleaned = cleaned.trim().trim_end_matches(['.', ' ']);
+    if cleaned.is_empty() {
+        return None;
+    }
+    let base = cleaned.split('.').next().unwrap_or_default();
+    let cleaned = if RESERVED.iter().any(|x| x.eq_ignore_ascii_case(base)) {
+        format!("_{}", cleaned)
+    } else {
+        cleaned.to_string()
+    };
+    Some(truncate(cleaned))
+}
+
+fn truncate(name: String) -> String {
+    if name.len() <= MAX_LEN {
+        return name;
+    }
+    let hash = Sha256::digest(name.as_bytes())
+        .iter()
+        .take(4)
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= MAX_EXT_LEN + 1 => name.split_at(i),
+        _ => (name.as_str(), ""),
+    };
+    let mut end = MAX_LEN - ext.len() - hash.len() - 1;
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}-{}{}", &stem[..end], hash, ext)
+}
@@ -1,8 +1,22 @@
+#[cfg(feature = "rayon")]
+use crate::events;
 use crate::{ManicError, Result};
 use derive_more::Display;
 use md5::Md5;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use reqwest::Url;
 use sha2::Digest;
 use sha2::{Sha224, Sha256, Sha384, Sha512};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+#[cfg(feature = "rayon")]
+use std::path::PathBuf;
+#[cfg(feature = "rayon")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "rayon")]
+use std::sync::{Condvar, Mutex};
 use tracing::debug;
 
 /// Available checksum types
@@ -25,6 +39,10 @@ pub enum Hash {
     SHA512(Sha512, String),
 }
 impl Hash {
+    /// New MD5 hash value
+    pub fn new_md5(to_verify: String) -> Self {
+        Self::MD5(Md5::new(), to_verify)
+    }
     /// New SHA224 hash value
     pub fn new_sha224(to_verify: String) -> Self {
         Self::SHA224(Sha224::new(), to_verify)
@@ -41,6 +59,26 @@ impl Hash {
     pub fn new_sha512(to_verify: String) -> Self {
         Self::SHA512(Sha512::new(), to_verify)
     }
+    /// Expected sum from a `#algo=hex` URL fragment, e.g. `#sha256=0ac1e9...`
+    ///
+    /// `md5`, `sha224`, `sha256`, `sha384` and `sha512` are recognized, anything else
+    /// or a digest of the wrong length is ignored
+    pub(crate) fn from_fragment(url: &Url) -> Option<Self> {
+        let (algo, hex) = url.fragment()?.split_once('=')?;
+        let (new, len): (fn(String) -> Self, usize) = match algo.to_ascii_lowercase().as_str() {
+            "md5" => (Self::new_md5, 32),
+            "sha224" => (Self::new_sha224, 56),
+            "sha256" => (Self::new_sha256, 64),
+            "sha384" => (Self::new_sha384, 96),
+            "sha512" => (Self::new_sha512, 128),
+            _ => return None,
+        };
+        if hex.len() != len || !hex.bytes().all(|x| x.is_ascii_hexdigit()) {
+            debug!("Ignoring URL fragment {:?}, not a {} digest", hex, algo);
+            return None;
+        }
+        Some(new(hex.to_ascii_lowercase()))
+    }
     /// Finalize the hasher and return the hex string of the final value
     pub fn finalize(self) -> String {
         match self {
@@ -64,6 +102,28 @@ impl Hash {
             Err(ManicError::SHA256MisMatch(to_verify))
         }
     }
+    /// The algorithm of this sum, to hash files against it with [`hash_files`]
+    #[cfg(feature = "threaded")]
+    pub(crate) fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            Self::MD5(..) => HashAlgorithm::MD5,
+            Self::SHA224(..) => HashAlgorithm::SHA224,
+            Self::SHA256(..) => HashAlgorithm::SHA256,
+            Self::SHA384(..) => HashAlgorithm::SHA384,
+            Self::SHA512(..) => HashAlgorithm::SHA512,
+        }
+    }
+    /// Hex string of the current value, the hasher starts over afterwards
+    #[cfg(feature = "rayon")]
+    pub(crate) fn finalize_reset(&mut self) -> String {
+        match self {
+            Self::SHA256(h, _) => format!("{:x}", h.finalize_reset()),
+            Self::SHA224(h, _) => format!("{:x}", h.finalize_reset()),
+            Self::SHA512(h, _) => format!("{:x}", h.finalize_reset()),
+            Self::SHA384(h, _) => format!("{:x}", h.finalize_reset()),
+            Self::MD5(h, _) => format!("{:x}", h.finalize_reset()),
+        }
+    }
     /// Update the hasher with data
     pub fn update(&mut self, data: &[u8]) {
         match self {
@@ -75,3 +135,151 @@ impl Hash {
         }
     }
 }
+
+/// Checksum types for hashing files without a reference sum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    MD5,
+    SHA224,
+    SHA256,
+    SHA384,
+    SHA512,
+}
+
+#[cfg(feature = "rayon")]
+impl HashAlgorithm {
+    fn hasher(self) -> Hash {
+        match self {
+            Self::MD5 => Hash::MD5(Md5::new(), String::new()),
+            Self::SHA224 => Hash::SHA224(Sha224::new(), String::new()),
+            Self::SHA256 => Hash::SHA256(Sha256::new(), String::new()),
+            Self::SHA384 => Hash::SHA384(Sha384::new(), String::new()),
+            Self::SHA512 => Hash::SHA512(Sha512::new(), String::new()),
+        }
+    }
+}
+
+/// Size of the reads files are hashed in
+const READ_BLOCK: usize = 64 * 1024;
+
+/// Counting semaphore for the files open at once
+#[cfg(feature = "rayon")]
+struct OpenLimit {
+    open: Mutex<usize>,
+    freed: Condvar,
+    max: usize,
+}
+
+#[cfg(feature = "rayon")]
+struct OpenPermit<'a>(&'a OpenLimit);
+
+#[cfg(feature = "rayon")]
+impl OpenLimit {
+    fn acquire(&self) -> OpenPermit<'_> {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        while *open >= self.max {
+            open = self.freed.wait(open).unwrap_or_else(|e| e.into_inner());
+        }
+        *open += 1;
+        OpenPermit(self)
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl Drop for OpenPermit<'_> {
+    fn drop(&mut self) {
+        *self.0.open.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Hash many files in parallel, returns the hex digest of each file in the order given
+///
+/// Files are spread over `pool`, or rayon's global pool if `None`, but at most `max_open`
+/// are read at once so spinning disks aren't thrashed by concurrent seeks.
+/// Each thread reuses its hasher and read buffer across files.
+/// Every finished file is reported with a `manic.hash.file` [event][crate#tracing-events].
+///
+/// The threaded `MultiDownloader` checks the downloads it spilled to disk through this
+#[cfg(feature = "rayon")]
+pub fn hash_files(
+    paths: &[PathBuf],
+    algo: HashAlgorithm,
+    max_open: usize,
+    pool: Option<&rayon::ThreadPool>,
+) -> Vec<(PathBuf, Result<String>)> {
+    hash_files_with(paths, algo, max_open, pool, |path: &Path| File::open(path))
+}
+
+/// [`hash_files`] reading every file through `open`, e.g. to hash through a decrypting reader
+///
+/// A file counts towards `max_open` from the call to `open` until its reader is dropped
+#[cfg(feature = "rayon")]
+pub fn hash_files_with<R, F>(
+    paths: &[PathBuf],
+    algo: HashAlgorithm,
+    max_open: usize,
+    pool: Option<&rayon::ThreadPool>,
+    open: F,
+) -> Vec<(PathBuf, Result<String>)>
+where
+    R: Read,
+    F: Fn(&Path) -> std::io::Result<R> + Sync,
+{
+    let limit = OpenLimit {
+        open: Mutex::new(0),
+        freed: Condvar::new(),
+        max: max_open.max(1),
+    };
+    let done = AtomicUsize::new(0);
+    let run = || {
+        paths
+            .par_iter()
+            .map_init(
+                || (algo.hasher(), vec![0u8; READ_BLOCK]),
+                |(hasher, buf), path| {
+                    let res = {
+                        let _permit = limit.acquire();
+                        open(path)
+                            .map_err(ManicError::from)
+                            .and_then(|file| read_all(file, hasher, buf))
+                    };
+                    // Also clears a hasher left half-fed by a failed read
+                    let digest = hasher.finalize_reset();
+                    let finished = done.fetch_add(1, Ordering::Relaxed) + 1;
+                    let bytes = *res.as_ref().unwrap_or(&0);
+                    events::hash_file(path, bytes, finished, paths.len());
+                    (path.clone(), res.map(|_| digest))
+                },
+            )
+            .collect()
+    };
+    match pool {
+        Some(pool) => pool.install(run),
+        None => run(),
+    }
+}
+
+fn read_into(path: &Path, hash: &mut Hash, buf: &mut [u8]) -> Result<()> {
+    read_all(File::open(path)?, hash, buf).map(|_| ())
+}
+
+/// Feed everything `file` holds to `hash`, returns the number of bytes read
+fn read_all(mut file: impl Read, hash: &mut Hash, buf: &mut [u8]) -> Result<u64> {
+    let mut read = 0;
+    loop {
+        let n = file.read(buf)?;
+        if n == 0 {
+            return Ok(read);
+        }
+        hash.update(&buf[..n]);
+        read += n as u64;
+    }
+}
+
+/// Hash the file at `path` as it is on disk and compare against `hash`
+pub(crate) fn verify_file(path: &Path, mut hash: Hash) -> Result<()> {
+    let mut buf = vec![0u8; READ_BLOCK];
+    read_into(path, &mut hash, &mut buf)?;
+    hash.verify()
+}
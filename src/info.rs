@@ -0,0 +1,113 @@
+use crate::{ManicError, Result};
+use reqwest::header::{
+    HeaderMap, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+    LAST_MODIFIED, SERVER,
+};
+use reqwest::{StatusCode, Url, Version};
+
+/// What a single probe request found out about a URL
+///
+/// Downloaders keep the probe they were created from,
+/// [`supports_chunks`][Self::supports_chunks] decides whether the file is split into chunks at all
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteInfo {
+    /// URL the probe ended up at after redirects
+    pub url: Url,
+    /// Size of the file, `None` if the server didn't report it
+    pub content_length: Option<u64>,
+    /// `Some(true)` for `Accept-Ranges: bytes`, `Some(false)` for `none`,
+    /// `None` if the server didn't say
+    pub accept_ranges: Option<bool>,
+    /// The `Server` header
+    pub server: Option<String>,
+    /// The `Content-Type` header
+    pub content_type: Option<String>,
+    /// The `ETag` header
+    pub etag: Option<String>,
+    /// The `Last-Modified` header
+    pub last_modified: Option<String>,
+    /// The `Cache-Control` header
+    pub cache_control: Option<String>,
+    /// HTTP version the probe was answered with, `None` for `file:` and `data:` URLs
+    pub http_version: Option<Version>,
+}
+
+impl RemoteInfo {
+    /// Read the probe's answer, a `206` to a ranged probe carries the file's size
+    /// in `Content-Range` since its `Content-Length` is the length of the range
+    pub(crate) fn from_headers(
+        url: &Url,
+        version: Version,
+        status: StatusCode,
+        headers: &HeaderMap,
+    ) -> Result<Self> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .map(|x| x.to_string())
+        };
+        let content_length = match headers.get(CONTENT_LENGTH) {
+            _ if status == StatusCode::PARTIAL_CONTENT => header(CONTENT_RANGE)
+                .as_deref()
+                .and_then(|x| x.rsplit('/').next())
+                .and_then(|x| x.trim().parse::<u64>().ok()),
+            Some(len) => Some(len.to_str()?.parse::<u64>()?),
+            None => None,
+        };
+        let accept_ranges = header(ACCEPT_RANGES).map(|x| {
+            x.split(',')
+                .any(|unit| unit.trim().eq_ignore_ascii_case("bytes"))
+        });
+        Ok(Self {
+            url: url.clone(),
+            content_length,
+            accept_ranges,
+            server: header(SERVER),
+            content_type: header(CONTENT_TYPE),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+            cache_control: header(CACHE_CONTROL),
+            http_version: Some(version),
+        })
+    }
+    /// Info for `file:` and `data:` URLs, read in one piece so ranges don't apply
+    pub(crate) fn local(url: &Url, len: u64) -> Self {
+        Self {
+            url: url.clone(),
+            content_length: Some(len),
+            accept_ranges: None,
+            server: None,
+            content_type: None,
+            etag: None,
+            last_modified: None,
+            cache_control: None,
+            http_version: None,
+        }
+    }
+    /// Whether the file can be fetched in several ranged requests, only an explicit
+    /// `Accept-Ranges: none` rules that out since many servers support ranges without saying so
+    pub fn supports_chunks(&self) -> bool {
+        self.accept_ranges != Some(false)
+    }
+}
+
+/// Check that every mirror reports the same length and, if all of them send one,
+/// the same strong `ETag` as the first one
+pub(crate) fn check_mirrors(probes: &[(Url, RemoteInfo)]) -> Result<()> {
+    let strong = |info: &RemoteInfo| info.etag.clone().filter(|x| !x.starts_with("W/"));
+    let (_, first) = probes.first().ok_or(ManicError::NoResults)?;
+    let compare_etags = probes.iter().all(|(_, info)| strong(info).is_some());
+    let divergent = probes
+        .iter()
+        .filter(|(_, info)| {
+            info.content_length != first.content_length
+                || (compare_etags && strong(info) != strong(first))
+        })
+        .map(|(url, _)| url.to_string())
+        .collect::<Vec<_>>();
+    if !divergent.is_empty() {
+        return Err(ManicError::MirrorMismatch(divergent));
+    }
+    Ok(())
+}
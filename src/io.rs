@@ -0,0 +1,91 @@
+use std::fs::File;
+#[cfg(any(feature = "remote-zip", feature = "decompress"))]
+use std::io::Read;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+/// Reads the chunks of a download one after another without joining them
+#[cfg(any(feature = "remote-zip", feature = "decompress"))]
+pub(crate) struct Blocks<B> {
+    /// Stored last to first so the next block is popped off the end
+    blocks: Vec<B>,
+    /// Bytes of the last block already read
+    pos: usize,
+}
+
+#[cfg(any(feature = "remote-zip", feature = "decompress"))]
+impl<B: AsRef<[u8]>> Blocks<B> {
+    pub(crate) fn new(mut blocks: Vec<B>) -> Self {
+        blocks.reverse();
+        Self { blocks, pos: 0 }
+    }
+}
+
+#[cfg(any(feature = "remote-zip", feature = "decompress"))]
+impl<B: AsRef<[u8]>> Read for Blocks<B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(block) = self.blocks.last() {
+            let n = (&block.as_ref()[self.pos..]).read(buf)?;
+            if n > 0 || buf.is_empty() {
+                self.pos += n;
+                return Ok(n);
+            }
+            self.blocks.pop();
+            self.pos = 0;
+        }
+        Ok(0)
+    }
+}
+
+/// Write the whole buffer at `offset` without touching the file's cursor,
+/// so disjoint ranges can be written concurrently through one handle
+#[cfg(unix)]
+pub(crate) fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+/// Write the whole buffer at `offset` without touching the file's cursor,
+/// so disjoint ranges can be written concurrently through one handle
+#[cfg(windows)]
+pub(crate) fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_write(buf, offset) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ))
+            }
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Buffer of sequential writes that hash the data on the way if none is configured
+pub(crate) const HASHED_WRITE_BUFFER: usize = 1024 * 1024;
+
+/// Write the parts in order through a buffer of `capacity` bytes, seeking only across gaps,
+/// so many small chunks end up as a few large writes
+pub(crate) fn write_sequential<'a>(
+    file: &File,
+    parts: impl IntoIterator<Item = (u64, &'a [u8])>,
+    capacity: usize,
+) -> io::Result<()> {
+    let mut out = BufWriter::with_capacity(capacity, file);
+    let mut pos = None;
+    for (offset, buf) in parts {
+        if pos != Some(offset) {
+            out.seek(SeekFrom::Start(offset))?;
+        }
+        out.write_all(buf)?;
+        pos = Some(offset + buf.len() as u64);
+    }
+    out.flush()
+}
@@ -0,0 +1,40 @@
+use crate::{ManicError, Result};
+use tracing::warn;
+
+/// Decides how the results of concurrently joined tasks are combined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JoinPolicy {
+    /// Every task has to succeed, any error fails the whole join.
+    /// Used for chunk downloads where a missing chunk means a corrupt file
+    #[default]
+    AllRequired,
+    /// Succeed as long as at least one task succeeded, errors of the failed tasks are logged
+    PartialOk,
+}
+
+impl JoinPolicy {
+    /// Combine the task results according to the policy
+    pub(crate) fn collect<T>(self, results: Vec<Result<T>>) -> Result<Vec<T>> {
+        let mut good = Vec::with_capacity(results.len());
+        let mut errs: Vec<ManicError> = Vec::new();
+        for res in results {
+            match res {
+                Ok(v) => good.push(v),
+                Err(e) => errs.push(e),
+            }
+        }
+        if errs.is_empty() {
+            return Ok(good);
+        }
+        match self {
+            Self::PartialOk if !good.is_empty() => {
+                for e in errs {
+                    warn!("Task failed: {}", e);
+                }
+                Ok(good)
+            }
+            _ if errs.len() == 1 => Err(errs.remove(0)),
+            _ => Err(errs.into()),
+        }
+    }
+}
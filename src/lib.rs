@@ -8,6 +8,29 @@
 //!
 //! The crate exposes debug logs through the [`tracing`][tracing] crate
 //!
+//! ## Tracing events
+//!
+//! Besides the debug logs, downloads emit a fixed set of events under the `manic::download` target
+//! for subscribers that export them, e.g. to OpenTelemetry. Their names and fields only change
+//! in breaking releases:
+//!
+//! | Name | Level | Fields |
+//! |---|---|---|
+//! | `manic.download.start` | INFO | `url`, `bytes`, `workers` |
+//! | `manic.download.chunk.retry` | WARN | `url`, `range`, `attempt`, `delay_ms`, `error.kind`, `error` |
+//! | `manic.download.complete` | INFO | `url`, `bytes`, `duration_ms` |
+//! | `manic.download.failed` | WARN | `url`, `duration_ms`, `error.kind`, `error` |
+//!
+//! [`hash_files`] reports its progress under the `manic::hash` target, one event per file
+//! as it's done, `done` counting the files finished so far out of `total`:
+//!
+//! | Name | Level | Fields |
+//! |---|---|---|
+//! | `manic.hash.file` | INFO | `path`, `bytes`, `done`, `total` |
+//!
+//! `error.kind` is one of `network`, `verification`, `filesystem`, `limit`, `response`,
+//! `usage`, `cancelled`, `internal` or `multiple`
+//!
 //! ## Feature flags
 //!
 //! - `progress`: Enables progress reporting using `indicatif`
@@ -16,6 +39,16 @@
 //! - `threaded`: Enables the native thread based downloader
 //! - `rustls`: Use rustls for HTTPS, on by default
 //! - `openssl`: Use openssl for HTTPS
+//! - `builder`: Enables the `derive_builder` based `DownloaderBuilder` and `MultiDownloaderBuilder`, on by default
+//! - `rayon`: Sorts downloaded chunks and hashes files with [`hash_files`] on rayon's thread pool, on by default
+//!   and required by `threaded`. Without it chunks are handled sequentially, which is as fast for typical chunk counts
+//! - `serde`: Enables `Serialize` and `Deserialize` for `SyncIndex` so it can be persisted between runs
+//! - `sig-verify`: Enables checking detached minisign and SSH signatures with [`Downloader::verify_signature`]
+//! - `remote-zip`: Enables `RemoteZip`, extracting single files from a remote zip archive with ranged requests
+//! - `decompress`: Enables decompressing gzip downloads as they're saved with `Downloader::decompress_on_save`
+//!   and unpacking `.tar.gz` archives as they're decompressed with `Downloader::download_and_extract`
+//! - `metalink`: Enables reading and writing RFC 5854 metalink files and downloading their
+//!   mirrors and checksums with `Downloader::from_metalink`
 //!
 //!
 //!
@@ -37,34 +70,80 @@
 //! ### Native threading example
 //!
 //! ```no_run
-//! use manic::threaded::Downloader;
+//! # #[cfg(feature = "threaded")]
 //! # fn main() -> Result<(), manic::ManicError> {
+//! use manic::threaded::Downloader;
 //! let client = Downloader::new("https://crates.io", 5)?;
 //! client.download()?;
 //! Ok(())
 //! # }
+//! # #[cfg(not(feature = "threaded"))]
+//! # fn main() {}
 //! ```
-#[macro_use]
-extern crate derive_builder;
 
 #[cfg(feature = "progress")]
 pub use indicatif::ProgressStyle;
-pub use reqwest::{header, Url};
+pub use reqwest::{header, Url, Version};
 
 #[cfg(feature = "async")]
 #[doc(inline)]
-pub use async_client::{Client, Downloader, MultiDownloader};
-pub use error::{ManicError, Result};
+pub use async_client::{Client, Downloader, MultiDownloader, RequestSigner};
+pub use client::{ClientOptions, HttpVersionPolicy, SocketOptions};
+#[cfg(feature = "decompress")]
+pub use decompress::{Compression, Decompressed};
+pub use error::{ErrorCode, ManicError, Result};
+pub use info::RemoteInfo;
+pub use join::JoinPolicy;
+pub use lock::LockPolicy;
+pub use metadata_cache::{MemoryMetadataCache, MetadataCache};
+#[cfg(feature = "metalink")]
+pub use metalink::{Metalink, MetalinkFile, Pieces};
+pub use priority::Priority;
+#[cfg(feature = "remote-zip")]
+pub use remote_zip::{RemoteZip, ZipEntry};
+#[cfg(feature = "sig-verify")]
+pub use signature::{SignaturePolicy, TrustedKey};
+#[cfg(feature = "async")]
+pub use sync_index::{DownloadStatus, SkipStrategy, SyncDigest, SyncEntry, SyncIndex};
 #[cfg(all(not(feature = "async"), feature = "threaded"))]
 #[doc(inline)]
 pub use threaded::{Client, Downloader, MultiDownloader};
+pub use to_url::ToUrl;
 
 #[cfg(feature = "async")]
 pub mod async_client;
+mod client;
+#[cfg(feature = "decompress")]
+mod decompress;
 mod error;
+mod events;
+mod filename;
 
 mod hash;
+mod info;
+mod io;
+mod join;
+mod limit;
+mod local;
+mod lock;
+mod metadata_cache;
+#[cfg(feature = "metalink")]
+mod metalink;
+mod partial;
+mod priority;
+#[cfg(feature = "remote-zip")]
+mod remote_zip;
+mod retry;
+#[cfg(feature = "sig-verify")]
+mod signature;
+#[cfg(feature = "async")]
+mod sync_index;
 #[cfg(feature = "threaded")]
 pub mod threaded;
+mod to_url;
+#[cfg(feature = "async")]
+pub mod util;
 
-pub use hash::Hash;
+#[cfg(feature = "rayon")]
+pub use hash::{hash_files, hash_files_with};
+pub use hash::{Hash, HashAlgorithm};
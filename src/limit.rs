@@ -0,0 +1,90 @@
+use crate::filename;
+use crate::{ManicError, Result};
+use reqwest::Url;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Cap on the bytes received over all chunk requests of one download
+///
+/// Guards against servers that ignore `Range` or send more than they announced,
+/// the announced length itself is checked before anything is fetched
+#[derive(Debug)]
+pub(crate) struct SizeLimit {
+    max: u64,
+    received: AtomicU64,
+}
+
+impl SizeLimit {
+    pub(crate) fn new(max: u64) -> Self {
+        Self {
+            max,
+            received: AtomicU64::new(0),
+        }
+    }
+    /// Fail with [`ManicError::TooLarge`] if `len` is over the cap
+    pub(crate) fn check(&self, len: u64) -> Result<()> {
+        if len > self.max {
+            return Err(ManicError::TooLarge {
+                limit: self.max,
+                size: len,
+            });
+        }
+        Ok(())
+    }
+    /// Count `n` more received bytes, fails once the total is over the cap
+    pub(crate) fn add(&self, n: u64) -> Result<()> {
+        let total = self.received.fetch_add(n, Ordering::Relaxed) + n;
+        self.check(total)
+    }
+    /// Give back bytes of a failed attempt that's about to be retried
+    pub(crate) fn release(&self, n: u64) {
+        self.received.fetch_sub(n, Ordering::Relaxed);
+    }
+}
+
+/// Cap on the downloaded data a batch keeps in memory, finished downloads
+/// that don't fit anymore are written to `staging` instead
+#[derive(Debug)]
+pub(crate) struct MemoryCap {
+    max: u64,
+    used: AtomicU64,
+    staging: PathBuf,
+}
+
+impl MemoryCap {
+    pub(crate) fn new(max: u64, staging: PathBuf) -> Self {
+        Self {
+            max,
+            used: AtomicU64::new(0),
+            staging,
+        }
+    }
+    /// Reserve `len` bytes, false if they don't fit under the cap
+    pub(crate) fn admit(&self, len: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(len).filter(|total| *total <= self.max)
+            })
+            .is_ok()
+    }
+    pub(crate) fn staging(&self) -> &Path {
+        &self.staging
+    }
+    /// Staging path for a spilled download, see [`staging_path`]
+    pub(crate) fn staging_path(&self, url: &Url, name: &str) -> PathBuf {
+        staging_path(&self.staging, url, name)
+    }
+}
+
+/// Path in `staging` for a spilled download, prefixed with a hash of the URL
+/// so downloads with the same name don't overwrite each other
+pub(crate) fn staging_path(staging: &Path, url: &Url, name: &str) -> PathBuf {
+    let hash = Sha256::digest(url.as_str().as_bytes())
+        .iter()
+        .take(4)
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let name = filename::sanitize(name).unwrap_or_default();
+    staging.join(format!("{}-{}", hash, name))
+}
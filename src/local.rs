@@ -0,0 +1,37 @@
+use crate::{ManicError, Result};
+use data_url::DataUrl;
+use reqwest::Url;
+
+/// Whether the URL is served without a network request, `file:` and `data:` URLs are
+pub(crate) fn is_local(url: &Url) -> bool {
+    matches!(url.scheme(), "file" | "data")
+}
+
+/// Size of the content behind a local URL
+pub(crate) fn local_len(url: &Url) -> Result<u64> {
+    match url.scheme() {
+        "file" => Ok(std::fs::metadata(file_path(url)?)?.len()),
+        _ => Ok(decode_data(url)?.len() as u64),
+    }
+}
+
+/// Read the content behind a local URL
+pub(crate) fn read_local(url: &Url) -> Result<Vec<u8>> {
+    match url.scheme() {
+        "file" => Ok(std::fs::read(file_path(url)?)?),
+        _ => decode_data(url),
+    }
+}
+
+fn file_path(url: &Url) -> Result<std::path::PathBuf> {
+    url.to_file_path()
+        .map_err(|_| ManicError::NoFilename(url.to_string()))
+}
+
+fn decode_data(url: &Url) -> Result<Vec<u8>> {
+    let data = DataUrl::process(url.as_str()).map_err(|e| ManicError::DataUrl(e.to_string()))?;
+    let (body, _) = data
+        .decode_to_vec()
+        .map_err(|e| ManicError::DataUrl(e.to_string()))?;
+    Ok(body)
+}
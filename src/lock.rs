@@ -0,0 +1,109 @@
+use crate::{ManicError, Result};
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Decides what happens when another process is already writing to the same output path
+///
+/// Locking is advisory and done on a `<target>.lock` file next to the output,
+/// the lock is held on the open handle so a crashed process never leaves a stale lock behind.
+/// The lock file itself is left in place, removing it would let a waiting process lock an unlinked file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockPolicy {
+    /// Don't lock the output path
+    #[default]
+    Disabled,
+    /// Wait up to the given duration for the lock, then fail with [`ManicError::ConcurrentDownload`]
+    Wait(Duration),
+    /// Fail with [`ManicError::ConcurrentDownload`] right away if the lock is taken
+    Fail,
+}
+
+/// Held exclusive lock, released on drop
+#[derive(Debug)]
+pub(crate) struct LockGuard {
+    file: File,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.file.unlock() {
+            debug!("Failed to release lock: {}", e);
+        }
+    }
+}
+
+impl LockPolicy {
+    /// Blocking lock acquisition
+    #[cfg(feature = "threaded")]
+    pub(crate) fn acquire(self, target: &Path) -> Result<Option<LockGuard>> {
+        let timeout = match self {
+            Self::Disabled => return Ok(None),
+            Self::Wait(t) => t,
+            Self::Fail => Duration::ZERO,
+        };
+        let file = open_lock_file(target)?;
+        let start = Instant::now();
+        loop {
+            if try_lock(&file, target)? {
+                return Ok(Some(LockGuard { file }));
+            }
+            if start.elapsed() >= timeout {
+                return Err(ManicError::ConcurrentDownload(target.display().to_string()));
+            }
+            std::thread::sleep(RETRY_INTERVAL);
+        }
+    }
+    /// Lock acquisition that doesn't block the runtime while waiting
+    #[cfg(feature = "async")]
+    pub(crate) async fn acquire_async(self, target: &Path) -> Result<Option<LockGuard>> {
+        let timeout = match self {
+            Self::Disabled => return Ok(None),
+            Self::Wait(t) => t,
+            Self::Fail => Duration::ZERO,
+        };
+        let file = open_lock_file(target)?;
+        let start = Instant::now();
+        loop {
+            if try_lock(&file, target)? {
+                return Ok(Some(LockGuard { file }));
+            }
+            if start.elapsed() >= timeout {
+                return Err(ManicError::ConcurrentDownload(target.display().to_string()));
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    }
+}
+
+fn lock_path(target: &Path) -> PathBuf {
+    let mut name = target
+        .file_name()
+        .map(|x| x.to_os_string())
+        .unwrap_or_default();
+    name.push(".lock");
+    target.with_file_name(name)
+}
+
+fn open_lock_file(target: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_path(target))?)
+}
+
+fn try_lock(file: &File, target: &Path) -> Result<bool> {
+    match file.try_lock_exclusive() {
+        Ok(()) => {
+            debug!("Locked {}", target.display());
+            Ok(true)
+        }
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,65 @@
+use crate::info::RemoteInfo;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Storage for probe results so repeated downloads of a URL skip the probe round-trip
+///
+/// Keyed by the URL as given, before redirects. Implement it to back the cache with a file
+/// or database, [`MemoryMetadataCache`] keeps entries in memory for a fixed time
+pub trait MetadataCache: Send + Sync + std::fmt::Debug {
+    /// The stored probe of `url`, `None` if there's none or it's stale
+    fn get(&self, url: &Url) -> Option<RemoteInfo>;
+    /// Store a fresh probe of `url`
+    fn put(&self, url: &Url, info: &RemoteInfo);
+}
+
+/// In-memory [`MetadataCache`] whose entries expire after a fixed time to live
+#[derive(Debug)]
+pub struct MemoryMetadataCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<Url, (Instant, RemoteInfo)>>,
+}
+
+impl MemoryMetadataCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+    /// Number of stored entries, expired ones included until they're looked up
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries().is_empty()
+    }
+    pub fn clear(&self) {
+        self.entries().clear();
+    }
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<Url, (Instant, RemoteInfo)>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl MetadataCache for MemoryMetadataCache {
+    fn get(&self, url: &Url) -> Option<RemoteInfo> {
+        let mut entries = self.entries();
+        match entries.get(url) {
+            Some((stored, info)) if stored.elapsed() < self.ttl => Some(info.clone()),
+            Some(_) => {
+                entries.remove(url);
+                None
+            }
+            None => None,
+        }
+    }
+    fn put(&self, url: &Url, info: &RemoteInfo) {
+        self.entries()
+            .insert(url.clone(), (Instant::now(), info.clone()));
+    }
+}
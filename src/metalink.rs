@@ -0,0 +1,417 @@
+use crate::{Hash, ManicError, Result};
+use quick_xml::events::{BytesDecl, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use reqwest::Url;
+use std::fs::File;
+use std::io::{self, Read};
+use std::num::NonZeroU64;
+use std::path::Path;
+use tracing::warn;
+
+const NAMESPACE: &str = "urn:ietf:params:xml:ns:metalink";
+
+/// Constructor of a hash type, see [`hash_type`]
+type NewHash = fn(String) -> Hash;
+
+/// Files described by an RFC 5854 metalink (`.meta4`) document
+#[derive(Debug, Clone, Default)]
+pub struct Metalink {
+    pub files: Vec<MetalinkFile>,
+}
+
+/// One file of a metalink, its mirrors and checksums
+///
+/// Download it with [`Downloader::from_metalink`][crate::Downloader::from_metalink]
+#[derive(Debug, Clone)]
+pub struct MetalinkFile {
+    /// Filename the file is saved as
+    pub name: String,
+    /// Mirrors, most preferred first
+    pub urls: Vec<Url>,
+    /// Size of the file in bytes
+    pub size: Option<u64>,
+    /// Checksums of the whole file
+    pub hashes: Vec<Hash>,
+    /// Checksums of consecutive pieces of the file
+    pub pieces: Option<Pieces>,
+}
+
+/// Checksums of consecutive `length` byte pieces of a file, the last piece may be shorter
+#[derive(Debug, Clone)]
+pub struct Pieces {
+    pub length: NonZeroU64,
+    pub hashes: Vec<Hash>,
+}
+
+impl Metalink {
+    /// Parse a metalink document
+    ///
+    /// Hashes of types other than `md5`, `sha-224`, `sha-256`, `sha-384` and `sha-512` are skipped
+    /// with a warning, as are `metaurl` elements. Mirrors are ordered by their `priority`,
+    /// mirrors without one go last
+    pub fn parse(xml: &str) -> Result<Self> {
+        let mut reader = Reader::from_str(xml);
+        reader.config_mut().trim_text(true);
+        let mut files = Vec::new();
+        let mut file: Option<FileBuilder> = None;
+        // Open elements below the current `file`
+        let mut path: Vec<Element> = Vec::new();
+        loop {
+            match reader.read_event().map_err(format_err)? {
+                Event::Start(e) => {
+                    let element = Element::start(&e)?;
+                    match (&mut file, &element) {
+                        (None, Element::File(name)) => {
+                            file = Some(FileBuilder::new(name.clone()));
+                            continue;
+                        }
+                        (Some(file), Element::Pieces(length, algo)) => {
+                            file.pieces = Some((*length, *algo, Some(Vec::new())));
+                        }
+                        _ => {}
+                    }
+                    if file.is_some() {
+                        path.push(element);
+                    }
+                }
+                Event::Text(text) => {
+                    let (Some(file), Some(element)) = (&mut file, path.last()) else {
+                        continue;
+                    };
+                    let text = text.unescape().map_err(format_err)?;
+                    file.text(element, path.len(), text.trim())?;
+                }
+                Event::End(_) if file.is_some() => match path.pop() {
+                    Some(_) => {}
+                    // The end of the `file` itself
+                    None => files.push(file.take().unwrap().build()),
+                },
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        if file.is_some() {
+            return Err(ManicError::Metalink("unclosed file element".to_string()));
+        }
+        Ok(Self { files })
+    }
+    /// Write the metalink as an RFC 5854 document
+    pub fn to_xml(&self) -> String {
+        let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+        // Writing into a Vec can't fail
+        write_document(&mut writer, &self.files).expect("writing to memory");
+        String::from_utf8(writer.into_inner()).expect("the document is built from strings")
+    }
+}
+
+impl MetalinkFile {
+    /// Describe a finished download saved at `path`, to republish it from `urls`
+    ///
+    /// The file is hashed with SHA-256 as a whole and, if `piece_length` is set, in pieces of that length.
+    /// A `piece_length` of 0 is an error
+    pub fn describe(
+        path: impl AsRef<Path>,
+        urls: Vec<Url>,
+        piece_length: Option<u64>,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let piece_length = match piece_length {
+            Some(length) => Some(NonZeroU64::new(length).ok_or_else(|| {
+                ManicError::Metalink("piece length must be greater than 0".into())
+            })?),
+            None => None,
+        };
+        let name = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .ok_or_else(|| ManicError::NoFilename(path.display().to_string()))?
+            .to_string();
+        let mut input = File::open(path)?;
+        let mut whole = Hash::new_sha256(String::new());
+        let mut piece = Hash::new_sha256(String::new());
+        let mut pieces = Vec::new();
+        let mut size = 0;
+        let mut in_piece = 0;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            whole.update(&buf[..n]);
+            size += n as u64;
+            let Some(length) = piece_length else {
+                continue;
+            };
+            let mut rest = &buf[..n];
+            while !rest.is_empty() {
+                let take = rest.len().min((length.get() - in_piece) as usize);
+                piece.update(&rest[..take]);
+                rest = &rest[take..];
+                in_piece += take as u64;
+                if in_piece == length.get() {
+                    let done = std::mem::replace(&mut piece, Hash::new_sha256(String::new()));
+                    pieces.push(Hash::new_sha256(done.finalize()));
+                    in_piece = 0;
+                }
+            }
+        }
+        if in_piece > 0 {
+            pieces.push(Hash::new_sha256(piece.finalize()));
+        }
+        Ok(Self {
+            name,
+            urls,
+            size: Some(size),
+            hashes: vec![Hash::new_sha256(whole.finalize())],
+            pieces: piece_length.map(|length| Pieces {
+                length,
+                hashes: pieces,
+            }),
+        })
+    }
+    /// The strongest of the whole file hashes
+    pub(crate) fn strongest_hash(&self) -> Option<&Hash> {
+        self.hashes.iter().max_by_key(|x| strength(x))
+    }
+}
+
+impl Pieces {
+    /// Check the downloaded `blocks`, in offset order, against the piece hashes
+    pub(crate) fn verify(&self, blocks: Vec<&[u8]>) -> Result<()> {
+        let mismatch = |index: usize| ManicError::PieceMismatch {
+            index,
+            offset: index as u64 * self.length.get(),
+        };
+        let mut expected = self.hashes.iter().enumerate();
+        let mut current: Option<(usize, Hash)> = None;
+        let mut in_piece = 0;
+        for mut block in blocks {
+            while !block.is_empty() {
+                if current.is_none() {
+                    let (index, hash) =
+                        expected.next().ok_or_else(|| mismatch(self.hashes.len()))?;
+                    current = Some((index, hash.clone()));
+                }
+                let (_, hash) = current.as_mut().unwrap();
+                let take = block.len().min((self.length.get() - in_piece) as usize);
+                hash.update(&block[..take]);
+                block = &block[take..];
+                in_piece += take as u64;
+                if in_piece == self.length.get() {
+                    let (index, hash) = current.take().unwrap();
+                    hash.verify().map_err(|_| mismatch(index))?;
+                    in_piece = 0;
+                }
+            }
+        }
+        if let Some((index, hash)) = current {
+            hash.verify().map_err(|_| mismatch(index))?;
+        }
+        // Pieces the data didn't reach
+        match expected.next() {
+            Some((index, _)) => Err(mismatch(index)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Element of a `file` the parser cares about
+enum Element {
+    File(String),
+    Url(Option<u32>),
+    Size,
+    /// Whole file hash of a supported type
+    Hash(Option<NewHash>),
+    /// Piece length and hash type
+    Pieces(NonZeroU64, Option<NewHash>),
+    Other,
+}
+
+impl Element {
+    fn start(e: &BytesStart<'_>) -> Result<Self> {
+        let attr = |name: &str| -> Result<Option<String>> {
+            match e.try_get_attribute(name).map_err(format_err)? {
+                Some(x) => Ok(Some(x.unescape_value().map_err(format_err)?.into_owned())),
+                None => Ok(None),
+            }
+        };
+        Ok(match e.local_name().as_ref() {
+            b"file" => Self::File(
+                attr("name")?.ok_or_else(|| ManicError::Metalink("file without a name".into()))?,
+            ),
+            b"url" => Self::Url(attr("priority")?.and_then(|x| x.parse().ok())),
+            b"size" => Self::Size,
+            b"hash" => Self::Hash(attr("type")?.and_then(|x| hash_type(&x))),
+            b"pieces" => {
+                let length = attr("length")?
+                    .and_then(|x| x.parse().ok())
+                    .ok_or_else(|| ManicError::Metalink("pieces without a length".into()))?;
+                Self::Pieces(length, attr("type")?.and_then(|x| hash_type(&x)))
+            }
+            _ => Self::Other,
+        })
+    }
+}
+
+/// A `file` element being parsed
+struct FileBuilder {
+    name: String,
+    urls: Vec<(Option<u32>, Url)>,
+    size: Option<u64>,
+    hashes: Vec<Hash>,
+    /// Piece length, hash type and hashes, `None` once the pieces are dropped
+    pieces: Option<(NonZeroU64, Option<NewHash>, Option<Vec<Hash>>)>,
+}
+
+impl FileBuilder {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            urls: Vec::new(),
+            size: None,
+            hashes: Vec::new(),
+            pieces: None,
+        }
+    }
+    /// Text of `element`, `depth` elements below the `file`
+    fn text(&mut self, element: &Element, depth: usize, text: &str) -> Result<()> {
+        match element {
+            Element::Url(priority) if depth == 1 => {
+                let url = Url::parse(text)
+                    .map_err(|e| ManicError::Metalink(format!("invalid url {:?}: {}", text, e)))?;
+                self.urls.push((*priority, url));
+            }
+            Element::Size if depth == 1 => {
+                let size = text
+                    .parse()
+                    .map_err(|_| ManicError::Metalink(format!("invalid size {:?}", text)))?;
+                self.size = Some(size);
+            }
+            Element::Hash(Some(new)) if depth == 1 => self.hashes.push(new(text.to_lowercase())),
+            Element::Hash(None) if depth == 1 => {
+                warn!(file = %self.name, "Skipping a hash of an unsupported type");
+            }
+            Element::Hash(_) if depth == 2 => match &mut self.pieces {
+                Some((_, Some(new), Some(hashes))) => hashes.push(new(text.to_lowercase())),
+                Some((_, None, hashes @ Some(_))) => {
+                    warn!(file = %self.name, "Skipping piece hashes of an unsupported type");
+                    *hashes = None;
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+    fn build(mut self) -> MetalinkFile {
+        // Stable, so mirrors of the same priority keep the document's order
+        self.urls
+            .sort_by_key(|(priority, _)| priority.unwrap_or(u32::MAX));
+        MetalinkFile {
+            name: self.name,
+            urls: self.urls.into_iter().map(|(_, url)| url).collect(),
+            size: self.size,
+            hashes: self.hashes,
+            pieces: match self.pieces {
+                Some((length, Some(_), Some(hashes))) => Some(Pieces { length, hashes }),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Constructor of a hash from the metalink's name of its type
+fn hash_type(name: &str) -> Option<NewHash> {
+    Some(match name.to_ascii_lowercase().as_str() {
+        "md5" => Hash::new_md5,
+        "sha-224" => Hash::new_sha224,
+        "sha-256" => Hash::new_sha256,
+        "sha-384" => Hash::new_sha384,
+        "sha-512" => Hash::new_sha512,
+        _ => return None,
+    })
+}
+
+/// The metalink's name of the hash's type
+fn type_name(hash: &Hash) -> &'static str {
+    match hash {
+        Hash::MD5(..) => "md5",
+        Hash::SHA224(..) => "sha-224",
+        Hash::SHA256(..) => "sha-256",
+        Hash::SHA384(..) => "sha-384",
+        Hash::SHA512(..) => "sha-512",
+    }
+}
+
+fn strength(hash: &Hash) -> u8 {
+    match hash {
+        Hash::MD5(..) => 0,
+        Hash::SHA224(..) => 1,
+        Hash::SHA256(..) => 2,
+        Hash::SHA384(..) => 3,
+        Hash::SHA512(..) => 4,
+    }
+}
+
+fn write_document(writer: &mut Writer<Vec<u8>>, files: &[MetalinkFile]) -> io::Result<()> {
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    writer
+        .create_element("metalink")
+        .with_attribute(("xmlns", NAMESPACE))
+        .write_inner_content(|writer| {
+            for file in files {
+                write_file(writer, file)?;
+            }
+            Ok(())
+        })?;
+    Ok(())
+}
+
+fn write_file(writer: &mut Writer<Vec<u8>>, file: &MetalinkFile) -> io::Result<()> {
+    writer
+        .create_element("file")
+        .with_attribute(("name", file.name.as_str()))
+        .write_inner_content(|writer| {
+            if let Some(size) = file.size {
+                writer
+                    .create_element("size")
+                    .write_text_content(BytesText::new(&size.to_string()))?;
+            }
+            for hash in &file.hashes {
+                writer
+                    .create_element("hash")
+                    .with_attribute(("type", type_name(hash)))
+                    .write_text_content(BytesText::new(&hash.to_string()))?;
+            }
+            if let Some(pieces) = &file.pieces {
+                let algo = pieces.hashes.first().map_or("sha-256", type_name);
+                writer
+                    .create_element("pieces")
+                    .with_attribute(("length", pieces.length.to_string().as_str()))
+                    .with_attribute(("type", algo))
+                    .write_inner_content(|writer| {
+                        for hash in &pieces.hashes {
+                            writer
+                                .create_element("hash")
+                                .write_text_content(BytesText::new(&hash.to_string()))?;
+                        }
+                        Ok(())
+                    })?;
+            }
+            for (i, url) in file.urls.iter().enumerate() {
+                writer
+                    .create_element("url")
+                    .with_attribute(("priority", (i + 1).to_string().as_str()))
+                    .write_text_content(BytesText::new(url.as_str()))?;
+            }
+            Ok(())
+        })?;
+    Ok(())
+}
+
+fn format_err(e: impl std::fmt::Display) -> ManicError {
+    ManicError::Metalink(e.to_string())
+}
@@ -0,0 +1,53 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Output written under `<target>.part` and only moved to the target once complete
+///
+/// The partial file is deleted when the guard is dropped before [`persist`][Self::persist],
+/// whether because of an error or because the download future was dropped
+#[derive(Debug)]
+pub(crate) struct PartialFile {
+    part: PathBuf,
+    target: PathBuf,
+    done: bool,
+}
+
+impl PartialFile {
+    pub(crate) fn new(target: &Path) -> Self {
+        let mut name = target
+            .file_name()
+            .map(|x| x.to_os_string())
+            .unwrap_or_default();
+        name.push(".part");
+        Self {
+            part: target.with_file_name(name),
+            target: target.to_path_buf(),
+            done: false,
+        }
+    }
+    /// Where the data has to be written
+    pub(crate) fn path(&self) -> &Path {
+        &self.part
+    }
+    /// Move the finished file to its target
+    pub(crate) fn persist(mut self) -> io::Result<()> {
+        fs::rename(&self.part, &self.target)?;
+        self.done = true;
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        match fs::remove_file(&self.part) {
+            Ok(()) => debug!("Removed partial file {}", self.part.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => debug!("Failed to remove {}: {}", self.part.display(), e),
+        }
+    }
+}
@@ -0,0 +1,12 @@
+/// Order in which a [`MultiDownloader`][crate::MultiDownloader] starts its downloads
+///
+/// Downloads with a higher priority are started first whenever the batch has room for another one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// Started before everything else, e.g. for downloads a user is waiting on
+    High,
+    #[default]
+    Normal,
+    /// Started once nothing else is waiting, e.g. for prefetches
+    Low,
+}
@@ -0,0 +1,378 @@
+use crate::async_client::{ChunkVec, Downloader};
+use crate::filename;
+use crate::io::Blocks;
+use crate::partial::PartialFile;
+use crate::{ManicError, Result};
+use flate2::read::DeflateDecoder;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+const EOCD_SIG: u32 = 0x0605_4b50;
+const EOCD_LEN: u64 = 22;
+const ZIP64_LOCATOR_SIG: u32 = 0x0706_4b50;
+const ZIP64_LOCATOR_LEN: u64 = 20;
+const ZIP64_EOCD_SIG: u32 = 0x0606_4b50;
+const ZIP64_EOCD_LEN: u64 = 56;
+const CENTRAL_SIG: u32 = 0x0201_4b50;
+const LOCAL_SIG: u32 = 0x0403_4b50;
+const LOCAL_LEN: u64 = 30;
+/// Longest archive comment that can follow the end of central directory record
+const MAX_COMMENT: u64 = 0xFFFF;
+/// Header ID of the extra field holding the 64-bit sizes and offset
+const ZIP64_EXTRA: u16 = 0x0001;
+/// A 32-bit field set to this has its value in the Zip64 extra field
+const ZIP64_MARKER: u32 = 0xFFFF_FFFF;
+const FLAG_ENCRYPTED: u16 = 1;
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+/// Used by WinZip AES encryption in place of the real method
+const AES: u16 = 99;
+/// Bytes inflated at a time on the way to the file
+const INFLATE_BUFFER: usize = 64 * 1024;
+
+/// File in a [`RemoteZip`], as listed in the archive's central directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipEntry {
+    /// Path inside the archive, directories end with `/`
+    pub name: String,
+    /// Size once extracted
+    pub size: u64,
+    /// Bytes fetched to extract the entry
+    pub compressed_size: u64,
+    pub crc32: u32,
+    method: u16,
+    flags: u16,
+    offset: u64,
+}
+
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+    /// Fail before anything is fetched if the entry can't be extracted
+    fn check_supported(&self) -> Result<()> {
+        let reason = if self.flags & FLAG_ENCRYPTED != 0 || self.method == AES {
+            "encrypted entries are not supported".to_string()
+        } else if self.method != STORED && self.method != DEFLATED {
+            format!("compression method {} is not supported", self.method)
+        } else {
+            return Ok(());
+        };
+        Err(ManicError::ZipUnsupported {
+            name: self.name.clone(),
+            reason,
+        })
+    }
+    /// Inflate the compressed bytes into `path` and check them against the size and CRC32
+    /// from the central directory
+    ///
+    /// At most one byte past the recorded size is inflated, so an entry that inflates
+    /// to more than it claims is rejected without filling the disk
+    fn decode(&self, data: &ChunkVec, path: &Path) -> Result<()> {
+        let blocks = Blocks::new(data.blocks());
+        let input: Box<dyn Read> = if self.method == DEFLATED {
+            Box::new(DeflateDecoder::new(blocks))
+        } else {
+            Box::new(blocks)
+        };
+        let mut input = input.take(self.size.saturating_add(1));
+        let mut output = BufWriter::new(File::create(path)?);
+        let mut hasher = crc32fast::Hasher::new();
+        let mut written = 0u64;
+        let mut buf = vec![0; INFLATE_BUFFER];
+        loop {
+            let n = input
+                .read(&mut buf)
+                .map_err(|e| format_error(&format!("can't inflate {}: {}", self.name, e)))?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            output.write_all(&buf[..n])?;
+            written += n as u64;
+        }
+        if written > self.size {
+            return Err(format_error(&format!(
+                "{} inflates to more than its recorded {} bytes",
+                self.name, self.size
+            )));
+        }
+        output.flush()?;
+        let actual = hasher.finalize();
+        if actual != self.crc32 || written != self.size {
+            return Err(ManicError::CrcMismatch {
+                name: self.name.clone(),
+                expected: self.crc32,
+                actual,
+            });
+        }
+        Ok(())
+    }
+    /// Where the entry goes under `dest`, each component of its name sanitized
+    fn target(&self, dest: &Path) -> Result<PathBuf> {
+        let mut path = dest.to_path_buf();
+        for part in self.name.split(['/', '\\']).filter(|x| !x.is_empty()) {
+            if part == "." || part == ".." {
+                return Err(format_error(&format!(
+                    "entry {} points outside the destination",
+                    self.name
+                )));
+            }
+            path.push(
+                filename::sanitize(part)
+                    .ok_or_else(|| format_error(&format!("unusable entry name {}", self.name)))?,
+            );
+        }
+        Ok(path)
+    }
+}
+
+/// Zip archive on a server that supports ranges, read without downloading all of it
+///
+/// [`open`][Self::open] fetches only the central directory at the end of the archive and
+/// [`extract`][Self::extract] only the compressed bytes of the entries asked for,
+/// large entries are split across the downloader's workers like a regular download
+/// and inflated straight into their file.
+/// Stored and deflated entries are supported, Zip64 archives included
+#[derive(Debug)]
+pub struct RemoteZip {
+    downloader: Downloader,
+    entries: Vec<ZipEntry>,
+}
+
+impl RemoteZip {
+    /// Read the central directory of the archive at the downloader's URL
+    pub async fn open(downloader: Downloader) -> Result<Self> {
+        let len = downloader.get_len();
+        if len < EOCD_LEN {
+            return Err(format_error("too short for a zip archive"));
+        }
+        // Without an archive comment the record is at the very end, the Zip64 locator right before it
+        let mut tail = downloader
+            .fetch_range(len.saturating_sub(EOCD_LEN + ZIP64_LOCATOR_LEN), len - 1)
+            .await?;
+        // With a short comment the record can be there while the locator before it is cut off
+        let mut eocd = find_eocd(&tail).filter(|&pos| pos as u64 >= ZIP64_LOCATOR_LEN);
+        if eocd.is_none() {
+            if len > tail.len() as u64 {
+                let start = len.saturating_sub(EOCD_LEN + ZIP64_LOCATOR_LEN + MAX_COMMENT);
+                tail = downloader.fetch_range(start, len - 1).await?;
+            }
+            eocd = find_eocd(&tail);
+        }
+        let pos = eocd.ok_or_else(|| format_error("no end of central directory record"))?;
+        let mut r = ZipReader(&tail[pos + 10..]);
+        let mut count = r.u16()? as u64;
+        let mut cd_size = r.u32()? as u64;
+        let mut cd_offset = r.u32()? as u64;
+        if let Some(locator) = pos
+            .checked_sub(ZIP64_LOCATOR_LEN as usize)
+            .map(|x| &tail[x..pos])
+            .filter(|x| x.starts_with(&ZIP64_LOCATOR_SIG.to_le_bytes()))
+        {
+            let offset = ZipReader(&locator[8..]).u64()?;
+            let end = offset
+                .checked_add(ZIP64_EOCD_LEN - 1)
+                .filter(|&end| end < len)
+                .ok_or_else(|| format_error("Zip64 record is past the end of the archive"))?;
+            let record = downloader.fetch_range(offset, end).await?;
+            let mut r = ZipReader(&record);
+            if r.u32()? != ZIP64_EOCD_SIG {
+                return Err(format_error("no Zip64 end of central directory record"));
+            }
+            r.take(28)?;
+            count = r.u64()?;
+            cd_size = r.u64()?;
+            cd_offset = r.u64()?;
+        }
+        cd_offset
+            .checked_add(cd_size)
+            .filter(|&end| end <= len)
+            .ok_or_else(|| format_error("central directory is past the end of the archive"))?;
+        let cd = match cd_size {
+            0 => Vec::new(),
+            n => downloader.fetch_range(cd_offset, cd_offset + n - 1).await?,
+        };
+        let mut r = ZipReader(&cd);
+        let entries = (0..count)
+            .map(|_| central_entry(&mut r))
+            .collect::<Result<Vec<_>>>()?;
+        debug!(
+            "Read {} zip entries from {}",
+            entries.len(),
+            downloader.url()
+        );
+        Ok(Self {
+            downloader,
+            entries,
+        })
+    }
+    /// Entries in the order of the central directory
+    pub fn entries(&self) -> &[ZipEntry] {
+        &self.entries
+    }
+    /// Extract the entries called `names` into `dest` and return the paths written
+    ///
+    /// Every name is looked up and checked before anything is fetched. Entry names are turned
+    /// into paths under `dest` with each component sanitized, names leaving `dest` are rejected.
+    /// Files are only moved into place once their CRC32 matches
+    pub async fn extract<I, S>(&self, names: I, dest: impl AsRef<Path>) -> Result<Vec<PathBuf>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let dest = dest.as_ref();
+        let chosen = names
+            .into_iter()
+            .map(|name| {
+                let name = name.as_ref();
+                let entry = self
+                    .entries
+                    .iter()
+                    .find(|x| x.name == name)
+                    .ok_or_else(|| ManicError::ZipEntryNotFound(name.to_string()))?;
+                entry.check_supported()?;
+                Ok((entry, entry.target(dest)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut written = Vec::with_capacity(chosen.len());
+        for (entry, target) in chosen {
+            if entry.is_dir() {
+                tokio::fs::create_dir_all(&target).await?;
+            } else {
+                let data = self.fetch_entry(entry).await?;
+                if let Some(parent) = target.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let partial = PartialFile::new(&target);
+                let (entry, part) = (entry.clone(), partial.path().to_path_buf());
+                tokio::task::spawn_blocking(move || entry.decode(&data, &part)).await??;
+                partial.persist()?;
+            }
+            written.push(target);
+        }
+        Ok(written)
+    }
+    /// The compressed bytes of `entry`, found through its local header
+    async fn fetch_entry(&self, entry: &ZipEntry) -> Result<ChunkVec> {
+        let len = self.downloader.get_len();
+        let past_end = || format_error(&format!("{} is past the end of the archive", entry.name));
+        let header_end = entry
+            .offset
+            .checked_add(LOCAL_LEN - 1)
+            .filter(|&end| end < len)
+            .ok_or_else(past_end)?;
+        let header = self
+            .downloader
+            .fetch_range(entry.offset, header_end)
+            .await?;
+        let mut r = ZipReader(&header);
+        if r.u32()? != LOCAL_SIG {
+            return Err(format_error(&format!("no local header for {}", entry.name)));
+        }
+        r.take(22)?;
+        // The local extra field may differ from the central one, only its length matters here
+        let start = (header_end + 1)
+            .checked_add(r.u16()? as u64 + r.u16()? as u64)
+            .ok_or_else(past_end)?;
+        if entry.compressed_size == 0 {
+            return Ok(ChunkVec::from_buf(Vec::new()));
+        }
+        let end = start
+            .checked_add(entry.compressed_size - 1)
+            .filter(|&end| end < len)
+            .ok_or_else(past_end)?;
+        self.downloader.download_range(start, end).await
+    }
+}
+
+/// Offset of the end of central directory record in the tail of the archive, searched
+/// from the end so a signature inside the comment isn't mistaken for it
+fn find_eocd(tail: &[u8]) -> Option<usize> {
+    let last = tail.len().checked_sub(EOCD_LEN as usize)?;
+    (0..=last).rev().find(|&i| {
+        let comment = u16::from_le_bytes([tail[i + 20], tail[i + 21]]) as usize;
+        tail[i..].starts_with(&EOCD_SIG.to_le_bytes())
+            && i + EOCD_LEN as usize + comment == tail.len()
+    })
+}
+
+fn central_entry(r: &mut ZipReader<'_>) -> Result<ZipEntry> {
+    if r.u32()? != CENTRAL_SIG {
+        return Err(format_error("bad central directory entry"));
+    }
+    r.take(4)?;
+    let flags = r.u16()?;
+    let method = r.u16()?;
+    r.take(4)?;
+    let crc32 = r.u32()?;
+    let mut compressed_size = r.u32()? as u64;
+    let mut size = r.u32()? as u64;
+    let name_len = r.u16()? as usize;
+    let extra_len = r.u16()? as usize;
+    let comment_len = r.u16()? as usize;
+    r.take(8)?;
+    let mut offset = r.u32()? as u64;
+    let name = String::from_utf8_lossy(r.take(name_len)?).into_owned();
+    let mut extra = ZipReader(r.take(extra_len)?);
+    r.take(comment_len)?;
+    while !extra.0.is_empty() {
+        let id = extra.u16()?;
+        let len = extra.u16()? as usize;
+        let mut field = ZipReader(extra.take(len)?);
+        if id != ZIP64_EXTRA {
+            continue;
+        }
+        // Only the values whose 32-bit field is maxed out are there, in this order
+        for value in [&mut size, &mut compressed_size, &mut offset] {
+            if *value == ZIP64_MARKER as u64 {
+                *value = field.u64()?;
+            }
+        }
+    }
+    Ok(ZipEntry {
+        name,
+        size,
+        compressed_size,
+        crc32,
+        method,
+        flags,
+        offset,
+    })
+}
+
+fn format_error(reason: &str) -> ManicError {
+    ManicError::ZipFormat(reason.to_string())
+}
+
+/// Reader for the little-endian fields of zip records
+struct ZipReader<'a>(&'a [u8]);
+
+impl<'a> ZipReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(format_error("truncated record"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(
+            self.take(2)?.try_into().expect("2 bytes"),
+        ))
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(
+            self.take(4)?.try_into().expect("4 bytes"),
+        ))
+    }
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(
+            self.take(8)?.try_into().expect("8 bytes"),
+        ))
+    }
+}
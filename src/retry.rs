@@ -0,0 +1,87 @@
+use crate::ManicError;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+/// Backoff before the first retry, doubled on every further attempt
+const BASE_DELAY: Duration = Duration::from_millis(250);
+/// Upper bound for a single backoff
+const MAX_DELAY: Duration = Duration::from_secs(30);
+/// Range failures in a row before falling back to a single request
+const RANGE_FALLBACK: u32 = 3;
+
+/// Retry settings for chunk requests
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Retry {
+    pub(crate) attempts: u32,
+    pub(crate) jitter: bool,
+    /// Range failures in a row after which the file is fetched in one request, 0 never falls back
+    pub(crate) range_fallback: u32,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            jitter: true,
+            range_fallback: RANGE_FALLBACK,
+        }
+    }
+}
+
+impl Retry {
+    /// Backoff before retry number `attempt`, counted from 0
+    ///
+    /// With jitter on this is "full jitter", a random duration in `[0, base * 2^attempt]`,
+    /// so workers failing at the same time don't come back in lockstep
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let max = BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(MAX_DELAY);
+        if self.jitter {
+            max.mul_f64(fastrand::f64())
+        } else {
+            max
+        }
+    }
+    /// Whether another attempt is left and the error is worth retrying
+    pub(crate) fn should_retry(&self, attempt: u32, err: &ManicError) -> bool {
+        attempt < self.attempts && err.is_retryable()
+    }
+}
+
+/// Ranged requests of one download that failed in a row because the range was ignored or refused
+#[derive(Debug)]
+pub(crate) struct RangeFailures {
+    threshold: u32,
+    count: AtomicU32,
+}
+
+impl RangeFailures {
+    pub(crate) fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            count: AtomicU32::new(0),
+        }
+    }
+    /// Count a failed ranged request, a successful one starts over unless it's too late
+    pub(crate) fn record(&self, failed: bool) {
+        if failed {
+            self.count.fetch_add(1, Ordering::Relaxed);
+        } else if !self.tripped() {
+            self.count.store(0, Ordering::Relaxed);
+        }
+    }
+    /// Whether ranged requests are given up on
+    pub(crate) fn tripped(&self) -> bool {
+        self.threshold > 0 && self.count.load(Ordering::Relaxed) >= self.threshold
+    }
+}
+
+/// The server or a proxy in between ignored the range or refused it with `416`
+pub(crate) fn is_range_failure(err: &ManicError) -> bool {
+    match err {
+        ManicError::RangeIgnored(_) => true,
+        ManicError::NetError(e) => e.status().map(|x| x.as_u16()) == Some(416),
+        _ => false,
+    }
+}
@@ -0,0 +1,300 @@
+use crate::{ManicError, Result, ToUrl};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use blake2::Blake2b512;
+use reqwest::Url;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use sha2::{Digest, Sha256, Sha512};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use tracing::warn;
+
+const READ_BLOCK: usize = 64 * 1024;
+const SSHSIG_MAGIC: &[u8] = b"SSHSIG";
+
+/// Public key a downloaded file's detached signature has to verify against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrustedKey {
+    /// A minisign public key, only prehashed (`ED`) signatures are accepted
+    Minisign { key_id: [u8; 8], key: [u8; 32] },
+    /// An ed25519 SSH key, signatures are made with `ssh-keygen -Y sign -n <namespace>`
+    Ssh { key: [u8; 32], namespace: String },
+}
+
+impl TrustedKey {
+    /// Parse a minisign public key, either the whole `.pub` file or just its base64 line
+    pub fn minisign(public_key: &str) -> Result<Self> {
+        let line = public_key
+            .lines()
+            .map(str::trim)
+            .rfind(|x| !x.is_empty() && !x.starts_with("untrusted comment:"))
+            .ok_or_else(|| format_error("empty minisign public key"))?;
+        let raw = decode(line)?;
+        if raw.len() != 42 || &raw[..2] != b"Ed" {
+            return Err(format_error("not a minisign ed25519 public key"));
+        }
+        Ok(Self::Minisign {
+            key_id: raw[2..10].try_into().expect("8 byte key id"),
+            key: raw[10..].try_into().expect("32 byte key"),
+        })
+    }
+    /// Parse an `ssh-ed25519` key from an allowed signers line or an OpenSSH `.pub` file,
+    /// signatures have to be made for `namespace`, `ssh-keygen` uses `file` by default
+    pub fn ssh(allowed_signer: &str, namespace: &str) -> Result<Self> {
+        let blob = allowed_signer
+            .split_whitespace()
+            .skip_while(|x| *x != "ssh-ed25519")
+            .nth(1)
+            .ok_or_else(|| format_error("no ssh-ed25519 key found"))?;
+        let key = ssh_key(&mut SshReader(&decode(blob)?))?;
+        Ok(Self::Ssh {
+            key,
+            namespace: namespace.to_string(),
+        })
+    }
+    /// Key id as the signing tool shows it, hex for minisign and `SHA256:` fingerprints for SSH
+    pub fn key_id(&self) -> String {
+        match self {
+            Self::Minisign { key_id, .. } => {
+                key_id.iter().rev().map(|b| format!("{:02X}", b)).collect()
+            }
+            Self::Ssh { key, .. } => {
+                let mut blob = Vec::new();
+                put_string(&mut blob, b"ssh-ed25519");
+                put_string(&mut blob, key);
+                format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(&blob)))
+            }
+        }
+    }
+    fn public_key(&self) -> &[u8; 32] {
+        match self {
+            Self::Minisign { key, .. } | Self::Ssh { key, .. } => key,
+        }
+    }
+    fn check(&self, message: &[u8], signature: &[u8]) -> Result<()> {
+        UnparsedPublicKey::new(&ED25519, self.public_key())
+            .verify(message, signature)
+            .map_err(|_| self.mismatch())
+    }
+    fn mismatch(&self) -> ManicError {
+        ManicError::SignatureMismatch {
+            key_id: self.key_id(),
+        }
+    }
+}
+
+/// Detached signature check run on the saved file before it's moved into place
+///
+/// The signature is fetched from a sidecar URL, `{url}` in the pattern is replaced with the download URL.
+/// By default that's `{url}.minisig` for minisign keys and `{url}.sig` for SSH keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignaturePolicy {
+    key: TrustedKey,
+    sidecar: Option<String>,
+    required: bool,
+}
+
+impl SignaturePolicy {
+    /// Require a valid signature by `key`
+    pub fn new(key: TrustedKey) -> Self {
+        Self {
+            key,
+            sidecar: None,
+            required: true,
+        }
+    }
+    /// Fetch the signature from `pattern` with `{url}` replaced by the download URL
+    pub fn sidecar(mut self, pattern: &str) -> Self {
+        self.sidecar = Some(pattern.to_string());
+        self
+    }
+    /// Accept files without a signature, a signature that is there still has to be valid
+    pub fn optional(mut self) -> Self {
+        self.required = false;
+        self
+    }
+    /// Fail if the signature is required, otherwise log that the check is skipped
+    pub(crate) fn missing(&self, sidecar: &Url) -> Result<()> {
+        if self.required {
+            return Err(ManicError::SignatureMissing(sidecar.to_string()));
+        }
+        warn!("No signature at {}, skipping the check", sidecar);
+        Ok(())
+    }
+    pub(crate) fn sidecar_url(&self, url: &Url) -> Result<Url> {
+        let pattern = match (&self.sidecar, &self.key) {
+            (Some(pattern), _) => pattern.as_str(),
+            (None, TrustedKey::Minisign { .. }) => "{url}.minisig",
+            (None, TrustedKey::Ssh { .. }) => "{url}.sig",
+        };
+        pattern.replace("{url}", url.as_str()).to_url()
+    }
+    /// Verify `signature`, the sidecar's content, over `content` read in blocks
+    pub(crate) fn verify(&self, content: Signed<'_>, signature: &[u8]) -> Result<()> {
+        let signature =
+            std::str::from_utf8(signature).map_err(|_| format_error("signature isn't text"))?;
+        match &self.key {
+            TrustedKey::Minisign { key_id, .. } => self.verify_minisign(content, signature, key_id),
+            TrustedKey::Ssh { namespace, .. } => self.verify_ssh(content, signature, namespace),
+        }
+    }
+    fn verify_minisign(
+        &self,
+        content: Signed<'_>,
+        signature: &str,
+        key_id: &[u8; 8],
+    ) -> Result<()> {
+        let mut lines = signature.lines().map(str::trim_end);
+        let mut line = || {
+            lines
+                .next()
+                .ok_or_else(|| format_error("truncated minisign signature"))
+        };
+        line()?;
+        let sig = decode(line()?)?;
+        let comment = line()?
+            .strip_prefix("trusted comment: ")
+            .ok_or_else(|| format_error("missing trusted comment"))?;
+        let global = decode(line()?)?;
+        if sig.len() != 74 || global.len() != 64 {
+            return Err(format_error("bad minisign signature length"));
+        }
+        if &sig[..2] != b"ED" {
+            return Err(format_error(
+                "only prehashed minisign signatures are supported",
+            ));
+        }
+        if &sig[2..10] != key_id {
+            return Err(self.key.mismatch());
+        }
+        let mut digest = Blake2b512::new();
+        content.read(|x| digest.update(x))?;
+        self.key.check(&digest.finalize(), &sig[10..])?;
+        // The global signature covers the trusted comment so it can't be swapped
+        let mut signed = sig[10..].to_vec();
+        signed.extend_from_slice(comment.as_bytes());
+        self.key.check(&signed, &global)
+    }
+    fn verify_ssh(&self, content: Signed<'_>, signature: &str, namespace: &str) -> Result<()> {
+        let armored = signature
+            .lines()
+            .map(str::trim)
+            .filter(|x| !x.starts_with("-----"))
+            .collect::<String>();
+        let blob = decode(&armored)?;
+        let mut r = SshReader(&blob);
+        if r.take(SSHSIG_MAGIC.len())? != SSHSIG_MAGIC || r.u32()? != 1 {
+            return Err(format_error("not an SSH signature"));
+        }
+        let key = ssh_key(&mut SshReader(r.string()?))?;
+        let sig_namespace = r.string()?;
+        let reserved = r.string()?;
+        let hash_alg = r.string()?;
+        let mut sig = SshReader(r.string()?);
+        if &key != self.key.public_key() || sig_namespace != namespace.as_bytes() {
+            return Err(self.key.mismatch());
+        }
+        if sig.string()? != b"ssh-ed25519" {
+            return Err(format_error("not an ed25519 signature"));
+        }
+        let sig = sig.string()?;
+        let digest = match hash_alg {
+            b"sha512" => {
+                let mut h = Sha512::new();
+                content.read(|x| h.update(x))?;
+                h.finalize().to_vec()
+            }
+            b"sha256" => {
+                let mut h = Sha256::new();
+                content.read(|x| h.update(x))?;
+                h.finalize().to_vec()
+            }
+            _ => return Err(format_error("unsupported SSH signature hash")),
+        };
+        let mut signed = SSHSIG_MAGIC.to_vec();
+        put_string(&mut signed, sig_namespace);
+        put_string(&mut signed, reserved);
+        put_string(&mut signed, hash_alg);
+        put_string(&mut signed, &digest);
+        self.key.check(&signed, sig)
+    }
+}
+
+/// Content a signature is checked over
+pub(crate) enum Signed<'a> {
+    /// A saved file, read in blocks
+    File(&'a Path),
+    /// Downloaded data still in memory, in offset order
+    Blocks(Vec<&'a [u8]>),
+}
+
+impl Signed<'_> {
+    fn read(&self, mut f: impl FnMut(&[u8])) -> Result<()> {
+        let path = match self {
+            Self::File(path) => path,
+            Self::Blocks(blocks) => {
+                blocks.iter().for_each(|x| f(x));
+                return Ok(());
+            }
+        };
+        let mut file = File::open(path)?;
+        let mut buf = vec![0u8; READ_BLOCK];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            f(&buf[..n]);
+        }
+    }
+}
+
+fn decode(input: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(input.trim())
+        .map_err(|e| format_error(&e.to_string()))
+}
+
+fn format_error(reason: &str) -> ManicError {
+    ManicError::SignatureFormat(reason.to_string())
+}
+
+fn put_string(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// The key of an `ssh-ed25519` public key blob
+fn ssh_key(r: &mut SshReader<'_>) -> Result<[u8; 32]> {
+    if r.string()? != b"ssh-ed25519" {
+        return Err(format_error("not an ssh-ed25519 key"));
+    }
+    r.string()?
+        .try_into()
+        .map_err(|_| format_error("bad ssh-ed25519 key length"))
+}
+
+/// Reader for the length-prefixed fields of the SSH wire format
+struct SshReader<'a>(&'a [u8]);
+
+impl<'a> SshReader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(format_error("truncated SSH data"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(
+            self.take(4)?.try_into().expect("4 bytes"),
+        ))
+    }
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let n = self.u32()? as usize;
+        self.take(n)
+    }
+}
@@ -0,0 +1,170 @@
+use reqwest::Url;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// Bytes at each end of a file hashed for [`SkipStrategy::SizeAndEdges`]
+const DEFAULT_EDGE_WINDOW: u64 = 64 * 1024;
+
+/// What was downloaded from a URL on a previous run
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SyncEntry {
+    /// SHA256 of the whole file, hex encoded
+    pub digest: String,
+    pub size: u64,
+    pub downloaded_at: SystemTime,
+    /// URL the download ended up at after redirects
+    pub final_url: String,
+    /// SHA256 of the first `edge_window` bytes
+    pub prefix_digest: String,
+    /// SHA256 of the last `edge_window` bytes
+    pub suffix_digest: String,
+    pub edge_window: u64,
+}
+
+/// Record of finished downloads for mirror jobs against servers without `ETag` or `Last-Modified`
+///
+/// Persist it between runs, e.g. with the `serde` feature, and hand it to
+/// `MultiDownloader::skip_unchanged` to leave out files that look the same as last time
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SyncIndex {
+    edge_window: u64,
+    entries: HashMap<String, SyncEntry>,
+}
+
+impl Default for SyncIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Digests of a file's content as a [`SyncIndex`] records them, fed the content in order
+///
+/// Only the last `edge_window` bytes are buffered so a file never has to be read into memory whole
+#[derive(Debug, Clone)]
+pub struct SyncDigest {
+    whole: Sha256,
+    prefix: Sha256,
+    tail: Vec<u8>,
+    size: u64,
+    edge_window: u64,
+}
+
+impl SyncDigest {
+    fn new(edge_window: u64) -> Self {
+        Self {
+            whole: Sha256::new(),
+            prefix: Sha256::new(),
+            tail: Vec::new(),
+            size: 0,
+            edge_window,
+        }
+    }
+    /// Feed the next part of the content
+    pub fn update(&mut self, data: &[u8]) {
+        self.whole.update(data);
+        let in_prefix = self
+            .edge_window
+            .saturating_sub(self.size)
+            .min(data.len() as u64);
+        self.prefix.update(&data[..in_prefix as usize]);
+        self.size += data.len() as u64;
+
+        let window = self.edge_window as usize;
+        let keep = data.len().min(window);
+        self.tail.extend_from_slice(&data[data.len() - keep..]);
+        // Trim only once the buffer doubles so the drain stays amortised
+        if self.tail.len() >= window * 2 {
+            self.tail.drain(..self.tail.len() - window);
+        }
+    }
+    fn finish(self, final_url: &Url) -> SyncEntry {
+        let window = self.tail.len().min(self.edge_window as usize);
+        SyncEntry {
+            digest: format!("{:x}", self.whole.finalize()),
+            size: self.size,
+            downloaded_at: SystemTime::now(),
+            final_url: final_url.to_string(),
+            prefix_digest: format!("{:x}", self.prefix.finalize()),
+            suffix_digest: digest(&self.tail[self.tail.len() - window..]),
+            edge_window: self.edge_window,
+        }
+    }
+}
+
+/// How [`SyncIndex`] entries are compared against the remote file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SkipStrategy {
+    /// Unchanged if the length the server reports matches, no extra requests
+    #[default]
+    Size,
+    /// Also fetch the first and last bytes with ranged requests and compare their digests
+    ///
+    /// Opt-in heuristic, a change in the middle of a file that keeps its size goes unnoticed
+    SizeAndEdges,
+}
+
+/// Whether a [`Downloaded`][crate::async_client::Downloaded] entry was fetched or left out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadStatus {
+    Downloaded,
+    /// Judged unchanged against a [`SyncIndex`], nothing was fetched
+    SkippedUnchanged,
+}
+
+impl SyncIndex {
+    pub fn new() -> Self {
+        Self {
+            edge_window: DEFAULT_EDGE_WINDOW,
+            entries: HashMap::new(),
+        }
+    }
+    /// Hash `bytes` at each end of newly recorded files, 64 KiB by default
+    pub fn edge_window(mut self, bytes: u64) -> Self {
+        self.edge_window = bytes.max(1);
+        self
+    }
+    pub fn get(&self, url: &Url) -> Option<&SyncEntry> {
+        self.entries.get(url.as_str())
+    }
+    pub fn remove(&mut self, url: &Url) -> Option<SyncEntry> {
+        self.entries.remove(url.as_str())
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// Start digesting content to [`record`][Self::record], with this index's edge window
+    pub fn digest(&self) -> SyncDigest {
+        SyncDigest::new(self.edge_window)
+    }
+    /// Record the content downloaded from `url`, replacing what was there
+    pub fn record(&mut self, url: &Url, final_url: &Url, digest: SyncDigest) {
+        self.entries
+            .insert(url.to_string(), digest.finish(final_url));
+    }
+}
+
+impl SyncEntry {
+    /// Byte ranges, inclusive, covered by the prefix and suffix digests
+    pub(crate) fn edges(&self) -> ((u64, u64), (u64, u64)) {
+        let window = std::cmp::min(self.edge_window, self.size);
+        (
+            (0, window.saturating_sub(1)),
+            (self.size - window, self.size.saturating_sub(1)),
+        )
+    }
+    pub(crate) fn edges_match(&self, prefix: &[u8], suffix: &[u8]) -> bool {
+        digest(prefix) == self.prefix_digest && digest(suffix) == self.suffix_digest
+    }
+}
+
+fn digest(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
@@ -1,19 +1,32 @@
 use super::downloader::join_all;
-use crate::header::RANGE;
-use crate::threaded::Client;
+use super::request::RequestContext;
+use crate::events;
+use crate::hash;
+use crate::header::{IF_RANGE, RANGE};
+use crate::io::{write_all_at, write_sequential, HASHED_WRITE_BUFFER};
+use crate::partial::PartialFile;
+use crate::retry::{is_range_failure, RangeFailures, Retry};
 use crate::Hash;
+use crate::HttpVersionPolicy;
+use crate::JoinPolicy;
+use crate::ToUrl;
 use crate::{ManicError, Result};
 use bytes::Bytes;
 #[cfg(feature = "progress")]
 use indicatif::ProgressBar;
 use rayon::prelude::*;
+use reqwest::blocking::Client;
+use reqwest::StatusCode;
 use rusty_pool::ThreadPool;
 use std::fs::File;
-use std::io::SeekFrom;
-use std::io::{Seek, Write};
+use std::io::Read;
 use std::path::Path;
 use std::sync::Arc;
-use tracing::{info, instrument};
+use std::time::Instant;
+use tracing::{debug, instrument};
+
+/// Size of the reads a chunk's response body is streamed in
+pub(crate) const READ_BLOCK: usize = 64 * 1024;
 
 /// Iterator over remote file chunks that returns a formatted [`RANGE`][reqwest::header::RANGE] header value
 #[derive(Debug, Clone, Copy)]
@@ -30,30 +43,186 @@ pub struct ChunkVec {
 }
 
 impl ChunkVec {
+    /// Save to `path`, the data goes to `<path>.part` first which is removed if saving fails
     pub fn save_to_file<T: AsRef<Path>>(&self, path: T, pool: ThreadPool) -> Result<()> {
-        let f = File::create(path)?;
-        self.save(f, pool)
+        let partial = PartialFile::new(path.as_ref());
+        let f = File::create(partial.path())?;
+        self.save(f, pool)?;
+        Ok(partial.persist()?)
+    }
+    /// Save to `path` like [`save_to_file`][Self::save_to_file], but check the file read back from disk
+    /// against `hash` instead of the buffer, it's only moved into place if it matches
+    pub fn save_and_verify<T: AsRef<Path>>(
+        &self,
+        path: T,
+        hash: Hash,
+        pool: ThreadPool,
+    ) -> Result<()> {
+        Ok(self.stage_verified(path.as_ref(), hash, pool)?.persist()?)
+    }
+    /// Everything [`save_and_verify`][Self::save_and_verify] does but the move into place,
+    /// so more checks can run on the `.part` file
+    pub(crate) fn stage_verified(
+        &self,
+        path: &Path,
+        hash: Hash,
+        pool: ThreadPool,
+    ) -> Result<PartialFile> {
+        let partial = PartialFile::new(path);
+        let f = File::create(partial.path())?;
+        self.save(f, pool)?;
+        hash::verify_file(partial.path(), hash)?;
+        Ok(partial)
+    }
+    /// Save to `path` like [`save_to_file`][Self::save_to_file], but write the chunks in order through
+    /// a buffer of `buffer_size` bytes instead of once per chunk, fewer syscalls for many small chunks
+    pub fn save_to_file_buffered<T: AsRef<Path>>(&self, path: T, buffer_size: usize) -> Result<()> {
+        let partial = PartialFile::new(path.as_ref());
+        let f = File::create(partial.path())?;
+        self.save_buffered(&f, buffer_size)?;
+        Ok(partial.persist()?)
+    }
+    /// Save to `path` like [`save_to_file`][Self::save_to_file], writing the chunks in offset order
+    /// and hashing them in the same pass, returns the hex SHA256 of the data
+    pub fn save_and_hash<T: AsRef<Path>>(&self, path: T) -> Result<String> {
+        let partial = PartialFile::new(path.as_ref());
+        let f = File::create(partial.path())?;
+        let hash = self.save_hashed(&f, Hash::new_sha256(String::new()), HASHED_WRITE_BUFFER)?;
+        partial.persist()?;
+        Ok(hash.finalize())
+    }
+    /// Write the chunks in order through a buffer of `buffer_size` bytes, feeding each to `hash` on the way
+    pub(crate) fn save_hashed(
+        &self,
+        output: &File,
+        mut hash: Hash,
+        buffer_size: usize,
+    ) -> Result<Hash> {
+        let parts = self.chunks.iter().map(|x| {
+            hash.update(&x.buf);
+            (x.low, x.buf.as_ref())
+        });
+        write_sequential(output, parts, buffer_size)?;
+        output.sync_all()?;
+        Ok(hash)
+    }
+    pub(crate) fn save_buffered(&self, output: &File, buffer_size: usize) -> Result<()> {
+        let parts = self.chunks.iter().map(|x| (x.low, x.buf.as_ref()));
+        write_sequential(output, parts, buffer_size)?;
+        output.sync_all()?;
+        Ok(())
     }
     pub(crate) fn save(&self, output: File, pool: ThreadPool) -> Result<()> {
+        let output = Arc::new(output);
         let mut fut_vec = Vec::new();
         for i in self.chunks.iter() {
-            let f = output.try_clone()?;
+            let f = output.clone();
             let c = i.clone();
-            fut_vec.push(pool.evaluate(|| c.save(f)))
+            fut_vec.push(pool.evaluate(move || c.save(&f)))
         }
-        join_all(fut_vec)?;
+        join_all(fut_vec, JoinPolicy::AllRequired)?;
         output.sync_all()?;
         Ok(())
     }
+    /// Total size of the downloaded data
+    pub(crate) fn byte_len(&self) -> u64 {
+        self.chunks.iter().map(|x| x.buf.len() as u64).sum()
+    }
+    /// The chunks' data in offset order
+    #[cfg(any(feature = "sig-verify", feature = "decompress", feature = "metalink"))]
+    pub(crate) fn blocks(&self) -> Vec<&[u8]> {
+        self.chunks.iter().map(|x| x.buf.as_ref()).collect()
+    }
+    /// The chunks' buffers in offset order
+    #[cfg(feature = "decompress")]
+    pub(crate) fn into_blocks(self) -> Vec<Bytes> {
+        Arc::try_unwrap(self.chunks)
+            .unwrap_or_else(|shared| shared.as_ref().clone())
+            .into_iter()
+            .map(|x| x.buf)
+            .collect()
+    }
     pub fn to_vec(&self) -> Vec<u8> {
         self.chunks
             .iter()
             .flat_map(|x| x.buf.to_vec())
             .collect::<Vec<u8>>()
     }
-    pub(crate) fn verify(&self, mut hash: Hash) -> Result<()> {
-        self.chunks.iter().for_each(|x| hash.update(x.buf.as_ref()));
-        hash.verify()
+    /// Hash the chunks and compare against `hash`
+    ///
+    /// The progress bar switches to a "verifying" phase counting the hashed bytes from zero,
+    /// so a long hash pass doesn't look like a hang at 100%
+    pub(crate) fn verify(
+        &self,
+        mut hash: Hash,
+        #[cfg(feature = "progress")] pb: Option<&ProgressBar>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        #[cfg(feature = "progress")]
+        if let Some(bar) = pb {
+            bar.set_message("verifying");
+            bar.set_length(self.chunks.iter().map(|x| x.buf.len() as u64).sum());
+            bar.set_position(0);
+        }
+        for chunk in self.chunks.iter() {
+            hash.update(chunk.buf.as_ref());
+            #[cfg(feature = "progress")]
+            if let Some(bar) = pb {
+                bar.inc(chunk.buf.len() as u64);
+            }
+        }
+        let res = hash.verify();
+        debug!("Verified in {:?}", start.elapsed());
+        #[cfg(feature = "progress")]
+        if let (Some(bar), Ok(())) = (pb, &res) {
+            bar.set_message("verified");
+        }
+        res
+    }
+}
+
+impl ChunkVec {
+    /// Wrap content that was fetched in one piece
+    pub(crate) fn from_buf(buf: Vec<u8>) -> Self {
+        let len = buf.len() as u64;
+        let hi = len.saturating_sub(1);
+        Self::from(vec![Chunk {
+            buf: Bytes::from(buf),
+            low: 0,
+            hi,
+            pos: 1,
+            len: hi,
+            bytes: format!("bytes=0-{}", hi),
+        }])
+    }
+}
+
+impl ChunkVec {
+    /// Reassemble chunks fetched with custom scheduling so they can be saved, verified or collected
+    ///
+    /// The chunks are ordered by offset, each one has to hold exactly its range and together
+    /// they have to cover the content from byte 0 without gaps or overlaps
+    pub fn from_parts(mut chunks: Vec<Chunk>) -> Result<Self> {
+        chunks.sort_unstable_by_key(|x| x.low);
+        let mut next = 0;
+        for (i, chunk) in chunks.iter_mut().enumerate() {
+            if chunk.hi < chunk.low || chunk.buf.len() as u64 != chunk.hi - chunk.low + 1 {
+                return Err(ManicError::InvalidChunk(format!(
+                    "{} holds {} bytes",
+                    chunk.bytes,
+                    chunk.buf.len()
+                )));
+            }
+            if chunk.low != next {
+                return Err(ManicError::InvalidChunk(format!(
+                    "{} doesn't start at byte {}",
+                    chunk.bytes, next
+                )));
+            }
+            next = chunk.hi + 1;
+            chunk.pos = i as u64 + 1;
+        }
+        Ok(Self::from(chunks))
     }
 }
 
@@ -76,6 +245,26 @@ pub struct Chunk {
     pub bytes: String,
 }
 
+impl Chunk {
+    /// Empty chunk for bytes `low..=hi`, fill `buf` before passing it to [`ChunkVec::from_parts`]
+    pub fn new(low: u64, hi: u64) -> Result<Self> {
+        if hi < low {
+            return Err(ManicError::InvalidChunk(format!(
+                "range {}-{} ends before it starts",
+                low, hi
+            )));
+        }
+        Ok(Self {
+            buf: Bytes::new(),
+            low,
+            hi,
+            pos: 1,
+            len: hi - low,
+            bytes: format!("bytes={}-{}", low, hi),
+        })
+    }
+}
+
 impl AsRef<Chunk> for Chunk {
     fn as_ref(&self) -> &Chunk {
         self
@@ -84,28 +273,91 @@ impl AsRef<Chunk> for Chunk {
 
 impl Chunk {
     #[instrument(skip(self, output), fields(low = % self.low, hi = % self.hi, range = % self.bytes, pos = % self.pos))]
-    pub(crate) fn save(self, mut output: File) -> Result<()> {
-        output.seek(SeekFrom::Start(self.low))?;
-        info!("Seeked");
-        let n = output.write(self.buf.as_ref())?;
-        info!("Written {} bytes", n);
+    pub(crate) fn save(&self, output: &File) -> Result<()> {
+        write_all_at(output, self.buf.as_ref(), self.low)?;
         Ok(())
     }
-    #[instrument(skip(self, client, pb), fields(range = % self.bytes))]
-    pub(crate) fn download(
-        mut self,
-        client: Client,
-        url: String,
-        #[cfg(feature = "progress")] pb: Option<ProgressBar>,
-    ) -> Result<Self> {
-        let resp = client.get(url).header(RANGE, self.bytes.clone()).send()?;
-        let res = resp.bytes()?;
-        #[cfg(feature = "progress")]
-        if let Some(bar) = pb {
-            bar.inc(res.len() as u64);
+    #[instrument(skip(self, ctx), fields(range = % self.bytes))]
+    pub(crate) fn download(mut self, ctx: &RequestContext) -> Result<Self> {
+        if ctx.range_failures.tripped() {
+            return Err(ManicError::RangeIgnored(self.bytes.clone()));
+        }
+        let mut attempt = 0;
+        loop {
+            let mut received = 0;
+            let res = self.fetch(ctx, &mut received);
+            match &res {
+                Ok(_) => ctx.range_failures.record(false),
+                Err(e) if is_range_failure(e) => {
+                    // Whatever came back wasn't the range, the bytes don't count
+                    ctx.release(std::mem::take(&mut received));
+                    ctx.range_failures.record(true);
+                }
+                Err(_) => {}
+            }
+            match res {
+                Ok(buf) => {
+                    self.buf = buf;
+                    return Ok(self);
+                }
+                Err(e) if ctx.retry.should_retry(attempt, &e) => {
+                    ctx.release(received);
+                    let delay = ctx.retry.delay(attempt);
+                    events::chunk_retry(&ctx.url, &self.bytes, attempt + 1, delay, &e);
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
         }
-        self.buf = res;
-        Ok(self)
+    }
+    /// One attempt at fetching the range, `received` counts the bytes taken into account so far
+    fn fetch(&self, ctx: &RequestContext, received: &mut u64) -> Result<Bytes> {
+        let mut req = ctx
+            .client
+            .get(ctx.url.clone())
+            .header(RANGE, self.bytes.clone());
+        if let Some(etag) = &ctx.if_range {
+            req = req.header(IF_RANGE, etag);
+        }
+        let mut resp = ctx.send(req)?.error_for_status()?;
+        self.check_status(resp.status())?;
+        let mut buf = Vec::with_capacity((self.hi - self.low + 1) as usize);
+        let mut block = vec![0u8; READ_BLOCK];
+        loop {
+            let n = resp.read(&mut block)?;
+            if n == 0 {
+                break;
+            }
+            if let Some(limit) = &ctx.limit {
+                limit.add(n as u64)?;
+            }
+            self.check_received(buf.len() + n)?;
+            *received += n as u64;
+            #[cfg(feature = "progress")]
+            if let Some(bar) = &ctx.pb {
+                bar.inc(n as u64);
+            }
+            buf.extend_from_slice(&block[..n]);
+        }
+        Ok(Bytes::from(buf))
+    }
+}
+
+impl Chunk {
+    /// A plain `200 OK` is the whole file, only acceptable for a range starting at zero
+    fn check_status(&self, status: StatusCode) -> Result<()> {
+        if status != StatusCode::PARTIAL_CONTENT && self.low != 0 {
+            return Err(ManicError::RangeIgnored(self.bytes.clone()));
+        }
+        Ok(())
+    }
+    /// Fail as soon as the body runs past the end of the range
+    fn check_received(&self, received: usize) -> Result<()> {
+        if received as u64 > self.hi - self.low + 1 {
+            return Err(ManicError::RangeIgnored(self.bytes.clone()));
+        }
+        Ok(())
     }
 }
 
@@ -126,32 +378,42 @@ impl Chunks {
             current_pos: 1,
         })
     }
+    /// Download every chunk of `url` on `pool`, failing if any of them fails
     pub fn download(
         &self,
         client: Client,
         url: String,
         #[cfg(feature = "progress")] pb: Option<ProgressBar>,
         pool: ThreadPool,
+    ) -> Result<ChunkVec> {
+        let ctx = RequestContext {
+            client,
+            url: url.to_url()?,
+            limit: None,
+            retry: Retry::default(),
+            // Nothing to fall back to here, a range the server refuses is an error
+            range_failures: RangeFailures::new(0),
+            http_version: HttpVersionPolicy::default(),
+            if_range: None,
+            #[cfg(feature = "progress")]
+            pb,
+        };
+        self.download_with(Arc::new(ctx), pool)
+    }
+    pub(crate) fn download_with(
+        &self,
+        ctx: Arc<RequestContext>,
+        pool: ThreadPool,
     ) -> Result<ChunkVec> {
         let chnk_vec = self.collect::<Vec<Chunk>>();
         let fut_vec = chnk_vec
             .into_par_iter()
             .map(|x| {
-                let client1 = client.clone();
-                let url1 = url.clone();
-                #[cfg(feature = "progress")]
-                let pb1 = pb.clone();
-                pool.evaluate(|| {
-                    x.download(
-                        client1,
-                        url1,
-                        #[cfg(feature = "progress")]
-                        pb1,
-                    )
-                })
+                let ctx = ctx.clone();
+                pool.evaluate(move || x.download(&ctx))
             })
             .collect::<Vec<_>>();
-        let list = join_all(fut_vec)?;
+        let list = join_all(fut_vec, JoinPolicy::AllRequired)?;
         Ok(ChunkVec::from(list))
     }
 }
@@ -1,32 +1,112 @@
 #![allow(dead_code)]
 
-use super::chunk::{ChunkVec, Chunks};
-use super::multi::Downloaded;
+use super::chunk::{ChunkVec, Chunks, READ_BLOCK};
+use super::multi::{Downloaded, Payload};
+use super::request::RequestContext;
+use crate::events;
+use crate::filename;
+use crate::info::{check_mirrors, RemoteInfo};
+use crate::io::HASHED_WRITE_BUFFER;
+use crate::limit::{MemoryCap, SizeLimit};
+use crate::local::{is_local, local_len, read_local};
+use crate::partial::PartialFile;
+use crate::retry::{RangeFailures, Retry};
+#[cfg(feature = "sig-verify")]
+use crate::signature::Signed;
+use crate::to_url::{check_scheme, check_workers};
+use crate::ClientOptions;
 use crate::Hash;
-use crate::{ManicError, Result};
+use crate::MetadataCache;
+#[cfg(feature = "sig-verify")]
+use crate::SignaturePolicy;
+use crate::ToUrl;
+#[cfg(feature = "decompress")]
+use crate::{Compression, Decompressed};
+use crate::{HttpVersionPolicy, SocketOptions};
+use crate::{JoinPolicy, LockPolicy, ManicError, Result};
+#[cfg(feature = "metalink")]
+use crate::{MetalinkFile, Pieces};
+#[cfg(feature = "builder")]
+use derive_builder::Builder;
 #[cfg(feature = "progress")]
 use indicatif::ProgressBar;
-use rayon::prelude::*;
 use reqwest::blocking::Client;
-use reqwest::header::{CONTENT_LENGTH, RANGE};
+use reqwest::header::{CONTENT_LENGTH, IF_MODIFIED_SINCE, IF_NONE_MATCH, RANGE};
+use reqwest::{StatusCode, Url};
 use rusty_pool::JoinHandle;
 use rusty_pool::ThreadPool;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::net::IpAddr;
 use std::path::Path;
-use tracing::{debug, instrument};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, instrument, warn};
 
-#[derive(Clone, Builder)]
+/// Used for `data:` URLs, which carry no name of their own
+const DATA_FILENAME: &str = "download";
+
+/// Outcome of [`Downloader::download_conditional`]
+#[derive(Debug, Clone)]
+pub struct DownloadResult {
+    /// The new content, `None` if the server answered `304 Not Modified`
+    pub data: Option<ChunkVec>,
+    /// `ETag` to revalidate with next time
+    pub etag: Option<String>,
+    /// `Last-Modified` to revalidate with next time
+    pub last_modified: Option<String>,
+    /// Whether the cached copy is still fresh
+    pub not_modified: bool,
+}
+
+#[derive(Clone)]
+#[cfg_attr(feature = "builder", derive(Builder))]
+#[cfg_attr(
+    feature = "builder",
+    builder(build_fn(validate = "Self::validate", error = "ManicError"))
+)]
 pub struct Downloader {
     filename: String,
-    #[builder(default, setter(skip))]
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
     client: Client,
+    #[cfg_attr(feature = "builder", builder(default))]
+    client_opts: ClientOptions,
+    /// The client was passed in by the caller and can't be rebuilt from `client_opts`
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    custom_client: bool,
     workers: u8,
-    url: reqwest::Url,
+    url: Url,
     hash: Option<Hash>,
     length: u64,
     chunks: Chunks,
+    #[cfg_attr(feature = "builder", builder(default))]
+    check_size: bool,
+    #[cfg_attr(feature = "builder", builder(default))]
+    lock: LockPolicy,
+    #[cfg_attr(feature = "builder", builder(default))]
+    max_size: Option<u64>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    write_buffer: Option<usize>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    retry: Retry,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    info: Option<RemoteInfo>,
+    /// Strong `ETag` sent as `If-Range` with chunk requests, set for the download of `download_conditional`
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    if_range: Option<String>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    metadata_cache: Option<Arc<dyn MetadataCache>>,
     pool: ThreadPool,
+    #[cfg(feature = "sig-verify")]
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    signature: Option<SignaturePolicy>,
+    #[cfg(feature = "decompress")]
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    decompress: Option<Compression>,
+    /// Piece hashes of a metalink, checked on every download
+    #[cfg(feature = "metalink")]
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    pieces: Option<Pieces>,
     #[cfg(feature = "progress")]
     pb: Option<ProgressBar>,
 }
@@ -38,55 +118,103 @@ impl Downloader {
     pub fn get_url(&self) -> String {
         self.url.to_string()
     }
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+    pub(crate) fn hash(&self) -> Option<&Hash> {
+        self.hash.as_ref()
+    }
     pub fn get_len(&self) -> u64 {
         self.length
     }
     pub fn filename(&self) -> &str {
         &self.filename
     }
-    pub(crate) fn new_multi(url: &str, workers: u8, pool: ThreadPool) -> Result<Self> {
+    /// The `(low, hi)` byte ranges the file is split into, inclusive on both ends
+    ///
+    /// Only for inspection, nothing is downloaded. `file:` and `data:` URLs are read in one piece regardless
+    pub fn chunk_plan(&self) -> Vec<(u64, u64)> {
+        self.chunks.map(|x| (x.low, x.hi)).collect()
+    }
+    /// What the probe request found out about the URL, `None` for [`new_manual`][Self::new_manual]
+    pub fn remote_info(&self) -> Option<&RemoteInfo> {
+        self.info.as_ref()
+    }
+    /// Name the file gets when [`download_and_save`][Self::download_and_save] is given a directory
+    ///
+    /// Names taken from the URL are percent-decoded, stripped of control characters and
+    /// truncated to 255 bytes, a name set through the builder goes through the same checks here
+    pub fn target_filename(&self) -> Result<String> {
+        filename::sanitize(&self.filename)
+            .ok_or_else(|| ManicError::NoFilename(self.url.to_string()))
+    }
+    pub(crate) fn new_multi(url: Url, workers: u8, pool: ThreadPool) -> Result<Self> {
+        check_workers(workers)?;
         let client = Client::new();
-        let length = content_length(&client, url)?;
-        Self::assemble_downloader(url, workers, length, client, pool)
+        let info = probe(&client, &url)?;
+        Self::assemble_probed(url, workers, info, client, pool)
     }
     fn assemble_downloader(
-        url: &str,
+        url: Url,
         workers: u8,
         length: u64,
         client: Client,
         pool: ThreadPool,
     ) -> Result<Self> {
-        let parsed = reqwest::Url::parse(url)?;
+        check_scheme(&url)?;
+        check_workers(workers)?;
         if length == 0 {
             return Err(ManicError::NoLen);
         }
         let chunks = Chunks::new(0, length - 1, length / workers as u64)?;
-        let filename = Self::url_to_filename(&parsed)?;
-        #[cfg(not(feature = "progress"))]
-        return Ok(Self {
+        let filename = Self::url_to_filename(&url)?;
+        let hash = Hash::from_fragment(&url);
+        Ok(Self {
             filename,
             client,
+            client_opts: ClientOptions::default(),
+            custom_client: false,
             workers,
-            url: parsed,
-            hash: None,
+            url,
+            hash,
             length,
             chunks,
             pool,
-        });
-        #[cfg(feature = "progress")]
-        return Ok(Self {
-            filename,
-            client,
-            workers,
-            url: parsed,
-            hash: None,
-            length,
-            chunks,
+            check_size: false,
+            lock: LockPolicy::default(),
+            max_size: None,
+            write_buffer: None,
+            retry: Retry::default(),
+            info: None,
+            if_range: None,
+            metadata_cache: None,
+            #[cfg(feature = "sig-verify")]
+            signature: None,
+            #[cfg(feature = "decompress")]
+            decompress: None,
+            #[cfg(feature = "metalink")]
+            pieces: None,
+            #[cfg(feature = "progress")]
             pb: None,
-            pool,
-        });
+        })
     }
-    pub fn new_manual(url: &str, workers: u8, length: u64) -> Result<Self> {
+    /// Single chunk if the server refuses ranges, otherwise same as [`assemble_downloader`][Self::assemble_downloader]
+    fn assemble_probed(
+        url: Url,
+        workers: u8,
+        info: RemoteInfo,
+        client: Client,
+        pool: ThreadPool,
+    ) -> Result<Self> {
+        let length = info.content_length.ok_or(ManicError::NoLen)?;
+        let workers = if info.supports_chunks() { workers } else { 1 };
+        let mut downloader = Self::assemble_downloader(url, workers, length, client, pool)?;
+        downloader.info = Some(info);
+        Ok(downloader)
+    }
+    pub fn new_manual(url: impl ToUrl, workers: u8, length: u64) -> Result<Self> {
+        let url = url.to_url()?;
+        check_workers(workers)?;
         let client = Client::new();
         let pool = rusty_pool::Builder::new()
             .max_size(workers as usize)
@@ -95,6 +223,12 @@ impl Downloader {
     }
     /// Create a new downloader
     ///
+    /// Besides HTTP(S), `file:` URLs are read from disk and `data:` URLs are decoded inline,
+    /// both go through the same hash verification
+    ///
+    /// A `#sha256=<hex>` fragment, or `md5`, `sha224`, `sha384` and `sha512`, sets the hash to verify
+    /// against as if passed to [`verify`][Self::verify], other fragments are ignored
+    ///
     /// # Arguments
     /// * `url` - URL of the file
     /// * `workers` - amount of concurrent tasks
@@ -109,24 +243,152 @@ impl Downloader {
     /// # Ok(())
     /// # }
     /// ```
-    pub fn new(url: &str, workers: u8) -> Result<Self> {
+    pub fn new(url: impl ToUrl, workers: u8) -> Result<Self> {
+        let url = url.to_url()?;
+        check_workers(workers)?;
         let client = Client::new();
-        let length = content_length(&client, url)?;
+        let info = probe(&client, &url)?;
         let pool = rusty_pool::Builder::new()
             .max_size(workers as usize)
             .build();
-        Self::assemble_downloader(url, workers, length, client, pool)
+        Self::assemble_probed(url, workers, info, client, pool)
     }
-    pub fn url_to_filename(url: &reqwest::Url) -> Result<String> {
-        url.path_segments()
-            .and_then(|segments| segments.last())
-            .and_then(|name| {
-                if name.is_empty() {
-                    None
-                } else {
-                    Some(name.to_string())
-                }
+    /// Create a new downloader using a preconfigured client, e.g. one built from [`ClientOptions`]
+    ///
+    /// The client is used as is, setters that would rebuild it like [`bind_address`][Self::bind_address]
+    /// fail with [`ManicError::CustomClient`], set those options on the client instead
+    pub fn new_with_client(url: impl ToUrl, workers: u8, client: Client) -> Result<Self> {
+        let url = url.to_url()?;
+        check_workers(workers)?;
+        let info = probe(&client, &url)?;
+        let pool = rusty_pool::Builder::new()
+            .max_size(workers as usize)
+            .build();
+        let mut downloader = Self::assemble_probed(url, workers, info, client, pool)?;
+        downloader.custom_client = true;
+        Ok(downloader)
+    }
+    /// Fetch the size, range support, type and cache headers of a URL in one request
+    /// without creating a downloader
+    pub fn probe(url: impl ToUrl) -> Result<RemoteInfo> {
+        let url = url.to_url()?;
+        probe(&Client::new(), &url)
+    }
+    /// Create a new downloader, taking the probe from `cache` if it's there and storing it otherwise
+    ///
+    /// [`clone_for`][Self::clone_for] goes through the same cache
+    pub fn new_cached(url: impl ToUrl, workers: u8, cache: Arc<dyn MetadataCache>) -> Result<Self> {
+        let url = url.to_url()?;
+        check_workers(workers)?;
+        let client = Client::new();
+        let info = cached_probe(Some(cache.as_ref()), &client, &url)?;
+        let pool = rusty_pool::Builder::new()
+            .max_size(workers as usize)
+            .build();
+        let mut downloader = Self::assemble_probed(url, workers, info, client, pool)?;
+        downloader.metadata_cache = Some(cache);
+        Ok(downloader)
+    }
+    /// Probe every mirror of a file and check they serve the same version before
+    /// ranges are split across them, returns the first mirror's probe
+    ///
+    /// The mirrors have to report the same length and, if all of them send one, the same strong
+    /// `ETag`, otherwise [`ManicError::MirrorMismatch`] lists the ones that differ from the first
+    pub fn probe_mirrors<U: ToUrl>(mirrors: impl IntoIterator<Item = U>) -> Result<RemoteInfo> {
+        let client = Client::new();
+        let mut probes = mirrors
+            .into_iter()
+            .map(|x| {
+                let url = x.to_url()?;
+                let info = probe(&client, &url)?;
+                Ok((url, info))
             })
+            .collect::<Result<Vec<_>>>()?;
+        check_mirrors(&probes)?;
+        Ok(probes.swap_remove(0).1)
+    }
+    /// Create a downloader for a file described by a [`Metalink`][crate::Metalink]
+    ///
+    /// The mirrors are probed in priority order, unreachable ones are skipped with a warning and
+    /// the others have to serve the same file as with [`probe_mirrors`][Self::probe_mirrors].
+    /// The file is downloaded from the most preferred mirror that answered and saved under the
+    /// metalink's name. It's verified against the strongest of the metalink's hashes and each
+    /// piece against its piece hash, a piece that doesn't match fails with [`ManicError::PieceMismatch`]
+    #[cfg(feature = "metalink")]
+    pub fn from_metalink(file: &MetalinkFile, workers: u8) -> Result<Self> {
+        check_workers(workers)?;
+        let client = Client::new();
+        let probes = file.urls.iter().map(|url| probe(&client, url));
+        let mut reachable = Vec::new();
+        let mut last_err = None;
+        for (url, res) in file.urls.iter().zip(probes) {
+            match res {
+                Ok(info) => reachable.push((url.clone(), info)),
+                Err(e) => {
+                    warn!(url = %url, error = %e, "Skipping unreachable mirror");
+                    last_err = Some(e);
+                }
+            }
+        }
+        if reachable.is_empty() {
+            return Err(last_err.unwrap_or(ManicError::NoResults));
+        }
+        check_mirrors(&reachable)?;
+        let (url, info) = reachable.swap_remove(0);
+        if let (Some(expected), Some(actual)) = (file.size, info.content_length) {
+            if expected != actual {
+                return Err(ManicError::SizeMismatch { expected, actual });
+            }
+        }
+        let pool = rusty_pool::Builder::new()
+            .max_size(workers as usize)
+            .build();
+        let mut downloader = Self::assemble_probed(url, workers, info, client, pool)?;
+        downloader.filename = file.name.clone();
+        if let Some(hash) = file.strongest_hash() {
+            downloader.hash = Some(hash.clone());
+        }
+        downloader.pieces = file.pieces.clone();
+        Ok(downloader)
+    }
+    /// Same as [`probe`][Self::probe], answered from `cache` if possible
+    pub fn probe_cached(url: impl ToUrl, cache: &dyn MetadataCache) -> Result<RemoteInfo> {
+        let url = url.to_url()?;
+        cached_probe(Some(cache), &Client::new(), &url)
+    }
+    /// New downloader for `url` with this one's client, thread pool, workers, retries and limits
+    ///
+    /// The length, filename and chunks are probed again for the new URL,
+    /// the hash, decompression and progress bar belong to a single file and aren't carried over
+    pub fn clone_for(&self, url: impl ToUrl) -> Result<Self> {
+        let url = url.to_url()?;
+        let info = cached_probe(self.metadata_cache.as_deref(), &self.client, &url)?;
+        let mut downloader = Self::assemble_probed(
+            url,
+            self.workers,
+            info,
+            self.client.clone(),
+            self.pool.clone(),
+        )?;
+        downloader.client_opts = self.client_opts.clone();
+        downloader.custom_client = self.custom_client;
+        downloader.check_size = self.check_size;
+        downloader.lock = self.lock;
+        downloader.max_size = self.max_size;
+        downloader.write_buffer = self.write_buffer;
+        downloader.retry = self.retry;
+        downloader.metadata_cache = self.metadata_cache.clone();
+        #[cfg(feature = "sig-verify")]
+        {
+            downloader.signature = self.signature.clone();
+        }
+        Ok(downloader)
+    }
+    pub fn url_to_filename(url: &Url) -> Result<String> {
+        url.path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .and_then(filename::from_url_segment)
+            .or_else(|| (url.scheme() == "data").then(|| DATA_FILENAME.to_string()))
             .ok_or_else(|| ManicError::NoFilename(url.to_string()))
     }
     /// Enable progress reporting
@@ -155,6 +417,114 @@ impl Downloader {
         self.hash = Some(hash);
         self.to_owned()
     }
+    /// Replace the client with one built from `opts`, a client passed to
+    /// `new_with_client` is kept and the setter fails instead
+    fn rebuild_client(&mut self, setter: &'static str, opts: ClientOptions) -> Result<()> {
+        if self.custom_client {
+            return Err(ManicError::CustomClient(setter));
+        }
+        self.client = opts.build_blocking()?;
+        self.client_opts = opts;
+        Ok(())
+    }
+    /// Bind outgoing connections to a local address, rebuilds the client from its [`ClientOptions`]
+    pub fn bind_address(&mut self, addr: IpAddr) -> Result<&mut Self> {
+        self.rebuild_client("bind_address", self.client_opts.clone().bind_address(addr))?;
+        Ok(self)
+    }
+    /// Set the TCP options of chunk connections, rebuilds the client from its [`ClientOptions`]
+    pub fn socket_options(&mut self, socket: SocketOptions) -> Result<&mut Self> {
+        self.rebuild_client(
+            "socket_options",
+            self.client_opts.clone().socket_options(socket),
+        )?;
+        Ok(self)
+    }
+    /// Reuse connections between chunk requests, on by default, rebuilds the client from its [`ClientOptions`]
+    ///
+    /// Turn it off for servers with broken keep-alive, every request then opens its own connection
+    pub fn keep_alive(&mut self, enabled: bool) -> Result<&mut Self> {
+        self.rebuild_client("keep_alive", self.client_opts.clone().keep_alive(enabled))?;
+        Ok(self)
+    }
+    /// Restrict the HTTP versions chunk requests may use, rebuilds the client from its [`ClientOptions`]
+    ///
+    /// Protocol and handshake failures under a forced version come back as [`ManicError::HttpVersion`],
+    /// transport failures stay network errors and are retried as usual
+    pub fn http_version(&mut self, policy: HttpVersionPolicy) -> Result<&mut Self> {
+        self.rebuild_client(
+            "http_version",
+            self.client_opts.clone().http_version(policy),
+        )?;
+        Ok(self)
+    }
+    /// Decompress the download from `format` as [`download_and_save`][Self::download_and_save] writes it
+    ///
+    /// The hash and a signature set with [`verify_signature`][Self::verify_signature] are checked
+    /// against the file as served before anything is decompressed. Saved into a directory the file
+    /// is named without the format's extension, e.g. `notes.txt.gz` becomes `notes.txt`
+    #[cfg(feature = "decompress")]
+    pub fn decompress_on_save(&mut self, format: Compression) -> &mut Self {
+        self.decompress = Some(format);
+        self
+    }
+    /// Check a detached signature of the download before it's moved into place or handed out,
+    /// fails with [`ManicError::SignatureMismatch`] if it doesn't verify
+    ///
+    /// Saved files are checked as `<path>.part`, downloads returned in memory before they're returned
+    #[cfg(feature = "sig-verify")]
+    pub fn verify_signature(&mut self, policy: SignaturePolicy) -> &mut Self {
+        self.signature = Some(policy);
+        self
+    }
+    /// Let [`download_and_save`][Self::download_and_save] write the chunks in order through a buffer
+    /// of `bytes` instead of writing each chunk on its own, worth it when there are many small chunks
+    pub fn write_buffer_size(&mut self, bytes: usize) -> &mut Self {
+        self.write_buffer = Some(bytes);
+        self
+    }
+    /// Check that the saved file's size matches the content length after
+    /// [`download_and_save`][Self::download_and_save], catches partial writes e.g. on a full disk
+    pub fn check_size(&mut self, check: bool) -> &mut Self {
+        self.check_size = check;
+        self
+    }
+    /// Lock the output path while saving so concurrent downloads to it don't clobber each other
+    pub fn lock_policy(&mut self, policy: LockPolicy) -> &mut Self {
+        self.lock = policy;
+        self
+    }
+    /// Refuse downloads larger than `bytes`
+    ///
+    /// The content length is checked before anything is fetched, the received bytes are counted
+    /// as well so a server sending more than it announced is cut off with [`ManicError::TooLarge`]
+    pub fn max_size(&mut self, bytes: u64) -> &mut Self {
+        self.max_size = Some(bytes);
+        self
+    }
+    /// Retry failed chunk requests up to `attempts` times with exponential backoff,
+    /// only errors [`ErrorCode::is_retryable`][crate::ErrorCode::is_retryable] accepts are retried:
+    /// connection errors, timeouts, errors reading the response body, 429 and 5xx responses
+    pub fn retries(&mut self, attempts: u32) -> &mut Self {
+        self.retry.attempts = attempts;
+        self
+    }
+    /// Randomize the retry backoff to a duration between zero and the exponential delay, on by default
+    ///
+    /// Keeps workers that failed together from retrying in lockstep against the same mirror
+    pub fn jitter(&mut self, jitter: bool) -> &mut Self {
+        self.retry.jitter = jitter;
+        self
+    }
+    /// Give up on ranged requests once `failures` of them in a row came back with more than
+    /// the range or a `416`, and fetch the file in a single request instead, 3 by default
+    ///
+    /// Turns a proxy that mangles `Range` into a slower download instead of a failed one,
+    /// 0 fails with [`ManicError::RangeIgnored`] right away
+    pub fn range_fallback(&mut self, failures: u32) -> &mut Self {
+        self.retry.range_fallback = failures;
+        self
+    }
     /// Download the file and verify if hash is set
     ///
     /// # Example
@@ -168,31 +538,150 @@ impl Downloader {
     /// # Ok(())
     /// # }
     /// ```
-    #[instrument(skip(self), fields(URL = % self.url, tasks = % self.workers))]
     pub fn download(&self) -> Result<ChunkVec> {
+        self.download_checked(true)
+    }
+    /// [`download`][Self::download], hashing the data in memory only if `verify` is set,
+    /// [`download_and_save`][Self::download_and_save] hashes while writing instead
+    #[instrument(skip(self, verify), fields(URL = % self.url, tasks = % self.workers))]
+    fn download_checked(&self, verify: bool) -> Result<ChunkVec> {
+        let start = Instant::now();
+        events::download_start(&self.url, self.length, self.workers);
+        let res = self.fetch_and_verify(verify);
+        match &res {
+            Ok(data) => events::download_complete(&self.url, data.byte_len(), start.elapsed()),
+            Err(e) => events::download_failed(&self.url, start.elapsed(), e),
+        }
+        res
+    }
+    fn fetch_and_verify(&self, verify: bool) -> Result<ChunkVec> {
         let mb = &self.length / 1000000;
         debug!("File size: {}MB", mb);
-        let chnks = self.chunks;
-        let url = self.url.clone();
-        let client = self.client.clone();
-        #[cfg(feature = "progress")]
-        let pb = self.pb.clone();
-        let result = chnks.download(
-            client,
-            url.to_string(),
+        let ctx = self.context()?;
+        let result = if is_local(&self.url) {
+            let buf = read_local(&self.url)?;
             #[cfg(feature = "progress")]
-            pb,
-            self.pool.clone(),
-        )?;
+            if let Some(bar) = &ctx.pb {
+                bar.inc(buf.len() as u64);
+            }
+            ChunkVec::from_buf(buf)
+        } else {
+            let ctx = Arc::new(ctx);
+            match self.chunks.download_with(ctx.clone(), self.pool.clone()) {
+                Err(e) if ctx.range_failures.tripped() => {
+                    warn!(url = %self.url, error = %e, "Ranged requests keep failing, downloading in a single request");
+                    ChunkVec::from_buf(self.fetch_whole()?)
+                }
+                res => res?,
+            }
+        };
+        #[cfg(feature = "metalink")]
+        if let Some(pieces) = &self.pieces {
+            pieces.verify(result.blocks())?;
+        }
+        if verify {
+            self.check(&result)?;
+        }
+        Ok(result)
+    }
+    /// Check the data in memory against the hash and signature if they're set
+    fn check(&self, result: &ChunkVec) -> Result<()> {
         if let Some(hash) = &self.hash {
-            result.verify(hash.clone())?;
+            result.verify(
+                hash.clone(),
+                #[cfg(feature = "progress")]
+                self.pb.as_ref(),
+            )?;
             debug!("Compared");
         }
-        Ok(result)
+        #[cfg(feature = "sig-verify")]
+        self.check_signature(Signed::Blocks(result.blocks()))?;
+        Ok(())
+    }
+    /// Fetch the whole file in one plain request, for servers and proxies that mangle ranged ones
+    fn fetch_whole(&self) -> Result<Vec<u8>> {
+        // Fresh size limit, the bytes of the ranged attempts don't count
+        let ctx = self.context()?;
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &ctx.pb {
+            bar.set_position(0);
+        }
+        let mut resp = ctx
+            .send(ctx.client.get(self.url.clone()))?
+            .error_for_status()?;
+        let mut buf = Vec::with_capacity(self.length as usize);
+        let mut block = vec![0u8; READ_BLOCK];
+        loop {
+            let n = resp.read(&mut block)?;
+            if n == 0 {
+                break;
+            }
+            if let Some(limit) = &ctx.limit {
+                limit.add(n as u64)?;
+            }
+            #[cfg(feature = "progress")]
+            if let Some(bar) = &ctx.pb {
+                bar.inc(n as u64);
+            }
+            buf.extend_from_slice(&block[..n]);
+        }
+        Ok(buf)
+    }
+    fn context(&self) -> Result<RequestContext> {
+        let limit = self.max_size.map(SizeLimit::new);
+        if let Some(limit) = &limit {
+            limit.check(self.length)?;
+        }
+        Ok(RequestContext {
+            client: self.client.clone(),
+            url: self.url.clone(),
+            limit,
+            retry: self.retry,
+            range_failures: RangeFailures::new(
+                self.retry.range_fallback.min(self.chunks.count() as u32),
+            ),
+            http_version: self.client_opts.http_version_policy(),
+            if_range: self.if_range.clone(),
+            #[cfg(feature = "progress")]
+            pb: self.pb.clone(),
+        })
     }
-    pub fn multi_download(self) -> Result<Downloaded> {
-        let res = self.download()?;
-        Ok(Downloaded::new(self.get_url(), self.filename, res))
+    /// Download for a [`MultiDownloader`][super::MultiDownloader], spilled to the staging
+    /// directory over the memory cap
+    ///
+    /// The hash of a spilled download is left to the batch, which hashes all of them
+    /// from disk at once
+    pub(crate) fn multi_download(self, cap: Option<Arc<MemoryCap>>) -> Result<Downloaded> {
+        let res = self.download_checked(cap.is_none())?;
+        let data = match cap {
+            Some(cap) if !cap.admit(res.byte_len()) => {
+                let path = cap.staging_path(&self.url, &self.filename);
+                std::fs::create_dir_all(cap.staging())?;
+                res.save_to_file(&path, self.pool.clone())?;
+                debug!(
+                    "Over the memory cap, spilled {} to {}",
+                    self.url,
+                    path.display()
+                );
+                #[cfg(feature = "sig-verify")]
+                if let Err(e) = self.check_signature(Signed::File(&path)) {
+                    let _ = std::fs::remove_file(&path);
+                    return Err(e);
+                }
+                Payload::Spilled(path)
+            }
+            Some(_) => {
+                self.check(&res)?;
+                Payload::Memory(res)
+            }
+            None => Payload::Memory(res),
+        };
+        Ok(Downloaded::new(
+            self.url.clone(),
+            self.filename,
+            data,
+            self.lock,
+        ))
     }
     /// Used to download, save to a file and verify against a SHA256 sum,
     /// returns an error if the connection fails or if the sum doesn't match the one provided
@@ -217,55 +706,253 @@ impl Downloader {
     ///
     #[instrument(skip(self))]
     pub fn download_and_save(&self, path: &str) -> Result<()> {
-        let mut result = {
+        let file_path = {
             let original_path = Path::new(path);
-            let file_path = if original_path.is_dir() {
-                original_path.join(&self.filename)
+            if original_path.is_dir() {
+                let name = self.target_filename()?;
+                #[cfg(feature = "decompress")]
+                let name = match self.decompress {
+                    Some(format) => format.strip_extension(&name),
+                    None => name,
+                };
+                original_path.join(name)
             } else {
                 original_path.to_path_buf()
-            };
-            File::create(file_path)?
+            }
         };
-        let data = self.download()?;
-        let c = result.try_clone()?;
-        data.save(c, self.pool.clone())?;
+        let _lock = self.lock.acquire(&file_path)?;
+        #[cfg(feature = "decompress")]
+        if let Some(format) = self.decompress {
+            return self.save_decompressed(&file_path, format);
+        }
+        let partial = PartialFile::new(&file_path);
+        let mut result = File::create(partial.path())?;
+        let data = self.download_checked(false)?;
+        match (&self.hash, self.write_buffer) {
+            // One pass over the data writes and hashes it
+            (Some(hash), size) => data
+                .save_hashed(&result, hash.clone(), size.unwrap_or(HASHED_WRITE_BUFFER))?
+                .verify()?,
+            (None, Some(size)) => data.save_buffered(&result, size)?,
+            (None, None) => data.save(result.try_clone()?, self.pool.clone())?,
+        }
         result.sync_all()?;
         result.flush()?;
-        Ok(())
+        if self.check_size {
+            let actual = result.metadata()?.len();
+            if actual != self.length {
+                return Err(ManicError::SizeMismatch {
+                    expected: self.length,
+                    actual,
+                });
+            }
+        }
+        drop(result);
+        #[cfg(feature = "sig-verify")]
+        self.check_signature(Signed::File(partial.path()))?;
+        Ok(partial.persist()?)
+    }
+    /// Download the file and return a reader over its decompressed content
+    ///
+    /// The hash and signature are checked against the file as served. The content is decompressed
+    /// from the chunks in memory as it's read, so an archive like a `.tar.gz` can be handed to
+    /// an extractor without staging it on disk
+    #[cfg(feature = "decompress")]
+    pub fn download_decompressed(&self, format: Compression) -> Result<Decompressed> {
+        let data = self.download()?;
+        Ok(Decompressed::new(format, data.into_blocks()))
+    }
+    /// Download a compressed tar archive and unpack it into `dir` as it's decompressed
+    ///
+    /// The hash and signature are checked against the archive as served before anything is
+    /// extracted, the archive itself is never written to disk. An entry whose path or link target
+    /// leads outside `dir` fails with [`ManicError::UnsafeEntry`], entries unpacked before it stay in `dir`
+    #[cfg(feature = "decompress")]
+    pub fn download_and_extract(&self, dir: impl AsRef<Path>, format: Compression) -> Result<()> {
+        self.download_decompressed(format)?.extract(dir.as_ref())
+    }
+    /// Download and check the compressed file, then decompress it into `<path>.part` and move it into place
+    #[cfg(feature = "decompress")]
+    fn save_decompressed(&self, path: &Path, format: Compression) -> Result<()> {
+        // The hash and signature are of the file as served
+        let data = self.download_checked(true)?;
+        if self.check_size && data.byte_len() != self.length {
+            return Err(ManicError::SizeMismatch {
+                expected: self.length,
+                actual: data.byte_len(),
+            });
+        }
+        let partial = PartialFile::new(path);
+        format.decompress(data.blocks(), partial.path())?;
+        Ok(partial.persist()?)
+    }
+    /// Fetch the sidecar signature and verify `content` against it
+    #[cfg(feature = "sig-verify")]
+    fn check_signature(&self, content: Signed<'_>) -> Result<()> {
+        let policy = match &self.signature {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        let sidecar = policy.sidecar_url(&self.url)?;
+        let signature = if is_local(&sidecar) {
+            match read_local(&sidecar) {
+                Err(ManicError::IOError(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return policy.missing(&sidecar)
+                }
+                res => res?,
+            }
+        } else {
+            let resp = self.client.get(sidecar.clone()).send()?;
+            if resp.status() == StatusCode::NOT_FOUND {
+                return policy.missing(&sidecar);
+            }
+            resp.error_for_status()?.bytes()?.to_vec()
+        };
+        policy.verify(content, &signature)
+    }
+    /// Download only if the file changed since it was fetched with the given validators
+    ///
+    /// A conditional request for the first byte with `If-None-Match` and `If-Modified-Since` is sent first,
+    /// on `304 Not Modified` nothing is downloaded. Otherwise the file is downloaded as usual and returned with its new validators
+    /// so callers can update their cache. The chunks are planned from this downloader's probe,
+    /// if the new length differs the download fails with [`ManicError::SizeMismatch`].
+    /// Chunk requests carry the new strong `ETag` in `If-Range`, a file that changes again
+    /// while it's downloaded fails with [`ManicError::RangeIgnored`] instead of mixing both versions
+    pub fn download_conditional(
+        &self,
+        etag: Option<&str>,
+        last_modified: Option<&str>,
+    ) -> Result<DownloadResult> {
+        if is_local(&self.url) {
+            return Ok(DownloadResult {
+                data: Some(self.download()?),
+                etag: None,
+                last_modified: None,
+                not_modified: false,
+            });
+        }
+        let mut req = self.client.get(self.url.clone()).header(RANGE, "bytes=0-0");
+        if let Some(etag) = etag {
+            req = req.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = last_modified {
+            req = req.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        let resp = req.send()?.error_for_status()?;
+        let info =
+            RemoteInfo::from_headers(resp.url(), resp.version(), resp.status(), resp.headers())?;
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            // A 304 doesn't have to repeat the validators, the cached ones are still current
+            return Ok(DownloadResult {
+                data: None,
+                etag: info.etag.or_else(|| etag.map(str::to_string)),
+                last_modified: info
+                    .last_modified
+                    .or_else(|| last_modified.map(str::to_string)),
+                not_modified: true,
+            });
+        }
+        match info.content_length {
+            Some(actual) if actual != self.length => {
+                return Err(ManicError::SizeMismatch {
+                    expected: self.length,
+                    actual,
+                })
+            }
+            _ => {}
+        }
+        let mut changed = self.clone();
+        changed.if_range = info.etag.clone().filter(|x| !x.starts_with("W/"));
+        // A range refused because of If-Range means the file changed, a single request would fetch the new one
+        changed.retry.range_fallback = 0;
+        Ok(DownloadResult {
+            data: Some(changed.download()?),
+            etag: info.etag,
+            last_modified: info.last_modified,
+            not_modified: false,
+        })
+    }
+    /// Download, check the saved file against `hash` and a signature set with
+    /// [`verify_signature`][Self::verify_signature] and only then move it to `path`
+    ///
+    /// The data is written to `<path>.part` next to the target so the final rename never crosses devices.
+    /// On a mismatch the partial file is removed and an existing file at `path` is left untouched
+    pub fn download_verify_install<T: AsRef<Path>>(&self, path: T, hash: Hash) -> Result<()> {
+        let path = path.as_ref();
+        let _lock = self.lock.acquire(path)?;
+        let data = self.download_checked(false)?;
+        let partial = data.stage_verified(path, hash, self.pool.clone())?;
+        #[cfg(feature = "sig-verify")]
+        self.check_signature(Signed::File(partial.path()))?;
+        Ok(partial.persist()?)
     }
 }
 
+#[cfg(feature = "builder")]
+impl DownloaderBuilder {
+    /// Reject URLs with a scheme no downloader can fetch before anything is built
+    fn validate(&self) -> Result<()> {
+        if let Some(workers) = self.workers {
+            check_workers(workers)?;
+        }
+        match &self.url {
+            Some(url) => check_scheme(url),
+            None => Ok(()),
+        }
+    }
+}
+
+/// [`probe`] unless `cache` has the answer, local URLs aren't cached since reading their size is cheap
+fn cached_probe(
+    cache: Option<&dyn MetadataCache>,
+    client: &Client,
+    url: &Url,
+) -> Result<RemoteInfo> {
+    let cache = match cache {
+        Some(cache) if !is_local(url) => cache,
+        _ => return probe(client, url),
+    };
+    if let Some(info) = cache.get(url) {
+        debug!("Using cached metadata of {}", url);
+        return Ok(info);
+    }
+    let info = probe(client, url)?;
+    cache.put(url, &info);
+    Ok(info)
+}
+
 #[instrument(skip(client, url), fields(URL = % url))]
-fn content_length(client: &Client, url: &str) -> Result<u64> {
-    let resp = client.head(url).send()?;
+fn probe(client: &Client, url: &Url) -> Result<RemoteInfo> {
+    check_scheme(url)?;
+    if is_local(url) {
+        return Ok(RemoteInfo::local(url, local_len(url)?));
+    }
+    let resp = client.head(url.clone()).send()?;
     debug!("Response code: {}", resp.status());
     debug!("Received HEAD response: {:?}", resp.headers());
-    let len = resp
-        .headers()
-        .get("content-length")
-        .ok_or(ManicError::NoLen);
-    if len.is_ok() && resp.status().is_success() {
-        len?.to_str()
-            .map_err(|_x| ManicError::NoLen)?
-            .parse::<u64>()
-            .map_err(|e| e.into())
+    if resp.status().is_success() && resp.headers().contains_key(CONTENT_LENGTH) {
+        RemoteInfo::from_headers(resp.url(), resp.version(), resp.status(), resp.headers())
     } else {
-        let resp = client.get(url).header(RANGE, "0-0").send()?;
+        let resp = client.get(url.clone()).header(RANGE, "bytes=0-0").send()?;
         debug!("Response code: {}", resp.status());
         debug!("Received GET 1B response: {:?}", resp.headers());
-        resp.headers()
-            .get(CONTENT_LENGTH)
-            .ok_or(ManicError::NoLen)?
-            .to_str()?
-            .parse::<u64>()
-            .map_err(|e| e.into())
+        RemoteInfo::from_headers(resp.url(), resp.version(), resp.status(), resp.headers())
     }
 }
 
-pub(crate) fn join_all<T: Clone + Send>(i: Vec<JoinHandle<Result<T>>>) -> Result<Vec<T>> {
-    i.into_par_iter()
-        .map(|x| x.try_await_complete().map_err(ManicError::Canceled))
-        .collect::<Result<Vec<Result<T>>>>()?
-        .into_par_iter()
-        .collect()
+pub(crate) fn join_all<T: Send>(
+    i: Vec<JoinHandle<Result<T>>>,
+    policy: JoinPolicy,
+) -> Result<Vec<T>> {
+    // Waits in the calling thread, blocking rayon's workers on tasks that need them
+    // for their own joins deadlocks once the downloads outnumber rayon's threads
+    let results = i
+        .into_iter()
+        .map(|x| {
+            x.try_await_complete()
+                .map_err(ManicError::Canceled)
+                .and_then(|r| r)
+        })
+        .collect::<Vec<Result<T>>>();
+    policy.collect(results)
 }
@@ -1,7 +1,10 @@
 mod chunk;
 pub mod downloader;
 mod multi;
+mod request;
 
+pub use chunk::{Chunk, ChunkVec, Chunks};
+pub use downloader::DownloadResult;
 #[doc(inline)]
 pub use downloader::Downloader;
 #[cfg(feature = "progress")]
@@ -3,17 +3,26 @@
 use super::chunk::ChunkVec;
 use super::downloader::join_all;
 use super::Downloader;
-use crate::{Hash, ManicError, Result};
+use crate::filename;
+use crate::limit::MemoryCap;
+use crate::partial::PartialFile;
+use crate::to_url::check_workers;
+#[cfg(feature = "sig-verify")]
+use crate::SignaturePolicy;
+use crate::{hash_files, Hash, JoinPolicy, LockPolicy, ManicError, Priority, Result, ToUrl};
+#[cfg(feature = "builder")]
+use derive_builder::Builder;
 #[cfg(feature = "progress")]
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use reqwest::Url;
 use rusty_pool::ThreadPool;
 use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::{Mutex, MutexGuard};
 
 #[derive(Clone)]
-pub struct Map(Arc<Mutex<HashMap<String, Downloader>>>);
+pub struct Map(Arc<Mutex<HashMap<Url, Downloader>>>);
 
 impl Default for Map {
     fn default() -> Self {
@@ -25,22 +34,22 @@ impl Map {
     pub(crate) fn new() -> Self {
         Self(Arc::new(Mutex::new(HashMap::new())))
     }
-    pub(crate) fn lock(&self) -> Result<MutexGuard<'_, HashMap<String, Downloader>>> {
+    pub(crate) fn lock(&self) -> Result<MutexGuard<'_, HashMap<Url, Downloader>>> {
         self.0
             .lock()
             .map_err(|e| ManicError::PoisonError(e.to_string()))
     }
-    pub(crate) fn as_inner(&self) -> &Arc<Mutex<HashMap<String, Downloader>>> {
+    pub(crate) fn as_inner(&self) -> &Arc<Mutex<HashMap<Url, Downloader>>> {
         &self.0
     }
-    pub(crate) fn into_inner(self) -> Arc<Mutex<HashMap<String, Downloader>>> {
+    pub(crate) fn into_inner(self) -> Arc<Mutex<HashMap<Url, Downloader>>> {
         self.0
     }
-    pub(crate) fn insert(&self, k: String, v: Downloader) -> Result<Option<Downloader>> {
+    pub(crate) fn insert(&self, k: Url, v: Downloader) -> Result<Option<Downloader>> {
         let mut lock = self.lock()?;
         Ok(lock.insert(k, v))
     }
-    pub(crate) fn get(&self, k: &str) -> Result<Downloader> {
+    pub(crate) fn get(&self, k: &Url) -> Result<Downloader> {
         let lock = self.lock()?;
         let res = lock.get(k);
         res.cloned().ok_or(ManicError::NotFound)
@@ -49,37 +58,95 @@ impl Map {
 
 #[derive(Debug, Clone)]
 pub struct Downloaded {
-    url: String,
+    url: Url,
     name: String,
-    data: ChunkVec,
+    data: Payload,
+    lock: LockPolicy,
+}
+
+/// Where a finished download's data ended up
+#[derive(Debug, Clone)]
+pub(crate) enum Payload {
+    Memory(ChunkVec),
+    /// Written to the staging directory because it didn't fit under the memory cap
+    Spilled(PathBuf),
 }
 
 impl Downloaded {
-    pub(crate) fn new(url: String, name: String, data: ChunkVec) -> Self {
-        Self { url, name, data }
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// The downloaded data, `None` if it was spilled to disk
+    pub fn data(&self) -> Option<&ChunkVec> {
+        match &self.data {
+            Payload::Memory(data) => Some(data),
+            Payload::Spilled(_) => None,
+        }
+    }
+    /// Path of the staged file if the download was spilled to disk
+    pub fn path(&self) -> Option<&Path> {
+        match &self.data {
+            Payload::Memory(_) => None,
+            Payload::Spilled(path) => Some(path),
+        }
+    }
+    pub(crate) fn new(url: Url, name: String, data: Payload, lock: LockPolicy) -> Self {
+        Self {
+            url,
+            name,
+            data,
+            lock,
+        }
     }
     pub(crate) fn save<T: AsRef<Path>>(&self, output_dir: T, pool: ThreadPool) -> Result<()> {
-        let output_path = output_dir.as_ref().join(Path::new(&self.name));
-        self.data.save_to_file(output_path, pool)
+        let name = filename::sanitize(&self.name)
+            .ok_or_else(|| ManicError::NoFilename(self.url.to_string()))?;
+        let output_path = output_dir.as_ref().join(name);
+        let _lock = self.lock.acquire(&output_path)?;
+        match &self.data {
+            Payload::Memory(data) => data.save_to_file(output_path, pool),
+            Payload::Spilled(path) => {
+                let partial = PartialFile::new(&output_path);
+                std::fs::copy(path, partial.path())?;
+                Ok(partial.persist()?)
+            }
+        }
     }
 }
 
-#[derive(Clone, Builder)]
+#[derive(Clone)]
+#[cfg_attr(feature = "builder", derive(Builder))]
 pub struct MultiDownloader {
-    #[builder(default)]
+    #[cfg_attr(feature = "builder", builder(default))]
     downloaders: Map,
-    #[builder(default, setter(skip))]
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
     #[cfg(feature = "progress")]
     progress: Option<Arc<MultiProgress>>,
     #[cfg(feature = "progress")]
     progress_style: Option<ProgressStyle>,
-    #[builder(default, setter(skip))]
+    #[cfg_attr(feature = "builder", builder(default))]
+    policy: JoinPolicy,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    max_memory: Option<u64>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    staging_dir: Option<PathBuf>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
     pool: ThreadPool,
     workers: u8,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    priorities: HashMap<Url, Priority>,
+    /// Position of each URL in the order it was first added
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    added: HashMap<Url, usize>,
 }
 
 impl MultiDownloader {
-    pub fn new(#[cfg(feature = "progress")] progress: bool, workers: u8) -> MultiDownloader {
+    /// New batch whose downloads run on a shared pool of `workers` threads, at least 1
+    pub fn new(#[cfg(feature = "progress")] progress: bool, workers: u8) -> Result<Self> {
+        check_workers(workers)?;
         #[cfg(feature = "progress")]
         let pb = if progress {
             Some(Arc::new(MultiProgress::new()))
@@ -89,45 +156,180 @@ impl MultiDownloader {
         let pool = rusty_pool::Builder::new()
             .max_size(workers as usize)
             .build();
-        Self {
+        Ok(Self {
             downloaders: Map::new(),
             #[cfg(feature = "progress")]
             progress: pb,
             #[cfg(feature = "progress")]
             progress_style: None,
+            policy: JoinPolicy::default(),
+            max_memory: None,
+            staging_dir: None,
             pool,
             workers,
-        }
+            priorities: HashMap::new(),
+            added: HashMap::new(),
+        })
     }
-    pub fn add(&mut self, url: String) -> Result<()> {
+    pub fn add(&mut self, url: impl ToUrl) -> Result<()> {
+        let url = url.to_url()?;
         #[allow(unused_mut)]
-        let mut client = Downloader::new_multi(&url, self.workers, self.pool.clone())?;
+        let mut client = Downloader::new_multi(url.clone(), self.workers, self.pool.clone())?;
         #[cfg(feature = "progress")]
         if let Some(pb) = &self.progress {
             let mpb = ProgressBar::new(client.get_len());
             let to_add = pb.add(mpb);
             client.connect_progress(to_add);
         }
-        self.downloaders.insert(url, client)?;
+        self.downloaders.insert(url.clone(), client)?;
+        let next = self.added.len();
+        self.added.entry(url).or_insert(next);
+        Ok(())
+    }
+    /// Add a URL that's started by `priority` instead of [`Priority::Normal`]
+    pub fn add_with_priority(&mut self, url: impl ToUrl, priority: Priority) -> Result<()> {
+        let url = url.to_url()?;
+        self.add(&url)?;
+        self.priorities.insert(url, priority);
         Ok(())
     }
-    pub fn verify(&mut self, url: String, hash: Hash) -> Result<()> {
+    pub fn verify(&mut self, url: impl ToUrl, hash: Hash) -> Result<()> {
+        let url = url.to_url()?;
         let mut lock = self.downloaders.lock()?;
         let chosen: &mut Downloader = lock.get_mut(&url).ok_or(ManicError::NotFound)?;
         let modified = chosen.verify(hash);
         lock.insert(url, modified).ok_or(ManicError::NotFound)?;
         Ok(())
     }
+    /// Check the download of an added URL against a detached signature,
+    /// see [`Downloader::verify_signature`]
+    #[cfg(feature = "sig-verify")]
+    pub fn verify_signature(&mut self, url: impl ToUrl, policy: SignaturePolicy) -> Result<()> {
+        let url = url.to_url()?;
+        let mut lock = self.downloaders.lock()?;
+        let chosen = lock.get_mut(&url).ok_or(ManicError::NotFound)?;
+        chosen.verify_signature(policy);
+        Ok(())
+    }
+    /// Number of distinct URLs added
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.downloaders.lock()?.len())
+    }
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.downloaders.lock()?.is_empty())
+    }
+    /// Set how failed downloads affect the result of [`download_all`][Self::download_all],
+    /// by default any failure fails the whole batch
+    pub fn join_policy(&mut self, policy: JoinPolicy) -> &mut Self {
+        self.policy = policy;
+        self
+    }
+    /// Cap the downloaded data [`download_all`][Self::download_all] keeps in memory.
+    ///
+    /// Downloads that finish once the cap is reached are written to the staging directory
+    /// and returned as a [`path`][Downloaded::path] instead of bytes. Only finished downloads
+    /// count, the ones still in flight hold their chunks on top of the cap.
+    /// Staged files are left for the caller to move or remove
+    pub fn max_memory(&mut self, bytes: u64) -> &mut Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+    /// Directory downloads over the [`max_memory`][Self::max_memory] cap are written to,
+    /// `manic` in the system temp directory by default
+    pub fn staging_dir<T: AsRef<Path>>(&mut self, dir: T) -> &mut Self {
+        self.staging_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+    fn memory_cap(&self) -> Option<Arc<MemoryCap>> {
+        let staging = self
+            .staging_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("manic"));
+        self.max_memory
+            .map(|max| Arc::new(MemoryCap::new(max, staging)))
+    }
+    /// Set the priority of an added URL, [`Priority::Normal`] by default
+    pub fn priority(&mut self, url: impl ToUrl, priority: Priority) -> Result<()> {
+        let url = url.to_url()?;
+        if !self.downloaders.lock()?.contains_key(&url) {
+            return Err(ManicError::NotFound);
+        }
+        self.priorities.insert(url, priority);
+        Ok(())
+    }
+    /// Downloads start by priority, those of equal priority in the order they were added
+    fn start_order(&self, url: &Url) -> (Priority, usize) {
+        (
+            self.priorities.get(url).copied().unwrap_or_default(),
+            self.added.get(url).copied().unwrap_or(usize::MAX),
+        )
+    }
+    /// Download every added URL, the thread pool starts them by [`Priority`] as threads free up
     pub fn download_all(&self) -> Result<Vec<Downloaded>> {
+        let cap = self.memory_cap();
         let mut fut_vec = Vec::new();
-        let lock = self.downloaders.lock()?;
-        for v in lock.values() {
-            let c = v.clone();
-            fut_vec.push(self.pool.evaluate(|| c.multi_download()));
+        let mut queue = self
+            .downloaders
+            .lock()?
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        queue.sort_by_key(|x| self.start_order(x.url()));
+        for c in queue {
+            let cap = cap.clone();
+            fut_vec.push(self.pool.evaluate(|| c.multi_download(cap)));
+        }
+        let done = join_all(fut_vec, self.policy)?;
+        self.verify_spilled(done)
+    }
+    /// Check the downloads spilled to disk against their hashes, read back in parallel
+    /// with [`hash_files`]
+    ///
+    /// A spilled file that doesn't match is removed and fails the batch as set by the join policy
+    fn verify_spilled(&self, done: Vec<Downloaded>) -> Result<Vec<Downloaded>> {
+        let expected = {
+            let map = self.downloaders.lock()?;
+            done.iter()
+                .map(|x| {
+                    let hash = map.get(x.url()).and_then(|d| d.hash())?;
+                    Some((x.path()?.to_path_buf(), hash.algorithm(), hash.to_string()))
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut algos = Vec::new();
+        for (_, algo, _) in expected.iter().flatten() {
+            if !algos.contains(algo) {
+                algos.push(*algo);
+            }
+        }
+        let mut failed = done.iter().map(|_| None).collect::<Vec<_>>();
+        for algo in algos {
+            let group = expected
+                .iter()
+                .enumerate()
+                .filter_map(|(i, x)| x.as_ref().filter(|x| x.1 == algo).map(|x| (i, x)))
+                .collect::<Vec<_>>();
+            let paths = group.iter().map(|(_, x)| x.0.clone()).collect::<Vec<_>>();
+            let hashed = hash_files(&paths, algo, self.workers as usize, None);
+            for ((i, (path, _, want)), (_, got)) in group.into_iter().zip(hashed) {
+                let err = match got {
+                    Ok(got) if &got == want => continue,
+                    Ok(got) => ManicError::SHA256MisMatch(got),
+                    Err(e) => e,
+                };
+                let _ = std::fs::remove_file(path);
+                failed[i] = Some(err);
+            }
         }
-        Ok(join_all(fut_vec)?.to_vec())
+        let results = done
+            .into_iter()
+            .zip(failed)
+            .map(|(x, err)| err.map_or(Ok(x), Err))
+            .collect();
+        self.policy.collect(results)
     }
-    pub fn download_one(&self, url: String) -> Result<ChunkVec> {
+    pub fn download_one(&self, url: impl ToUrl) -> Result<ChunkVec> {
+        let url = url.to_url()?;
         let chosen = self.downloaders.get(&url)?;
         chosen.download()
     }
@@ -0,0 +1,38 @@
+use crate::limit::SizeLimit;
+use crate::retry::{RangeFailures, Retry};
+use crate::{HttpVersionPolicy, Result};
+#[cfg(feature = "progress")]
+use indicatif::ProgressBar;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::Url;
+
+/// Everything a chunk request needs besides its range
+#[derive(Debug)]
+pub(crate) struct RequestContext {
+    pub(crate) client: Client,
+    pub(crate) url: Url,
+    pub(crate) limit: Option<SizeLimit>,
+    pub(crate) retry: Retry,
+    pub(crate) range_failures: RangeFailures,
+    pub(crate) http_version: HttpVersionPolicy,
+    /// Sent as `If-Range` so a changed file isn't mixed into the chunks already received
+    pub(crate) if_range: Option<String>,
+    #[cfg(feature = "progress")]
+    pub(crate) pb: Option<ProgressBar>,
+}
+
+impl RequestContext {
+    pub(crate) fn send(&self, req: RequestBuilder) -> Result<Response> {
+        req.send().map_err(|e| self.http_version.explain(e))
+    }
+    /// Give back `n` bytes of an attempt that didn't count, they'll be received again
+    pub(crate) fn release(&self, n: u64) {
+        if let Some(limit) = &self.limit {
+            limit.release(n);
+        }
+        #[cfg(feature = "progress")]
+        if let Some(bar) = &self.pb {
+            bar.dec(n);
+        }
+    }
+}
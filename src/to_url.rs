@@ -0,0 +1,112 @@
+use crate::{ManicError, Result};
+use reqwest::Url;
+
+/// Conversion into a normalized [`Url`], implemented for strings and [`Url`] itself
+///
+/// Strings are parsed eagerly so garbage input fails with [`ManicError::InvalidUrl`]
+/// before any request is made. Normalization lowercases the scheme and host, drops default ports,
+/// decodes percent-encoded unreserved characters and uppercases the remaining escapes,
+/// so two spellings of the same URL compare equal
+pub trait ToUrl {
+    fn to_url(self) -> Result<Url>;
+}
+
+impl ToUrl for Url {
+    fn to_url(self) -> Result<Url> {
+        Ok(normalize(self))
+    }
+}
+
+impl ToUrl for &Url {
+    fn to_url(self) -> Result<Url> {
+        Ok(normalize(self.clone()))
+    }
+}
+
+impl ToUrl for &str {
+    fn to_url(self) -> Result<Url> {
+        let parsed = Url::parse(self).map_err(|e| ManicError::InvalidUrl {
+            input: self.to_string(),
+            reason: e.to_string(),
+        })?;
+        Ok(normalize(parsed))
+    }
+}
+
+impl ToUrl for String {
+    fn to_url(self) -> Result<Url> {
+        self.as_str().to_url()
+    }
+}
+
+impl ToUrl for &String {
+    fn to_url(self) -> Result<Url> {
+        self.as_str().to_url()
+    }
+}
+
+/// Schemes the downloaders can fetch, `file` and `data` are read without a request
+pub(crate) const SUPPORTED_SCHEMES: &[&str] = &["http", "https", "file", "data"];
+
+/// Fail with [`ManicError::UnsupportedScheme`] for URLs no downloader can fetch
+pub(crate) fn check_scheme(url: &Url) -> Result<()> {
+    if SUPPORTED_SCHEMES.contains(&url.scheme()) {
+        Ok(())
+    } else {
+        Err(ManicError::UnsupportedScheme {
+            scheme: url.scheme().to_string(),
+            url: url.to_string(),
+        })
+    }
+}
+
+/// Fail with [`ManicError::InvalidWorkers`] for a worker count of zero,
+/// the chunk size is the length divided by it
+pub(crate) fn check_workers(workers: u8) -> Result<()> {
+    if workers == 0 {
+        return Err(ManicError::InvalidWorkers);
+    }
+    Ok(())
+}
+
+/// Parsing with [`Url`] already takes care of the scheme, host and default port,
+/// this only has to make percent-encoding consistent
+fn normalize(mut url: Url) -> Url {
+    let path = normalize_escapes(url.path());
+    url.set_path(&path);
+    if let Some(query) = url.query() {
+        let query = normalize_escapes(query);
+        url.set_query(Some(&query));
+    }
+    url
+}
+
+fn normalize_escapes(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = String::with_capacity(input.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = &input[i + 1..i + 3];
+            let parsed = hex
+                .bytes()
+                .all(|c| c.is_ascii_hexdigit())
+                .then(|| u8::from_str_radix(hex, 16).ok())
+                .flatten();
+            if let Some(b) = parsed {
+                if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+                    out.push(b as char);
+                } else {
+                    out.push('%');
+                    out.push_str(&hex.to_ascii_uppercase());
+                }
+                i += 3;
+                continue;
+            }
+        }
+        // Only ASCII is left after Url's own encoding, so byte-wise copying is safe
+        out.push(bytes[i] as char);
+        i += 1;
+    }
+    out
+}
@@ -0,0 +1,345 @@
+//! Building blocks shared by the downloaders that are useful on their own
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+/// Token bucket rate limiter, e.g. for capping download bandwidth in bytes per second
+///
+/// The bucket starts full, holds at most `burst` tokens and refills at `rate` tokens per second.
+/// Waiters are served in FIFO order and acquisitions larger than the burst are taken in
+/// burst-sized pieces, queueing again after each one, so a huge request can't starve small ones.
+/// Clones share the same bucket
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    bucket: Mutex<Bucket>,
+    /// Held by the waiter at the front of the queue, tokio's mutex hands it out in FIFO order
+    queue: tokio::sync::Mutex<()>,
+    rate_changed: Notify,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: u64,
+    burst: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst as f64);
+        self.updated = now;
+    }
+    /// Take `n` tokens or tell how long until they're there
+    fn take(&mut self, n: u64) -> Option<Duration> {
+        self.refill(Instant::now());
+        let missing = n as f64 - self.tokens;
+        if missing <= 0.0 {
+            self.tokens -= n as f64;
+            None
+        } else {
+            Some(Duration::from_secs_f64(missing / self.rate as f64))
+        }
+    }
+}
+
+impl RateLimiter {
+    /// New limiter allowing `rate` tokens per second with bursts of up to `burst`, both at least 1
+    pub fn new(rate: u64, burst: u64) -> Self {
+        let burst = burst.max(1);
+        Self {
+            inner: Arc::new(Inner {
+                bucket: Mutex::new(Bucket {
+                    rate: rate.max(1),
+                    burst,
+                    tokens: burst as f64,
+                    updated: Instant::now(),
+                }),
+                queue: tokio::sync::Mutex::new(()),
+                rate_changed: Notify::new(),
+            }),
+        }
+    }
+    /// Wait until `n` tokens are available and take them
+    ///
+    /// Dropping the future gives up its place in the queue, tokens of pieces already taken stay spent
+    pub async fn acquire(&self, mut n: u64) {
+        while n > 0 {
+            let piece = n.min(self.bucket().burst);
+            let _turn = self.inner.queue.lock().await;
+            loop {
+                // Registered before checking so a rate change in between isn't missed
+                let changed = self.inner.rate_changed.notified();
+                let wait = match self.bucket().take(piece) {
+                    Some(wait) => wait,
+                    None => break,
+                };
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = changed => {}
+                }
+            }
+            n -= piece;
+        }
+    }
+    /// Take `n` tokens if they're available right now and nobody is waiting
+    pub fn try_acquire(&self, n: u64) -> bool {
+        let _turn = match self.inner.queue.try_lock() {
+            Ok(turn) => turn,
+            Err(_) => return false,
+        };
+        self.bucket().take(n).is_none()
+    }
+    /// Tokens added per second
+    pub fn rate(&self) -> u64 {
+        self.bucket().rate
+    }
+    /// Change the rate, waiters pick it up right away
+    pub fn set_rate(&self, rate: u64) {
+        {
+            let mut bucket = self.bucket();
+            bucket.refill(Instant::now());
+            bucket.rate = rate.max(1);
+        }
+        self.inner.rate_changed.notify_waiters();
+    }
+    fn bucket(&self) -> std::sync::MutexGuard<'_, Bucket> {
+        self.inner
+            .bucket
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Cap on the chunk requests in flight across every downloader it's passed to,
+/// e.g. to stay within the connection pool of a shared [`Client`][crate::async_client::Client]
+///
+/// By default requests are admitted in the order they ask, so a download that queued all its
+/// chunks first holds every slot until it's nearly done. With [`fair_share`][Self::fair_share]
+/// the next free slot goes to the download with the fewest bytes in flight instead, and
+/// concurrent downloads converge towards equal throughput. Clones share the same slots
+#[derive(Debug, Clone)]
+pub struct RequestScheduler {
+    inner: Arc<SchedulerInner>,
+}
+
+#[derive(Debug)]
+struct SchedulerInner {
+    state: Mutex<SchedulerState>,
+    changed: Notify,
+}
+
+#[derive(Debug)]
+struct SchedulerState {
+    max: usize,
+    fair: bool,
+    in_flight: usize,
+    next_ticket: u64,
+    next_scope: u64,
+    /// Bytes in flight per download, only downloads with requests in flight are listed
+    scopes: HashMap<u64, u64>,
+    /// Waiting requests by ticket, tickets are handed out in arrival order
+    waiting: BTreeMap<u64, u64>,
+}
+
+impl SchedulerState {
+    /// Ticket of the request that gets the next free slot
+    fn next(&self) -> Option<u64> {
+        if !self.fair {
+            return self.waiting.keys().next().copied();
+        }
+        self.waiting
+            .iter()
+            .min_by_key(|(ticket, scope)| (self.scopes.get(scope).copied().unwrap_or(0), **ticket))
+            .map(|(ticket, _)| *ticket)
+    }
+}
+
+impl RequestScheduler {
+    /// New scheduler letting at most `max_in_flight` requests, at least 1, run at once
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            inner: Arc::new(SchedulerInner {
+                state: Mutex::new(SchedulerState {
+                    max: max_in_flight.max(1),
+                    fair: false,
+                    in_flight: 0,
+                    next_ticket: 0,
+                    next_scope: 0,
+                    scopes: HashMap::new(),
+                    waiting: BTreeMap::new(),
+                }),
+                changed: Notify::new(),
+            }),
+        }
+    }
+    /// Hand free slots to the download with the fewest bytes in flight instead of the first to ask
+    pub fn fair_share(self, enabled: bool) -> Self {
+        self.state().fair = enabled;
+        self
+    }
+    /// Requests currently holding a slot
+    pub fn in_flight(&self) -> usize {
+        self.state().in_flight
+    }
+    /// New scope for the requests of one download
+    pub(crate) fn register(&self) -> Scope {
+        let mut state = self.state();
+        state.next_scope += 1;
+        Scope {
+            scheduler: self.clone(),
+            id: state.next_scope,
+        }
+    }
+    fn state(&self) -> std::sync::MutexGuard<'_, SchedulerState> {
+        self.inner
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The requests of one download on a [`RequestScheduler`]
+#[derive(Debug, Clone)]
+pub(crate) struct Scope {
+    scheduler: RequestScheduler,
+    id: u64,
+}
+
+impl Scope {
+    /// Wait for a slot for a request of `bytes`, it's given back when the returned guard is dropped
+    pub(crate) async fn admit(&self, bytes: u64) -> Admission {
+        let ticket = {
+            let mut state = self.scheduler.state();
+            state.next_ticket += 1;
+            let ticket = state.next_ticket;
+            state.waiting.insert(ticket, self.id);
+            ticket
+        };
+        // Gives up the place in the queue if the future is dropped while waiting
+        let _waiting = Waiting {
+            scheduler: &self.scheduler,
+            ticket,
+        };
+        loop {
+            // Registered before checking so a slot freed in between isn't missed
+            let changed = self.scheduler.inner.changed.notified();
+            {
+                let mut state = self.scheduler.state();
+                if state.in_flight < state.max && state.next() == Some(ticket) {
+                    state.waiting.remove(&ticket);
+                    state.in_flight += 1;
+                    *state.scopes.entry(self.id).or_insert(0) += bytes;
+                    drop(state);
+                    // Someone else may be next in line now
+                    self.scheduler.inner.changed.notify_waiters();
+                    return Admission {
+                        scope: self.clone(),
+                        bytes,
+                    };
+                }
+            }
+            changed.await;
+        }
+    }
+}
+
+struct Waiting<'a> {
+    scheduler: &'a RequestScheduler,
+    ticket: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        if self
+            .scheduler
+            .state()
+            .waiting
+            .remove(&self.ticket)
+            .is_some()
+        {
+            self.scheduler.inner.changed.notify_waiters();
+        }
+    }
+}
+
+/// Slot of a [`RequestScheduler`] held by one request
+#[derive(Debug)]
+pub(crate) struct Admission {
+    scope: Scope,
+    bytes: u64,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        {
+            let mut state = self.scope.scheduler.state();
+            state.in_flight -= 1;
+            if let Some(bytes) = state.scopes.get_mut(&self.scope.id) {
+                *bytes = bytes.saturating_sub(self.bytes);
+                if *bytes == 0 {
+                    state.scopes.remove(&self.scope.id);
+                }
+            }
+        }
+        self.scope.scheduler.inner.changed.notify_waiters();
+    }
+}
+
+/// Pauses every download it's passed to without tearing it down
+///
+/// Paused downloads send no new chunk requests and stop reading the bodies of the ones
+/// in flight between frames, their connections stay open and they continue where they
+/// were on [`resume`][Self::resume]. Unlike [`DownloadHandle::pause`][crate::async_client::DownloadHandle::pause]
+/// nothing is requested again, but a server may time out a connection that's paused for long.
+/// Clones share the same state
+#[derive(Debug, Clone)]
+pub struct PauseToken {
+    paused: Arc<watch::Sender<bool>>,
+}
+
+impl Default for PauseToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PauseToken {
+    /// New token, not paused
+    pub fn new() -> Self {
+        Self {
+            paused: Arc::new(watch::channel(false).0),
+        }
+    }
+    /// Returns false if it was already paused
+    pub fn pause(&self) -> bool {
+        self.paused
+            .send_if_modified(|paused| !std::mem::replace(paused, true))
+    }
+    /// Returns false if it wasn't paused
+    pub fn resume(&self) -> bool {
+        self.paused
+            .send_if_modified(|paused| std::mem::replace(paused, false))
+    }
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+    /// Wait until the token isn't paused
+    pub(crate) async fn resumed(&self) {
+        let mut rx = self.paused.subscribe();
+        while *rx.borrow_and_update() {
+            if rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -1,6 +1,15 @@
+use futures::future::BoxFuture;
 use log::LevelFilter;
-use manic::{Downloader, Hash, ManicError, Result};
+use manic::async_client::{ChunkRequest, ChunkResponse, ChunkService, Client, Request};
+use manic::{
+    Downloader, ErrorCode, Hash, HttpVersionPolicy, LockPolicy, ManicError, MultiDownloader,
+    Priority, RequestSigner, Result, SocketOptions,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use warp::Filter;
 
 #[tokio::test]
 async fn local() -> Result<()> {
@@ -27,3 +36,2128 @@ async fn local() -> Result<()> {
     }
     Ok(())
 }
+
+#[tokio::test]
+async fn local_save_checked() -> Result<()> {
+    tokio::spawn(crate::start_server(8002, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let dir = tempfile::tempdir()?;
+    let mut dl = Downloader::new("http://127.0.0.1:8002/croc.zip", 4).await?;
+    dl.check_size(true);
+    dl.download_and_save(dir.path().to_str().unwrap()).await?;
+    let saved = std::fs::metadata(dir.path().join("croc.zip"))?;
+    assert_eq!(saved.len(), dl.get_len());
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_concurrent_save() -> Result<()> {
+    tokio::spawn(crate::start_server(8004, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let dir = tempfile::tempdir()?;
+    let target = dir.path().join("croc.zip");
+    let mut dl = Downloader::new("http://127.0.0.1:8004/croc.zip", 4).await?;
+    dl.lock_policy(LockPolicy::Fail);
+    let path = target.to_str().unwrap();
+    let (first, second) = tokio::join!(dl.download_and_save(path), dl.download_and_save(path));
+    let results = [first, second];
+    assert_eq!(results.iter().filter(|x| x.is_ok()).count(), 1);
+    assert!(results
+        .iter()
+        .any(|x| matches!(x, Err(ManicError::ConcurrentDownload(_)))));
+    let mut hash = Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    );
+    hash.update(&std::fs::read(&target)?);
+    hash.verify()
+}
+
+#[tokio::test]
+async fn local_memory_budget() -> Result<()> {
+    tokio::spawn(crate::start_server(8005, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    #[cfg(feature = "progress")]
+    let mut multi = MultiDownloader::new(false).await;
+    #[cfg(not(feature = "progress"))]
+    let mut multi = MultiDownloader::new().await;
+    for i in 0..5 {
+        let url = format!("http://127.0.0.1:8005/croc.zip?copy={}", i);
+        multi.add(url.clone(), 4).await?;
+        multi
+            .verify(
+                url,
+                Hash::new_sha256(
+                    "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+                ),
+            )
+            .await?;
+    }
+    let dir = tempfile::tempdir()?;
+    multi.memory_budget(64 * 1024).staging_dir(dir.path());
+    let done = multi.download_all().await?;
+    assert_eq!(done.len(), 5);
+    // Each download is larger than the budget, so they run one by one and every one but the
+    // last gives up its share for the next
+    let spilled = done.iter().filter_map(|x| x.path()).collect::<Vec<_>>();
+    assert_eq!(spilled.len(), 4);
+    for path in spilled {
+        assert_eq!(std::fs::metadata(path)?.len(), 2251551);
+    }
+    assert_eq!(done.iter().filter(|x| x.data().is_some()).count(), 1);
+
+    // Two downloads fit, at most two are kept in memory
+    multi.memory_budget(2 * 2251551);
+    let done = multi.download_all().await?;
+    assert_eq!(done.len(), 5);
+    assert_eq!(done.iter().filter(|x| x.data().is_some()).count(), 2);
+    assert_eq!(done.iter().filter(|x| x.path().is_some()).count(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_url_normalization() -> Result<()> {
+    tokio::spawn(crate::start_server(8006, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    #[cfg(feature = "progress")]
+    let mut multi = MultiDownloader::new(false).await;
+    #[cfg(not(feature = "progress"))]
+    let mut multi = MultiDownloader::new().await;
+    multi.add("HTTP://127.0.0.1:8006/croc.zip", 2).await?;
+    multi.add("http://127.0.0.1:8006/%63roc.zip", 2).await?;
+    assert_eq!(multi.len().await, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_url() {
+    let res = Downloader::new("not a url", 2).await;
+    assert!(matches!(res, Err(ManicError::InvalidUrl { .. })));
+}
+
+#[tokio::test]
+async fn local_save_layouts() -> Result<()> {
+    tokio::spawn(crate::start_server(8007, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let dir = tempfile::tempdir()?;
+    for workers in [1, 3, 7, 10, 64] {
+        let target = dir.path().join(format!("croc_{}.zip", workers));
+        let dl = Downloader::new("http://127.0.0.1:8007/croc.zip", workers).await?;
+        dl.download_and_save(target.to_str().unwrap()).await?;
+        let mut hash = Hash::new_sha256(
+            "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+        );
+        hash.update(&std::fs::read(&target)?);
+        hash.verify()?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_file_and_data_urls() -> Result<()> {
+    let path = std::fs::canonicalize("tests/static/croc.zip")?;
+    let url = manic::Url::from_file_path(path).unwrap();
+    let mut dl = Downloader::new(url, 4).await?;
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download().await?;
+    let mut dl = Downloader::new("data:text/plain;base64,aGVsbG8=", 4).await?;
+    dl.verify(Hash::new_sha256(
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_string(),
+    ));
+    assert_eq!(dl.download().await?.to_vec().await, b"hello");
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct FakeSigner {
+    seen: Mutex<Vec<(String, String, bool)>>,
+}
+
+impl RequestSigner for FakeSigner {
+    fn sign<'a>(&'a self, req: &'a mut Request) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.seen.lock().unwrap().push((
+                req.method().to_string(),
+                req.url().path().to_string(),
+                req.headers().contains_key("range"),
+            ));
+            req.headers_mut()
+                .insert("x-signature", "signed".parse().unwrap());
+            Ok(())
+        })
+    }
+}
+
+#[tokio::test]
+async fn local_signed_requests() -> Result<()> {
+    let signed = warp::header::exact("x-signature", "signed");
+    let file = warp::path!("signed" / "croc.zip")
+        .and(signed)
+        .and(warp::fs::file("tests/static/croc.zip"));
+    let redirect = warp::path!("redirect" / "croc.zip")
+        .and(signed)
+        .map(|| warp::redirect::temporary(warp::http::Uri::from_static("/signed/croc.zip")));
+    tokio::spawn(warp::serve(file.or(redirect)).run(([127, 0, 0, 1], 8008)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let unsigned = Client::new()
+        .get("http://127.0.0.1:8008/signed/croc.zip")
+        .send()
+        .await?;
+    assert!(unsigned.status().is_client_error());
+    let signer = Arc::new(FakeSigner::default());
+    let mut dl =
+        Downloader::new_signed("http://127.0.0.1:8008/redirect/croc.zip", 4, signer.clone())
+            .await?;
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download().await?;
+    let seen = signer.seen.lock().unwrap();
+    for path in ["/redirect/croc.zip", "/signed/croc.zip"] {
+        assert!(seen.contains(&("HEAD".to_string(), path.to_string(), false)));
+        let chunks = seen
+            .iter()
+            .filter(|(method, p, range)| method == "GET" && p == path && *range)
+            .count();
+        assert_eq!(chunks, dl.get_len().div_ceil(dl.get_len() / 4) as usize);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_max_size() -> Result<()> {
+    let path = std::fs::canonicalize("tests/static/croc.zip")?;
+    let url = manic::Url::from_file_path(path).unwrap();
+    let mut dl = Downloader::new(url, 2).await?;
+    dl.max_size(1000);
+    assert!(matches!(
+        dl.download().await,
+        Err(ManicError::TooLarge {
+            limit: 1000,
+            size: 2251551
+        })
+    ));
+    // Ignores Range and sends the whole file for every chunk
+    let full = warp::path!("croc.zip").map(|| std::fs::read("tests/static/croc.zip").unwrap());
+    tokio::spawn(warp::serve(full).run(([127, 0, 0, 1], 8009)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new_manual("http://127.0.0.1:8009/croc.zip", 1, 1000).await?;
+    dl.max_size(2000);
+    assert!(matches!(
+        dl.download().await,
+        Err(ManicError::TooLarge { limit: 2000, .. })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_retries() -> Result<()> {
+    // The first three requests get a 503, the rest are served normally
+    let hits = Arc::new(AtomicUsize::new(0));
+    let failing = warp::path!("croc.zip")
+        .map(move || hits.fetch_add(1, Ordering::SeqCst))
+        .and_then(|n: usize| async move {
+            match n < 3 {
+                true => Ok(warp::http::StatusCode::SERVICE_UNAVAILABLE),
+                false => Err(warp::reject::not_found()),
+            }
+        });
+    let file = warp::path!("croc.zip").and(warp::fs::file("tests/static/croc.zip"));
+    tokio::spawn(warp::serve(failing.or(file)).run(([127, 0, 0, 1], 8010)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let url = "http://127.0.0.1:8010/croc.zip";
+    let dl = Downloader::new_manual(url, 1, 2251551).await?;
+    assert!(matches!(
+        dl.download().await,
+        Err(ManicError::NetError(e)) if e.status().map(|x| x.as_u16()) == Some(503)
+    ));
+    let mut dl = Downloader::new_manual(url, 1, 2251551).await?;
+    dl.retries(2).jitter(false);
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn target_filename() -> Result<()> {
+    let long = format!("http://127.0.0.1:1/{}.tar.gz", "%41".repeat(400));
+    let name = Downloader::new_manual(long.as_str(), 1, 1)
+        .await?
+        .target_filename()?;
+    assert!(name.len() <= 255);
+    assert!(name.starts_with("AAAA"));
+    assert!(name.ends_with(".gz"));
+    let again = Downloader::new_manual(long.as_str(), 1, 1)
+        .await?
+        .target_filename()?;
+    assert_eq!(name, again);
+    let cases = [
+        ("new%0Aline%0D.txt", "newline.txt"),
+        ("..%2F..%2Fetc%2Fpasswd", ".._.._etc_passwd"),
+        ("con.txt", "_con.txt"),
+        ("trailing.%20.", "trailing"),
+    ];
+    for (segment, expected) in cases {
+        let url = format!("http://127.0.0.1:1/{}", segment);
+        let dl = Downloader::new_manual(url, 1, 1).await?;
+        assert_eq!(dl.target_filename()?, expected);
+    }
+    assert!(matches!(
+        Downloader::new_manual("http://127.0.0.1:1/%0A%0D", 1, 1).await,
+        Err(ManicError::NoFilename(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_probe() -> Result<()> {
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counted = hits.clone();
+    let file = warp::path!("croc.zip").and(warp::fs::file("tests/static/croc.zip"));
+    // Refuses ranges, so the downloader has to fall back to a single request
+    let single = warp::path!("single" / "croc.zip")
+        .map(move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        })
+        .untuple_one()
+        .and(warp::fs::file("tests/static/croc.zip"))
+        .map(|reply| warp::reply::with_header(reply, "accept-ranges", "none"));
+    // Refuses HEAD, so the probe asks for the first byte instead
+    let ranges = Arc::new(Mutex::new(Vec::new()));
+    let seen = ranges.clone();
+    let no_head = warp::get()
+        .and(warp::path!("no-head" / "croc.zip"))
+        .and(warp::header::optional::<String>("range"))
+        .map(move |range| seen.lock().unwrap().push(range))
+        .untuple_one()
+        .and(warp::fs::file("tests/static/croc.zip"));
+    tokio::spawn(warp::serve(file.or(single).or(no_head)).run(([127, 0, 0, 1], 8011)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let info = Downloader::probe("http://127.0.0.1:8011/croc.zip").await?;
+    assert_eq!(info.content_length, Some(2251551));
+    assert_eq!(info.accept_ranges, Some(true));
+    assert_eq!(info.content_type.as_deref(), Some("application/zip"));
+    assert!(info.last_modified.is_some());
+    let mut dl = Downloader::new("http://127.0.0.1:8011/single/croc.zip", 4).await?;
+    assert_eq!(dl.remote_info().unwrap().accept_ranges, Some(false));
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download().await?;
+    // The HEAD probe and one GET for the whole file
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+    // The size comes from Content-Range, not the length of the one byte sent
+    let info = Downloader::probe("http://127.0.0.1:8011/no-head/croc.zip").await?;
+    assert_eq!(info.content_length, Some(2251551));
+    assert_eq!(*ranges.lock().unwrap(), [Some("bytes=0-0".to_string())]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_cancelled_save() -> Result<()> {
+    // Counts the chunk requests and never answers them
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counted = hits.clone();
+    let stalled = warp::path!("croc.zip").and_then(move || {
+        counted.fetch_add(1, Ordering::SeqCst);
+        futures::future::pending::<std::result::Result<String, warp::Rejection>>()
+    });
+    tokio::spawn(warp::serve(stalled).run(([127, 0, 0, 1], 8012)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let dir = tempfile::tempdir()?;
+    let mut dl = Downloader::new_manual("http://127.0.0.1:8012/croc.zip", 2, 2251551).await?;
+    dl.retries(3);
+    let save = dl.download_and_save(dir.path().to_str().unwrap());
+    assert!(tokio::time::timeout(Duration::from_secs(1), save)
+        .await
+        .is_err());
+    let started = hits.load(Ordering::SeqCst);
+    assert!(started > 0);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(hits.load(Ordering::SeqCst), started);
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+    Ok(())
+}
+
+/// Counts the chunk requests in flight on the client side, dropped requests stop counting
+#[cfg(feature = "builder")]
+#[derive(Debug)]
+struct InFlight {
+    inner: Arc<dyn ChunkService>,
+    current: Arc<AtomicUsize>,
+    peak: Arc<AtomicUsize>,
+}
+
+#[cfg(feature = "builder")]
+struct Leave(Arc<AtomicUsize>);
+
+#[cfg(feature = "builder")]
+impl Drop for Leave {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "builder")]
+impl ChunkService for InFlight {
+    fn call(&self, req: ChunkRequest) -> BoxFuture<'_, Result<ChunkResponse>> {
+        Box::pin(async move {
+            let n = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(n, Ordering::SeqCst);
+            let _leave = Leave(self.current.clone());
+            self.inner.call(req).await
+        })
+    }
+}
+
+#[cfg(feature = "builder")]
+#[tokio::test]
+async fn local_pause_resume() -> Result<()> {
+    // The first chunk request is answered right away, the rest wait until the gate opens
+    let hits = Arc::new(AtomicUsize::new(0));
+    let counted = hits.clone();
+    let (gate, gate_rx) = tokio::sync::watch::channel(false);
+    let gated = warp::path!("croc.zip")
+        .and_then(move || {
+            let n = counted.fetch_add(1, Ordering::SeqCst);
+            let mut open = gate_rx.clone();
+            async move {
+                while n > 0 && !*open.borrow() {
+                    let _ = open.changed().await;
+                }
+                Ok::<_, warp::Rejection>(())
+            }
+        })
+        .untuple_one()
+        .and(warp::fs::file("tests/static/croc.zip"));
+    tokio::spawn(warp::serve(gated).run(([127, 0, 0, 1], 8013)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    // 8 chunks for 3 workers, so the pending ones have to wait for a free worker
+    let mut builder = manic::async_client::DownloaderBuilder::default();
+    builder
+        .filename("croc.zip".to_string())
+        .workers(3)
+        .url(manic::Url::parse("http://127.0.0.1:8013/croc.zip").unwrap())
+        .hash(Some(Hash::new_sha256(
+            "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+        )))
+        .length(2251551)
+        .chunks(manic::async_client::Chunks::new(0, 2251550, 281444)?);
+    #[cfg(feature = "progress")]
+    builder.pb(None);
+    let mut dl = builder.build()?;
+    assert_eq!(dl.chunk_plan().len(), 8);
+    let (current, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let (counted, seen) = (current.clone(), peak.clone());
+    dl.with_service(move |inner| InFlight {
+        inner,
+        current: counted.clone(),
+        peak: seen.clone(),
+    });
+    let handle = dl.start();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    // The first chunk finished and its worker moved on to the next one
+    let started = hits.load(Ordering::SeqCst);
+    assert_eq!(started, 4);
+    assert_eq!(current.load(Ordering::SeqCst), 3);
+    assert!(handle.pause());
+    assert!(!handle.pause());
+    assert_eq!(handle.state(), manic::async_client::DownloadState::Paused);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(hits.load(Ordering::SeqCst), started);
+    assert_eq!(current.load(Ordering::SeqCst), 0);
+    gate.send(true).unwrap();
+    assert!(handle.resume());
+    assert!(handle.paused_for() >= Duration::from_millis(500));
+    handle.await_result().await?;
+    // Every chunk but the first one that finished before the pause is requested again,
+    // never more than the workers at once
+    assert_eq!(hits.load(Ordering::SeqCst), started + 7);
+    assert_eq!(peak.load(Ordering::SeqCst), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_cancel_handle() -> Result<()> {
+    let stalled = warp::path!("croc.zip")
+        .and_then(futures::future::pending::<std::result::Result<String, warp::Rejection>>);
+    tokio::spawn(warp::serve(stalled).run(([127, 0, 0, 1], 8014)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let dl = Downloader::new_manual("http://127.0.0.1:8014/croc.zip", 2, 2251551).await?;
+    let handle = dl.start();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(handle.cancel());
+    assert!(!handle.resume());
+    assert!(matches!(
+        handle.await_result().await,
+        Err(ManicError::Cancelled)
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn unsupported_scheme() {
+    for url in ["ftp://127.0.0.1/croc.zip", "mailto:someone@example.com"] {
+        assert!(matches!(
+            Downloader::new(url, 1).await,
+            Err(ManicError::UnsupportedScheme { .. })
+        ));
+        assert!(matches!(
+            Downloader::new_manual(url, 1, 1).await,
+            Err(ManicError::UnsupportedScheme { .. })
+        ));
+    }
+}
+
+#[cfg(feature = "builder")]
+#[test]
+fn builder_unsupported_scheme() {
+    let built = manic::async_client::DownloaderBuilder::default()
+        .filename("croc.zip".to_string())
+        .workers(1)
+        .url(manic::Url::parse("ftp://127.0.0.1/croc.zip").unwrap())
+        .hash(None)
+        .length(1)
+        .chunks(manic::async_client::Chunks::new(0, 0, 1).unwrap())
+        .build();
+    let err = built.unwrap_err();
+    assert!(err.to_string().contains("http, https, file, data"));
+    assert!(matches!(err, ManicError::UnsupportedScheme { .. }));
+}
+
+#[cfg(feature = "progress")]
+#[tokio::test]
+async fn local_verify_progress() -> Result<()> {
+    let path = std::fs::canonicalize("tests/static/croc.zip")?;
+    let url = manic::Url::from_file_path(path).unwrap();
+    let mut dl = Downloader::new(url, 2).await?;
+    let bar = indicatif::ProgressBar::hidden();
+    dl.connect_progress(bar.clone());
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download().await?;
+    assert_eq!(bar.message(), "verified");
+    assert_eq!(bar.length(), Some(2251551));
+    assert_eq!(bar.position(), 2251551);
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_max_memory() -> Result<()> {
+    let staging = tempfile::tempdir()?;
+    #[cfg(feature = "progress")]
+    let mut multi = MultiDownloader::new(false).await;
+    #[cfg(not(feature = "progress"))]
+    let mut multi = MultiDownloader::new().await;
+    let path = std::fs::canonicalize("tests/static/croc.zip")?;
+    let croc = manic::Url::from_file_path(path).unwrap();
+    multi.add(croc.clone(), 4).await?;
+    multi.add("data:text/plain;base64,aGVsbG8=", 1).await?;
+    multi.max_memory(1024).staging_dir(staging.path());
+    let done = multi.download_all().await?;
+    assert_eq!(done.len(), 2);
+    for d in done {
+        if d.url() == &croc {
+            assert!(d.data().is_none());
+            let spilled = d.path().unwrap();
+            assert!(spilled.starts_with(staging.path()));
+            assert_eq!(std::fs::metadata(spilled)?.len(), 2251551);
+        } else {
+            assert!(d.path().is_none());
+            assert_eq!(d.data().unwrap().to_vec().await, b"hello");
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_save_and_verify() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let data = Downloader::new("data:text/plain;base64,aGVsbG8=", 1)
+        .await?
+        .download()
+        .await?;
+    let target = dir.path().join("hello.txt");
+    let res = data
+        .save_and_verify(&target, Hash::new_sha256("0".repeat(64)))
+        .await;
+    assert!(matches!(res, Err(ManicError::SHA256MisMatch(_))));
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+    data.save_and_verify(
+        &target,
+        Hash::new_sha256(
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_string(),
+        ),
+    )
+    .await?;
+    assert_eq!(std::fs::read(&target)?, b"hello");
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_range_ignored() -> Result<()> {
+    // Answers every request with the whole file and doesn't advertise Accept-Ranges
+    let whole = warp::path!("croc.zip").map(|| std::fs::read("tests/static/croc.zip").unwrap());
+    tokio::spawn(warp::serve(whole).run(([127, 0, 0, 1], 8015)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new("http://127.0.0.1:8015/croc.zip", 4).await?;
+    assert_eq!(dl.remote_info().unwrap().accept_ranges, None);
+    dl.range_fallback(0);
+    let res = dl.download().await;
+    assert!(
+        matches!(&res, Err(ManicError::RangeIgnored(_))),
+        "{:?}",
+        res
+    );
+    let mut dl = Downloader::new("http://127.0.0.1:8015/croc.zip", 1).await?;
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn clone_for() -> Result<()> {
+    let path = std::fs::canonicalize("tests/static/croc.zip")?;
+    let croc = manic::Url::from_file_path(path).unwrap();
+    let mut template = Downloader::new("data:text/plain;base64,aGVsbG8=", 3).await?;
+    template.max_size(1000);
+    let dl = template.clone_for(croc.clone()).await?;
+    assert_eq!(dl.url(), &croc);
+    assert_eq!(dl.filename(), "croc.zip");
+    assert_eq!(dl.get_len(), 2251551);
+    assert!(matches!(
+        dl.download().await,
+        Err(ManicError::TooLarge { limit: 1000, .. })
+    ));
+    let dl = dl.clone_for("data:text/plain;base64,aGVsbG8h").await?;
+    assert_eq!(dl.download().await?.to_vec().await, b"hello!");
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_http_version() -> Result<()> {
+    tokio::spawn(crate::start_server(8016, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new("http://127.0.0.1:8016/croc.zip", 4).await?;
+    assert_eq!(
+        dl.remote_info().unwrap().http_version,
+        Some(manic::Version::HTTP_11)
+    );
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.http_version(HttpVersionPolicy::Http1Only)?;
+    dl.download().await?;
+    // Speaks nothing but HTTP/1.1, so the HTTP/2 connection preface gets garbage back
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", 8017)).await?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await;
+            let _ = stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n")
+                .await;
+        }
+    });
+    let mut dl = Downloader::new_manual("http://127.0.0.1:8017/croc.zip", 1, 2251551).await?;
+    dl.http_version(HttpVersionPolicy::Http2Only)?;
+    let res = dl.download().await;
+    assert!(
+        matches!(
+            &res,
+            Err(ManicError::HttpVersion {
+                policy: HttpVersionPolicy::Http2Only,
+                ..
+            })
+        ),
+        "{:?}",
+        res
+    );
+    // A refused connection is no protocol error, it's retried like without a forced version
+    let capture = Capture::default();
+    let _guard = tracing::subscriber::set_default(capture.clone());
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let url = format!("http://127.0.0.1:{}/croc.zip", port);
+    let mut dl = Downloader::new_manual(url, 1, 2251551).await?;
+    dl.http_version(HttpVersionPolicy::Http1Only)?;
+    dl.retries(2).jitter(false);
+    let err = dl.download().await.unwrap_err();
+    assert_eq!(err.code(), ErrorCode::Connect);
+    let retries = capture
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(name, _)| name == "manic.download.chunk.retry")
+        .count();
+    assert_eq!(retries, 2);
+    Ok(())
+}
+
+/// Name and field names of an event
+type Recorded = (String, Vec<String>);
+
+/// Records every `manic::download` event
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<Recorded>>>);
+
+impl tracing::Subscriber for Capture {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+    fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        tracing::span::Id::from_u64(1)
+    }
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+    fn event(&self, event: &tracing::Event<'_>) {
+        let meta = event.metadata();
+        if meta.target() == "manic::download" {
+            let fields = meta.fields().iter().map(|f| f.name().to_string()).collect();
+            self.0
+                .lock()
+                .unwrap()
+                .push((meta.name().to_string(), fields));
+        }
+    }
+    fn enter(&self, _: &tracing::span::Id) {}
+    fn exit(&self, _: &tracing::span::Id) {}
+}
+
+#[tokio::test]
+async fn local_tracing_events() -> Result<()> {
+    let capture = Capture::default();
+    let _guard = tracing::subscriber::set_default(capture.clone());
+    // The first request gets a 503, the rest are served normally
+    let hits = Arc::new(AtomicUsize::new(0));
+    let failing = warp::path!("croc.zip")
+        .map(move || hits.fetch_add(1, Ordering::SeqCst))
+        .and_then(|n: usize| async move {
+            match n < 1 {
+                true => Ok(warp::http::StatusCode::SERVICE_UNAVAILABLE),
+                false => Err(warp::reject::not_found()),
+            }
+        });
+    let file = warp::path!("croc.zip").and(warp::fs::file("tests/static/croc.zip"));
+    tokio::spawn(warp::serve(failing.or(file)).run(([127, 0, 0, 1], 8018)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new_manual("http://127.0.0.1:8018/croc.zip", 1, 2251551).await?;
+    dl.retries(1).jitter(false);
+    dl.download().await?;
+    let dl = Downloader::new_manual("http://127.0.0.1:8018/missing.zip", 1, 10).await?;
+    assert!(dl.download().await.is_err());
+    let fields = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    let expected = vec![
+        (
+            "manic.download.start".to_string(),
+            fields(&["url", "bytes", "workers"]),
+        ),
+        (
+            "manic.download.chunk.retry".to_string(),
+            fields(&["url", "range", "attempt", "delay_ms", "error.kind", "error"]),
+        ),
+        (
+            "manic.download.complete".to_string(),
+            fields(&["url", "bytes", "duration_ms"]),
+        ),
+        (
+            "manic.download.start".to_string(),
+            fields(&["url", "bytes", "workers"]),
+        ),
+        (
+            "manic.download.failed".to_string(),
+            fields(&["url", "duration_ms", "error.kind", "error"]),
+        ),
+    ];
+    assert_eq!(*capture.0.lock().unwrap(), expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn download_verify_install() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let target = dir.path().join("hello.txt");
+    std::fs::write(&target, b"old")?;
+    let dl = Downloader::new("data:text/plain;base64,aGVsbG8=", 1).await?;
+    let res = dl
+        .download_verify_install(&target, Hash::new_sha256("0".repeat(64)))
+        .await;
+    assert!(matches!(res, Err(ManicError::SHA256MisMatch(_))));
+    assert_eq!(std::fs::read(&target)?, b"old");
+    assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+    dl.download_verify_install(
+        &target,
+        Hash::new_sha256(
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_string(),
+        ),
+    )
+    .await?;
+    assert_eq!(std::fs::read(&target)?, b"hello");
+    Ok(())
+}
+
+#[cfg(feature = "decompress")]
+#[tokio::test]
+async fn local_decompress_on_save() -> Result<()> {
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+    let dir = tempfile::tempdir()?;
+    let served = dir.path().join("served");
+    std::fs::create_dir(&served)?;
+    let text = "manic decompresses this on save\n"
+        .repeat(32 * 1024)
+        .into_bytes();
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&text)?;
+    let gz = encoder.finish()?;
+    std::fs::write(served.join("notes.txt.gz"), &gz)?;
+    std::fs::write(served.join("broken.txt.gz"), &gz[..gz.len() / 2])?;
+    tokio::spawn(warp::serve(warp::fs::dir(served)).run(([127, 0, 0, 1], 8044)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut served_hash = Hash::new_sha256(String::new());
+    served_hash.update(&gz);
+    let served_hash = served_hash.finalize();
+    let out = dir.path().join("out");
+    std::fs::create_dir(&out)?;
+    let out_str = out.to_str().unwrap();
+    // The hash is of the compressed bytes, a mismatch leaves nothing behind
+    let mut dl = Downloader::new("http://127.0.0.1:8044/notes.txt.gz", 4).await?;
+    dl.decompress_on_save(manic::Compression::Gzip);
+    let res = dl
+        .verify(Hash::new_sha256("0".repeat(64)))
+        .download_and_save(out_str)
+        .await;
+    assert!(
+        matches!(res, Err(ManicError::SHA256MisMatch(_))),
+        "{:?}",
+        res
+    );
+    assert_eq!(std::fs::read_dir(&out)?.count(), 0);
+    dl.verify(Hash::new_sha256(served_hash))
+        .download_and_save(out_str)
+        .await?;
+    assert_eq!(std::fs::read(out.join("notes.txt"))?, text);
+    let mut dl = Downloader::new("http://127.0.0.1:8044/broken.txt.gz", 4).await?;
+    dl.decompress_on_save(manic::Compression::Gzip);
+    let err = dl.download_and_save(out_str).await.unwrap_err();
+    assert_eq!(err.code(), ErrorCode::Decompress, "{}", err);
+    assert!(!out.join("broken.txt").exists());
+    Ok(())
+}
+
+#[cfg(feature = "decompress")]
+#[tokio::test]
+async fn local_download_decompressed() -> Result<()> {
+    use flate2::write::GzEncoder;
+    use std::io::{Read, Write};
+    let dir = tempfile::tempdir()?;
+    let text = "streamed straight out of memory\n"
+        .repeat(16 * 1024)
+        .into_bytes();
+    let mut gz = Vec::new();
+    // Two members, as written by appending to a .gz
+    for half in text.chunks(text.len() / 2) {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(half)?;
+        gz.extend(encoder.finish()?);
+    }
+    let path = dir.path().join("notes.txt.gz");
+    std::fs::write(&path, &gz)?;
+    let url = manic::Url::from_file_path(&path).unwrap();
+    let mut out = Vec::new();
+    Downloader::new(url, 4)
+        .await?
+        .download_decompressed(manic::Compression::Gzip)
+        .await?
+        .read_to_end(&mut out)?;
+    assert_eq!(out, text);
+    Ok(())
+}
+
+#[cfg(feature = "decompress")]
+#[tokio::test]
+async fn local_download_and_extract() -> Result<()> {
+    use flate2::write::GzEncoder;
+    fn archive(build: impl FnOnce(&mut tar::Builder<Vec<u8>>) -> std::io::Result<()>) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        build(&mut builder).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut encoder, &builder.into_inner().unwrap()).unwrap();
+        encoder.finish().unwrap()
+    }
+    fn header(kind: tar::EntryType, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_size(size);
+        header.set_mode(0o755);
+        header
+    }
+    let dir = tempfile::tempdir()?;
+    let served = dir.path().join("served");
+    std::fs::create_dir(&served)?;
+    let tool = "#!/bin/sh\necho manic\n".repeat(8 * 1024);
+    let gz = archive(|b| {
+        b.append_data(&mut header(tar::EntryType::Directory, 0), "app/", &[][..])?;
+        b.append_data(
+            &mut header(tar::EntryType::Regular, tool.len() as u64),
+            "app/bin/tool",
+            tool.as_bytes(),
+        )?;
+        b.append_data(
+            &mut header(tar::EntryType::Regular, 6),
+            "app/share/README",
+            &b"manic\n"[..],
+        )?;
+        b.append_link(
+            &mut header(tar::EntryType::Symlink, 0),
+            "app/share/tool",
+            "../bin/tool",
+        )
+    });
+    std::fs::write(served.join("app.tar.gz"), &gz)?;
+    // tar::Builder refuses `..`, so the name is written into the header directly
+    std::fs::write(
+        served.join("escape.tar.gz"),
+        archive(|b| {
+            b.append_data(&mut header(tar::EntryType::Regular, 2), "ok", &b"ok"[..])?;
+            let mut evil = header(tar::EntryType::Regular, 4);
+            evil.as_old_mut().name[..11].copy_from_slice(b"../evil.txt");
+            evil.set_cksum();
+            b.append(&evil, &b"evil"[..])
+        }),
+    )?;
+    std::fs::write(
+        served.join("link.tar.gz"),
+        archive(|b| {
+            b.append_link(
+                &mut header(tar::EntryType::Symlink, 0),
+                "sub/up",
+                "../../..",
+            )
+        }),
+    )?;
+    tokio::spawn(warp::serve(warp::fs::dir(served)).run(([127, 0, 0, 1], 8051)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut served_hash = Hash::new_sha256(String::new());
+    served_hash.update(&gz);
+    let served_hash = served_hash.finalize();
+    let out = dir.path().join("out");
+    std::fs::create_dir(&out)?;
+    // The hash is of the compressed archive, a mismatch extracts nothing
+    let mut dl = Downloader::new("http://127.0.0.1:8051/app.tar.gz", 4).await?;
+    let res = dl
+        .verify(Hash::new_sha256("0".repeat(64)))
+        .download_and_extract(&out, manic::Compression::Gzip)
+        .await;
+    assert!(
+        matches!(res, Err(ManicError::SHA256MisMatch(_))),
+        "{:?}",
+        res
+    );
+    assert_eq!(std::fs::read_dir(&out)?.count(), 0);
+    dl.verify(Hash::new_sha256(served_hash))
+        .download_and_extract(&out, manic::Compression::Gzip)
+        .await?;
+    assert_eq!(std::fs::read_to_string(out.join("app/bin/tool"))?, tool);
+    assert_eq!(std::fs::read(out.join("app/share/README"))?, b"manic\n");
+    assert_eq!(
+        std::fs::read_link(out.join("app/share/tool"))?,
+        std::path::Path::new("../bin/tool")
+    );
+    assert_eq!(std::fs::read_dir(&out)?.count(), 1);
+    for name in ["escape.tar.gz", "link.tar.gz"] {
+        let dest = dir.path().join(name).join("out");
+        std::fs::create_dir_all(&dest)?;
+        let err = Downloader::new(format!("http://127.0.0.1:8051/{}", name), 4)
+            .await?
+            .download_and_extract(&dest, manic::Compression::Gzip)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ManicError::UnsafeEntry(_)), "{}", err);
+    }
+    assert!(!dir.path().join("escape.tar.gz/evil.txt").exists());
+    assert!(!dir.path().join("link.tar.gz/out/sub/up").exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_download_conditional() -> Result<()> {
+    tokio::spawn(crate::start_server(8019, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let dl = Downloader::new("http://127.0.0.1:8019/croc.zip", 4).await?;
+    let fresh = dl.download_conditional(None, None).await?;
+    assert!(!fresh.not_modified);
+    assert_eq!(fresh.data.unwrap().to_vec().await.len(), 2251551);
+    let last_modified = fresh.last_modified.unwrap();
+    let cached = dl.download_conditional(None, Some(&last_modified)).await?;
+    assert!(cached.not_modified);
+    assert!(cached.data.is_none());
+    assert_eq!(cached.last_modified, Some(last_modified));
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_download_conditional_if_range() -> Result<()> {
+    use warp::http::{Response, StatusCode};
+    // Serves ranges only while `If-Range` matches the current ETag, like a server whose file changed
+    let data = Arc::new(std::fs::read("tests/static/croc.zip")?);
+    let version = Arc::new(AtomicUsize::new(1));
+    // Bump the version right after answering the conditional probe, 0 keeps it
+    let bump = Arc::new(AtomicUsize::new(0));
+    let failing = Arc::new(AtomicUsize::new(0));
+    let if_ranges = Arc::new(Mutex::new(Vec::new()));
+    let (current, bumping, fail, seen) = (
+        version.clone(),
+        bump.clone(),
+        failing.clone(),
+        if_ranges.clone(),
+    );
+    let file = warp::path!("croc.zip")
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("if-range"))
+        .map(move |range: Option<String>, if_range: Option<String>| {
+            if fail.load(Ordering::SeqCst) != 0 {
+                return Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Vec::new())
+                    .unwrap();
+            }
+            let etag = format!("\"v{}\"", current.load(Ordering::SeqCst));
+            let range = range
+                .as_deref()
+                .and_then(|x| x.strip_prefix("bytes="))
+                .and_then(|x| x.split_once('-'))
+                .map(|(low, hi)| (low.parse::<usize>().unwrap(), hi.parse::<usize>().unwrap()));
+            if range == Some((0, 0)) {
+                if bumping.load(Ordering::SeqCst) != 0 {
+                    current.fetch_add(1, Ordering::SeqCst);
+                }
+            } else if range.is_some() {
+                seen.lock().unwrap().push(if_range.clone());
+            }
+            let builder = Response::builder()
+                .header("etag", &etag)
+                .header("accept-ranges", "bytes");
+            match range.filter(|_| if_range.as_ref().is_none_or(|x| *x == etag)) {
+                Some((low, hi)) => builder
+                    .status(StatusCode::PARTIAL_CONTENT)
+                    .header(
+                        "content-range",
+                        format!("bytes {}-{}/{}", low, hi, data.len()),
+                    )
+                    .body(data[low..=hi].to_vec())
+                    .unwrap(),
+                None => builder.body(data.to_vec()).unwrap(),
+            }
+        });
+    tokio::spawn(warp::serve(file).run(([127, 0, 0, 1], 8043)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let dl = Downloader::new("http://127.0.0.1:8043/croc.zip", 4).await?;
+    let fresh = dl.download_conditional(None, None).await?;
+    assert_eq!(fresh.data.unwrap().to_vec().await.len(), 2251551);
+    assert_eq!(fresh.etag.as_deref(), Some("\"v1\""));
+    let sent = std::mem::take(&mut *if_ranges.lock().unwrap());
+    assert_eq!(sent.len(), dl.chunk_plan().len());
+    assert!(
+        sent.iter().all(|x| x.as_deref() == Some("\"v1\"")),
+        "{:?}",
+        sent
+    );
+    // The file changes between the probe and the chunks, nothing of the new version is mixed in
+    bump.store(1, Ordering::SeqCst);
+    let err = dl.download_conditional(None, None).await.unwrap_err();
+    assert!(matches!(err, ManicError::RangeIgnored(_)), "{}", err);
+    // An error status on the probe is reported as such
+    failing.store(1, Ordering::SeqCst);
+    let err = dl.download_conditional(None, None).await.unwrap_err();
+    assert_eq!(err.code(), ErrorCode::ServerError, "{}", err);
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_priority() -> Result<()> {
+    // Records the order the files are fetched in, HEAD probes aren't recorded
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let recorded = order.clone();
+    let get = warp::get()
+        .and(warp::path::tail())
+        .map(move |tail: warp::path::Tail| {
+            recorded.lock().unwrap().push(tail.as_str().to_string());
+        })
+        .untuple_one()
+        .and(warp::fs::file("tests/static/croc.zip"));
+    let head = warp::head()
+        .and(warp::path::tail())
+        .map(|_| ())
+        .untuple_one()
+        .and(warp::fs::file("tests/static/croc.zip"));
+    tokio::spawn(warp::serve(get.or(head)).run(([127, 0, 0, 1], 8020)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    #[cfg(feature = "progress")]
+    let mut multi = MultiDownloader::new(false).await;
+    #[cfg(not(feature = "progress"))]
+    let mut multi = MultiDownloader::new().await;
+    for name in ["low_1.zip", "low_2.zip", "normal.zip"] {
+        multi
+            .add(format!("http://127.0.0.1:8020/{}", name), 1)
+            .await?;
+    }
+    multi
+        .add_with_priority("http://127.0.0.1:8020/high.zip", 1, Priority::High)
+        .await?;
+    for name in ["low_1.zip", "low_2.zip"] {
+        multi
+            .priority(format!("http://127.0.0.1:8020/{}", name), Priority::Low)
+            .await?;
+    }
+    multi.max_concurrent(1);
+    order.lock().unwrap().clear();
+    assert_eq!(multi.download_all().await?.len(), 4);
+    let order = order.lock().unwrap();
+    assert_eq!(order[0], "high.zip");
+    assert_eq!(order[1], "normal.zip");
+    // Equal priorities start in the order they were added
+    assert_eq!(order[2], "low_1.zip");
+    assert_eq!(order[3], "low_2.zip");
+    Ok(())
+}
+
+/// Serves croc.zip under any name, recording the names of the GETs in order and answering each after `delay`
+fn recorded_server(port: u16, delay: Duration) -> Arc<Mutex<Vec<String>>> {
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let recorded = order.clone();
+    let get = warp::get()
+        .and(warp::path::tail())
+        .and_then(move |tail: warp::path::Tail| {
+            recorded.lock().unwrap().push(tail.as_str().to_string());
+            async move {
+                tokio::time::sleep(delay).await;
+                Ok::<_, warp::Rejection>(())
+            }
+        })
+        .untuple_one()
+        .and(warp::fs::file("tests/static/croc.zip"));
+    let head = warp::head().and(warp::fs::file("tests/static/croc.zip"));
+    tokio::spawn(warp::serve(get.or(head)).run(([127, 0, 0, 1], port)));
+    order
+}
+
+#[tokio::test]
+async fn local_priority_admission() -> Result<()> {
+    let order = recorded_server(8056, Duration::from_millis(500));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let url = |name: &str| format!("http://127.0.0.1:8056/{}", name);
+    // Waits until `n` GETs were recorded
+    let requested = |n: usize| {
+        let order = order.clone();
+        async move {
+            while order.lock().unwrap().len() < n {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+    };
+    for preempt in [false, true] {
+        #[cfg(feature = "progress")]
+        let mut multi = MultiDownloader::new(false).await;
+        #[cfg(not(feature = "progress"))]
+        let mut multi = MultiDownloader::new().await;
+        for name in ["low_1.zip", "low_2.zip"] {
+            multi.add_with_priority(url(name), 1, Priority::Low).await?;
+        }
+        multi.max_concurrent(1).preempt_low(preempt);
+        order.lock().unwrap().clear();
+        let batch = multi.clone();
+        let running = tokio::spawn(async move { batch.download_all().await });
+        requested(1).await;
+        // Added through the clone while low_1 is running
+        multi
+            .add_with_priority(url("high.zip"), 1, Priority::High)
+            .await?;
+        let done = running.await.unwrap()?;
+        assert_eq!(done.len(), 3);
+        for x in &done {
+            assert_eq!(x.data().unwrap().to_vec().await.len(), 2251551);
+        }
+        let order = order.lock().unwrap().clone();
+        if preempt {
+            // low_1 is paused for high.zip and resumed before low_2
+            assert_eq!(order, ["low_1.zip", "high.zip", "low_1.zip", "low_2.zip"]);
+        } else {
+            // high.zip waits for low_1 but goes before low_2
+            assert_eq!(order, ["low_1.zip", "high.zip", "low_2.zip"]);
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_priority_aging() -> Result<()> {
+    let order = recorded_server(8057, Duration::ZERO);
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    for age in [None, Some(Duration::from_millis(200))] {
+        #[cfg(feature = "progress")]
+        let mut multi = MultiDownloader::new(false).await;
+        #[cfg(not(feature = "progress"))]
+        let mut multi = MultiDownloader::new().await;
+        multi
+            .add_with_priority("http://127.0.0.1:8057/low.zip", 1, Priority::Low)
+            .await?;
+        multi.add("http://127.0.0.1:8057/normal.zip", 1).await?;
+        multi.max_concurrent(1);
+        if let Some(age) = age {
+            multi.max_queue_age(age);
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        order.lock().unwrap().clear();
+        multi.download_all().await?;
+        let order = order.lock().unwrap().clone();
+        match age {
+            None => assert_eq!(order, ["normal.zip", "low.zip"]),
+            // Waited long enough to count as Normal, and it was added first
+            Some(_) => assert_eq!(order, ["low.zip", "normal.zip"]),
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn chunk_plan() -> Result<()> {
+    for workers in [1, 3, 7, 64] {
+        let dl = Downloader::new_manual("http://127.0.0.1/croc.zip", workers, 2251551).await?;
+        let plan = dl.chunk_plan();
+        assert_eq!(plan.first().unwrap().0, 0);
+        assert_eq!(plan.last().unwrap().1, 2251550);
+        assert!(plan.windows(2).all(|x| x[0].1 + 1 == x[1].0));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_socket_options() -> Result<()> {
+    tokio::spawn(crate::start_server(8021, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new("http://127.0.0.1:8021/croc.zip", 4).await?;
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.socket_options(
+        SocketOptions::default()
+            .nodelay(false)
+            .keepalive(Duration::from_secs(30)),
+    )?;
+    dl.download().await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_in_flight_bound() -> Result<()> {
+    // Every chunk request is held for a moment so overlapping ones are counted
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let (counted, seen) = (in_flight.clone(), peak.clone());
+    let slow = warp::path!("croc.zip")
+        .and_then(move || {
+            let (counted, seen) = (counted.clone(), seen.clone());
+            async move {
+                let n = counted.fetch_add(1, Ordering::SeqCst) + 1;
+                seen.fetch_max(n, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                counted.fetch_sub(1, Ordering::SeqCst);
+                Ok::<_, warp::Rejection>(())
+            }
+        })
+        .untuple_one()
+        .and(warp::fs::file("tests/static/croc.zip"));
+    tokio::spawn(warp::serve(slow).run(([127, 0, 0, 1], 8022)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new_manual("http://127.0.0.1:8022/croc.zip", 2, 2251551).await?;
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    assert_eq!(dl.chunk_plan().len(), 3);
+    dl.download().await?;
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+    // Downloads run in the background keep to the bound as well
+    peak.store(0, Ordering::SeqCst);
+    dl.start().await_result().await?;
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[tokio::test]
+async fn error_codes() -> Result<()> {
+    let mut codes = ErrorCode::ALL
+        .iter()
+        .map(|x| x.as_u32())
+        .collect::<Vec<_>>();
+    codes.sort_unstable();
+    codes.dedup();
+    assert_eq!(codes.len(), ErrorCode::ALL.len());
+    let err = ManicError::SizeMismatch {
+        expected: 2,
+        actual: 1,
+    };
+    assert_eq!(err.code(), ErrorCode::SizeMismatch);
+    assert_eq!(err.code().category(), "verification");
+    assert!(err.to_string().ends_with("[E2002]"));
+    assert!(!err.is_retryable());
+    // Nothing listens on port 9, connecting fails right away
+    let err = Downloader::new("http://127.0.0.1:9/croc.zip", 1)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), ErrorCode::Connect);
+    assert!(err.is_retryable());
+    assert!(err.to_string().ends_with("[E1001]"));
+    Ok(())
+}
+
+#[cfg(feature = "sig-verify")]
+#[tokio::test]
+async fn local_verify_signature() -> Result<()> {
+    use manic::{SignaturePolicy, TrustedKey};
+    // Serves croc.zip along with the minisign and SSH signature fixtures next to it
+    tokio::spawn(warp::serve(warp::fs::dir("tests/static")).run(([127, 0, 0, 1], 8023)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let minisign = TrustedKey::minisign(&std::fs::read_to_string("tests/static/minisign.pub")?)?;
+    let ssh = TrustedKey::ssh(
+        &std::fs::read_to_string("tests/static/allowed_signers")?,
+        "file",
+    )?;
+    assert_eq!(minisign.key_id(), "69247B0F1E5C3A8D");
+    assert_eq!(
+        ssh.key_id(),
+        "SHA256:pf+7Am+6qytYzW39ThynJ3Bcq/q7/C5fJyAQtVUPQO8"
+    );
+    for key in [minisign, ssh] {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().to_str().unwrap();
+        let target = dir.path().join("croc.zip");
+        let mut dl = Downloader::new("http://127.0.0.1:8023/croc.zip", 4).await?;
+        let policy = SignaturePolicy::new(key.clone());
+        dl.verify_signature(policy.clone());
+        dl.download_and_save(path).await?;
+        assert!(target.exists());
+        std::fs::remove_file(&target)?;
+
+        let ext = if matches!(key, TrustedKey::Minisign { .. }) {
+            "minisig"
+        } else {
+            "sig"
+        };
+        dl.verify_signature(policy.clone().sidecar(&format!("{{url}}.bad.{}", ext)));
+        let res = dl.download_and_save(path).await;
+        assert!(
+            matches!(&res, Err(ManicError::SignatureMismatch { key_id }) if *key_id == key.key_id()),
+            "{:?}",
+            res
+        );
+        assert!(!target.exists());
+        // The install and in-memory paths check the same signature
+        let hash = Hash::new_sha256(
+            "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+        );
+        let res = dl.download_verify_install(&target, hash).await;
+        assert!(
+            matches!(&res, Err(ManicError::SignatureMismatch { .. })),
+            "{:?}",
+            res
+        );
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+        let res = dl.start().await_result().await;
+        assert!(matches!(res, Err(ManicError::SignatureMismatch { .. })));
+        #[cfg(feature = "progress")]
+        let mut multi = MultiDownloader::new(false).await;
+        #[cfg(not(feature = "progress"))]
+        let mut multi = MultiDownloader::new().await;
+        multi.add("http://127.0.0.1:8023/croc.zip", 4).await?;
+        multi
+            .verify_signature(
+                "http://127.0.0.1:8023/croc.zip",
+                policy.clone().sidecar(&format!("{{url}}.bad.{}", ext)),
+            )
+            .await?;
+        let res = multi.download_all().await;
+        assert!(matches!(res, Err(ManicError::SignatureMismatch { .. })));
+
+        dl.verify_signature(policy.clone().sidecar("{url}.missing"));
+        let res = dl.download_and_save(path).await;
+        assert!(
+            matches!(&res, Err(ManicError::SignatureMissing(_))),
+            "{:?}",
+            res
+        );
+        dl.verify_signature(policy.sidecar("{url}.missing").optional());
+        dl.download_and_save(path).await?;
+        assert!(target.exists());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_chunks_download() -> Result<()> {
+    use manic::async_client::Chunks;
+    tokio::spawn(crate::start_server(8061, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let data = Chunks::new(0, 2251550, 600_000)?
+        .download(
+            &Client::new(),
+            "http://127.0.0.1:8061/croc.zip".to_string(),
+            #[cfg(feature = "progress")]
+            None,
+        )
+        .await?;
+    let mut hash = Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    );
+    hash.update(&data.to_vec().await);
+    hash.verify()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn chunk_vec_from_parts() -> Result<()> {
+    use manic::async_client::{Chunk, ChunkVec};
+    let mut tail = Chunk::new(3, 4)?;
+    tail.buf = b"lo".to_vec();
+    let mut head = Chunk::new(0, 2)?;
+    head.buf = b"hel".to_vec();
+    let data = ChunkVec::from_parts(vec![tail.clone(), head.clone()])?;
+    assert_eq!(data.to_vec().await, b"hello");
+    assert!(matches!(Chunk::new(4, 3), Err(ManicError::InvalidChunk(_))));
+    let mut overlapping = Chunk::new(2, 3)?;
+    overlapping.buf = b"ll".to_vec();
+    assert!(matches!(
+        ChunkVec::from_parts(vec![head.clone(), overlapping, tail.clone()]),
+        Err(ManicError::InvalidChunk(_))
+    ));
+    let short = Chunk::new(3, 4)?;
+    assert!(matches!(
+        ChunkVec::from_parts(vec![head.clone(), short]),
+        Err(ManicError::InvalidChunk(_))
+    ));
+    // Bytes 3..=4 are missing
+    let mut after_gap = Chunk::new(5, 6)?;
+    after_gap.buf = b"!!".to_vec();
+    assert!(matches!(
+        ChunkVec::from_parts(vec![head, after_gap]),
+        Err(ManicError::InvalidChunk(_))
+    ));
+    // The content has to start at byte 0
+    assert!(matches!(
+        ChunkVec::from_parts(vec![tail]),
+        Err(ManicError::InvalidChunk(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_rate_limit() -> Result<()> {
+    tokio::spawn(crate::start_server(8024, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new("http://127.0.0.1:8024/croc.zip", 4).await?;
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    // 2251551 bytes at 4 MiB/s after a 256 KiB burst take a bit under half a second
+    dl.rate_limit(manic::util::RateLimiter::new(4 << 20, 256 << 10));
+    let start = std::time::Instant::now();
+    dl.download().await?;
+    assert!(
+        start.elapsed() >= Duration::from_millis(400),
+        "{:?}",
+        start.elapsed()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn save_buffered() -> Result<()> {
+    use manic::async_client::{Chunk, ChunkVec};
+    let mut head = Chunk::new(0, 2)?;
+    head.buf = b"hel".to_vec();
+    let mut tail = Chunk::new(3, 4)?;
+    tail.buf = b"lo".to_vec();
+    let data = ChunkVec::from_parts(vec![head, tail])?;
+    let dir = tempfile::tempdir()?;
+    let target = dir.path().join("out");
+    // A buffer smaller than the data
+    data.save_to_file_buffered(&target, 2).await?;
+    assert_eq!(std::fs::read(&target)?, b"hello");
+
+    tokio::spawn(crate::start_server(8025, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new("http://127.0.0.1:8025/croc.zip", 4).await?;
+    dl.write_buffer_size(64 << 10);
+    dl.download_and_save(dir.path().to_str().unwrap()).await?;
+    let mut hash = Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    );
+    hash.update(&std::fs::read(dir.path().join("croc.zip"))?);
+    hash.verify()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn zero_workers() {
+    let res = Downloader::new("data:text/plain;base64,aGVsbG8=", 0).await;
+    assert!(matches!(res, Err(ManicError::InvalidWorkers)), "{:?}", res);
+    let res = Downloader::new_manual("http://127.0.0.1:1/croc.zip", 0, 10).await;
+    assert!(matches!(res, Err(ManicError::InvalidWorkers)), "{:?}", res);
+}
+
+#[tokio::test]
+async fn local_skip_unchanged() -> Result<()> {
+    use manic::{DownloadStatus, SkipStrategy, SyncIndex};
+    let dir = tempfile::tempdir()?;
+    for i in 0..5u8 {
+        std::fs::write(dir.path().join(format!("f{}", i)), vec![i; 200_000])?;
+    }
+    tokio::spawn(warp::serve(warp::fs::dir(dir.path().to_path_buf())).run(([127, 0, 0, 1], 8026)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    // One mirror run, returns the names of the files that were actually fetched
+    async fn run(index: &mut SyncIndex, strategy: SkipStrategy) -> Result<Vec<String>> {
+        #[cfg(feature = "progress")]
+        let mut multi = MultiDownloader::new(false).await;
+        #[cfg(not(feature = "progress"))]
+        let mut multi = MultiDownloader::new().await;
+        for i in 0..5 {
+            multi
+                .add(format!("http://127.0.0.1:8026/f{}", i), 2)
+                .await?;
+        }
+        let skipped = multi.skip_unchanged(index, strategy).await?;
+        let results = multi.download_all().await?;
+        assert_eq!(results.len(), 5);
+        let mut fetched = Vec::new();
+        for res in results.iter() {
+            res.record(index).await?;
+            match res.status() {
+                DownloadStatus::Downloaded => fetched.push(res.name().to_string()),
+                DownloadStatus::SkippedUnchanged => {
+                    assert!(skipped.contains(res.url()));
+                    assert!(res.data().is_none());
+                }
+            }
+        }
+        fetched.sort();
+        Ok(fetched)
+    }
+    let mut index = SyncIndex::new();
+    assert_eq!(run(&mut index, SkipStrategy::Size).await?.len(), 5);
+    assert_eq!(index.len(), 5);
+    assert!(run(&mut index, SkipStrategy::Size).await?.is_empty());
+
+    // Grows, the size alone gives it away
+    std::fs::write(dir.path().join("f2"), vec![2; 200_001])?;
+    assert_eq!(run(&mut index, SkipStrategy::Size).await?, ["f2"]);
+
+    // Same size, only the edge digests notice
+    let mut changed = vec![3; 200_000];
+    changed[0] = 0;
+    std::fs::write(dir.path().join("f3"), changed)?;
+    assert_eq!(
+        run(&mut index.clone(), SkipStrategy::Size).await?,
+        Vec::<String>::new()
+    );
+    assert_eq!(run(&mut index, SkipStrategy::SizeAndEdges).await?, ["f3"]);
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&index).unwrap();
+        let restored: SyncIndex = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, index);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_skip_unchanged_edges() -> Result<()> {
+    use manic::{SkipStrategy, SyncIndex};
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("kept"), b"abcdefghij")?;
+    std::fs::write(dir.path().join("gone"), b"0123456789")?;
+    tokio::spawn(warp::serve(warp::fs::dir(dir.path().to_path_buf())).run(([127, 0, 0, 1], 8059)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    #[cfg(feature = "progress")]
+    let mut multi = MultiDownloader::new(false).await;
+    #[cfg(not(feature = "progress"))]
+    let mut multi = MultiDownloader::new().await;
+    let mut index = SyncIndex::new();
+    for (name, content) in [("kept", &b"abcdefghij"[..]), ("gone", &b"0123456789"[..])] {
+        let url = manic::Url::parse(&format!("http://127.0.0.1:8059/{}", name)).unwrap();
+        multi.add(url.clone(), 2).await?;
+        let mut digest = index.digest();
+        digest.update(content);
+        index.record(&url, &url, digest);
+    }
+    // Its edges can't be fetched anymore, that only keeps this file from being skipped
+    std::fs::remove_file(dir.path().join("gone"))?;
+    let skipped = multi
+        .skip_unchanged(&index, SkipStrategy::SizeAndEdges)
+        .await?;
+    assert_eq!(
+        skipped,
+        [manic::Url::parse("http://127.0.0.1:8059/kept").unwrap()]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_total_bandwidth() -> Result<()> {
+    tokio::spawn(crate::start_server(8027, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    #[cfg(feature = "progress")]
+    let mut multi = MultiDownloader::new(false).await;
+    #[cfg(not(feature = "progress"))]
+    let mut multi = MultiDownloader::new().await;
+    for i in 0..3 {
+        multi
+            .add(format!("http://127.0.0.1:8027/croc.zip?copy={}", i), 4)
+            .await?;
+    }
+    assert_eq!(multi.total_downloaded(), 0);
+    let done = multi.download_all().await?;
+    assert_eq!(done.len(), 3);
+    assert_eq!(multi.total_downloaded(), 3 * 2251551);
+    assert_eq!(multi.total_remaining(), 0);
+    assert!(multi.total_speed() > 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_fair_share() -> Result<()> {
+    use manic::util::{RateLimiter, RequestScheduler};
+    tokio::spawn(crate::start_server(8028, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    // Two downloads over a 1 MiB/s link with two connections, the second starts a second later.
+    // Returns how far apart they finish
+    async fn finish_gap(fair: bool) -> Result<Duration> {
+        let link = RateLimiter::new(1 << 20, 64 << 10);
+        let scheduler = RequestScheduler::new(2).fair_share(fair);
+        let mut first = Downloader::new("http://127.0.0.1:8028/croc.zip?copy=1", 8).await?;
+        let mut second = Downloader::new("http://127.0.0.1:8028/croc.zip?copy=2", 8).await?;
+        for dl in [&mut first, &mut second] {
+            dl.rate_limit(link.clone()).scheduler(&scheduler);
+        }
+        let first = tokio::spawn(async move {
+            first.download().await?;
+            Ok::<_, manic::ManicError>(std::time::Instant::now())
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        second.download().await?;
+        let second_done = std::time::Instant::now();
+        let first_done = first.await.unwrap()?;
+        assert_eq!(scheduler.in_flight(), 0);
+        Ok(second_done - first_done)
+    }
+    let fifo = finish_gap(false).await?;
+    let fair = finish_gap(true).await?;
+    assert!(fair * 4 < fifo * 3, "fair {:?}, fifo {:?}", fair, fifo);
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_pause_token() -> Result<()> {
+    use manic::util::{PauseToken, RateLimiter};
+    tokio::spawn(crate::start_server(8029, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let token = PauseToken::new();
+    let mut dl = Downloader::new("http://127.0.0.1:8029/croc.zip", 4).await?;
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.pause_token(token.clone())
+        .rate_limit(RateLimiter::new(4 << 20, 64 << 10));
+    let mut task = tokio::spawn(async move { dl.download().await });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(token.pause());
+    assert!(!token.pause());
+    // At 4 MiB/s the whole file takes about half a second, paused it has to still be running
+    assert!(tokio::time::timeout(Duration::from_secs(1), &mut task)
+        .await
+        .is_err());
+    assert!(token.resume());
+    assert!(!token.is_paused());
+    task.await.unwrap()?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_metadata_cache() -> Result<()> {
+    use manic::{MemoryMetadataCache, MetadataCache, ToUrl};
+    tokio::spawn(crate::start_server(8030, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let cache = Arc::new(MemoryMetadataCache::new(Duration::from_secs(60)));
+    let dl = Downloader::new_cached("http://127.0.0.1:8030/croc.zip", 4, cache.clone()).await?;
+    assert_eq!(cache.len(), 1);
+    // Nothing listens on 8031, only the cached probe makes these work
+    let url = "http://127.0.0.1:8031/croc.zip".to_url()?;
+    cache.put(&url, dl.remote_info().unwrap());
+    let cached = Downloader::new_cached(&url, 4, cache.clone()).await?;
+    assert_eq!(cached.get_len(), 2251551);
+    assert_eq!(cached.filename(), "croc.zip");
+    assert_eq!(
+        Downloader::probe_cached(&url, cache.as_ref())
+            .await?
+            .content_length,
+        Some(2251551)
+    );
+    assert!(Downloader::probe(&url).await.is_err());
+    let expired = MemoryMetadataCache::new(Duration::ZERO);
+    expired.put(&url, dl.remote_info().unwrap());
+    assert!(Downloader::probe_cached(&url, &expired).await.is_err());
+    assert!(expired.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_probe_mirrors() -> Result<()> {
+    tokio::spawn(warp::serve(warp::fs::dir("tests/static")).run(([127, 0, 0, 1], 8032)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let info = Downloader::probe_mirrors([
+        "http://127.0.0.1:8032/croc.zip",
+        "http://127.0.0.1:8032/croc.zip?mirror=2",
+    ])
+    .await?;
+    assert_eq!(info.content_length, Some(2251551));
+    let err = Downloader::probe_mirrors([
+        "http://127.0.0.1:8032/croc.zip",
+        "http://127.0.0.1:8032/croc.zip?mirror=2",
+        "http://127.0.0.1:8032/croc.zip.sig",
+    ])
+    .await
+    .unwrap_err();
+    match err {
+        ManicError::MirrorMismatch(mirrors) => {
+            assert_eq!(mirrors, ["http://127.0.0.1:8032/croc.zip.sig"])
+        }
+        e => panic!("expected MirrorMismatch, got {:?}", e),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_save_and_hash() -> Result<()> {
+    use manic::async_client::{Chunk, ChunkVec};
+    let mut head = Chunk::new(0, 2)?;
+    head.buf = b"hel".to_vec();
+    let mut tail = Chunk::new(3, 4)?;
+    tail.buf = b"lo".to_vec();
+    let dir = tempfile::tempdir()?;
+    let digest = ChunkVec::from_parts(vec![tail, head])?
+        .save_and_hash(dir.path().join("hello"))
+        .await?;
+    assert_eq!(
+        digest,
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+    );
+    assert_eq!(std::fs::read(dir.path().join("hello"))?, b"hello");
+
+    // download_and_save hashes while writing, a mismatch leaves nothing behind
+    tokio::spawn(crate::start_server(8033, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new("http://127.0.0.1:8033/croc.zip", 4).await?;
+    dl.verify(Hash::new_sha256(digest));
+    let res = dl.download_and_save(dir.path().to_str().unwrap()).await;
+    assert!(
+        matches!(res, Err(ManicError::SHA256MisMatch(_))),
+        "{:?}",
+        res
+    );
+    assert!(!dir.path().join("croc.zip").exists());
+    assert!(!dir.path().join("croc.zip.part").exists());
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download_and_save(dir.path().to_str().unwrap()).await?;
+    assert_eq!(
+        std::fs::metadata(dir.path().join("croc.zip"))?.len(),
+        2251551
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_map_request() -> Result<()> {
+    use manic::async_client::RequestParts;
+    // Every request has to carry the range it asks for in `sig`, `all` without one
+    let checked = Arc::new(AtomicUsize::new(0));
+    let counter = checked.clone();
+    let sig = warp::header::optional::<String>("range")
+        .and(warp::query::<std::collections::HashMap<String, String>>())
+        .and_then(
+            move |range: Option<String>, query: std::collections::HashMap<String, String>| {
+                let counter = counter.clone();
+                async move {
+                    let expected = range
+                        .as_deref()
+                        .map_or("all", |x| x.trim_start_matches("bytes="));
+                    if query.get("sig").map(String::as_str) != Some(expected) {
+                        return Err(warp::reject::not_found());
+                    }
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }
+            },
+        )
+        .untuple_one();
+    let file = warp::path!("croc.zip")
+        .and(sig.clone())
+        .and(warp::fs::file("tests/static/croc.zip"));
+    let redirect = warp::path!("redirect" / "croc.zip")
+        .and(sig)
+        .map(|| warp::redirect::temporary(warp::http::Uri::from_static("/croc.zip")));
+    tokio::spawn(warp::serve(file.or(redirect)).run(([127, 0, 0, 1], 8034)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let sign = |mut parts: RequestParts| {
+        let range = parts.headers().get("range").map_or("all".to_string(), |x| {
+            x.to_str().unwrap().trim_start_matches("bytes=").to_string()
+        });
+        parts
+            .url_mut()
+            .query_pairs_mut()
+            .clear()
+            .append_pair("sig", &range);
+        parts
+    };
+    let mut dl = Downloader::new_manual("http://127.0.0.1:8034/croc.zip", 4, 2251551).await?;
+    dl.map_request(sign)?;
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download().await?;
+    let chunks = dl.chunk_plan().len();
+    assert_eq!(checked.swap(0, Ordering::SeqCst), chunks);
+    // The probe and every chunk go through the redirect, both hops are mapped
+    let mut redirected = dl
+        .clone_for("http://127.0.0.1:8034/redirect/croc.zip")
+        .await?;
+    redirected.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    redirected.download().await?;
+    assert_eq!(checked.load(Ordering::SeqCst), 2 * (1 + chunks));
+    // Both hops of the constructor's probe are mapped as well
+    Downloader::new_mapped("http://127.0.0.1:8034/redirect/croc.zip", 4, sign).await?;
+    assert_eq!(checked.load(Ordering::SeqCst), 2 * (2 + chunks));
+    // A caller's client isn't swapped for a rebuilt one
+    let mut custom = Downloader::new_with_client(
+        "http://127.0.0.1:8034/croc.zip?sig=all",
+        4,
+        manic::Client::new(),
+    )
+    .await?;
+    let err = custom.map_request(sign).unwrap_err();
+    assert_eq!(err.code(), ErrorCode::CustomClient);
+    let err = custom.keep_alive(false).unwrap_err();
+    assert_eq!(err.code(), ErrorCode::CustomClient);
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_fragment_hash() -> Result<()> {
+    tokio::spawn(crate::start_server(8035, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let url = "http://127.0.0.1:8035/croc.zip";
+    let good = "0AC1E91826EABD78B1ACA342AC11292A7399A2FDF714158298BAE1D1BD12390B";
+    let bad = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    Downloader::new(format!("{}#sha256={}", url, good), 4)
+        .await?
+        .download()
+        .await?;
+    let res = Downloader::new(format!("{}#sha256={}", url, bad), 4)
+        .await?
+        .download()
+        .await;
+    assert!(
+        matches!(res, Err(ManicError::SHA256MisMatch(_))),
+        "{:?}",
+        res
+    );
+    // Unknown algorithms and digests of the wrong length are left alone
+    for fragment in ["#blake3=abc", "#sha256=abc", "#section-2"] {
+        Downloader::new(format!("{}{}", url, fragment), 4)
+            .await?
+            .download()
+            .await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_keep_alive_off() -> Result<()> {
+    // Counts the chunk requests that didn't ask for the connection to be closed
+    let kept = Arc::new(AtomicUsize::new(0));
+    let counter = kept.clone();
+    let file = warp::path!("croc.zip")
+        .and(warp::header::optional::<String>("connection"))
+        .map(move |connection: Option<String>| {
+            if connection.as_deref() != Some("close") {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .untuple_one()
+        .and(warp::fs::file("tests/static/croc.zip"));
+    tokio::spawn(warp::serve(file).run(([127, 0, 0, 1], 8036)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new_manual("http://127.0.0.1:8036/croc.zip", 4, 2251551).await?;
+    dl.keep_alive(false)?;
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download().await?;
+    assert_eq!(kept.load(Ordering::SeqCst), 0);
+    Ok(())
+}
+
+/// Fails the first attempt at every chunk before it reaches the server
+#[derive(Debug)]
+struct FailFirst {
+    inner: Arc<dyn ChunkService>,
+    failed: Arc<AtomicUsize>,
+}
+
+impl ChunkService for FailFirst {
+    fn call(&self, req: ChunkRequest) -> BoxFuture<'_, Result<ChunkResponse>> {
+        Box::pin(async move {
+            if req.attempt == 0 {
+                self.failed.fetch_add(1, Ordering::SeqCst);
+                return Err(ManicError::IOError(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "injected",
+                )));
+            }
+            self.inner.call(req).await
+        })
+    }
+}
+
+#[tokio::test]
+async fn local_service_retry() -> Result<()> {
+    tokio::spawn(crate::start_server(8037, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let failed = Arc::new(AtomicUsize::new(0));
+    let counter = failed.clone();
+    let mut dl = Downloader::new("http://127.0.0.1:8037/croc.zip", 4).await?;
+    dl.retries(1)
+        .jitter(false)
+        .with_service(move |inner| FailFirst {
+            inner,
+            failed: counter.clone(),
+        });
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download().await?;
+    assert_eq!(failed.load(Ordering::SeqCst), dl.chunk_plan().len());
+    Ok(())
+}
+
+/// Logs its name and the chunk's range on the way in
+#[derive(Debug)]
+struct Tag {
+    name: &'static str,
+    inner: Arc<dyn ChunkService>,
+    log: Arc<Mutex<Vec<(&'static str, String)>>>,
+}
+
+impl ChunkService for Tag {
+    fn call(&self, req: ChunkRequest) -> BoxFuture<'_, Result<ChunkResponse>> {
+        self.log.lock().unwrap().push((self.name, req.header()));
+        self.inner.call(req)
+    }
+}
+
+#[tokio::test]
+async fn local_service_order() -> Result<()> {
+    tokio::spawn(crate::start_server(8038, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let shared = log.clone();
+    let mut dl = Downloader::new("http://127.0.0.1:8038/croc.zip", 4).await?;
+    dl.with_service(move |http| {
+        let inner = Tag {
+            name: "inner",
+            inner: http,
+            log: shared.clone(),
+        };
+        Tag {
+            name: "outer",
+            inner: Arc::new(inner),
+            log: shared.clone(),
+        }
+    });
+    dl.download().await?;
+    let log = log.lock().unwrap();
+    for (low, hi) in dl.chunk_plan() {
+        let range = format!("bytes={}-{}", low, hi);
+        let order = log
+            .iter()
+            .filter(|(_, r)| *r == range)
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>();
+        assert_eq!(order, ["outer", "inner"]);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_range_fallback() -> Result<()> {
+    // Answers every request with the whole file, like a proxy that drops `Range`
+    let plain = Arc::new(AtomicUsize::new(0));
+    let counter = plain.clone();
+    let data = std::fs::read("tests/static/croc.zip")?;
+    let file = warp::path!("croc.zip")
+        .and(warp::header::optional::<String>("range"))
+        .map(move |range: Option<String>| {
+            if range.is_none() {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            data.clone()
+        });
+    tokio::spawn(warp::serve(file).run(([127, 0, 0, 1], 8039)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new_manual("http://127.0.0.1:8039/croc.zip", 4, 2251551).await?;
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download().await?;
+    assert_eq!(plain.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn local_range_fallback_max_size() -> Result<()> {
+    use warp::http::{Response, StatusCode};
+    // Ignores the range of the first request for the start of the file, serves ranges afterwards
+    let ignored = Arc::new(AtomicUsize::new(0));
+    let counter = ignored.clone();
+    let data = Arc::new(std::fs::read("tests/static/croc.zip")?);
+    let file = warp::path!("croc.zip")
+        .and(warp::header::<String>("range"))
+        .map(move |range: String| {
+            let (low, hi) = range
+                .strip_prefix("bytes=")
+                .and_then(|x| x.split_once('-'))
+                .map(|(low, hi)| (low.parse::<usize>().unwrap(), hi.parse::<usize>().unwrap()))
+                .unwrap();
+            if low == 0 && counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                return Response::new(data.to_vec());
+            }
+            Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    "content-range",
+                    format!("bytes {}-{}/{}", low, hi, data.len()),
+                )
+                .body(data[low..=hi].to_vec())
+                .unwrap()
+        });
+    tokio::spawn(warp::serve(file).run(([127, 0, 0, 1], 8058)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let mut dl = Downloader::new_manual("http://127.0.0.1:8058/croc.zip", 4, 2251551).await?;
+    // Not a byte to spare, the body that ran past its range mustn't count
+    dl.max_size(2251551);
+    dl.download().await?;
+    assert_eq!(ignored.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+#[cfg(feature = "metalink")]
+#[tokio::test]
+async fn local_metalink() -> Result<()> {
+    use manic::{Metalink, MetalinkFile};
+    let strings = |hashes: &[Hash]| hashes.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    tokio::spawn(crate::start_server(8052, None, None));
+    tokio::spawn(crate::start_server(8053, None, None));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let meta = Metalink::parse(&std::fs::read_to_string("tests/static/croc.meta4")?)?;
+    assert_eq!(meta.files.len(), 1);
+    let file = &meta.files[0];
+    assert_eq!(file.name, "croc.zip");
+    assert_eq!(file.size, Some(2251551));
+    // Ordered by priority, the metaurl is dropped and the url without a priority goes last
+    let ports = file
+        .urls
+        .iter()
+        .map(|x| x.port().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(ports, [8054, 8052, 8053, 8055]);
+    // The sha-1 hash is skipped
+    assert_eq!(
+        strings(&file.hashes),
+        ["0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b"]
+    );
+    let pieces = file.pieces.as_ref().unwrap();
+    assert_eq!(pieces.length.get(), 1048576);
+    assert_eq!(pieces.hashes.len(), 3);
+    // Nothing listens on 8054, so the file comes from the next mirror
+    let dir = tempfile::tempdir()?;
+    let dl = Downloader::from_metalink(file, 4).await?;
+    assert_eq!(dl.filename(), "croc.zip");
+    assert_eq!(dl.url().port(), Some(8052));
+    dl.download_and_save(dir.path().to_str().unwrap()).await?;
+    let saved = dir.path().join("croc.zip");
+    assert_eq!(
+        std::fs::read(&saved)?,
+        std::fs::read("tests/static/croc.zip")?
+    );
+    // Only the piece hashes are left to catch a corrupt piece
+    let mut broken = file.clone();
+    broken.hashes.clear();
+    broken.pieces.as_mut().unwrap().hashes[1] = Hash::new_sha256("0".repeat(64));
+    let res = Downloader::from_metalink(&broken, 4)
+        .await?
+        .download()
+        .await;
+    assert!(
+        matches!(
+            res,
+            Err(ManicError::PieceMismatch {
+                index: 1,
+                offset: 1048576
+            })
+        ),
+        "{:?}",
+        res
+    );
+    let mut resized = file.clone();
+    resized.size = Some(1000);
+    assert!(matches!(
+        Downloader::from_metalink(&resized, 4).await,
+        Err(ManicError::SizeMismatch {
+            expected: 1000,
+            actual: 2251551
+        })
+    ));
+    // Describing the saved file gives back the fixture's checksums
+    let mirrors = file.urls[1..3].to_vec();
+    let described = MetalinkFile::describe(&saved, mirrors.clone(), Some(1048576))?;
+    let xml = Metalink {
+        files: vec![described],
+    }
+    .to_xml();
+    let reparsed = Metalink::parse(&xml)?;
+    let again = &reparsed.files[0];
+    assert_eq!(again.name, "croc.zip");
+    assert_eq!(again.urls, mirrors);
+    assert_eq!(again.size, file.size);
+    assert_eq!(strings(&again.hashes), strings(&file.hashes));
+    let again_pieces = again.pieces.as_ref().unwrap();
+    assert_eq!(again_pieces.length, pieces.length);
+    assert_eq!(strings(&again_pieces.hashes), strings(&pieces.hashes));
+    // A piece length of 0 can't split the file
+    assert!(matches!(
+        MetalinkFile::describe(&saved, mirrors, Some(0)),
+        Err(ManicError::Metalink(_))
+    ));
+    Ok(())
+}
@@ -1,2 +1,5 @@
 mod local;
+mod rate_limiter;
 mod remote;
+#[cfg(feature = "remote-zip")]
+mod zip_archive;
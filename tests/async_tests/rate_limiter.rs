@@ -0,0 +1,87 @@
+use manic::util::RateLimiter;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[tokio::test(start_paused = true)]
+async fn steady_rate() {
+    let limiter = RateLimiter::new(1000, 100);
+    // Drain the initial burst, after that tokens only come at the rate
+    limiter.acquire(100).await;
+    let start = Instant::now();
+    limiter.acquire(1000).await;
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(1050), "{:?}", elapsed);
+}
+
+#[tokio::test(start_paused = true)]
+async fn burst() {
+    let limiter = RateLimiter::new(1000, 100);
+    let start = Instant::now();
+    limiter.acquire(100).await;
+    assert_eq!(start.elapsed(), Duration::ZERO);
+    assert!(!limiter.try_acquire(1));
+    tokio::time::advance(Duration::from_millis(10)).await;
+    assert!(limiter.try_acquire(10));
+    assert!(!limiter.try_acquire(1));
+    // Idle time never fills the bucket past the burst
+    tokio::time::advance(Duration::from_secs(10)).await;
+    assert!(!limiter.try_acquire(101));
+    assert!(limiter.try_acquire(100));
+}
+
+#[tokio::test(start_paused = true)]
+async fn fairness() {
+    let limiter = RateLimiter::new(1000, 100);
+    limiter.acquire(100).await;
+    let done = Arc::new(Mutex::new(Vec::new()));
+    let big = {
+        let (limiter, done) = (limiter.clone(), done.clone());
+        tokio::spawn(async move {
+            limiter.acquire(10_000).await;
+            done.lock().unwrap().push("big");
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    let start = Instant::now();
+    limiter.acquire(10).await;
+    done.lock().unwrap().push("small");
+    // Only the big request's current piece is ahead in the queue
+    assert!(
+        start.elapsed() <= Duration::from_millis(200),
+        "{:?}",
+        start.elapsed()
+    );
+    big.await.unwrap();
+    assert_eq!(*done.lock().unwrap(), ["small", "big"]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn dropped_acquire() {
+    let limiter = RateLimiter::new(1000, 100);
+    limiter.acquire(100).await;
+    let waiting = tokio::time::timeout(Duration::from_millis(10), limiter.acquire(100)).await;
+    assert!(waiting.is_err());
+    // The abandoned waiter doesn't hold the queue
+    tokio::time::advance(Duration::from_millis(100)).await;
+    assert!(limiter.try_acquire(100));
+}
+
+#[tokio::test(start_paused = true)]
+async fn set_rate() {
+    let limiter = RateLimiter::new(10, 10);
+    limiter.acquire(10).await;
+    let waiter = {
+        let limiter = limiter.clone();
+        tokio::spawn(async move {
+            let start = Instant::now();
+            limiter.acquire(10).await;
+            start.elapsed()
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    limiter.set_rate(1000);
+    assert_eq!(limiter.rate(), 1000);
+    assert!(waiter.await.unwrap() < Duration::from_millis(50));
+}
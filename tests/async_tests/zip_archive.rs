@@ -0,0 +1,317 @@
+use manic::{Downloader, ErrorCode, ManicError, RemoteZip, Result};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use warp::Filter;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+/// Incompressible bytes, the same for the same seed
+fn noise(len: usize, mut seed: u64) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as u8
+        })
+        .collect()
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Serves `path` with range support, counting the bytes of ranged requests and the requests without a range
+fn serve(port: u16, path: std::path::PathBuf) -> (Arc<AtomicU64>, Arc<AtomicUsize>) {
+    let ranged = Arc::new(AtomicU64::new(0));
+    let whole = Arc::new(AtomicUsize::new(0));
+    let (bytes, plain) = (ranged.clone(), whole.clone());
+    let file = warp::path!("bundle.zip")
+        .and(warp::method())
+        .and(warp::header::optional::<String>("range"))
+        .map(move |method: warp::http::Method, range: Option<String>| {
+            match range.as_deref().and_then(|x| x.strip_prefix("bytes=")) {
+                Some(range) => {
+                    let (low, hi) = range.split_once('-').unwrap();
+                    let len = hi.parse::<u64>().unwrap() - low.parse::<u64>().unwrap() + 1;
+                    bytes.fetch_add(len, Ordering::SeqCst);
+                }
+                None if method == warp::http::Method::GET => {
+                    plain.fetch_add(1, Ordering::SeqCst);
+                }
+                None => {}
+            }
+        })
+        .untuple_one()
+        .and(warp::fs::file(path));
+    tokio::spawn(warp::serve(file).run(([127, 0, 0, 1], port)));
+    (ranged, whole)
+}
+
+#[tokio::test]
+async fn local_zip_extract() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let manifest = "{\"name\": \"manic\", \"files\": 4}\n"
+        .repeat(4096)
+        .into_bytes();
+    let tool = noise(1536 * 1024, 1);
+    let archive = dir.path().join("bundle.zip");
+    {
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&archive)?);
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("manifest.json", deflated).unwrap();
+        zip.write_all(&manifest)?;
+        zip.add_directory("bin/", stored).unwrap();
+        zip.start_file("bin/tool", stored).unwrap();
+        zip.write_all(&tool)?;
+        for (i, name) in ["bulk/a.bin", "bulk/b.bin"].iter().enumerate() {
+            zip.start_file(*name, stored).unwrap();
+            zip.write_all(&noise(4 * 1024 * 1024, i as u64 + 2))?;
+        }
+        zip.finish().unwrap();
+    }
+    let (ranged, whole) = serve(8040, archive.clone());
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let remote =
+        RemoteZip::open(Downloader::new("http://127.0.0.1:8040/bundle.zip", 4).await?).await?;
+    let names = remote
+        .entries()
+        .iter()
+        .map(|x| x.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            "manifest.json",
+            "bin/",
+            "bin/tool",
+            "bulk/a.bin",
+            "bulk/b.bin"
+        ]
+    );
+    let selected: u64 = remote
+        .entries()
+        .iter()
+        .filter(|x| x.name == "manifest.json" || x.name == "bin/tool")
+        .map(|x| x.compressed_size)
+        .sum();
+    assert!(selected < manifest.len() as u64 + tool.len() as u64);
+    let out = dir.path().join("out");
+    let written = remote.extract(["manifest.json", "bin/tool"], &out).await?;
+    assert_eq!(
+        written,
+        [out.join("manifest.json"), out.join("bin").join("tool")]
+    );
+    assert_eq!(std::fs::read(&written[0])?, manifest);
+    assert_eq!(std::fs::read(&written[1])?, tool);
+    // The directory records and headers are all that's fetched besides the two entries
+    let transferred = ranged.load(Ordering::SeqCst);
+    assert!(
+        transferred >= selected && transferred < selected + 4096,
+        "{}",
+        transferred
+    );
+    assert!(transferred * 4 < std::fs::metadata(&archive)?.len());
+    assert_eq!(whole.load(Ordering::SeqCst), 0);
+    let res = remote.extract(["missing.txt"], &out).await;
+    assert!(matches!(res, Err(ManicError::ZipEntryNotFound(_))));
+    Ok(())
+}
+
+/// Local header, central directory entry and data of one stored file
+struct Entry {
+    name: &'static str,
+    data: Vec<u8>,
+    flags: u16,
+    zip64: bool,
+}
+
+/// Builds a Zip64 archive by hand, small enough for a test but with every size and offset
+/// moved to the Zip64 fields, followed by an archive comment
+fn zip64_archive(entries: &[Entry]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for e in entries {
+        let offset = out.len() as u64;
+        let (len, crc) = (e.data.len() as u64, crc32(&e.data));
+        let small = |x: u64| if e.zip64 { u32::MAX } else { x as u32 };
+        out.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        out.extend_from_slice(&45u16.to_le_bytes());
+        out.extend_from_slice(&e.flags.to_le_bytes());
+        out.extend_from_slice(&[0; 6]);
+        out.extend_from_slice(&crc.to_le_bytes());
+        out.extend_from_slice(&small(len).to_le_bytes());
+        out.extend_from_slice(&small(len).to_le_bytes());
+        out.extend_from_slice(&(e.name.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(if e.zip64 { 20u16 } else { 0 }).to_le_bytes());
+        out.extend_from_slice(e.name.as_bytes());
+        if e.zip64 {
+            out.extend_from_slice(&1u16.to_le_bytes());
+            out.extend_from_slice(&16u16.to_le_bytes());
+            out.extend_from_slice(&len.to_le_bytes());
+            out.extend_from_slice(&len.to_le_bytes());
+        }
+        out.extend_from_slice(&e.data);
+        central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        central.extend_from_slice(&45u16.to_le_bytes());
+        central.extend_from_slice(&45u16.to_le_bytes());
+        central.extend_from_slice(&e.flags.to_le_bytes());
+        central.extend_from_slice(&[0; 6]);
+        central.extend_from_slice(&crc.to_le_bytes());
+        central.extend_from_slice(&small(len).to_le_bytes());
+        central.extend_from_slice(&small(len).to_le_bytes());
+        central.extend_from_slice(&(e.name.len() as u16).to_le_bytes());
+        central.extend_from_slice(&(if e.zip64 { 28u16 } else { 0 }).to_le_bytes());
+        central.extend_from_slice(&[0; 10]);
+        central.extend_from_slice(&small(offset).to_le_bytes());
+        central.extend_from_slice(e.name.as_bytes());
+        if e.zip64 {
+            central.extend_from_slice(&1u16.to_le_bytes());
+            central.extend_from_slice(&24u16.to_le_bytes());
+            central.extend_from_slice(&len.to_le_bytes());
+            central.extend_from_slice(&len.to_le_bytes());
+            central.extend_from_slice(&offset.to_le_bytes());
+        }
+    }
+    let (cd_offset, cd_size) = (out.len() as u64, central.len() as u64);
+    out.extend_from_slice(&central);
+    let record = out.len() as u64;
+    out.extend_from_slice(&0x0606_4b50u32.to_le_bytes());
+    out.extend_from_slice(&44u64.to_le_bytes());
+    out.extend_from_slice(&45u16.to_le_bytes());
+    out.extend_from_slice(&45u16.to_le_bytes());
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    out.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    out.extend_from_slice(&cd_size.to_le_bytes());
+    out.extend_from_slice(&cd_offset.to_le_bytes());
+    out.extend_from_slice(&0x0706_4b50u32.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&record.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&[0xFF; 12]);
+    out.extend_from_slice(&7u16.to_le_bytes());
+    out.extend_from_slice(b"comment");
+    out
+}
+
+#[tokio::test]
+async fn local_zip64_extract() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let big = noise(64 * 1024, 7);
+    let archive = dir.path().join("bundle.zip");
+    std::fs::write(
+        &archive,
+        zip64_archive(&[
+            Entry {
+                name: "big.bin",
+                data: big.clone(),
+                flags: 0,
+                zip64: true,
+            },
+            Entry {
+                name: "secret.txt",
+                data: b"encrypted".to_vec(),
+                flags: 1,
+                zip64: false,
+            },
+        ]),
+    )?;
+    serve(8041, archive);
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let remote =
+        RemoteZip::open(Downloader::new("http://127.0.0.1:8041/bundle.zip", 4).await?).await?;
+    let entry = &remote.entries()[0];
+    assert_eq!(
+        (entry.size, entry.compressed_size),
+        (big.len() as u64, big.len() as u64)
+    );
+    let written = remote.extract(["big.bin"], dir.path().join("out")).await?;
+    assert_eq!(std::fs::read(&written[0])?, big);
+    match remote.extract(["secret.txt"], dir.path().join("out")).await {
+        Err(e) => assert_eq!(e.code(), ErrorCode::ZipUnsupported),
+        Ok(_) => panic!("extracted an encrypted entry"),
+    }
+    Ok(())
+}
+
+/// Overwrite the 8 bytes at `skip` into the last record starting with `sig`
+fn patch_u64(archive: &mut [u8], sig: u32, skip: usize, value: u64) {
+    let pos = archive
+        .windows(4)
+        .rposition(|x| x == sig.to_le_bytes())
+        .unwrap()
+        + skip;
+    archive[pos..pos + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+#[tokio::test]
+async fn local_zip_malformed() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let entry = || Entry {
+        name: "big.bin",
+        data: noise(4096, 9),
+        flags: 0,
+        zip64: true,
+    };
+    // Zip64 locator pointing at the last bytes of the address space
+    let mut locator = zip64_archive(&[entry()]);
+    patch_u64(&mut locator, 0x0706_4b50, 8, u64::MAX - 10);
+    std::fs::write(dir.path().join("locator.zip"), locator)?;
+    // Entry offset and compressed size in the central Zip64 extra field past the end
+    let mut offset = zip64_archive(&[entry()]);
+    patch_u64(&mut offset, 0x0201_4b50, 46 + 7 + 4 + 16, u64::MAX - 5);
+    std::fs::write(dir.path().join("offset.zip"), offset)?;
+    let mut size = zip64_archive(&[entry()]);
+    patch_u64(&mut size, 0x0201_4b50, 46 + 7 + 4 + 8, u64::MAX);
+    std::fs::write(dir.path().join("size.zip"), size)?;
+    // A megabyte of zeros whose central directory entry claims a kilobyte
+    let mut bomb = std::io::Cursor::new(Vec::new());
+    {
+        let mut zip = zip::ZipWriter::new(&mut bomb);
+        let deflated = FileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("big.bin", deflated).unwrap();
+        zip.write_all(&vec![0; 1024 * 1024])?;
+        zip.finish().unwrap();
+    }
+    let mut bomb = bomb.into_inner();
+    let central = bomb
+        .windows(4)
+        .rposition(|x| x == 0x0201_4b50u32.to_le_bytes())
+        .unwrap();
+    bomb[central + 24..central + 28].copy_from_slice(&1024u32.to_le_bytes());
+    std::fs::write(dir.path().join("bomb.zip"), bomb)?;
+    tokio::spawn(warp::serve(warp::fs::dir(dir.path().to_path_buf())).run(([127, 0, 0, 1], 8042)));
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let url = |name: &str| format!("http://127.0.0.1:8042/{}", name);
+    let err = RemoteZip::open(Downloader::new(url("locator.zip"), 4).await?)
+        .await
+        .unwrap_err();
+    assert_eq!(err.code(), ErrorCode::ZipFormat, "{}", err);
+    for name in ["offset.zip", "size.zip", "bomb.zip"] {
+        let remote = RemoteZip::open(Downloader::new(url(name), 4).await?).await?;
+        let err = remote
+            .extract(["big.bin"], dir.path().join("out"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::ZipFormat, "{}: {}", name, err);
+    }
+    assert_eq!(std::fs::read_dir(dir.path().join("out"))?.count(), 0);
+    Ok(())
+}
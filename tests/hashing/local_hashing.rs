@@ -0,0 +1,78 @@
+use manic::{Hash, HashAlgorithm};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+fn write_files(dir: &tempfile::TempDir, count: usize) -> manic::Result<Vec<PathBuf>> {
+    (0..count)
+        .map(|i| {
+            let path = dir.path().join(format!("file_{}", i));
+            std::fs::write(&path, vec![i as u8; i * 10_000])?;
+            Ok(path)
+        })
+        .collect()
+}
+
+#[test]
+fn local_hash_files() -> manic::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut paths = write_files(&dir, 20)?;
+    paths.push(dir.path().join("missing"));
+    let hashed = manic::hash_files(&paths, HashAlgorithm::SHA256, 2, None);
+    assert_eq!(hashed.len(), paths.len());
+    for ((path, res), expected) in hashed.iter().zip(&paths) {
+        assert_eq!(path, expected);
+        if path.ends_with("missing") {
+            assert!(res.is_err());
+            continue;
+        }
+        let mut hash = Hash::new_sha256(String::new());
+        hash.update(&std::fs::read(path)?);
+        assert_eq!(res.as_ref().unwrap(), &hash.finalize());
+    }
+    Ok(())
+}
+
+/// Counts itself as an open file until dropped and reads slowly so readers overlap
+struct Counted<'a> {
+    file: std::fs::File,
+    open: &'a AtomicUsize,
+}
+
+impl Read for Counted<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        std::thread::sleep(Duration::from_millis(5));
+        self.file.read(buf)
+    }
+}
+
+impl Drop for Counted<'_> {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn local_hash_files_open_limit() -> manic::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let paths = write_files(&dir, 20)?;
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(8)
+        .build()
+        .unwrap();
+    let open = AtomicUsize::new(0);
+    let max_open = AtomicUsize::new(0);
+    let hashed = manic::hash_files_with(&paths, HashAlgorithm::SHA256, 3, Some(&pool), |path| {
+        let file = std::fs::File::open(path)?;
+        let now = open.fetch_add(1, Ordering::SeqCst) + 1;
+        max_open.fetch_max(now, Ordering::SeqCst);
+        Ok(Counted { file, open: &open })
+    });
+    assert!(hashed.iter().all(|(_, res)| res.is_ok()));
+    assert_eq!(open.load(Ordering::SeqCst), 0);
+    let max_open = max_open.load(Ordering::SeqCst);
+    assert!(max_open <= 3, "{} files open at once", max_open);
+    assert!(max_open > 1, "files were never read in parallel");
+    Ok(())
+}
@@ -0,0 +1 @@
+mod local_hashing;
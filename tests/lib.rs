@@ -1,5 +1,7 @@
 #[cfg(feature = "async")]
 mod async_tests;
+#[cfg(feature = "rayon")]
+mod hashing;
 #[cfg(feature = "threaded")]
 mod threaded;
 
@@ -1,5 +1,10 @@
 use log::LevelFilter;
-use manic::{threaded::Downloader, Hash};
+use manic::{threaded::Downloader, Hash, ManicError};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use warp::Filter;
 
 #[test]
 fn local() -> manic::Result<()> {
@@ -25,3 +30,343 @@ fn local() -> manic::Result<()> {
         Err(err_vec.into())
     }
 }
+
+#[test]
+fn local_save_checked() -> manic::Result<()> {
+    super::start_threaded(8003, None, None);
+    let dir = tempfile::tempdir()?;
+    let mut dl = Downloader::new("http://127.0.0.1:8003/croc.zip", 4)?;
+    dl.check_size(true);
+    dl.download_and_save(dir.path().to_str().unwrap())?;
+    let saved = std::fs::metadata(dir.path().join("croc.zip"))?;
+    assert_eq!(saved.len(), dl.get_len());
+    Ok(())
+}
+
+#[test]
+fn local_chunks_download() -> manic::Result<()> {
+    use manic::threaded::{Chunks, Client};
+    super::start_threaded(8060, None, None);
+    std::thread::sleep(Duration::from_secs(3));
+    let data = Chunks::new(0, 2251550, 600_000)?.download(
+        Client::new(),
+        "http://127.0.0.1:8060/croc.zip".to_string(),
+        #[cfg(feature = "progress")]
+        None,
+        rusty_pool::ThreadPool::default(),
+    )?;
+    let mut hash = Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    );
+    hash.update(&data.to_vec());
+    hash.verify()?;
+    Ok(())
+}
+
+#[test]
+fn zero_workers() {
+    let res = Downloader::new("data:text/plain;base64,aGVsbG8=", 0);
+    assert!(matches!(res, Err(ManicError::InvalidWorkers)));
+    let res = Downloader::new_manual("http://127.0.0.1:1/croc.zip", 0, 10);
+    assert!(matches!(res, Err(ManicError::InvalidWorkers)));
+    #[cfg(feature = "progress")]
+    let res = manic::threaded::MultiDownloader::new(false, 0);
+    #[cfg(not(feature = "progress"))]
+    let res = manic::threaded::MultiDownloader::new(0);
+    assert!(matches!(res, Err(ManicError::InvalidWorkers)));
+}
+
+#[test]
+fn chunk_vec_from_parts() -> manic::Result<()> {
+    use manic::threaded::{Chunk, ChunkVec};
+    let mut tail = Chunk::new(3, 4)?;
+    tail.buf = b"lo".to_vec().into();
+    let mut head = Chunk::new(0, 2)?;
+    head.buf = b"hel".to_vec().into();
+    let data = ChunkVec::from_parts(vec![tail.clone(), head.clone()])?;
+    assert_eq!(data.to_vec(), b"hello");
+    // Bytes 3..=4 are missing
+    let mut after_gap = Chunk::new(5, 6)?;
+    after_gap.buf = b"!!".to_vec().into();
+    assert!(matches!(
+        ChunkVec::from_parts(vec![head, after_gap]),
+        Err(ManicError::InvalidChunk(_))
+    ));
+    // The content has to start at byte 0
+    assert!(matches!(
+        ChunkVec::from_parts(vec![tail]),
+        Err(ManicError::InvalidChunk(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn local_spilled_verify() -> manic::Result<()> {
+    super::start_threaded(8045, None, None);
+    super::start_threaded(8046, Some("other.zip"), None);
+    std::thread::sleep(Duration::from_secs(3));
+    let staging = tempfile::tempdir()?;
+    #[cfg(feature = "progress")]
+    let mut multi = manic::threaded::MultiDownloader::new(false, 4)?;
+    #[cfg(not(feature = "progress"))]
+    let mut multi = manic::threaded::MultiDownloader::new(4)?;
+    multi.add("http://127.0.0.1:8045/croc.zip")?;
+    multi.add("http://127.0.0.1:8046/other.zip")?;
+    multi.verify(
+        "http://127.0.0.1:8045/croc.zip",
+        Hash::new_sha256(
+            "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+        ),
+    )?;
+    multi.verify(
+        "http://127.0.0.1:8046/other.zip",
+        Hash::new_sha256(
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_string(),
+        ),
+    )?;
+    // Both are spilled and checked from disk, the one that doesn't match is removed
+    multi
+        .max_memory(1)
+        .staging_dir(staging.path())
+        .join_policy(manic::JoinPolicy::PartialOk);
+    let done = multi.download_all()?;
+    assert_eq!(done.len(), 1);
+    assert_eq!(done[0].url().as_str(), "http://127.0.0.1:8045/croc.zip");
+    assert!(done[0].path().unwrap().exists());
+    assert_eq!(std::fs::read_dir(staging.path())?.count(), 1);
+    multi.join_policy(manic::JoinPolicy::AllRequired);
+    let res = multi.download_all();
+    assert!(
+        matches!(res, Err(ManicError::SHA256MisMatch(_))),
+        "{:?}",
+        res
+    );
+    Ok(())
+}
+
+#[test]
+fn local_range_fallback() -> manic::Result<()> {
+    // Answers every request with the whole file, like a proxy that drops `Range`
+    let plain = Arc::new(AtomicUsize::new(0));
+    let counter = plain.clone();
+    let data = std::fs::read("tests/static/croc.zip")?;
+    let file = warp::path!("croc.zip")
+        .and(warp::header::optional::<String>("range"))
+        .map(move |range: Option<String>| {
+            if range.is_none() {
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+            data.clone()
+        });
+    super::spawn_server(warp::serve(file).run(([127, 0, 0, 1], 8047)));
+    std::thread::sleep(Duration::from_secs(3));
+    let mut dl = Downloader::new_manual("http://127.0.0.1:8047/croc.zip", 4, 2251551)?;
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download()?;
+    assert_eq!(plain.load(Ordering::SeqCst), 1);
+    // Without the fallback the ignored range fails the download
+    dl.range_fallback(0);
+    assert!(dl.download().is_err());
+    assert_eq!(plain.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[test]
+fn local_retries() -> manic::Result<()> {
+    // The first attempt at every range gets a 503, the next one is served
+    let seen = Arc::new(Mutex::new(HashSet::new()));
+    let tried = seen.clone();
+    let failing = warp::path!("croc.zip")
+        .and(warp::header::<String>("range"))
+        .and_then(move |range: String| {
+            let first = tried.lock().unwrap().insert(range);
+            async move {
+                match first {
+                    true => Ok(warp::http::StatusCode::SERVICE_UNAVAILABLE),
+                    false => Err(warp::reject::not_found()),
+                }
+            }
+        });
+    let file = warp::path!("croc.zip").and(warp::fs::file("tests/static/croc.zip"));
+    super::spawn_server(warp::serve(failing.or(file)).run(([127, 0, 0, 1], 8048)));
+    std::thread::sleep(Duration::from_secs(3));
+    let url = "http://127.0.0.1:8048/croc.zip";
+    let dl = Downloader::new_manual(url, 1, 2251551)?;
+    assert!(matches!(
+        dl.download(),
+        Err(ManicError::NetError(e)) if e.status().map(|x| x.as_u16()) == Some(503)
+    ));
+    seen.lock().unwrap().clear();
+    let mut dl = Downloader::new_manual(url, 4, 2251551)?;
+    dl.retries(1).jitter(false);
+    dl.verify(Hash::new_sha256(
+        "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+    ));
+    dl.download()?;
+    assert_eq!(seen.lock().unwrap().len(), dl.chunk_plan().len());
+    Ok(())
+}
+
+#[cfg(feature = "sig-verify")]
+#[test]
+fn local_verify_signature() -> manic::Result<()> {
+    use manic::{SignaturePolicy, TrustedKey};
+    // Serves croc.zip along with the minisign and SSH signature fixtures next to it
+    super::spawn_server(warp::serve(warp::fs::dir("tests/static")).run(([127, 0, 0, 1], 8049)));
+    std::thread::sleep(Duration::from_secs(3));
+    let minisign = TrustedKey::minisign(&std::fs::read_to_string("tests/static/minisign.pub")?)?;
+    let ssh = TrustedKey::ssh(
+        &std::fs::read_to_string("tests/static/allowed_signers")?,
+        "file",
+    )?;
+    for key in [minisign, ssh] {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().to_str().unwrap();
+        let target = dir.path().join("croc.zip");
+        let mut dl = Downloader::new("http://127.0.0.1:8049/croc.zip", 4)?;
+        let policy = SignaturePolicy::new(key.clone());
+        dl.verify_signature(policy.clone());
+        dl.download_and_save(path)?;
+        assert!(target.exists());
+        std::fs::remove_file(&target)?;
+        dl.download()?;
+
+        let ext = if matches!(key, TrustedKey::Minisign { .. }) {
+            "minisig"
+        } else {
+            "sig"
+        };
+        dl.verify_signature(policy.clone().sidecar(&format!("{{url}}.bad.{}", ext)));
+        let res = dl.download_and_save(path);
+        assert!(
+            matches!(&res, Err(ManicError::SignatureMismatch { key_id }) if *key_id == key.key_id()),
+            "{:?}",
+            res
+        );
+        assert!(!target.exists());
+        // The install and in-memory paths check the same signature
+        let hash = Hash::new_sha256(
+            "0ac1e91826eabd78b1aca342ac11292a7399a2fdf714158298bae1d1bd12390b".to_string(),
+        );
+        let res = dl.download_verify_install(&target, hash);
+        assert!(
+            matches!(&res, Err(ManicError::SignatureMismatch { .. })),
+            "{:?}",
+            res
+        );
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 0);
+        assert!(matches!(
+            dl.download(),
+            Err(ManicError::SignatureMismatch { .. })
+        ));
+
+        dl.verify_signature(policy.clone().sidecar("{url}.missing"));
+        let res = dl.download_and_save(path);
+        assert!(
+            matches!(&res, Err(ManicError::SignatureMissing(_))),
+            "{:?}",
+            res
+        );
+        dl.verify_signature(policy.sidecar("{url}.missing").optional());
+        dl.download_and_save(path)?;
+        assert!(target.exists());
+    }
+    Ok(())
+}
+
+#[test]
+fn local_download_conditional_if_range() -> manic::Result<()> {
+    use warp::http::{Response, StatusCode};
+    // Serves ranges only while `If-Range` matches the current ETag, like a server whose file changed
+    let data = Arc::new(std::fs::read("tests/static/croc.zip")?);
+    let version = Arc::new(AtomicUsize::new(1));
+    // Bump the version right after answering the conditional probe, 0 keeps it
+    let bump = Arc::new(AtomicUsize::new(0));
+    let if_ranges = Arc::new(Mutex::new(Vec::new()));
+    let (current, bumping, seen) = (version.clone(), bump.clone(), if_ranges.clone());
+    let file = warp::path!("croc.zip")
+        .and(warp::header::optional::<String>("range"))
+        .and(warp::header::optional::<String>("if-range"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .map(
+            move |range: Option<String>, if_range: Option<String>, if_none: Option<String>| {
+                let etag = format!("\"v{}\"", current.load(Ordering::SeqCst));
+                let builder = Response::builder()
+                    .header("etag", &etag)
+                    .header("accept-ranges", "bytes");
+                if if_none.as_ref() == Some(&etag) {
+                    return builder
+                        .status(StatusCode::NOT_MODIFIED)
+                        .body(Vec::new())
+                        .unwrap();
+                }
+                let range = range
+                    .as_deref()
+                    .and_then(|x| x.strip_prefix("bytes="))
+                    .and_then(|x| x.split_once('-'))
+                    .map(|(low, hi)| (low.parse::<usize>().unwrap(), hi.parse::<usize>().unwrap()));
+                if range == Some((0, 0)) {
+                    if bumping.load(Ordering::SeqCst) != 0 {
+                        current.fetch_add(1, Ordering::SeqCst);
+                    }
+                } else if range.is_some() {
+                    seen.lock().unwrap().push(if_range.clone());
+                }
+                match range.filter(|_| if_range.as_ref().is_none_or(|x| *x == etag)) {
+                    Some((low, hi)) => builder
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .header(
+                            "content-range",
+                            format!("bytes {}-{}/{}", low, hi, data.len()),
+                        )
+                        .body(data[low..=hi].to_vec())
+                        .unwrap(),
+                    None => builder.body(data.to_vec()).unwrap(),
+                }
+            },
+        );
+    super::spawn_server(warp::serve(file).run(([127, 0, 0, 1], 8050)));
+    std::thread::sleep(Duration::from_secs(3));
+    let url = "http://127.0.0.1:8050/croc.zip";
+    let dl = Downloader::new(url, 4)?;
+    let fresh = dl.download_conditional(None, None)?;
+    assert!(!fresh.not_modified);
+    assert_eq!(fresh.data.unwrap().to_vec().len(), 2251551);
+    assert_eq!(fresh.etag.as_deref(), Some("\"v1\""));
+    let sent = std::mem::take(&mut *if_ranges.lock().unwrap());
+    assert_eq!(sent.len(), dl.chunk_plan().len());
+    assert!(
+        sent.iter().all(|x| x.as_deref() == Some("\"v1\"")),
+        "{:?}",
+        sent
+    );
+    // Nothing is downloaded while the ETag still matches
+    let cached = dl.download_conditional(Some("\"v1\""), None)?;
+    assert!(cached.not_modified);
+    assert!(cached.data.is_none());
+    assert_eq!(cached.etag.as_deref(), Some("\"v1\""));
+    assert!(if_ranges.lock().unwrap().is_empty());
+    // The length comes from the probe's Content-Range, not the one byte it carried
+    let stale = Downloader::new_manual(url, 4, 1000)?;
+    assert!(matches!(
+        stale.download_conditional(None, None),
+        Err(ManicError::SizeMismatch {
+            expected: 1000,
+            actual: 2251551
+        })
+    ));
+    // The file changes between the probe and the chunks, every chunk refuses the new version
+    bump.store(1, Ordering::SeqCst);
+    let err = dl.download_conditional(None, None).unwrap_err();
+    let range_ignored = manic::ErrorCode::RangeIgnored.to_string();
+    match &err {
+        ManicError::RangeIgnored(_) => {}
+        ManicError::MultipleErrors(list) => {
+            assert!(list.lines().all(|x| x.contains(&range_ignored)), "{}", err)
+        }
+        _ => panic!("{}", err),
+    }
+    Ok(())
+}
@@ -1,12 +1,19 @@
 mod local_threaded;
 mod remote_threaded;
 
+use std::future::Future;
+
 pub(crate) fn start_threaded(port: u16, srv: Option<&'static str>, file: Option<&'static str>) {
+    spawn_server(crate::start_server(port, srv, file));
+}
+
+/// Run `server` on a thread of its own, for routes [`crate::start_server`] doesn't cover
+pub(crate) fn spawn_server(server: impl Future<Output = ()> + Send + 'static) {
     std::thread::spawn(move || {
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_io()
             .build()
             .unwrap();
-        rt.block_on(crate::start_server(port, srv, file));
+        rt.block_on(server);
     });
 }